@@ -1,6 +1,6 @@
 //! Custom deserialization for the RXH configuration file.
 
-use std::net::SocketAddr;
+use std::{collections::HashMap, io, num::NonZeroUsize, path::PathBuf, thread, time::Duration};
 
 use serde::{
     de::{self, Visitor},
@@ -9,7 +9,31 @@ use serde::{
     Serialize,
 };
 
-use super::{Action, Algorithm, Backend, Forward, Pattern, Server};
+use super::{
+    Acme,
+    Action,
+    Address,
+    Algorithm,
+    Backend,
+    BackendTls,
+    Cidr,
+    ClientAuth,
+    Compression,
+    ETag,
+    Fallback,
+    Files,
+    Forward,
+    Http2,
+    Http3,
+    Pattern,
+    Protocol,
+    ProxyProtocol,
+    ProxyProtocolVersion,
+    Server,
+    ServerTls,
+    Socket,
+    Udp,
+};
 use crate::sched;
 
 /// See [`one_or_many`] for details.
@@ -54,7 +78,7 @@ where
 }
 
 /// Allows specifying the upstream servers in a proxy configuration as a socket
-/// address or an object containing the address and weight.
+/// address or an object containing the address, weight and TLS options.
 ///
 /// ```toml
 /// [[server]]
@@ -73,22 +97,73 @@ where
 ///     { address = "127.0.0.1:8080", weight = 1 },
 ///     { address = "127.0.0.1:8081", weight = 3 },
 /// ]
+///
+/// [[server]]
+///
+/// listen = "127.0.0.1:8002"
+///
+/// # TLS backend, see [`super::BackendTls`] for all the options.
+/// forward = { address = "127.0.0.1:8443", tls = true, ca = "/etc/rxh/ca.pem" }
 /// ```
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub(super) enum BackendOption {
-    Simple(SocketAddr),
-    Weighted { address: SocketAddr, weight: usize },
+    Simple(Address),
+    Detailed {
+        address: Address,
+        #[serde(default = "super::default::weight")]
+        weight: usize,
+        #[serde(default)]
+        tls: bool,
+        ca: Option<PathBuf>,
+        cert: Option<PathBuf>,
+        key: Option<PathBuf>,
+        sni: Option<String>,
+        #[serde(default = "super::default::verify")]
+        verify: bool,
+        send_proxy_protocol: Option<ProxyProtocolVersion>,
+    },
 }
 
-impl From<BackendOption> for Backend {
-    fn from(value: BackendOption) -> Self {
-        let (address, weight) = match value {
-            BackendOption::Simple(address) => (address, 1),
-            BackendOption::Weighted { address, weight } => (address, weight),
+impl TryFrom<BackendOption> for Backend {
+    type Error = io::Error;
+
+    fn try_from(value: BackendOption) -> Result<Self, Self::Error> {
+        let (address, weight, tls, send_proxy_protocol) = match value {
+            BackendOption::Simple(address) => (address, super::default::weight(), None, None),
+
+            BackendOption::Detailed {
+                address,
+                weight,
+                tls,
+                ca,
+                cert,
+                key,
+                sni,
+                verify,
+                send_proxy_protocol,
+            } => {
+                let tls = if tls {
+                    Some(BackendTls::new(ca, cert, key, sni, verify)?)
+                } else if ca.is_some() || cert.is_some() || key.is_some() || sni.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("TLS options for backend {address} require 'tls = true'"),
+                    ));
+                } else {
+                    None
+                };
+
+                (address, weight, tls, send_proxy_protocol)
+            }
         };
 
-        Self { address, weight }
+        Ok(Self {
+            address,
+            weight,
+            tls,
+            send_proxy_protocol,
+        })
     }
 }
 
@@ -134,19 +209,27 @@ impl From<BackendOption> for Backend {
 ///     { address = "127.0.0.1:8080", weight = 1 },
 ///     { address = "127.0.0.1:8081", weight = 2 },
 /// ]
+/// ```
+///
+/// Backends are kept as [`BackendOption`] until the whole enum is matched
+/// because [`Backend`] conversion reads TLS files and can fail. Errors inside
+/// untagged enums are discarded by [`serde`], so the user would only see "data
+/// did not match any variant" instead of the actual problem.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub(super) enum ForwardOption {
     #[serde(deserialize_with = "one_or_many")]
-    Simple(Vec<Backend>),
+    Simple(Vec<BackendOption>),
     WithAlgorithm {
         algorithm: Algorithm,
-        backends: Vec<Backend>,
+        backends: Vec<BackendOption>,
     },
 }
 
-impl From<ForwardOption> for Forward {
-    fn from(value: ForwardOption) -> Self {
+impl TryFrom<ForwardOption> for Forward {
+    type Error = io::Error;
+
+    fn try_from(value: ForwardOption) -> Result<Self, Self::Error> {
         let (backends, algorithm) = match value {
             ForwardOption::Simple(backends) => (backends, Algorithm::Wrr),
 
@@ -156,16 +239,292 @@ impl From<ForwardOption> for Forward {
             } => (backends, algorithm),
         };
 
+        let backends = backends
+            .into_iter()
+            .map(Backend::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
         let scheduler = sched::make(algorithm, &backends);
 
-        Self {
+        Ok(Self {
             backends,
             algorithm,
             scheduler,
+        })
+    }
+}
+
+/// Static files can be configured with the root directory only or with a
+/// table, see [`Files`].
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub(super) enum FilesOption {
+    Simple(String),
+    Detailed {
+        root: String,
+        #[serde(default)]
+        etag: ETag,
+        #[serde(default)]
+        compression: Compression,
+        #[serde(default = "super::default::index", deserialize_with = "one_or_many")]
+        index: Vec<String>,
+        #[serde(default)]
+        autoindex: bool,
+        #[serde(default)]
+        try_files: Vec<String>,
+        #[serde(default)]
+        fallback: Fallback,
+    },
+}
+
+impl From<FilesOption> for Files {
+    fn from(value: FilesOption) -> Self {
+        match value {
+            FilesOption::Simple(root) => Self {
+                root,
+                etag: ETag::default(),
+                compression: Compression::default(),
+                index: super::default::index(),
+                autoindex: false,
+                try_files: Vec::new(),
+                fallback: Fallback::default(),
+            },
+
+            FilesOption::Detailed {
+                root,
+                etag,
+                compression,
+                index,
+                autoindex,
+                try_files,
+                fallback,
+            } => Self {
+                root,
+                etag,
+                compression,
+                index,
+                autoindex,
+                try_files,
+                fallback,
+            },
+        }
+    }
+}
+
+/// Raw TLS options of a server. Converting them into [`ServerTls`] requires
+/// reading the certificate and key files, which might fail.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ServerTlsOption {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    acme: Option<Acme>,
+    client_auth: Option<ClientAuth>,
+    #[serde(default = "super::default::reload_interval", with = "seconds")]
+    reload_interval: Duration,
+}
+
+impl TryFrom<ServerTlsOption> for ServerTls {
+    type Error = io::Error;
+
+    fn try_from(value: ServerTlsOption) -> Result<Self, Self::Error> {
+        let mut tls = match (value.cert, value.key, value.acme) {
+            (Some(cert), Some(key), None) => ServerTls::new(cert, key, value.client_auth)?,
+
+            (None, None, Some(acme)) => ServerTls::with_acme(acme, value.client_auth)?,
+
+            (_, _, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS 'cert' and 'key' can't be used with 'acme'",
+                ))
+            }
+
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS requires both 'cert' and 'key' or 'acme'",
+                ))
+            }
+        };
+
+        tls.reload_interval = value.reload_interval;
+
+        Ok(tls)
+    }
+}
+
+/// Durations are written in the config file as a number of seconds, which
+/// can be fractional: `reload_interval = 0.5`.
+pub(super) mod seconds {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+
+        Duration::try_from_secs_f64(seconds)
+            .map_err(|_| de::Error::custom(format!("invalid number of seconds: {seconds}")))
+    }
+}
+
+/// Status codes are written as numbers: `fallback = { status = 404 }`.
+pub(super) mod status_code {
+    use http::StatusCode;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(status.as_u16())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+        let status = u16::deserialize(deserializer)?;
+
+        StatusCode::from_u16(status)
+            .map_err(|_| de::Error::custom(format!("invalid status code: {status}")))
+    }
+}
+
+/// Same as [`seconds`] for optional durations.
+pub(super) mod optional_seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::seconds::serialize(duration, serializer),
+            None => serializer.serialize_none(),
         }
     }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Some(super::Seconds::deserialize(deserializer)?.0))
+    }
+}
+
+/// `reuse_port` accepts the number of accept loops or `true`, which uses one
+/// accept loop per CPU. See [`Socket`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReusePortOption {
+    Enabled(bool),
+    AcceptLoops(usize),
+}
+
+pub(super) fn reuse_port<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match ReusePortOption::deserialize(deserializer)? {
+        ReusePortOption::Enabled(false) => Ok(None),
+        ReusePortOption::Enabled(true) => Ok(Some(
+            thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        )),
+        ReusePortOption::AcceptLoops(0) => Err(de::Error::custom(
+            "'reuse_port' needs at least one accept loop",
+        )),
+        ReusePortOption::AcceptLoops(loops) => Ok(Some(loops)),
+    }
+}
+
+/// Trusting nobody would reject every TCP connection, so the list of trusted
+/// PROXY protocol sources can't be empty. See [`ProxyProtocol::trusted`].
+pub(super) fn trusted<'de, D>(deserializer: D) -> Result<Vec<Cidr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let trusted = Vec::<Cidr>::deserialize(deserializer)?;
+
+    if trusted.is_empty() {
+        return Err(de::Error::custom(
+            "'trusted' needs at least one address range",
+        ));
+    }
+
+    Ok(trusted)
 }
 
+/// HTTP/3 can be enabled with the default settings using `http3 = true` or
+/// configured with a table, see [`Http3`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Http3Option {
+    Enabled(bool),
+    Config(Http3),
+}
+
+impl From<Http3Option> for Option<Http3> {
+    fn from(value: Http3Option) -> Self {
+        match value {
+            Http3Option::Enabled(true) => Some(Http3::default()),
+            Http3Option::Enabled(false) => None,
+            Http3Option::Config(http3) => Some(http3),
+        }
+    }
+}
+
+/// The PROXY protocol can be enabled trusting loopback sources with
+/// `proxy_protocol = true` or configured with a table, see [`ProxyProtocol`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxyProtocolOption {
+    Enabled(bool),
+    Config(ProxyProtocol),
+}
+
+impl From<ProxyProtocolOption> for Option<ProxyProtocol> {
+    fn from(value: ProxyProtocolOption) -> Self {
+        match value {
+            ProxyProtocolOption::Enabled(true) => Some(ProxyProtocol::default()),
+            ProxyProtocolOption::Enabled(false) => None,
+            ProxyProtocolOption::Config(proxy_protocol) => Some(proxy_protocol),
+        }
+    }
+}
+
+/// Normalizes the extensions of `mime_types` (case insensitive and with an
+/// optional leading dot) and makes sure that media types can be sent in the
+/// `Content-Type` header. See [`Server::mime_types`].
+fn media_types<E: de::Error>(types: HashMap<String, String>) -> Result<HashMap<String, String>, E> {
+    let mut normalized = HashMap::with_capacity(types.len());
+
+    for (extension, media_type) in types {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+
+        let valid = media_type.split(';').next().is_some_and(|essence| {
+            essence
+                .trim()
+                .split_once('/')
+                .is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+        });
+
+        if extension.is_empty() || !valid || http::HeaderValue::from_str(&media_type).is_err() {
+            return Err(E::custom(format!(
+                "invalid mime type '{media_type}' for extension '{extension}'"
+            )));
+        }
+
+        normalized.insert(extension, media_type);
+    }
+
+    Ok(normalized)
+}
+
+/// Allows using [`seconds`] with [`de::MapAccess::next_value`].
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Seconds(#[serde(with = "seconds")] Duration);
+
 impl<'de> Deserialize<'de> for Server {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -184,12 +543,25 @@ struct ServerVisitor;
 #[serde(field_identifier, rename_all = "lowercase")]
 enum Field {
     Listen,
+    Protocol,
     Match,
     Forward,
     Serve,
     Uri,
     Name,
     Connections,
+    Tls,
+    Http2,
+    Http3,
+    Udp,
+    #[serde(rename = "shutdown_timeout")]
+    ShutdownTimeout,
+    #[serde(rename = "proxy_protocol")]
+    ProxyProtocol,
+    Socket,
+    #[serde(rename = "mime_types")]
+    MimeTypes,
+    Nosniff,
 }
 
 /// Custom errors that can happen while manually deserializing [`Server`].
@@ -247,6 +619,73 @@ enum Error {
 
     /// Couldn't find `match` clause or simple pattern.
     MissingConfig,
+
+    /// Patterns can only skip client authentication when the TLS handshake
+    /// allows clients without certificates. This is incorrect:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:8443"
+    /// tls = { cert = "cert.pem", key = "key.pem", client_auth = { ca = "ca.pem" } }
+    ///
+    /// match = [
+    ///     { uri = "/public", serve = "/var/www", client_auth = false },
+    /// ]
+    /// ```
+    ///
+    /// The default `reject = "alert"` doesn't let anonymous clients connect,
+    /// so `reject = "forbidden"` is required.
+    OptionalClientAuth,
+
+    /// HTTP/3 always uses TLS. This is incorrect:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:8000"
+    /// forward = "127.0.0.1:9000"
+    /// http3 = true
+    /// ```
+    Http3WithoutTls,
+
+    /// TCP and UDP servers forward whole connections or sessions, so they
+    /// need exactly one `forward` pattern, and TLS passthrough servers can
+    /// only use `forward` patterns. This is incorrect:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:5432"
+    /// protocol = "tcp"
+    /// serve = "/home/user/website"
+    /// ```
+    ForwardRequired(Protocol),
+
+    /// Some options only make sense for some protocols. This is incorrect:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:5432"
+    /// protocol = "tcp"
+    /// forward = "127.0.0.1:9000"
+    /// http3 = true
+    /// ```
+    UnsupportedOption(Protocol, &'static str),
+
+    /// UDP backends are reached without TLS or PROXY protocol and can't be
+    /// Unix sockets, and TLS passthrough backends can't use TLS. This is
+    /// incorrect:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:53"
+    /// protocol = "udp"
+    /// forward = { address = "127.0.0.1:5353", tls = true }
+    /// ```
+    UnsupportedBackend(Protocol),
 }
 
 impl std::fmt::Display for Error {
@@ -260,6 +699,35 @@ impl std::fmt::Display for Error {
             }
 
             Error::MissingConfig => "missing 'match' or simple configuration",
+
+            Error::OptionalClientAuth => {
+                "'client_auth = false' in patterns requires TLS client_auth with reject = 'forbidden'"
+            }
+
+            Error::Http3WithoutTls => "'http3' requires 'tls'",
+
+            Error::ForwardRequired(Protocol::TlsPassthrough) => {
+                "protocol 'tls_passthrough' only supports 'forward' patterns"
+            }
+
+            Error::ForwardRequired(protocol) => {
+                return write!(
+                    f,
+                    "protocol '{protocol}' requires a single 'forward' and no 'match'"
+                );
+            }
+
+            Error::UnsupportedOption(protocol, option) => {
+                return write!(f, "'{option}' can't be used with protocol '{protocol}'");
+            }
+
+            Error::UnsupportedBackend(Protocol::Udp) => {
+                "backends of protocol 'udp' must be IP addresses without TLS or PROXY protocol"
+            }
+
+            Error::UnsupportedBackend(protocol) => {
+                return write!(f, "backends of protocol '{protocol}' can't use TLS");
+            }
         };
 
         f.write_str(message)
@@ -277,11 +745,21 @@ impl<'de> Visitor<'de> for ServerVisitor {
     where
         M: de::MapAccess<'de>,
     {
-        let mut listen: Vec<SocketAddr> = vec![];
+        let mut listen: Vec<Address> = vec![];
+        let mut protocol: Option<Protocol> = None;
         let mut patterns: Vec<Pattern> = vec![];
         let mut simple_pattern: Option<Pattern> = None;
         let mut name = None;
+        let mut tls: Option<ServerTls> = None;
+        let mut http2: Option<Http2> = None;
+        let mut http3: Option<Option<Http3>> = None;
+        let mut udp: Option<Udp> = None;
+        let mut socket: Option<Socket> = None;
+        let mut proxy_protocol: Option<Option<ProxyProtocol>> = None;
+        let mut mime_types: Option<HashMap<String, String>> = None;
+        let mut nosniff: Option<bool> = None;
         let mut max_connections = super::default::max_connections();
+        let mut shutdown_timeout = super::default::shutdown_timeout();
         let mut uri = super::default::uri();
 
         while let Some(key) = map.next_key()? {
@@ -291,7 +769,15 @@ impl<'de> Visitor<'de> for ServerVisitor {
                         return Err(de::Error::duplicate_field("listen"));
                     }
 
-                    listen = map.next_value::<OneOrMany<SocketAddr>>()?.into();
+                    listen = map.next_value::<OneOrMany<Address>>()?.into();
+                }
+
+                Field::Protocol => {
+                    if protocol.is_some() {
+                        return Err(de::Error::duplicate_field("protocol"));
+                    }
+
+                    protocol = Some(map.next_value()?);
                 }
 
                 Field::Match => {
@@ -320,6 +806,8 @@ impl<'de> Visitor<'de> for ServerVisitor {
 
                     simple_pattern = Some(Pattern {
                         uri: super::default::uri(),
+                        sni: None,
+                        client_auth: super::default::client_auth(),
                         action: Action::Forward(map.next_value()?),
                     });
                 }
@@ -338,6 +826,8 @@ impl<'de> Visitor<'de> for ServerVisitor {
 
                     simple_pattern = Some(Pattern {
                         uri: super::default::uri(),
+                        sni: None,
+                        client_auth: super::default::client_auth(),
                         action: Action::Serve(map.next_value()?),
                     });
                 }
@@ -359,9 +849,77 @@ impl<'de> Visitor<'de> for ServerVisitor {
                 }
 
                 Field::Connections => max_connections = map.next_value()?,
+
+                Field::ShutdownTimeout => shutdown_timeout = map.next_value::<Seconds>()?.0,
+
+                Field::Tls => {
+                    if tls.is_some() {
+                        return Err(de::Error::duplicate_field("tls"));
+                    }
+
+                    tls = Some(map.next_value()?);
+                }
+
+                Field::Http2 => {
+                    if http2.is_some() {
+                        return Err(de::Error::duplicate_field("http2"));
+                    }
+
+                    http2 = Some(map.next_value()?);
+                }
+
+                Field::Http3 => {
+                    if http3.is_some() {
+                        return Err(de::Error::duplicate_field("http3"));
+                    }
+
+                    http3 = Some(map.next_value::<Http3Option>()?.into());
+                }
+
+                Field::Udp => {
+                    if udp.is_some() {
+                        return Err(de::Error::duplicate_field("udp"));
+                    }
+
+                    udp = Some(map.next_value()?);
+                }
+
+                Field::ProxyProtocol => {
+                    if proxy_protocol.is_some() {
+                        return Err(de::Error::duplicate_field("proxy_protocol"));
+                    }
+
+                    proxy_protocol = Some(map.next_value::<ProxyProtocolOption>()?.into());
+                }
+
+                Field::Socket => {
+                    if socket.is_some() {
+                        return Err(de::Error::duplicate_field("socket"));
+                    }
+
+                    socket = Some(map.next_value()?);
+                }
+
+                Field::MimeTypes => {
+                    if mime_types.is_some() {
+                        return Err(de::Error::duplicate_field("mime_types"));
+                    }
+
+                    mime_types = Some(media_types(map.next_value()?)?);
+                }
+
+                Field::Nosniff => {
+                    if nosniff.is_some() {
+                        return Err(de::Error::duplicate_field("nosniff"));
+                    }
+
+                    nosniff = Some(map.next_value()?);
+                }
             }
         }
 
+        let is_simple = simple_pattern.is_some();
+
         if let Some(mut pattern) = simple_pattern {
             pattern.uri = uri;
             patterns.push(pattern);
@@ -375,11 +933,89 @@ impl<'de> Visitor<'de> for ServerVisitor {
             return Err(de::Error::missing_field("listen"));
         }
 
+        let allows_anonymous = tls.as_ref().is_some_and(ServerTls::rejects_with_forbidden);
+
+        if !allows_anonymous && patterns.iter().any(|pattern| !pattern.client_auth) {
+            return Err(de::Error::custom(Error::OptionalClientAuth));
+        }
+
+        let http3 = http3.flatten();
+
+        if http3.is_some() && tls.is_none() {
+            return Err(de::Error::custom(Error::Http3WithoutTls));
+        }
+
+        let protocol = protocol.unwrap_or_default();
+
+        let proxy_protocol = proxy_protocol.flatten();
+
+        if protocol != Protocol::TlsPassthrough && patterns.iter().any(|p| p.sni.is_some()) {
+            return Err(de::Error::custom(Error::UnsupportedOption(protocol, "sni")));
+        }
+
+        if protocol != Protocol::Http {
+            let mut forwards = Vec::new();
+
+            for pattern in &patterns {
+                match &pattern.action {
+                    Action::Forward(forward) => forwards.push(forward),
+                    Action::Serve(_) => {
+                        return Err(de::Error::custom(Error::ForwardRequired(protocol)))
+                    }
+                }
+            }
+
+            if !is_simple && protocol != Protocol::TlsPassthrough {
+                return Err(de::Error::custom(Error::ForwardRequired(protocol)));
+            }
+
+            let unsupported = match protocol {
+                _ if http3.is_some() => Some("http3"),
+                Protocol::Udp | Protocol::TlsPassthrough if tls.is_some() => Some("tls"),
+                Protocol::Udp if proxy_protocol.is_some() => Some("proxy_protocol"),
+                _ if mime_types.is_some() => Some("mime_types"),
+                _ if nosniff.is_some() => Some("nosniff"),
+                _ => None,
+            };
+
+            if let Some(option) = unsupported {
+                return Err(de::Error::custom(Error::UnsupportedOption(
+                    protocol, option,
+                )));
+            }
+
+            let supported_backend = |backend: &Backend| match protocol {
+                Protocol::Udp => {
+                    matches!(backend.address, Address::Tcp(_))
+                        && backend.tls.is_none()
+                        && backend.send_proxy_protocol.is_none()
+                }
+                Protocol::TlsPassthrough => backend.tls.is_none(),
+                _ => true,
+            };
+
+            let mut backends = forwards.iter().flat_map(|forward| &forward.backends);
+
+            if !backends.all(supported_backend) {
+                return Err(de::Error::custom(Error::UnsupportedBackend(protocol)));
+            }
+        }
+
         Ok(Server {
             listen,
+            protocol,
             patterns,
             max_connections,
+            shutdown_timeout,
             name,
+            tls,
+            http2: http2.unwrap_or_default(),
+            http3,
+            udp: udp.unwrap_or_default(),
+            proxy_protocol,
+            socket: socket.unwrap_or_default(),
+            mime_types: mime_types.unwrap_or_default(),
+            nosniff: nosniff.unwrap_or_default(),
             log_name: String::from("unnamed"),
         })
     }
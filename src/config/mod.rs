@@ -2,12 +2,16 @@
 
 mod deser;
 
-use std::{fmt::Debug, net::SocketAddr};
+use std::{collections::HashMap, fmt::Debug, io, path::PathBuf, time::Duration};
 
-use deser::{BackendOption, ForwardOption};
+use deser::{BackendOption, FilesOption, ForwardOption, ServerTlsOption};
 use serde::{Deserialize, Serialize};
 
-use crate::sched::{self, Scheduler};
+pub use crate::net::{Address, Cidr};
+use crate::{
+    sched::{self, Scheduler},
+    tls,
+};
 
 /// This struct represents the entire configuration file, which describes a list
 /// of servers and their particular configuration options. For example, this
@@ -62,8 +66,14 @@ pub struct Config {
 /// details.
 #[derive(Serialize, Debug, Clone)]
 pub struct Server {
-    /// Socket addresses where this server listens.
-    pub listen: Vec<SocketAddr>,
+    /// Socket addresses where this server listens, TCP or Unix
+    /// (`unix:/path`).
+    pub listen: Vec<Address>,
+
+    /// Whether connections are parsed as HTTP or forwarded as they are. See
+    /// [`Protocol`].
+    #[serde(default)]
+    pub protocol: Protocol,
 
     /// Patterns that this server should match against.
     #[serde(rename = "match")]
@@ -76,12 +86,788 @@ pub struct Server {
     /// Optional server name to show in logs and forwarded requests.
     pub name: Option<String>,
 
+    /// Seconds that connections have to finish their requests after the
+    /// server starts shutting down. Idle connections are closed right away,
+    /// and whatever is still open after this time is closed as well.
+    #[serde(default = "default::shutdown_timeout", with = "deser::seconds")]
+    pub shutdown_timeout: Duration,
+
+    /// TLS configuration. When set, the server only accepts TLS connections.
+    pub tls: Option<ServerTls>,
+
+    /// HTTP/2 settings. See [`Http2`].
+    pub http2: Http2,
+
+    /// Optional HTTP/3 listener. See [`Http3`].
+    pub http3: Option<Http3>,
+
+    /// UDP sessions settings, only used with [`Protocol::Udp`]. See [`Udp`].
+    pub udp: Udp,
+
+    /// Reads the client address from PROXY protocol headers sent by a load
+    /// balancer. See [`ProxyProtocol`].
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// Options of the listening sockets, accepted connections and
+    /// connections to backends. See [`Socket`].
+    pub socket: Socket,
+
+    /// Media types of static files by extension, which take precedence over
+    /// the builtin ones:
+    ///
+    /// ```toml
+    /// [[server]]
+    ///
+    /// listen = "127.0.0.1:8000"
+    /// serve = "/var/www"
+    /// mime_types = { md = "text/plain", dat = "application/x-custom" }
+    /// ```
+    ///
+    /// Text types are sent with `charset=utf-8` unless they already have a
+    /// charset.
+    pub mime_types: HashMap<String, String>,
+
+    /// Sends `X-Content-Type-Options: nosniff` with static files so that
+    /// browsers don't guess a different media type than the one we send.
+    pub nosniff: bool,
+
     /// Log name inlcudes the IP address of the listening socket and also the
     /// optional name set by the user.
     #[serde(skip)]
     pub log_name: String,
 }
 
+/// Protocol spoken by the clients of a [`Server`]. By default servers speak
+/// HTTP, but they can also work as layer 4 load balancers for anything that
+/// runs on top of TCP, like databases:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:5432"
+/// protocol = "tcp"
+/// forward = ["10.0.0.2:5432", "10.0.0.3:5432"]
+/// ```
+///
+/// Datagrams can be load balanced as well, for DNS, syslog and the like:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:53"
+/// protocol = "udp"
+/// forward = ["10.0.0.2:53", "10.0.0.3:53"]
+/// ```
+///
+/// TLS connections can be forwarded without decrypting them. The backend is
+/// chosen by the server name that the client sends in the TLS handshake (SNI),
+/// see [`Pattern::sni`]:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:443"
+/// protocol = "tls_passthrough"
+///
+/// match = [
+///     { sni = "api.example.com", forward = ["10.0.0.2:443", "10.0.0.3:443"] },
+///     { sni = "*.example.com", forward = "10.0.0.4:443" },
+///     { forward = "10.0.0.5:443" },
+/// ]
+/// ```
+///
+/// TLS passthrough servers can only use `forward` patterns and can't use
+/// `tls` or `http3`. Their backends can't use TLS either, since the traffic is
+/// already encrypted by the client.
+///
+/// TCP and UDP servers need a single `forward` and can't use `match`, `serve`
+/// or `http3`. If `tls` is set on a TCP server, the TLS connection is
+/// terminated and the decrypted stream is forwarded. UDP servers can't use
+/// `tls` or `proxy_protocol`, and their backends must be IP addresses without
+/// TLS or PROXY protocol. A TCP and a UDP server can listen on the same
+/// address.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// HTTP/1.1, HTTP/2 and optionally HTTP/3.
+    #[default]
+    Http,
+
+    /// Raw TCP streams.
+    Tcp,
+
+    /// UDP datagrams, see [`Udp`].
+    Udp,
+
+    /// TLS connections routed by SNI and forwarded without terminating TLS.
+    #[serde(rename = "tls_passthrough")]
+    TlsPassthrough,
+}
+
+impl Protocol {
+    /// Returns `true` if servers of this protocol use UDP sockets instead of
+    /// listeners.
+    pub(crate) fn is_datagram(self) -> bool {
+        self == Self::Udp
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Http => "http",
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::TlsPassthrough => "tls_passthrough",
+        })
+    }
+}
+
+/// UDP has no connections, so servers with `protocol = "udp"` keep a session
+/// for each client address. The first datagram of a client picks a backend,
+/// and the following ones, as well as the replies of the backend, go through
+/// the same session until it has been idle for `session_timeout` seconds:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:514"
+/// protocol = "udp"
+/// forward = "10.0.0.2:514"
+///
+/// [server.udp]
+///
+/// session_timeout = 60
+/// ```
+///
+/// Sessions count towards `max_connections`. Datagrams of new clients are
+/// dropped while the limit is reached.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Udp {
+    /// Seconds without datagrams in any direction after which a session is
+    /// closed.
+    #[serde(default = "default::session_timeout", with = "deser::seconds")]
+    pub session_timeout: Duration,
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Self {
+            session_timeout: default::session_timeout(),
+        }
+    }
+}
+
+/// TLS configuration of a [`Server`]. The certificate chain and private key
+/// are PEM files:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:443"
+/// forward = "127.0.0.1:8080"
+/// tls = { cert = "/etc/rxh/example.com.pem", key = "/etc/rxh/example.com.key" }
+/// ```
+///
+/// Client certificates can be required as well, see [`ClientAuth`]:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:8443"
+///
+/// match = [
+///     { uri = "/public", serve = "/var/www", client_auth = false },
+///     { uri = "/", forward = "127.0.0.1:8080" },
+/// ]
+///
+/// [server.tls]
+///
+/// cert = "/etc/rxh/internal.pem"
+/// key = "/etc/rxh/internal.key"
+///
+/// [server.tls.client_auth]
+///
+/// ca = "/etc/rxh/clients-ca.pem"
+/// reject = "forbidden"
+/// headers = { subject = "X-Client-DN" }
+/// ```
+///
+/// Certificate and key files are checked for changes every `reload_interval`
+/// seconds (5 by default, `0` disables it) and loaded again when they are
+/// modified, see [`tls::server::Acceptor::watch_certificate`].
+///
+/// Instead of `cert` and `key`, certificates can be obtained automatically
+/// from an ACME certificate authority such as Let's Encrypt, see [`Acme`]:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:443"
+/// forward = "127.0.0.1:8080"
+///
+/// [server.tls.acme]
+///
+/// domains = ["example.com", "www.example.com"]
+/// contact = ["mailto:admin@example.com"]
+/// ```
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "ServerTlsOption")]
+pub struct ServerTls {
+    /// PEM file containing the certificate chain of the server. When using
+    /// [`Acme`] this file is located in the state directory.
+    pub cert: PathBuf,
+
+    /// PEM file containing the private key of the server. When using [`Acme`]
+    /// this file is located in the state directory.
+    pub key: PathBuf,
+
+    /// Automatic certificate provisioning.
+    pub acme: Option<Acme>,
+
+    /// Client certificate authentication (mutual TLS).
+    pub client_auth: Option<ClientAuth>,
+
+    /// How often the certificate and key files are checked for changes.
+    #[serde(with = "deser::seconds")]
+    pub reload_interval: Duration,
+
+    /// Acceptor built from the options above.
+    #[serde(skip)]
+    pub(crate) acceptor: tls::server::Acceptor,
+}
+
+impl ServerTls {
+    /// Loads the certificate and key of the server and builds the client
+    /// verifier if needed. See [`tls::server::Acceptor`].
+    pub fn new(
+        cert: PathBuf,
+        key: PathBuf,
+        client_auth: Option<ClientAuth>,
+    ) -> Result<Self, io::Error> {
+        if let Some(client_auth) = &client_auth {
+            client_auth.headers.validate()?;
+        }
+
+        let acceptor = tls::server::Acceptor::new(&cert, &key, client_auth.as_ref())?;
+
+        Ok(Self {
+            cert,
+            key,
+            acme: None,
+            client_auth,
+            reload_interval: default::reload_interval(),
+            acceptor,
+        })
+    }
+
+    /// Same as [`ServerTls::new`] but certificates are obtained with ACME and
+    /// stored in [`Acme::state`]. The server can start without certificates,
+    /// TLS handshakes fail until the first one is issued.
+    pub fn with_acme(acme: Acme, client_auth: Option<ClientAuth>) -> Result<Self, io::Error> {
+        if let Some(client_auth) = &client_auth {
+            client_auth.headers.validate()?;
+        }
+
+        acme.validate()?;
+
+        let acceptor = tls::server::Acceptor::with_acme(&acme, client_auth.as_ref())?;
+
+        Ok(Self {
+            cert: acme.cert_path(),
+            key: acme.key_path(),
+            acme: Some(acme),
+            client_auth,
+            reload_interval: default::reload_interval(),
+            acceptor,
+        })
+    }
+
+    /// Keeps the certificate and the ACME manager of `previous` if both use
+    /// ACME with the same settings. Otherwise reloading the configuration
+    /// would cancel orders in progress and start new ones.
+    pub(crate) fn keep_acme(&mut self, previous: &Self) {
+        if self.acme.is_some() && self.acme == previous.acme {
+            self.acceptor.keep_acme(&previous.acceptor);
+        }
+    }
+
+    /// Returns `true` if clients without a verified certificate get a `403`
+    /// response instead of a TLS alert.
+    pub(crate) fn rejects_with_forbidden(&self) -> bool {
+        self.client_auth
+            .as_ref()
+            .is_some_and(|client_auth| client_auth.reject == Reject::Forbidden)
+    }
+}
+
+impl Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerTls")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .field("acme", &self.acme)
+            .field("client_auth", &self.client_auth)
+            .field("reload_interval", &self.reload_interval)
+            .finish()
+    }
+}
+
+/// Client certificate authentication options. Clients must present a
+/// certificate signed by `ca`, and the verified identity of the client is
+/// forwarded to upstream servers, see [`IdentityHeaders`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientAuth {
+    /// PEM file containing the certificate authorities that sign client
+    /// certificates.
+    pub ca: PathBuf,
+
+    /// What to do with clients that don't send a certificate.
+    #[serde(default)]
+    pub reject: Reject,
+
+    /// Headers used for sending the client identity to upstream servers.
+    #[serde(default)]
+    pub headers: IdentityHeaders,
+}
+
+/// Options for obtaining and renewing certificates with the ACME protocol
+/// ([RFC 8555](https://www.rfc-editor.org/rfc/rfc8555)). Let's Encrypt is used
+/// by default, but any ACME server can be configured. For example, a local
+/// [Pebble](https://github.com/letsencrypt/pebble) instance for testing:
+///
+/// ```toml
+/// [server.tls.acme]
+///
+/// domains = ["test.localhost"]
+/// directory = "https://127.0.0.1:14000/dir"
+/// ca = "/etc/pebble/pebble.minica.pem"
+/// challenge = "http-01"
+/// state = "/tmp/rxh-acme"
+/// ```
+///
+/// The `http-01` challenge is answered by any RXH server listening on port 80,
+/// so the config file must contain one. The `tls-alpn-01` challenge is
+/// answered by this server and requires it to listen on port 443.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Acme {
+    /// Domains included in the certificate. The first one is also used for
+    /// naming the files stored in the state directory.
+    pub domains: Vec<String>,
+
+    /// Contact URLs for the account, usually `mailto:` addresses.
+    #[serde(default)]
+    pub contact: Vec<String>,
+
+    /// URL of the ACME directory resource.
+    #[serde(default = "default::acme_directory")]
+    pub directory: String,
+
+    /// PEM file with the CA that signs the certificate of the ACME server.
+    /// The Mozilla root certificates are used when not set.
+    pub ca: Option<PathBuf>,
+
+    /// Challenge used for proving that we control the domains.
+    #[serde(default)]
+    pub challenge: Challenge,
+
+    /// Directory where the account key, certificates and keys are stored.
+    #[serde(default = "default::acme_state")]
+    pub state: PathBuf,
+
+    /// Certificates are renewed this number of days before they expire.
+    #[serde(default = "default::renew_before")]
+    pub renew_before: u64,
+}
+
+impl Acme {
+    /// Path of the issued certificate chain.
+    pub fn cert_path(&self) -> PathBuf {
+        self.state.join(&self.domains[0]).join("cert.pem")
+    }
+
+    /// Path of the private key that belongs to the issued certificate.
+    pub fn key_path(&self) -> PathBuf {
+        self.state.join(&self.domains[0]).join("key.pem")
+    }
+
+    /// Path of the private key that identifies our ACME account.
+    pub fn account_key_path(&self) -> PathBuf {
+        self.state.join("account.key")
+    }
+
+    /// Makes sure that at least one domain is configured and that all of them
+    /// are valid DNS names. Wildcards are not allowed because they require
+    /// the `dns-01` challenge.
+    fn validate(&self) -> Result<(), io::Error> {
+        if self.domains.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ACME requires at least one domain",
+            ));
+        }
+
+        for domain in &self.domains {
+            let is_dns_name = matches!(
+                rustls::pki_types::ServerName::try_from(domain.as_str()),
+                Ok(rustls::pki_types::ServerName::DnsName(_))
+            );
+
+            if !is_dns_name || domain.starts_with("*.") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{domain}' is not a valid ACME domain"),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// ACME challenge types, see [`Acme`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Challenge {
+    /// The ACME server requests a token over plain HTTP on port 80.
+    #[serde(rename = "http-01")]
+    Http01,
+
+    /// The ACME server performs a TLS handshake on port 443 negotiating the
+    /// `acme-tls/1` protocol and expects a special certificate.
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// How unauthenticated clients are rejected. Clients that send a certificate
+/// which can't be verified always get a TLS alert.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Reject {
+    /// The TLS handshake fails, clients can't even send requests.
+    #[default]
+    Alert,
+
+    /// The TLS handshake completes but requests get an `HTTP 403 Forbidden`
+    /// response unless the matching [`Pattern`] has `client_auth = false`.
+    Forbidden,
+}
+
+/// Names of the headers that carry the verified client identity to upstream
+/// servers. Headers with these names sent by clients are always removed, so
+/// backends can trust them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityHeaders {
+    /// Distinguished name of the certificate subject.
+    #[serde(default = "default::subject_header")]
+    pub subject: String,
+
+    /// Subject alternative names of the certificate.
+    #[serde(default = "default::san_header")]
+    pub san: String,
+
+    /// SHA-256 fingerprint of the certificate.
+    #[serde(default = "default::fingerprint_header")]
+    pub fingerprint: String,
+}
+
+impl Default for IdentityHeaders {
+    fn default() -> Self {
+        Self {
+            subject: default::subject_header(),
+            san: default::san_header(),
+            fingerprint: default::fingerprint_header(),
+        }
+    }
+}
+
+impl IdentityHeaders {
+    /// Makes sure that all the names are valid HTTP header names.
+    fn validate(&self) -> Result<(), io::Error> {
+        for name in [&self.subject, &self.san, &self.fingerprint] {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{name}' is not a valid header name"),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Every server accepts both HTTP/1.1 and HTTP/2 on the same socket. Plain
+/// TCP listeners detect HTTP/2 with prior knowledge (h2c) by looking at the
+/// connection preface, TLS listeners negotiate `h2` with ALPN. The protocol
+/// can be tuned for each server:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:443"
+/// forward = "127.0.0.1:8080"
+///
+/// [server.http2]
+///
+/// max_concurrent_streams = 100
+/// initial_stream_window_size = 1048576
+/// initial_connection_window_size = 4194304
+/// keepalive_interval = 30
+/// keepalive_timeout = 10
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Http2 {
+    /// Maximum number of streams that a client can open concurrently on a
+    /// single connection.
+    #[serde(default = "default::max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+
+    /// Flow control window of each stream in bytes. Uses the HTTP/2 default
+    /// if not set.
+    pub initial_stream_window_size: Option<u32>,
+
+    /// Flow control window of the entire connection in bytes. Uses the
+    /// HTTP/2 default if not set.
+    pub initial_connection_window_size: Option<u32>,
+
+    /// Adjusts the window sizes automatically based on the measured
+    /// bandwidth-delay product. Fixed window sizes are ignored if enabled.
+    #[serde(default)]
+    pub adaptive_window: bool,
+
+    /// Seconds between `PING` frames sent to the client to keep idle
+    /// connections alive. `0` disables pings.
+    #[serde(default, with = "deser::seconds")]
+    pub keepalive_interval: Duration,
+
+    /// Seconds to wait for the `PING` acknowledgement before closing the
+    /// connection.
+    #[serde(default = "default::keepalive_timeout", with = "deser::seconds")]
+    pub keepalive_timeout: Duration,
+}
+
+impl Default for Http2 {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: default::max_concurrent_streams(),
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+            keepalive_interval: Duration::ZERO,
+            keepalive_timeout: default::keepalive_timeout(),
+        }
+    }
+}
+
+/// HTTP/3 runs on top of QUIC, so servers with TLS can also listen on the same
+/// port using UDP. Clients learn about it through the `Alt-Svc` header sent
+/// on responses to HTTP/1.1 and HTTP/2 requests:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:443"
+/// forward = "127.0.0.1:8080"
+/// tls = { cert = "/etc/rxh/example.com.pem", key = "/etc/rxh/example.com.key" }
+/// http3 = true
+/// ```
+///
+/// The defaults can be changed using a table instead:
+///
+/// ```toml
+/// [server.http3]
+///
+/// max_concurrent_streams = 100
+/// idle_timeout = 30
+/// max_age = 86400
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Http3 {
+    /// Maximum number of requests that a client can send concurrently on a
+    /// single connection.
+    #[serde(default = "default::http3_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+
+    /// Seconds without any activity before a connection is closed.
+    #[serde(default = "default::idle_timeout", with = "deser::seconds")]
+    pub idle_timeout: Duration,
+
+    /// Seconds that clients can remember the `Alt-Svc` advertisement.
+    #[serde(default = "default::max_age")]
+    pub max_age: u64,
+}
+
+impl Default for Http3 {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: default::http3_max_concurrent_streams(),
+            idle_timeout: default::idle_timeout(),
+            max_age: default::max_age(),
+        }
+    }
+}
+
+/// Low level options of the sockets used by a [`Server`]. The defaults are
+/// the same as the operating system defaults, except for the backlog:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "[::]:8000"
+/// forward = "127.0.0.1:8080"
+///
+/// [server.socket]
+///
+/// backlog = 4096
+/// tcp_nodelay = true
+/// keepalive = { time = 60, interval = 10, retries = 5 }
+/// reuse_port = 4
+/// ipv6_only = true
+///
+/// # Connections to backends.
+/// [server.socket.upstream]
+///
+/// tcp_nodelay = true
+/// keepalive = { time = 30 }
+/// ```
+///
+/// With `reuse_port`, each TCP address is bound multiple times using
+/// `SO_REUSEPORT` and every socket gets its own accept loop, so the kernel
+/// spreads incoming connections over the worker threads. `reuse_port = true`
+/// uses one accept loop per CPU. See [`crate::Master`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Socket {
+    /// Maximum number of connections waiting in the accept queue.
+    #[serde(default = "default::backlog")]
+    pub backlog: u32,
+
+    /// Number of sockets bound to each TCP address with `SO_REUSEPORT`, or
+    /// `None` if the option is not set. Only supported on Unix.
+    #[serde(default, deserialize_with = "deser::reuse_port")]
+    pub reuse_port: Option<usize>,
+
+    /// Sets `IPV6_V6ONLY` on IPv6 listeners. When disabled, listening on
+    /// `[::]` accepts IPv4 clients as well.
+    pub ipv6_only: Option<bool>,
+
+    /// Options of accepted TCP connections.
+    #[serde(flatten)]
+    pub accepted: TcpOptions,
+
+    /// Options of TCP connections to backends.
+    #[serde(default)]
+    pub upstream: TcpOptions,
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Self {
+            backlog: default::backlog(),
+            reuse_port: None,
+            ipv6_only: None,
+            accepted: TcpOptions::default(),
+            upstream: TcpOptions::default(),
+        }
+    }
+}
+
+impl Socket {
+    /// Number of accept loops for each address of the server.
+    pub fn accept_loops(&self) -> usize {
+        self.reuse_port.unwrap_or(1)
+    }
+
+    /// Whether sockets bound with `self` and `other` are the same. Options of
+    /// connections can change at any time, but these can't change without
+    /// binding the listener again.
+    #[cfg(not(unix))]
+    pub(crate) fn binds_like(&self, other: &Self) -> bool {
+        self.backlog == other.backlog
+            && self.reuse_port.is_some() == other.reuse_port.is_some()
+            && self.ipv6_only == other.ipv6_only
+    }
+}
+
+/// Options of connected TCP sockets. They are ignored for Unix sockets.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+
+    /// Enables TCP keepalive probes. See [`Keepalive`].
+    pub keepalive: Option<Keepalive>,
+}
+
+/// TCP keepalive timings in seconds. Options that are not set keep the
+/// operating system defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent (`TCP_KEEPIDLE`).
+    #[serde(default, with = "deser::optional_seconds")]
+    pub time: Option<Duration>,
+
+    /// Time between probes (`TCP_KEEPINTVL`).
+    #[serde(default, with = "deser::optional_seconds")]
+    pub interval: Option<Duration>,
+
+    /// Unanswered probes before the connection is closed (`TCP_KEEPCNT`).
+    pub retries: Option<u32>,
+}
+
+/// Servers behind load balancers that work at the TCP level only see the
+/// address of the balancer. With the PROXY protocol the balancer sends the
+/// address of the original client at the beginning of each connection, which
+/// is then used in logs and in the `Forwarded` header. Versions 1 and 2 are
+/// both accepted:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "0.0.0.0:8000"
+/// forward = "127.0.0.1:8080"
+/// proxy_protocol = true
+/// ```
+///
+/// When enabled, every connection must start with a PROXY protocol header, so
+/// clients can't connect directly. Anyone that can reach the server could
+/// send a fake address, so only loopback addresses are trusted by default.
+/// Balancers running on other hosts have to be listed explicitly, and the
+/// list can't be empty:
+///
+/// ```toml
+/// [server.proxy_protocol]
+///
+/// trusted = ["10.0.0.0/8", "2001:db8::/32"]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyProtocol {
+    /// Addresses that can send PROXY protocol headers. Connections from other
+    /// addresses are closed. Defaults to `127.0.0.0/8` and `::1`. Unix
+    /// sockets are always trusted.
+    #[serde(default = "default::trusted", deserialize_with = "deser::trusted")]
+    pub trusted: Vec<Cidr>,
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        Self {
+            trusted: default::trusted(),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    /// Returns `true` if `peer` is allowed to send PROXY protocol headers.
+    pub fn trusts(&self, peer: &Address) -> bool {
+        match peer {
+            Address::Tcp(address) => self.trusted.iter().any(|cidr| cidr.contains(address.ip())),
+            Address::Unix(_) => true,
+        }
+    }
+}
+
 /// This is a single element of a `match` list in the configuration of a server.
 /// See [`Server`] and [`deser`] module.
 ///
@@ -101,11 +887,45 @@ pub struct Pattern {
     #[serde(default = "default::uri")]
     pub uri: String,
 
+    /// Server name to match against when the server uses
+    /// [`Protocol::TlsPassthrough`]. Names starting with `*.` match any
+    /// subdomain. Patterns without `sni` match all connections, including
+    /// those that don't send a server name.
+    pub sni: Option<String>,
+
+    /// Whether requests matching this pattern need a verified client
+    /// certificate. Only relevant if the server has [`ClientAuth`] configured
+    /// with [`Reject::Forbidden`], see [`ServerTls`].
+    #[serde(default = "default::client_auth")]
+    pub client_auth: bool,
+
     /// Action to execute if this pattern matches the request.
     #[serde(flatten)]
     pub action: Action,
 }
 
+impl Pattern {
+    /// Returns `true` if a TLS connection for `server_name` matches the
+    /// [`Pattern::sni`] of this pattern. Names are compared ignoring case.
+    pub(crate) fn matches_sni(&self, server_name: Option<&str>) -> bool {
+        let (Some(sni), Some(server_name)) = (&self.sni, server_name) else {
+            return self.sni.is_none();
+        };
+
+        // Wildcards keep the dot, "*.example.com" matches ".example.com"
+        // suffixes with at least one more character in front.
+        match sni.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => {
+                let start = server_name.len().saturating_sub(suffix.len());
+                start > 0
+                    && server_name.is_char_boundary(start)
+                    && server_name[start..].eq_ignore_ascii_case(suffix)
+            }
+            _ => server_name.eq_ignore_ascii_case(sni),
+        }
+    }
+}
+
 /// One element in the "forward" list. This represents an upstream server and
 /// when multiple of them are present load balancing has to be performed.
 ///
@@ -119,15 +939,138 @@ pub struct Pattern {
 /// ]
 /// ```
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(from = "BackendOption")]
+#[serde(try_from = "BackendOption")]
 pub struct Backend {
-    /// Address of the upstream server.
-    pub address: SocketAddr,
+    /// Address of the upstream server, TCP or Unix (`unix:/path`).
+    pub address: Address,
 
     /// Some algorithms such as WRR (Weighted Round Robin) require each server
     /// to define a weight. For example, a server with 4 cores can have a weight
     /// of 1 while a server with 8 cores can have a weight of 2.
     pub weight: usize,
+
+    /// When set, connections to this backend are encrypted with TLS.
+    #[serde(flatten)]
+    pub tls: Option<BackendTls>,
+
+    /// Sends a PROXY protocol header with the address of the client at the
+    /// beginning of each connection, see [`ProxyProtocolVersion`].
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// Version of the PROXY protocol headers sent to a [`Backend`]. Useful for
+/// backends that need the address of the client but don't read the
+/// `Forwarded` header, or for tunnels of upgraded connections:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8000"
+/// forward = { address = "127.0.0.1:8080", send_proxy_protocol = "v2" }
+/// ```
+///
+/// The header is sent before anything else, including the TLS handshake if
+/// the backend uses TLS. See also [`ProxyProtocol`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// Human readable header.
+    V1,
+
+    /// Binary header.
+    V2,
+}
+
+/// TLS options for connecting to a [`Backend`]. All the options are written
+/// in the same object as the backend address:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8000"
+/// forward = [
+///     { address = "10.0.0.2:443", tls = true, sni = "api.example.com" },
+///     { address = "10.0.0.3:8443", tls = true, ca = "/etc/rxh/ca.pem" },
+///     { address = "10.0.0.4:8443", tls = true, verify = false },
+/// ]
+///
+/// # Mutual TLS, the backend requires a client certificate.
+///
+/// [[server]]
+///
+/// listen = "127.0.0.1:8001"
+/// forward = {
+///     address = "10.0.0.5:8443",
+///     tls = true,
+///     ca = "/etc/rxh/internal-ca.pem",
+///     cert = "/etc/rxh/client.pem",
+///     key = "/etc/rxh/client.key",
+/// }
+/// ```
+#[derive(Serialize, Clone)]
+pub struct BackendTls {
+    /// PEM file containing the certificate authorities used to verify the
+    /// backend certificate. Mozilla's root certificates are used if not set.
+    pub ca: Option<PathBuf>,
+
+    /// PEM file containing the client certificate chain for mutual TLS.
+    pub cert: Option<PathBuf>,
+
+    /// PEM file containing the private key of the client certificate.
+    pub key: Option<PathBuf>,
+
+    /// Server name sent in the SNI extension and checked against the backend
+    /// certificate. Defaults to the IP address of the backend.
+    pub sni: Option<String>,
+
+    /// Whether the backend certificate should be verified. Only disable this
+    /// for testing, anyone could impersonate the backend otherwise.
+    pub verify: bool,
+
+    /// Client configuration built from the options above.
+    #[serde(skip)]
+    pub(crate) connector: tls::client::Connector,
+}
+
+impl BackendTls {
+    /// Validates the options and loads all the certificates and keys needed
+    /// for connecting to the backend. See [`tls::client::Connector`].
+    pub fn new(
+        ca: Option<PathBuf>,
+        cert: Option<PathBuf>,
+        key: Option<PathBuf>,
+        sni: Option<String>,
+        verify: bool,
+    ) -> Result<Self, io::Error> {
+        let connector = tls::client::Connector::new(
+            ca.as_deref(),
+            cert.as_deref(),
+            key.as_deref(),
+            sni.as_deref(),
+            verify,
+        )?;
+
+        Ok(Self {
+            ca,
+            cert,
+            key,
+            sni,
+            verify,
+            connector,
+        })
+    }
+}
+
+impl Debug for BackendTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackendTls")
+            .field("ca", &self.ca)
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .field("sni", &self.sni)
+            .field("verify", &self.verify)
+            .finish()
+    }
 }
 
 /// Algorithm that should be used for load balancing. For now we only implement
@@ -172,7 +1115,7 @@ pub enum Algorithm {
 /// inside a [`Scheduler`]. We'll leave it here to match the config file and
 /// keep it symmetric.
 #[derive(Serialize, Deserialize)]
-#[serde(from = "ForwardOption")]
+#[serde(try_from = "ForwardOption")]
 pub struct Forward {
     /// Upstream servers.
     pub backends: Vec<Backend>,
@@ -198,7 +1141,7 @@ impl Clone for Forward {
     fn clone(&self) -> Self {
         Self {
             backends: self.backends.clone(),
-            algorithm: self.algorithm.clone(),
+            algorithm: self.algorithm,
             scheduler: sched::make(self.algorithm, &self.backends),
         }
     }
@@ -226,12 +1169,164 @@ pub enum Action {
     Forward(Forward),
 
     /// Serve static files from a root directory.
-    Serve(String),
+    Serve(Files),
+}
+
+/// Static files configuration. The simple syntax only sets the root
+/// directory, the table syntax accepts the rest of the options:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8000"
+///
+/// match = [
+///     { uri = "/assets", serve = "/var/www/assets" },
+///     { uri = "/", serve = { root = "/var/www", etag = "hash" } },
+/// ]
+///
+/// [[server]]
+///
+/// listen = "127.0.0.1:8001"
+/// serve = { root = "/srv/downloads", index = ["index.html", "index.htm"], autoindex = true }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "FilesOption")]
+pub struct Files {
+    /// Directory that contains the files.
+    pub root: String,
+
+    /// How entity tags are generated, see [`ETag`].
+    pub etag: ETag,
+
+    /// Content codings, see [`Compression`].
+    pub compression: Compression,
+
+    /// Files that are sent when a directory is requested, the first one that
+    /// exists wins. Defaults to `index.html`.
+    pub index: Vec<String>,
+
+    /// List the content of directories that have no index file. Hidden files
+    /// are never listed.
+    pub autoindex: bool,
+
+    /// Candidates that are tried in order instead of the request path, see
+    /// [`Fallback`]. Empty by default.
+    pub try_files: Vec<String>,
+
+    /// What to do when none of [`Files::try_files`] exists.
+    pub fallback: Fallback,
+}
+
+/// Single page apps route on the client, so any path that is not a file
+/// should get the app itself. Like `try_files` in nginx, candidates are
+/// checked in order and `$uri` is replaced by the request path. Candidates
+/// that end with a slash must be directories, which are served through their
+/// index files:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8000"
+/// serve = { root = "/var/www/app", try_files = ["$uri", "$uri/", "/index.html"] }
+/// ```
+///
+/// If none of them exists the response is a 404 by default. It can be any
+/// other status code or the request can be forwarded to a backend instead:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8001"
+///
+/// [server.serve]
+///
+/// root = "/var/www/static"
+/// try_files = ["$uri", "$uri/"]
+/// fallback = { forward = "127.0.0.1:3000" }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    /// Respond with this status code.
+    Status(#[serde(with = "deser::status_code")] http::StatusCode),
+
+    /// Forward the request like [`Action::Forward`] does.
+    Forward(Forward),
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Self::Status(http::StatusCode::NOT_FOUND)
+    }
+}
+
+/// Static files are compressed with Brotli, Zstandard or gzip if the client
+/// accepts any of them. Precompressed variants are preferred: if `app.js.br`,
+/// `app.js.zst` or `app.js.gz` exist next to `app.js` they are sent instead,
+/// with the `Content-Encoding` header. Otherwise, text based files of at least
+/// `min_size` bytes are compressed on the fly:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8000"
+///
+/// [server.serve]
+///
+/// root = "/var/www"
+/// compression = { precompressed = true, on_the_fly = true, min_size = 1024 }
+/// ```
+///
+/// Files compressed on the fly are streamed, so their responses have no
+/// `Content-Length` and range requests get the uncompressed file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Compression {
+    /// Send precompressed variants of files when they exist.
+    #[serde(default = "default::precompressed")]
+    pub precompressed: bool,
+
+    /// Compress files that don't have a precompressed variant.
+    #[serde(default = "default::on_the_fly")]
+    pub on_the_fly: bool,
+
+    /// Files smaller than this are not compressed on the fly, since the
+    /// savings don't make up for the work.
+    #[serde(default = "default::min_compression_size")]
+    pub min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            precompressed: default::precompressed(),
+            on_the_fly: default::on_the_fly(),
+            min_size: default::min_compression_size(),
+        }
+    }
+}
+
+/// Entity tags sent in the `ETag` header of static files, which clients use
+/// for conditional requests. The default `metadata` tags are built from the
+/// size and modification time of files, so they are cheap but weak: they
+/// can't be used in `If-Match` or `If-Range`. The `hash` tags are strong,
+/// built from a hash of the content which is computed once per modification
+/// of the file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ETag {
+    #[default]
+    Metadata,
+    Hash,
 }
 
 mod default {
     //! Default values for some configuration options.
 
+    use std::{path::PathBuf, time::Duration};
+
+    use super::Cidr;
+
     pub fn uri() -> String {
         String::from("/")
     }
@@ -239,4 +1334,96 @@ mod default {
     pub fn max_connections() -> usize {
         1024
     }
+
+    pub fn backlog() -> u32 {
+        1024
+    }
+
+    pub fn shutdown_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn client_auth() -> bool {
+        true
+    }
+
+    pub fn subject_header() -> String {
+        String::from("X-Client-Cert-Subject")
+    }
+
+    pub fn san_header() -> String {
+        String::from("X-Client-Cert-San")
+    }
+
+    pub fn fingerprint_header() -> String {
+        String::from("X-Client-Cert-Fingerprint")
+    }
+
+    pub fn reload_interval() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn acme_directory() -> String {
+        String::from("https://acme-v02.api.letsencrypt.org/directory")
+    }
+
+    pub fn acme_state() -> PathBuf {
+        PathBuf::from("/var/lib/rxh/acme")
+    }
+
+    pub fn renew_before() -> u64 {
+        30
+    }
+
+    pub fn max_concurrent_streams() -> u32 {
+        200
+    }
+
+    pub fn keepalive_timeout() -> Duration {
+        Duration::from_secs(20)
+    }
+
+    pub fn http3_max_concurrent_streams() -> u32 {
+        100
+    }
+
+    pub fn idle_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn session_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn trusted() -> Vec<Cidr> {
+        vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+    }
+
+    pub fn max_age() -> u64 {
+        24 * 60 * 60
+    }
+
+    pub fn index() -> Vec<String> {
+        vec![String::from("index.html")]
+    }
+
+    pub fn precompressed() -> bool {
+        true
+    }
+
+    pub fn on_the_fly() -> bool {
+        true
+    }
+
+    pub fn min_compression_size() -> u64 {
+        1024
+    }
+
+    pub fn weight() -> usize {
+        1
+    }
+
+    pub fn verify() -> bool {
+        true
+    }
 }
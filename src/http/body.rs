@@ -1,18 +1,261 @@
 //! Utilities for creating common request and response bodies.
 
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, SeekFrom},
+    ops::Range,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
 use bytes::Bytes;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+};
+
+/// Maximum size of the chunks read from files. Only one chunk per response is
+/// kept in memory, no matter how large the file is.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bodies of requests received from clients. HTTP/1.1 and HTTP/2 connections
+/// produce [`hyper::body::Incoming`] bodies while HTTP/3 connections have
+/// their own body type, but both can be forwarded in the same way.
+pub(crate) trait RequestBody:
+    Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>>> + Send + 'static
+{
+}
+
+impl<B> RequestBody for B where
+    B: Body<Data = Bytes, Error: Into<Box<dyn Error + Send + Sync>>> + Send + 'static
+{
+}
+
+/// Error of the bodies generated on this server. Bodies received from
+/// backends fail with [`hyper::Error`] while files fail with [`io::Error`].
+pub(crate) type BodyError = Box<dyn Error + Send + Sync>;
 
 /// Single chunk body.
-pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, BodyError> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
-#[allow(dead_code)]
-pub fn empty() -> BoxBody<Bytes, hyper::Error> {
+/// Streams the given `range` of `file`. See [`FileBody`].
+pub fn file(file: File, range: Range<u64>) -> BoxBody<Bytes, BodyError> {
+    file_segments(file, vec![FileSegment::File(range)])
+}
+
+/// Streams all the `segments` in order, reading file segments from `file`.
+/// See [`FileBody`].
+pub fn file_segments(file: File, segments: Vec<FileSegment>) -> BoxBody<Bytes, BodyError> {
+    FileBody::new(file, segments).boxed()
+}
+
+/// Streams everything that `reader` produces. See [`ReaderBody`].
+pub fn reader<R>(reader: R) -> BoxBody<Bytes, BodyError>
+where
+    R: AsyncRead + Send + Sync + 'static,
+{
+    ReaderBody {
+        reader: Box::pin(reader),
+        buf: vec![0; FILE_CHUNK_SIZE].into_boxed_slice(),
+    }
+    .boxed()
+}
+
+/// Body without content.
+pub fn empty() -> BoxBody<Bytes, BodyError> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
+
+/// Part of a [`FileBody`].
+pub(crate) enum FileSegment {
+    /// Bytes that are already in memory, like the headers of each part of a
+    /// `multipart/byteranges` response.
+    Bytes(Bytes),
+
+    /// Range of the file.
+    File(Range<u64>),
+}
+
+impl FileSegment {
+    /// Number of bytes in this segment.
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File(range) => range.end - range.start,
+        }
+    }
+}
+
+/// Body that sends a sequence of [`FileSegment`] and reads file segments in
+/// chunks of at most [`FILE_CHUNK_SIZE`] bytes. The next chunk is only read
+/// when the connection asks for it, which doesn't happen until the previous
+/// one has been sent, so slow clients don't make the file pile up in memory.
+///
+/// File segments end at their range even if the file keeps growing. If the
+/// file can't be read or gets truncated the body fails and the connection
+/// aborts the response, so the client can tell that it's incomplete.
+struct FileBody {
+    /// File being read.
+    file: File,
+
+    /// Segments that have not been sent yet. The first one is partially sent
+    /// if it's a file segment.
+    segments: VecDeque<FileSegment>,
+
+    /// Current position of the file cursor, [`None`] if unknown.
+    position: Option<u64>,
+
+    /// Whether a seek operation has been started but not completed.
+    seeking: bool,
+
+    /// Chunk buffer, allocated once.
+    buf: Box<[u8]>,
+}
+
+impl FileBody {
+    /// Creates a new [`FileBody`].
+    fn new(file: File, segments: Vec<FileSegment>) -> Self {
+        let largest = segments
+            .iter()
+            .filter(|segment| matches!(segment, FileSegment::File(_)))
+            .map(FileSegment::len)
+            .max()
+            .unwrap_or(0);
+
+        let capacity = largest.min(FILE_CHUNK_SIZE as u64) as usize;
+
+        Self {
+            file,
+            segments: segments.into(),
+            position: None,
+            seeking: false,
+            buf: vec![0; capacity].into_boxed_slice(),
+        }
+    }
+
+    /// Drops all the remaining segments and fails with `err`.
+    fn abort(&mut self, err: io::Error) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        self.segments.clear();
+        Poll::Ready(Some(Err(err.into())))
+    }
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        let range = loop {
+            match this.segments.front_mut() {
+                None => return Poll::Ready(None),
+                Some(segment) if segment.len() == 0 => drop(this.segments.pop_front()),
+                Some(FileSegment::File(range)) => break range.clone(),
+                Some(FileSegment::Bytes(_)) => {
+                    let Some(FileSegment::Bytes(bytes)) = this.segments.pop_front() else {
+                        unreachable!();
+                    };
+                    return Poll::Ready(Some(Ok(Frame::data(bytes))));
+                }
+            }
+        };
+
+        if this.position != Some(range.start) {
+            if !this.seeking {
+                let seek = Pin::new(&mut this.file).start_seek(SeekFrom::Start(range.start));
+                if let Err(err) = seek {
+                    return this.abort(err);
+                }
+                this.seeking = true;
+            }
+
+            let result = ready!(Pin::new(&mut this.file).poll_complete(cx));
+            this.seeking = false;
+
+            match result {
+                Ok(position) => this.position = Some(position),
+                Err(err) => return this.abort(err),
+            }
+        }
+
+        let size = (range.end - range.start).min(this.buf.len() as u64) as usize;
+        let mut buf = ReadBuf::new(&mut this.buf[..size]);
+
+        if let Err(err) = ready!(Pin::new(&mut this.file).poll_read(cx, &mut buf)) {
+            return this.abort(err);
+        }
+
+        let read = buf.filled().len() as u64;
+
+        if read == 0 {
+            let err = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file truncated while sending it",
+            );
+            return this.abort(err);
+        }
+
+        this.position = Some(range.start + read);
+
+        if let Some(FileSegment::File(range)) = this.segments.front_mut() {
+            range.start += read;
+        }
+
+        Poll::Ready(Some(Ok(Frame::data(Bytes::copy_from_slice(buf.filled())))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.segments.iter().all(|segment| segment.len() == 0)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.segments.iter().map(FileSegment::len).sum())
+    }
+}
+
+/// Body of unknown length that sends the data produced by a reader, like an
+/// encoder that compresses a file. As in [`FileBody`], the next chunk is only
+/// read when the connection asks for it.
+struct ReaderBody<R> {
+    /// Source of the data.
+    reader: Pin<Box<R>>,
+
+    /// Chunk buffer, allocated once.
+    buf: Box<[u8]>,
+}
+
+impl<R: AsyncRead> Body for ReaderBody<R> {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let mut buf = ReadBuf::new(&mut this.buf);
+
+        if let Err(err) = ready!(this.reader.as_mut().poll_read(cx, &mut buf)) {
+            return Poll::Ready(Some(Err(err.into())));
+        }
+
+        if buf.filled().is_empty() {
+            return Poll::Ready(None);
+        }
+
+        Poll::Ready(Some(Ok(Frame::data(Bytes::copy_from_slice(buf.filled())))))
+    }
+}
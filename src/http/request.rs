@@ -2,24 +2,24 @@
 //! the IP address of the client, which we can't obtain using [`hyper::Request`]
 //! alone. We also need to implement different RFCs for request forwarding.
 
-use std::net::SocketAddr;
-
-use http::{Extensions, HeaderMap};
+use http::{Extensions, HeaderMap, Uri, Version};
 use hyper::{
-    header::{self, HeaderValue},
+    header::{self, HeaderName, HeaderValue},
     Request,
 };
 
+use crate::{config::IdentityHeaders, net::Address, tls::server::ClientIdentity};
+
 /// Request received by this proxy from a client.
 pub(crate) struct ProxyRequest<T> {
     /// Original client request.
     request: Request<T>,
 
     /// Client socket.
-    client_addr: SocketAddr,
+    client_addr: Address,
 
     /// Local socket currently handling this request.
-    server_addr: SocketAddr,
+    server_addr: Address,
 
     /// Optional ID to use in the "by" parameter of the "Forwarded" header
     /// instead of the IP address.
@@ -30,8 +30,8 @@ impl<T> ProxyRequest<T> {
     /// Creates a new [`ProxyRequest`].
     pub fn new(
         request: Request<T>,
-        client_addr: SocketAddr,
-        server_addr: SocketAddr,
+        client_addr: Address,
+        server_addr: Address,
         proxy_id: Option<String>,
     ) -> Self {
         Self {
@@ -46,10 +46,100 @@ impl<T> ProxyRequest<T> {
         self.request.headers()
     }
 
+    /// Address of the client that sent the request.
+    pub fn client_addr(&self) -> &Address {
+        &self.client_addr
+    }
+
+    /// Address of the local socket that received the request.
+    pub fn server_addr(&self) -> &Address {
+        &self.server_addr
+    }
+
     pub fn extensions_mut(&mut self) -> &mut Extensions {
         self.request.extensions_mut()
     }
 
+    /// Sets the headers that carry the verified client certificate to the
+    /// upstream server. Whatever the client sent in these headers is removed
+    /// first, otherwise anyone could impersonate an authenticated client.
+    /// Values that are not valid header values are skipped.
+    pub fn set_client_identity(
+        &mut self,
+        names: &IdentityHeaders,
+        identity: Option<&ClientIdentity>,
+    ) {
+        let headers = self.request.headers_mut();
+
+        for name in [&names.subject, &names.san, &names.fingerprint] {
+            headers.remove(name.as_str());
+        }
+
+        let Some(identity) = identity else {
+            return;
+        };
+
+        let values = [
+            (&names.subject, &identity.subject),
+            (&names.san, &identity.san),
+            (&names.fingerprint, &identity.fingerprint),
+        ];
+
+        for (name, value) in values {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    /// Backends are always reached with HTTP/1.1, but requests received on
+    /// HTTP/2 and HTTP/3 connections carry the host in the URI authority
+    /// instead of the `Host` header, and their cookies may be split into
+    /// multiple headers. This rewrites such requests into regular HTTP/1.1
+    /// requests, as described in RFC 9113 section 8.3.1 and section 8.2.3
+    /// (RFC 9114 says the same for HTTP/3).
+    pub fn downgrade_to_http1(&mut self) {
+        if !matches!(self.request.version(), Version::HTTP_2 | Version::HTTP_3) {
+            return;
+        }
+
+        *self.request.version_mut() = Version::HTTP_11;
+
+        let uri = self.request.uri().clone();
+
+        if let Some(authority) = uri.authority() {
+            if !self.request.headers().contains_key(header::HOST) {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    self.request.headers_mut().insert(header::HOST, host);
+                }
+            }
+        }
+
+        if let Some(path_and_query) = uri.path_and_query() {
+            if let Ok(origin_form) = Uri::try_from(path_and_query.as_str()) {
+                *self.request.uri_mut() = origin_form;
+            }
+        }
+
+        let headers = self.request.headers_mut();
+
+        if headers.get_all(header::COOKIE).iter().count() > 1 {
+            let cookies = headers
+                .get_all(header::COOKIE)
+                .iter()
+                .map(|value| value.as_bytes())
+                .collect::<Vec<_>>()
+                .join(&b"; "[..]);
+
+            if let Ok(cookies) = HeaderValue::from_bytes(&cookies) {
+                headers.insert(header::COOKIE, cookies);
+            }
+        }
+    }
+
     /// Consumes the [`ProxyRequest`] returning a [`hyper::Request`] that
     /// contains a valid HTTP forwarded header. This is an implementation of
     /// RFC 7239, see the details in the section below.
@@ -73,16 +163,16 @@ impl<T> ProxyRequest<T> {
     /// ### HTTP `Forwarded` header parameters:
     ///
     /// - `for`: Identifies the client that initiated the request. This may be
-    /// an obfuscated ID or IP + optional TCP port.
+    ///   an obfuscated ID or IP + optional TCP port.
     ///
-    /// - `by`: Identifies the interface where the request came in to the
-    /// proxy server. This may be an obfuscated ID, IP or IP and TCP port.
+    /// - `by`: Identifies the interface where the request came in to the proxy
+    ///   server. This may be an obfuscated ID, IP or IP and TCP port.
     ///
-    /// - `host`: Original value of the `Host` HTTP header, as received by
-    /// the proxy.
+    /// - `host`: Original value of the `Host` HTTP header, as received by the
+    ///   proxy.
     ///
     /// - `proto`: Protocol used to make the request. For example, `http` or
-    /// `https`.
+    ///   `https`.
     ///
     /// All parameters are optional. IP addresses may be IPv4 or IPv6. If the
     /// address is IPv6 it must be enclosed in square brackets.
@@ -117,19 +207,21 @@ impl<T> ProxyRequest<T> {
     /// in the chain can manipulate the value. Even the original client can
     /// set any value to the `Forwarded` header.
     pub fn into_forwarded(mut self) -> Request<T> {
-        let host = if let Some(value) = self.request.headers().get(header::HOST) {
-            match value.to_str() {
-                Ok(host) => String::from(host),
-                Err(_) => self.server_addr.to_string(),
-            }
-        } else {
-            self.server_addr.to_string()
+        // Unix sockets don't have an IP address that we could use as the host.
+        let default_host = match &self.server_addr {
+            Address::Tcp(address) => address.to_string(),
+            Address::Unix(_) => String::from("localhost"),
         };
 
-        let by = self.proxy_id.unwrap_or(self.server_addr.to_string());
+        let host = match self.request.headers().get(header::HOST) {
+            Some(value) => value.to_str().map_or(default_host, String::from),
+            None => default_host,
+        };
+
+        let by = self.proxy_id.unwrap_or(node(&self.server_addr));
 
         // TODO: Proto
-        let mut forwarded = format!("for={};by={};host={}", self.client_addr, by, host);
+        let mut forwarded = format!("for={};by={};host={}", node(&self.client_addr), by, host);
 
         if let Some(value) = self.request.headers().get(header::FORWARDED) {
             if let Ok(previous_proxies) = value.to_str() {
@@ -146,8 +238,20 @@ impl<T> ProxyRequest<T> {
     }
 }
 
+/// Node identifier for the `for` and `by` parameters of the `Forwarded`
+/// header. Peers connected through Unix sockets don't have an IP address, so
+/// they are reported as `unknown` as described in section 6.2 of RFC 7239.
+fn node(address: &Address) -> String {
+    match address {
+        Address::Tcp(address) => address.to_string(),
+        Address::Unix(_) => String::from("unknown"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+
     use super::*;
 
     fn forwarded_header<T>(request: &Request<T>) -> String {
@@ -163,13 +267,13 @@ mod tests {
 
     #[test]
     fn forwarded_request() {
-        let client = "127.0.0.1:8000".parse().unwrap();
-        let proxy = "127.0.0.1:9000".parse().unwrap();
+        let client: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let proxy: SocketAddr = "127.0.0.1:9000".parse().unwrap();
 
         let request = ProxyRequest::new(
             Request::builder().body(crate::http::body::empty()).unwrap(),
-            client,
-            proxy,
+            client.into(),
+            proxy.into(),
             None,
         );
 
@@ -182,14 +286,14 @@ mod tests {
 
     #[test]
     fn forwarded_request_with_proxy_id() {
-        let client = "127.0.0.1:8000".parse().unwrap();
-        let proxy = "127.0.0.1:9000".parse().unwrap();
+        let client: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let proxy: SocketAddr = "127.0.0.1:9000".parse().unwrap();
         let proxy_id = String::from("rxh/main");
 
         let request = ProxyRequest::new(
             Request::builder().body(crate::http::body::empty()).unwrap(),
-            client,
-            proxy,
+            client.into(),
+            proxy.into(),
             Some(proxy_id.clone()),
         );
 
@@ -199,4 +303,55 @@ mod tests {
         assert!(forwarded.headers().contains_key(header::FORWARDED));
         assert_eq!(forwarded_header(&forwarded), expected.as_str());
     }
+
+    #[test]
+    fn forwarded_request_from_unix_socket() {
+        let client = "unix:/run/rxh.sock".parse().unwrap();
+        let proxy = "unix:/run/rxh.sock".parse().unwrap();
+
+        let request = ProxyRequest::new(
+            Request::builder().body(crate::http::body::empty()).unwrap(),
+            client,
+            proxy,
+            None,
+        );
+
+        let forwarded = request.into_forwarded();
+
+        assert_eq!(
+            forwarded_header(&forwarded),
+            "for=unknown;by=unknown;host=localhost"
+        );
+    }
+
+    #[test]
+    fn http2_request_downgraded_to_http1() {
+        let client: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let proxy: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        let mut request = ProxyRequest::new(
+            Request::builder()
+                .version(Version::HTTP_2)
+                .uri("https://example.com/api?page=1")
+                .header(header::COOKIE, "a=1")
+                .header(header::COOKIE, "b=2")
+                .body(crate::http::body::empty())
+                .unwrap(),
+            client.into(),
+            proxy.into(),
+            None,
+        );
+
+        request.downgrade_to_http1();
+        let forwarded = request.into_forwarded();
+
+        assert_eq!(forwarded.version(), Version::HTTP_11);
+        assert_eq!(forwarded.uri(), "/api?page=1");
+        assert_eq!(forwarded.headers()[header::HOST], "example.com");
+        assert_eq!(forwarded.headers()[header::COOKIE], "a=1; b=2");
+        assert_eq!(
+            forwarded_header(&forwarded),
+            format!("for={client};by={proxy};host=example.com")
+        );
+    }
 }
@@ -8,11 +8,13 @@ use hyper::{
     Response,
 };
 
+use super::body::BodyError;
+
 /// Common type for all responses, since [`Response`] is generic but we don't
 /// need so much flexibility at a proxy level. Most of the times the real
 /// response is generated by the target server and we just send it back to
 /// the client.
-pub(crate) type BoxBodyResponse = Response<BoxBody<Bytes, hyper::Error>>;
+pub(crate) type BoxBodyResponse = Response<BoxBody<Bytes, BodyError>>;
 
 /// Response sent back to the client at the end of the proxying process. It
 /// wraps the response received by the target server and when necessary we can
@@ -59,6 +61,30 @@ impl LocalResponse {
             .unwrap()
     }
 
+    /// Generic `HTTP 403 Forbidden` response.
+    pub fn forbidden() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(super::body::full("HTTP 403 FORBIDDEN"))
+            .unwrap()
+    }
+
+    /// Generic response with any status code and its reason as the body.
+    pub fn status(status: http::StatusCode) -> BoxBodyResponse {
+        let reason = status.canonical_reason().unwrap_or_default();
+
+        Self::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(super::body::full(format!(
+                "HTTP {} {}",
+                status.as_u16(),
+                reason.to_uppercase()
+            )))
+            .unwrap()
+    }
+
     pub fn bad_gateway() -> BoxBodyResponse {
         Self::builder()
             .status(http::StatusCode::BAD_GATEWAY)
@@ -0,0 +1,186 @@
+//! Paths of request URIs are percent encoded and might contain dot segments
+//! or repeated slashes, so the same resource can be requested in many ways.
+//! Patterns work with the path returned by [`route_path`] and static files
+//! with the one returned by [`normalize_path`].
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Characters that are percent encoded in paths, which are all of them except
+/// unreserved characters and slashes. See
+/// [RFC 3986 section 2.3](https://www.rfc-editor.org/rfc/rfc3986#section-2.3).
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Percent-decodes `path` and then removes dot segments and empty segments,
+/// so `/static//css/../%2E%2E/app%20v2.js` becomes `/app v2.js`. Dot segments
+/// never go above the root, and the trailing slash is kept since it means
+/// "directory" for static files.
+///
+/// Decoding happens first, so encoded slashes and dots like `..%2F` are
+/// treated like the characters they encode and can't hide a dot segment.
+/// Paths that contain NUL bytes, backslashes or invalid UTF-8 after decoding
+/// are rejected with [`None`]: they don't name anything that can be served,
+/// and backslashes are path separators on some platforms.
+pub(crate) fn normalize_path(path: &str) -> Option<String> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+
+    if decoded.contains(['\0', '\\']) {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(decoded.len());
+
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+
+    let last = decoded.rsplit('/').next().unwrap_or_default();
+
+    if segments.is_empty() || matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+
+    Some(normalized)
+}
+
+/// Normalizes `path` like [`normalize_path`] does for matching patterns.
+/// Forwarded requests keep their original path, and backends don't always
+/// treat encoded slashes and backslashes as separators, so these stay
+/// encoded: `/private/..%2Fpublic` doesn't match `/public`. Nothing is
+/// rejected, since any path can be forwarded. Bytes that are not UTF-8 are
+/// replaced, they can't match a pattern anyway.
+pub(crate) fn route_path(path: &str) -> String {
+    let mut segments = Vec::new();
+
+    for segment in path.split('/') {
+        let decoded = percent_decode_str(segment).decode_utf8_lossy();
+
+        match decoded.as_ref() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(decoded.replace('/', "%2F").replace('\\', "%5C")),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+
+    let last = path.rsplit('/').next().unwrap_or_default();
+    let last = percent_decode_str(last).decode_utf8_lossy();
+
+    if segments.is_empty() || matches!(last.as_ref(), "" | "." | "..") {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+/// Percent encodes a path returned by [`normalize_path`] so that it can be
+/// used in headers like `Location`.
+pub(crate) fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_paths() {
+        for (path, expected) in [
+            ("/", "/"),
+            ("", "/"),
+            ("/index.html", "/index.html"),
+            ("/docs/", "/docs/"),
+            ("/my%20file.txt", "/my file.txt"),
+            ("/caf%C3%A9", "/café"),
+            ("//docs///guide//", "/docs/guide/"),
+            ("/./docs/./guide", "/docs/guide"),
+            ("/docs/.", "/docs/"),
+            ("/docs/guide/..", "/docs/"),
+            ("/docs/../index.html", "/index.html"),
+            ("/a/b/c/../../d", "/a/d"),
+            ("/..", "/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/%2e%2e/%2E%2E/etc/passwd", "/etc/passwd"),
+            ("/docs/..%2f..%2fetc/passwd", "/etc/passwd"),
+            ("/docs%2fguide", "/docs/guide"),
+            ("/.hidden", "/.hidden"),
+            ("/...", "/..."),
+            ("/%", "/%"),
+            ("/100%25", "/100%"),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), Some(expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn rejected_paths() {
+        for path in [
+            "/file%00.txt",
+            "/%00",
+            "/..%5c..%5cwindows",
+            "/docs%5Cguide",
+            "/docs\\guide",
+            "/%ff%fe",
+            "/%C3",
+        ] {
+            assert_eq!(normalize_path(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn route_paths() {
+        for (path, expected) in [
+            ("/", "/"),
+            ("/docs/../index.html", "/index.html"),
+            ("/%70rivate/", "/private/"),
+            ("/%2e%2e/%2E%2E/etc/passwd", "/etc/passwd"),
+            ("/docs/guide/%2e%2e", "/docs/"),
+            ("/docs%2fguide", "/docs%2Fguide"),
+            ("/private/..%2fpublic", "/private/..%2Fpublic"),
+            ("/private/..%5cpublic", "/private/..%5Cpublic"),
+            ("/private/..\\public", "/private/..%5Cpublic"),
+            ("/file%00.txt", "/file\0.txt"),
+            ("/%ff", "/\u{FFFD}"),
+        ] {
+            assert_eq!(route_path(path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn encoded_paths() {
+        for (path, expected) in [
+            ("/docs/guide/", "/docs/guide/"),
+            ("/my file.txt", "/my%20file.txt"),
+            ("/café", "/caf%C3%A9"),
+            ("/100%", "/100%25"),
+            ("/a?b#c", "/a%3Fb%23c"),
+        ] {
+            assert_eq!(encode_path(path), expected, "{path}");
+        }
+    }
+}
@@ -1,20 +1,19 @@
 //! RXH is a reverse proxy, load balancer and static files server.
 
-#![feature(ptr_from_ref)]
-#![feature(is_some_and)]
-
 mod http;
 mod service;
 mod sync;
 mod task;
+mod tls;
 
 pub mod config;
+pub mod net;
 pub mod sched;
 
 use std::io;
 
 pub use task::{
-    master::Master,
+    master::{Master, ReloadHandle},
     server::{Server, ShutdownState, State},
 };
 
@@ -4,6 +4,8 @@ async fn main() -> Result<(), rxh::Error> {
 
     rxh::Master::init(config)?
         .shutdown_on(tokio::signal::ctrl_c())
+        .reload_on_sighup("rxh.toml")
+        .upgrade_on_sigusr2()
         .run()
         .await
 }
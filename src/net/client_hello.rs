@@ -0,0 +1,151 @@
+//! TLS ClientHello inspection for servers that forward TLS connections without
+//! terminating them, see [`crate::config::Protocol::TlsPassthrough`]. The
+//! first message that a TLS client sends is the ClientHello, which carries the
+//! name of the server that the client wants to reach in the SNI extension.
+//! That's enough to pick a backend, the rest of the handshake happens between
+//! the client and the backend.
+//!
+//! Parsing is done by [`rustls::server::Acceptor`], which doesn't need any
+//! certificate to read the ClientHello. The bytes read from the client are
+//! returned as they are, so they can be sent to the backend before tunneling
+//! the rest of the connection.
+
+use std::io;
+
+use rustls::server::Acceptor;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// ClientHello messages are usually smaller than 2 KiB, but post-quantum key
+/// shares and lots of extensions can make them grow. Clients that send more
+/// than this without completing the message are rejected.
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+/// Reads the ClientHello sent by the client on `stream`. Returns all the bytes
+/// that were read, which contain the ClientHello and possibly more data, and
+/// the server name requested by the client, if any.
+pub(crate) async fn read<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(Vec<u8>, Option<String>), io::Error> {
+    let mut acceptor = Acceptor::default();
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 4096];
+
+    loop {
+        if bytes.len() >= MAX_CLIENT_HELLO_SIZE {
+            return Err(invalid("TLS ClientHello is too large"));
+        }
+
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before TLS ClientHello",
+            ));
+        }
+
+        bytes.extend_from_slice(&chunk[..read]);
+
+        let mut new = &chunk[..read];
+        while !new.is_empty() {
+            if acceptor.read_tls(&mut new)? == 0 {
+                return Err(invalid("TLS ClientHello is too large"));
+            }
+        }
+
+        match acceptor.accept() {
+            Ok(None) => continue,
+            Ok(Some(accepted)) => {
+                let server_name = accepted.client_hello().server_name().map(String::from);
+                return Ok((bytes, server_name));
+            }
+            Err((err, _)) => return Err(invalid(&format!("invalid TLS ClientHello: {err}"))),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+
+    use super::*;
+
+    /// Builds the first flight of a TLS client that connects to `server_name`.
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(crate::tls::provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+
+        let server_name = ServerName::try_from(server_name.to_owned()).unwrap();
+        let mut client = ClientConnection::new(Arc::new(config), server_name).unwrap();
+
+        let mut bytes = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut bytes).unwrap();
+        }
+
+        bytes
+    }
+
+    #[tokio::test]
+    async fn server_name_is_extracted() {
+        let hello = client_hello("api.example.com");
+
+        let (bytes, server_name) = read(&mut hello.as_slice()).await.unwrap();
+        assert_eq!(bytes, hello);
+        assert_eq!(server_name.as_deref(), Some("api.example.com"));
+
+        // IP addresses are not sent in the SNI extension.
+        let hello = client_hello("127.0.0.1");
+        let (_, server_name) = read(&mut hello.as_slice()).await.unwrap();
+        assert_eq!(server_name, None);
+    }
+
+    #[tokio::test]
+    async fn fragmented_client_hello() {
+        let hello = client_hello("example.com");
+        let (first, second) = hello.split_at(10);
+
+        let mut stream = split_stream(first, second).await;
+        let (bytes, server_name) = read(&mut stream).await.unwrap();
+
+        assert_eq!(bytes, hello);
+        assert_eq!(server_name.as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn invalid_client_hello() {
+        assert!(read(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.is_err());
+
+        let hello = client_hello("example.com");
+        let truncated = &hello[..hello.len() / 2];
+        assert_eq!(
+            read(&mut &truncated[..]).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    /// Returns a stream that yields `first` and `second` in separate reads.
+    async fn split_stream(first: &[u8], second: &[u8]) -> tokio::io::DuplexStream {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        writer.write_all(first).await.unwrap();
+
+        let second = second.to_vec();
+        tokio::task::spawn(async move {
+            tokio::task::yield_now().await;
+            writer.write_all(&second).await.unwrap();
+        });
+
+        reader
+    }
+}
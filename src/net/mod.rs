@@ -0,0 +1,738 @@
+//! Network addresses and sockets. Servers can listen and backends can be
+//! reached on TCP sockets or on Unix domain sockets, which are written as
+//! `unix:/path/to/socket` in the config file:
+//!
+//! ```toml
+//! [[server]]
+//!
+//! listen = ["127.0.0.1:8000", "unix:/run/rxh.sock"]
+//! forward = "unix:/run/gunicorn.sock"
+//! ```
+
+pub(crate) mod client_hello;
+pub(crate) mod proxy_protocol;
+
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::{
+    fmt::{self, Display},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::config::{self, TcpOptions};
+
+/// Prefix of Unix domain socket addresses.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a socket, either TCP or Unix.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum Address {
+    /// IP address and port.
+    Tcp(SocketAddr),
+
+    /// Path of a Unix domain socket. Clients connected to Unix sockets are
+    /// usually unnamed, so the path might be empty.
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Returns the TCP socket address, if this is one.
+    pub fn as_tcp(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(address) => Some(*address),
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// TCP addresses with port 0 are assigned a random port when bound, so
+    /// they don't identify any particular socket.
+    pub(crate) fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Tcp(address) if address.port() == 0)
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(invalid_address(value));
+            }
+
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        value
+            .parse()
+            .map(Self::Tcp)
+            .map_err(|_| invalid_address(value))
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = io::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(address: Address) -> Self {
+        address.to_string()
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(address: SocketAddr) -> Self {
+        Self::Tcp(address)
+    }
+}
+
+/// Range of IP addresses written in CIDR notation, such as `10.0.0.0/8` or
+/// `2001:db8::/32`. A single address without prefix length is also accepted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    /// First address of the range.
+    address: IpAddr,
+
+    /// Number of leading bits that all the addresses in the range share.
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns `true` if `ip` belongs to this range. IPv4 addresses mapped to
+    /// IPv6, which is how dual stack sockets see IPv4 clients, are compared
+    /// as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{value}' is not a valid CIDR range"),
+            )
+        };
+
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = io::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+fn invalid_address(value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{value}' is not a valid address, expected 'ip:port' or 'unix:/path'"),
+    )
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/// Listening socket, TCP or Unix. UDP sockets don't accept connections, but
+/// they are bound, inherited and handed off just like listeners, so they are
+/// also represented here.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Udp(UdpSocket),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Self::Unix(listener)
+    }
+}
+
+impl Listener {
+    /// Binds a new socket to `address` and configures it with `options`. TCP
+    /// sockets use `SO_REUSEADDR`, and Unix socket files left behind by
+    /// processes that are gone are removed. Must be called from within a
+    /// Tokio runtime.
+    pub(crate) fn bind(address: &Address, options: &config::Socket) -> Result<Self, io::Error> {
+        match address {
+            Address::Tcp(address) => {
+                let socket = Socket::new(Domain::for_address(*address), Type::STREAM, None)?;
+
+                #[cfg(not(windows))]
+                socket.set_reuse_address(true)?;
+
+                if options.reuse_port.is_some() {
+                    #[cfg(unix)]
+                    socket.set_reuse_port(true)?;
+
+                    #[cfg(not(unix))]
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "'reuse_port' is only supported on Unix",
+                    ));
+                }
+
+                if let (Some(only_v6), true) = (options.ipv6_only, address.is_ipv6()) {
+                    socket.set_only_v6(only_v6)?;
+                }
+
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*address).into())?;
+                socket.listen(backlog(options))?;
+
+                Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+            }
+
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Self::Unix(bind_unix(path, backlog(options))?)),
+
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    /// Same as [`Listener::bind`] but binds a UDP socket. The backlog doesn't
+    /// apply to UDP, the rest of the `options` work the same way. Must be
+    /// called from within a Tokio runtime.
+    pub(crate) fn bind_udp(address: &Address, options: &config::Socket) -> Result<Self, io::Error> {
+        let Address::Tcp(address) = address else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("UDP sockets can't be bound to {address}"),
+            ));
+        };
+
+        let socket = Socket::new(Domain::for_address(*address), Type::DGRAM, None)?;
+
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+
+        if options.reuse_port.is_some() {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "'reuse_port' is only supported on Unix",
+            ));
+        }
+
+        if let (Some(only_v6), true) = (options.ipv6_only, address.is_ipv6()) {
+            socket.set_only_v6(only_v6)?;
+        }
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&(*address).into())?;
+
+        Ok(Self::Udp(UdpSocket::from_std(socket.into())?))
+    }
+
+    /// Adopts a listening socket created somewhere else, such as a previous
+    /// process or systemd. TCP addresses can belong to UDP sockets as well,
+    /// the type of the socket tells them apart. Must be called from within a
+    /// Tokio runtime.
+    #[cfg(unix)]
+    pub(crate) fn from_fd(address: &Address, fd: OwnedFd) -> Result<Self, io::Error> {
+        match address {
+            Address::Tcp(_) if SockRef::from(&fd).r#type()? == Type::DGRAM => {
+                let socket = std::net::UdpSocket::from(fd);
+                socket.set_nonblocking(true)?;
+                Ok(Self::Udp(UdpSocket::from_std(socket)?))
+            }
+            Address::Tcp(_) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Self::Tcp(TcpListener::from_std(listener)?))
+            }
+            Address::Unix(_) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix(UnixListener::from_std(listener)?))
+            }
+        }
+    }
+
+    /// Returns `true` if this is a UDP socket.
+    pub(crate) fn is_datagram(&self) -> bool {
+        matches!(self, Self::Udp(_))
+    }
+
+    /// Accepts a new connection and returns the address of the client. UDP
+    /// sockets can't accept connections.
+    pub(crate) async fn accept(&self) -> Result<(Stream, Address), io::Error> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Address::Tcp(address)))
+            }
+
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, address) = listener.accept().await?;
+                let path = address.as_pathname().map(Path::to_path_buf);
+                Ok((
+                    Stream::Unix(stream),
+                    Address::Unix(path.unwrap_or_default()),
+                ))
+            }
+
+            Self::Udp(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "UDP sockets don't accept connections",
+            )),
+        }
+    }
+
+    /// Address that this listener is bound to.
+    pub(crate) fn local_address(&self) -> Result<Address, io::Error> {
+        match self {
+            Self::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?)),
+
+            #[cfg(unix)]
+            Self::Unix(listener) => unix_address(&listener.local_addr()?),
+
+            Self::Udp(socket) => Ok(Address::Tcp(socket.local_addr()?)),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(listener) => listener.as_fd(),
+            Self::Unix(listener) => listener.as_fd(),
+            Self::Udp(socket) => socket.as_fd(),
+        }
+    }
+}
+
+/// Applies the `options` that can change after a socket is bound, which are
+/// all of them except `ipv6_only`. This allows the master to keep listening
+/// sockets when the configuration is reloaded, see
+/// [`crate::task::master::Master`].
+#[cfg(unix)]
+pub(crate) fn reconfigure(socket: &impl AsFd, options: &config::Socket) -> Result<(), io::Error> {
+    let socket = SockRef::from(socket);
+
+    if socket.domain()? != Domain::UNIX {
+        socket.set_reuse_port(options.reuse_port.is_some())?;
+    }
+
+    // Calling listen() again only updates the backlog.
+    if socket.r#type()? == Type::STREAM {
+        socket.listen(backlog(options))?;
+    }
+
+    Ok(())
+}
+
+/// Connected socket, TCP or Unix.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Applies `options` to TCP streams. Unix streams don't have any of these
+    /// options, so they are left as they are.
+    pub fn set_options(&self, options: &TcpOptions) -> Result<(), io::Error> {
+        let Self::Tcp(stream) = self else {
+            return Ok(());
+        };
+
+        if options.tcp_nodelay {
+            stream.set_nodelay(true)?;
+        }
+
+        if let Some(keepalive) = &options.keepalive {
+            let mut params = TcpKeepalive::new();
+
+            if let Some(time) = keepalive.time {
+                params = params.with_time(time);
+            }
+
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+
+            #[cfg(not(windows))]
+            if let Some(retries) = keepalive.retries {
+                params = params.with_retries(retries);
+            }
+
+            SockRef::from(stream).set_tcp_keepalive(&params)?;
+        }
+
+        Ok(())
+    }
+
+    /// Address of the local end of the connection. For connections accepted
+    /// by a [`Listener`] this is the address where the client connected.
+    pub fn local_address(&self) -> Result<Address, io::Error> {
+        match self {
+            Self::Tcp(stream) => Ok(Address::Tcp(stream.local_addr()?)),
+
+            #[cfg(unix)]
+            Self::Unix(stream) => unix_address(&stream.local_addr()?),
+        }
+    }
+}
+
+/// Opens a connection to `address` and configures it with `options`.
+pub(crate) async fn connect(address: &Address, options: &TcpOptions) -> Result<Stream, io::Error> {
+    let stream = match address {
+        Address::Tcp(address) => Stream::Tcp(TcpStream::connect(address).await?),
+
+        #[cfg(unix)]
+        Address::Unix(path) => Stream::Unix(UnixStream::connect(path).await?),
+
+        #[cfg(not(unix))]
+        Address::Unix(_) => return Err(unix_unsupported()),
+    };
+
+    stream.set_options(options)?;
+
+    Ok(stream)
+}
+
+/// Backlog of the listening sockets, clamped to what the system call takes.
+fn backlog(options: &config::Socket) -> i32 {
+    options.backlog.try_into().unwrap_or(i32::MAX)
+}
+
+/// Returns the address that a socket is bound to, whatever its type.
+#[cfg(unix)]
+pub(crate) fn local_address(fd: BorrowedFd) -> Result<Address, io::Error> {
+    use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+
+    let address: SockaddrStorage = getsockname(fd.as_raw_fd())?;
+
+    let unsupported =
+        || io::Error::new(io::ErrorKind::Unsupported, "socket is neither TCP nor Unix");
+
+    match address.family() {
+        Some(AddressFamily::Inet) => {
+            let address = address.as_sockaddr_in().ok_or_else(unsupported)?;
+            Ok(Address::Tcp(SocketAddr::V4((*address).into())))
+        }
+        Some(AddressFamily::Inet6) => {
+            let address = address.as_sockaddr_in6().ok_or_else(unsupported)?;
+            Ok(Address::Tcp(SocketAddr::V6((*address).into())))
+        }
+        Some(AddressFamily::Unix) => {
+            let address = address.as_unix_addr().ok_or_else(unsupported)?;
+            Ok(Address::Unix(
+                address.path().map(Path::to_path_buf).unwrap_or_default(),
+            ))
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+/// Converts the address of a Tokio Unix socket.
+#[cfg(unix)]
+fn unix_address(address: &tokio::net::unix::SocketAddr) -> Result<Address, io::Error> {
+    Ok(Address::Unix(
+        address
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+    ))
+}
+
+/// Binds a Unix listener to `path`. If the socket file already exists but
+/// nobody accepts connections on it, it was left behind by a process that
+/// didn't clean up, so it's removed. Anything else at `path`, like a regular
+/// file or a symlink, is never removed. Socket files are not removed when
+/// servers shut down, because another process might have inherited the
+/// listener, see [`crate::task::upgrade`].
+#[cfg(unix)]
+fn bind_unix(path: &Path, backlog: i32) -> Result<UnixListener, io::Error> {
+    let bind = || {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&socket2::SockAddr::unix(path)?)?;
+        socket.listen(backlog)?;
+        UnixListener::from_std(socket.into())
+    };
+
+    match bind() {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            use std::os::unix::fs::FileTypeExt;
+
+            let is_socket = std::fs::symlink_metadata(path)?.file_type().is_socket();
+
+            if !is_socket || std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(err);
+            }
+
+            std::fs::remove_file(path)?;
+            bind()
+        }
+        result => result,
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Keepalive;
+
+    #[test]
+    fn cidr_ranges() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+
+        let cidr: Cidr = "192.168.1.7".parse().unwrap();
+        assert_eq!(cidr.to_string(), "192.168.1.7/32");
+        assert!(cidr.contains("192.168.1.7".parse().unwrap()));
+        assert!(!cidr.contains("192.168.1.8".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+
+        for invalid in ["10.0.0.0/33", "10.0.0.0/", "example.com/8", "::/129"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_options_are_applied() {
+        let options = config::Socket {
+            backlog: 16,
+            reuse_port: Some(1),
+            ipv6_only: Some(true),
+            accepted: TcpOptions {
+                tcp_nodelay: true,
+                keepalive: Some(Keepalive {
+                    time: Some(Duration::from_secs(60)),
+                    interval: Some(Duration::from_secs(10)),
+                    retries: Some(3),
+                }),
+            },
+            upstream: TcpOptions::default(),
+        };
+
+        let listener = Listener::bind(&"[::1]:0".parse().unwrap(), &options).unwrap();
+
+        let Listener::Tcp(tcp) = &listener else {
+            panic!("expected TCP listener");
+        };
+
+        let socket = SockRef::from(tcp);
+        assert!(socket.only_v6().unwrap());
+        #[cfg(unix)]
+        assert!(socket.reuse_port().unwrap());
+
+        let address = listener.local_address().unwrap();
+        let client = connect(&address, &options.accepted).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        accepted.set_options(&options.accepted).unwrap();
+
+        for stream in [&client, &accepted] {
+            let Stream::Tcp(stream) = stream else {
+                panic!("expected TCP stream");
+            };
+
+            let socket = SockRef::from(stream);
+            assert!(socket.tcp_nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            #[cfg(target_os = "linux")]
+            {
+                assert_eq!(
+                    socket.tcp_keepalive_time().unwrap(),
+                    Duration::from_secs(60)
+                );
+                assert_eq!(
+                    socket.tcp_keepalive_interval().unwrap(),
+                    Duration::from_secs(10)
+                );
+                assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+            }
+        }
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn inherited_sockets_keep_their_type() {
+        let address = "127.0.0.1:0".parse().unwrap();
+        let options = config::Socket::default();
+
+        for listener in [
+            Listener::bind(&address, &options).unwrap(),
+            Listener::bind_udp(&address, &options).unwrap(),
+        ] {
+            let local = listener.local_address().unwrap();
+            let fd = listener.as_fd().try_clone_to_owned().unwrap();
+            let inherited = Listener::from_fd(&local, fd).unwrap();
+
+            assert_eq!(inherited.is_datagram(), listener.is_datagram());
+            assert_eq!(inherited.local_address().unwrap(), local);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! PROXY protocol headers, see the
+//! [specification](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt).
+//! Load balancers that work at the TCP level (HAProxy, AWS NLB and others)
+//! can't add a `Forwarded` header, so they send the address of the client
+//! before any other data on the connection instead. There are two versions:
+//!
+//! - Version 1 is a single line of text:
+//!
+//! ```text
+//! PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n
+//! ```
+//!
+//! - Version 2 is binary. It starts with a fixed 12 bytes signature, followed
+//!   by the command, address family, length of the addresses and the addresses
+//!   themselves. It can also carry additional TLVs, which we ignore.
+//!
+//! Headers are read byte by byte where needed so that nothing after the
+//! header is consumed, the rest of the stream belongs to TLS or HTTP. Backends
+//! that expect these headers can get them as well, see [`encode`].
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyProtocolVersion;
+
+/// Signature of version 2 headers.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Prefix of version 1 headers.
+const PREFIX: &[u8] = b"PROXY ";
+
+/// Maximum length of version 1 headers including the final CRLF.
+const MAX_V1_LENGTH: usize = 107;
+
+/// Addresses of the original connection sent by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    /// Address of the client that connected to the proxy.
+    pub source: SocketAddr,
+
+    /// Address where the client connected to.
+    pub destination: SocketAddr,
+}
+
+/// Reads a version 1 or version 2 header from `stream`. Returns [`None`] if
+/// the proxy doesn't know the original addresses, which happens with
+/// `PROXY UNKNOWN` or the `LOCAL` command used by health checks, or if they
+/// are not IP addresses. Connections that don't start with a valid header
+/// produce an error.
+pub(crate) async fn read<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<Header>, io::Error> {
+    let mut start = [0; PREFIX.len()];
+    stream.read_exact(&mut start).await?;
+
+    if start == PREFIX {
+        read_v1(stream).await
+    } else if start == SIGNATURE[..PREFIX.len()] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Reads the rest of a version 1 header after [`PREFIX`].
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Header>, io::Error> {
+    let mut line = Vec::from(PREFIX);
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_LENGTH {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+
+    parse_v1(line)
+}
+
+/// Parses the fields of a version 1 header, without prefix and CRLF.
+fn parse_v1(line: &str) -> Result<Option<Header>, io::Error> {
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let parse_ip = |ip: &str| -> Result<IpAddr, io::Error> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| invalid("invalid address in PROXY protocol header"))?;
+
+                match (*protocol, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                    _ => Err(invalid("address family mismatch in PROXY protocol header")),
+                }
+            };
+
+            let parse_port = |port: &str| -> Result<u16, io::Error> {
+                // Leading zeros are not allowed by the specification.
+                if port.len() > 1 && port.starts_with('0') {
+                    return Err(invalid("invalid port in PROXY protocol header"));
+                }
+
+                port.parse()
+                    .map_err(|_| invalid("invalid port in PROXY protocol header"))
+            };
+
+            Ok(Some(Header {
+                source: SocketAddr::new(parse_ip(source)?, parse_port(source_port)?),
+                destination: SocketAddr::new(parse_ip(destination)?, parse_port(destination_port)?),
+            }))
+        }
+
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+/// Reads the rest of a version 2 header after the first bytes of
+/// [`SIGNATURE`].
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Header>, io::Error> {
+    // Rest of the signature, version and command, family and length.
+    let mut fixed = [0; SIGNATURE.len() - PREFIX.len() + 4];
+    stream.read_exact(&mut fixed).await?;
+
+    let (signature, fixed) = fixed.split_at(SIGNATURE.len() - PREFIX.len());
+
+    if signature != &SIGNATURE[PREFIX.len()..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+
+    let [version_and_command, family, high, low] = fixed.try_into().unwrap();
+
+    if version_and_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0; u16::from_be_bytes([high, low]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_and_command & 0x0F {
+        // LOCAL, the connection was opened by the proxy itself.
+        0x0 => Ok(None),
+        // PROXY.
+        0x1 => parse_v2_addresses(family, &addresses),
+        _ => Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+}
+
+/// Parses the address block of a version 2 header. TLVs that might come after
+/// the addresses are ignored.
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Result<Option<Header>, io::Error> {
+    let too_short = || invalid("PROXY protocol v2 addresses are too short");
+
+    // The low nibble is the transport protocol, STREAM or DGRAM.
+    match family >> 4 {
+        // AF_INET.
+        0x1 => {
+            let block: [u8; 12] = addresses
+                .get(..12)
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap();
+            let ip = |bytes: &[u8]| IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()));
+
+            Ok(Some(Header {
+                source: SocketAddr::new(ip(&block[0..4]), port(&block[8..10])),
+                destination: SocketAddr::new(ip(&block[4..8]), port(&block[10..12])),
+            }))
+        }
+
+        // AF_INET6.
+        0x2 => {
+            let block: [u8; 36] = addresses
+                .get(..36)
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap();
+            let ip =
+                |bytes: &[u8]| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()));
+
+            Ok(Some(Header {
+                source: SocketAddr::new(ip(&block[0..16]), port(&block[32..34])),
+                destination: SocketAddr::new(ip(&block[16..32]), port(&block[34..36])),
+            }))
+        }
+
+        // AF_UNSPEC and AF_UNIX don't give us an IP address.
+        0x0 | 0x3 => Ok(None),
+
+        _ => Err(invalid("unsupported PROXY protocol v2 address family")),
+    }
+}
+
+/// Builds the header that tells a backend the original addresses of the
+/// connection. If they are unknown, for example because the client connected
+/// through a Unix socket, the header says so and the backend should use the
+/// addresses of the connection itself.
+pub(crate) fn encode(version: ProxyProtocolVersion, header: Option<Header>) -> Vec<u8> {
+    // Both addresses must belong to the same family. Dual stack sockets can
+    // mix them, in which case the IPv4 one is mapped to IPv6.
+    let header = header.map(
+        |Header {
+             source,
+             destination,
+         }| {
+            let map = |address: SocketAddr| match address.ip() {
+                IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), address.port()),
+                IpAddr::V6(_) => address,
+            };
+
+            if source.is_ipv4() == destination.is_ipv4() {
+                Header {
+                    source,
+                    destination,
+                }
+            } else {
+                Header {
+                    source: map(source),
+                    destination: map(destination),
+                }
+            }
+        },
+    );
+
+    match version {
+        ProxyProtocolVersion::V1 => encode_v1(header),
+        ProxyProtocolVersion::V2 => encode_v2(header),
+    }
+}
+
+fn encode_v1(header: Option<Header>) -> Vec<u8> {
+    let Some(Header {
+        source,
+        destination,
+    }) = header
+    else {
+        return Vec::from(*b"PROXY UNKNOWN\r\n");
+    };
+
+    let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+
+    format!(
+        "PROXY {protocol} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn encode_v2(header: Option<Header>) -> Vec<u8> {
+    let mut bytes = Vec::from(SIGNATURE);
+
+    let Some(Header {
+        source,
+        destination,
+    }) = header
+    else {
+        // LOCAL command with AF_UNSPEC and no addresses.
+        bytes.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        return bytes;
+    };
+
+    let addresses = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            [source.octets(), destination.octets()].concat()
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            [source.octets(), destination.octets()].concat()
+        }
+        _ => unreachable!("addresses are mapped to the same family in encode()"),
+    };
+
+    // PROXY command, then AF_INET or AF_INET6 over STREAM.
+    let family = if source.is_ipv4() { 0x11 } else { 0x21 };
+    let length = (addresses.len() + 4) as u16;
+
+    bytes.extend_from_slice(&[0x21, family]);
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(&addresses);
+    bytes.extend_from_slice(&source.port().to_be_bytes());
+    bytes.extend_from_slice(&destination.port().to_be_bytes());
+
+    bytes
+}
+
+/// Reads a big endian port number.
+fn port(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header from `bytes` and returns it with the bytes that were not
+    /// consumed.
+    async fn read_from(mut bytes: &[u8]) -> (Result<Option<Header>, io::Error>, &[u8]) {
+        let header = read(&mut bytes).await;
+        (header, bytes)
+    }
+
+    fn header(source: &str, destination: &str) -> Option<Header> {
+        Some(Header {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    #[tokio::test]
+    async fn v1_headers() {
+        let (result, rest) =
+            read_from(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            header("203.0.113.7:51234", "192.0.2.1:443")
+        );
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = read_from(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            header("[2001:db8::1]:4000", "[2001:db8::2]:80")
+        );
+
+        let (result, rest) = read_from(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn invalid_v1_headers() {
+        for bytes in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.1 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 051234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 70000 443\r\n",
+            &[b"PROXY UNKNOWN ".as_slice(), &[b'x'; 128]].concat(),
+        ] {
+            let (result, _) = read_from(bytes).await;
+            assert!(result.is_err(), "{:?}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let mut ipv4 = Vec::from(SIGNATURE);
+        ipv4.extend_from_slice(&[0x21, 0x11, 0, 12 + 3]);
+        ipv4.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1, 0xC8, 0x22, 0x01, 0xBB]);
+        // TLV with no value, must be skipped.
+        ipv4.extend_from_slice(&[0x04, 0, 0]);
+        ipv4.extend_from_slice(b"GET");
+
+        let (result, rest) = read_from(&ipv4).await;
+        assert_eq!(
+            result.unwrap(),
+            header("203.0.113.7:51234", "192.0.2.1:443")
+        );
+        assert_eq!(rest, b"GET");
+
+        let mut ipv6 = Vec::from(SIGNATURE);
+        ipv6.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0x0F, 0xA0, 0x00, 0x50]);
+
+        let (result, _) = read_from(&ipv6).await;
+        assert_eq!(
+            result.unwrap(),
+            header("[2001:db8::1]:4000", "[2001:db8::2]:80")
+        );
+
+        let mut local = Vec::from(SIGNATURE);
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        local.extend_from_slice(b"GET");
+
+        let (result, rest) = read_from(&local).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn encoded_headers_can_be_read() {
+        let headers = [
+            header("203.0.113.7:51234", "192.0.2.1:443"),
+            header("[2001:db8::1]:4000", "[2001:db8::2]:80"),
+            None,
+        ];
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for expected in headers {
+                let bytes = encode(version, expected);
+                let (result, rest) = read_from(&bytes).await;
+                assert_eq!(result.unwrap(), expected);
+                assert!(rest.is_empty());
+            }
+
+            let (result, _) = read_from(&encode(version, header("1.2.3.4:5", "[::1]:6"))).await;
+            assert_eq!(result.unwrap(), header("[::ffff:1.2.3.4]:5", "[::1]:6"));
+        }
+
+        assert_eq!(
+            encode(
+                ProxyProtocolVersion::V1,
+                header("203.0.113.7:51234", "192.0.2.1:443")
+            ),
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_v2_headers() {
+        let mut version = Vec::from(SIGNATURE);
+        version.extend_from_slice(&[0x11, 0x11, 0, 0]);
+
+        let mut short = Vec::from(SIGNATURE);
+        short.extend_from_slice(&[0x21, 0x11, 0, 4, 1, 2, 3, 4]);
+
+        let mut truncated = Vec::from(SIGNATURE);
+        truncated.extend_from_slice(&[0x21, 0x11, 0, 12, 1, 2, 3]);
+
+        for bytes in [version, short, truncated] {
+            let (result, _) = read_from(&bytes).await;
+            assert!(result.is_err());
+        }
+    }
+}
@@ -1,7 +1,5 @@
 //! Load balancing and scheduler implementations.
 
-use std::net::SocketAddr;
-
 mod wrr;
 
 pub use wrr::WeightedRoundRobin;
@@ -11,8 +9,8 @@ use crate::config::{Algorithm, Backend};
 /// A scheduler provides an algorithm for load balancing between multiple
 /// backend servers.
 pub trait Scheduler {
-    /// Returns the address of the server that should process the next request.
-    fn next_server(&self) -> SocketAddr;
+    /// Returns the backend server that should process the next request.
+    fn next_server(&self) -> &Backend;
 
     // Notify the scheduler when a server has processed a request. This is
     // useful for implementing load balancing algorithms such as "Least
@@ -1,5 +1,3 @@
-use std::net::SocketAddr;
-
 use super::Scheduler;
 use crate::{config::Backend, sync::ring::Ring};
 
@@ -19,7 +17,7 @@ pub struct WeightedRoundRobin {
     /// request is going to be sent just by looking at the weight of each server
     /// once, so we can calculate one cycle at the beginning of the program and
     /// then return values from it.
-    cycle: Ring<SocketAddr>,
+    cycle: Ring<Backend>,
 }
 
 impl WeightedRoundRobin {
@@ -31,7 +29,7 @@ impl WeightedRoundRobin {
         for backend in backends {
             let mut weight = backend.weight;
             while weight > 0 {
-                cycle.push(backend.address);
+                cycle.push(backend.clone());
                 weight -= 1;
             }
         }
@@ -43,8 +41,8 @@ impl WeightedRoundRobin {
 }
 
 impl Scheduler for WeightedRoundRobin {
-    fn next_server(&self) -> SocketAddr {
-        self.cycle.next_as_owned()
+    fn next_server(&self) -> &Backend {
+        self.cycle.next_as_ref()
     }
 }
 
@@ -54,13 +52,13 @@ mod tests {
 
     #[test]
     fn weighted_round_robin() {
-        let backends = vec![
+        let backends = [
             ("127.0.0.1:8080", 1),
             ("127.0.0.1:8081", 3),
             ("127.0.0.1:8082", 2),
         ];
 
-        let expected = vec![
+        let expected = [
             "127.0.0.1:8080",
             "127.0.0.1:8081",
             "127.0.0.1:8081",
@@ -75,12 +73,14 @@ mod tests {
                 .map(|(addr, weight)| Backend {
                     address: addr.parse().unwrap(),
                     weight: *weight,
+                    tls: None,
+                    send_proxy_protocol: None,
                 })
                 .collect(),
         );
 
         for server in expected {
-            assert_eq!(server, wrr.next_server().to_string());
+            assert_eq!(server, wrr.next_server().address.to_string());
         }
     }
 }
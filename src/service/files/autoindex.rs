@@ -0,0 +1,217 @@
+//! Directory listings for directories without index file, enabled with
+//! [`crate::config::Files::autoindex`]. Listings are HTML pages for browsers
+//! and JSON arrays for clients that prefer `application/json`:
+//!
+//! ```json
+//! [
+//!     { "name": "docs", "type": "directory", "size": null, "modified": "Mon, 01 Jan 2024 00:00:00 GMT" },
+//!     { "name": "notes.txt", "type": "file", "size": 1024, "modified": "Mon, 01 Jan 2024 00:00:00 GMT" }
+//! ]
+//! ```
+//!
+//! Hidden files, whose name starts with a dot, are never listed. Neither are
+//! symlinks that point outside of the root directory, since they can't be
+//! served anyway.
+
+use std::{fmt::Write, path::Path, time::SystemTime};
+
+use http::{header, request, HeaderMap};
+use httpdate::HttpDate;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Serialize;
+
+use crate::http::{
+    body,
+    response::{BoxBodyResponse, LocalResponse},
+};
+
+/// Characters that are percent encoded in links. Unreserved characters are
+/// left as they are, see [RFC 3986 section 2.3](https://www.rfc-editor.org/rfc/rfc3986#section-2.3).
+const LINK: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Directory entry.
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Kind,
+    size: Option<u64>,
+    #[serde(serialize_with = "http_date")]
+    modified: Option<SystemTime>,
+}
+
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Directory,
+    File,
+}
+
+/// Responds with the listing of `path`, which must be a directory inside the
+/// root `directory`. `uri_path` is the decoded path that the client requested.
+pub(super) async fn list(
+    request: &request::Parts,
+    uri_path: &str,
+    directory: &Path,
+    path: &Path,
+) -> BoxBodyResponse {
+    let Ok(entries) = entries(directory, path).await else {
+        return LocalResponse::not_found();
+    };
+
+    let (content_type, content) = if prefers_json(&request.headers) {
+        ("application/json", serde_json::to_string(&entries).unwrap())
+    } else {
+        ("text/html; charset=utf-8", html(uri_path, &entries))
+    };
+
+    LocalResponse::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::VARY, "Accept")
+        .body(body::full(content))
+        .unwrap()
+}
+
+/// Reads the visible entries of `path`, directories first and then sorted by
+/// name.
+async fn entries(directory: &Path, path: &Path) -> Result<Vec<Entry>, std::io::Error> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(path).await?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let Some(target) = super::resolve(directory, &entry.path()) else {
+            continue;
+        };
+
+        let Ok(metadata) = tokio::fs::metadata(target).await else {
+            continue;
+        };
+
+        let kind = match metadata.is_dir() {
+            true => Kind::Directory,
+            false => Kind::File,
+        };
+
+        entries.push(Entry {
+            name,
+            size: (kind == Kind::File).then_some(metadata.len()),
+            kind,
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    entries.sort_by(|a, b| (&a.kind, &a.name).cmp(&(&b.kind, &b.name)));
+
+    Ok(entries)
+}
+
+/// Whether the client prefers JSON over HTML according to its `Accept`
+/// header. Browsers get HTML, and so does everyone else by default.
+fn prefers_json(headers: &HeaderMap) -> bool {
+    let mut json = 0;
+    let mut html = 0;
+
+    let values = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    for value in values {
+        let mut params = value.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+
+        let Some(weight) = params
+            .find_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+            .map_or(Some(1000), super::qvalue)
+        else {
+            continue;
+        };
+
+        match media_type.as_str() {
+            "application/json" => json = json.max(weight),
+            "text/html" => html = html.max(weight),
+            _ => {}
+        }
+    }
+
+    json > html
+}
+
+/// Renders the HTML listing of the directory at `uri_path`.
+fn html(uri_path: &str, entries: &[Entry]) -> String {
+    let title = escape(&format!("Index of {uri_path}"));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n"
+    );
+
+    if uri_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let slash = if entry.kind == Kind::Directory {
+            "/"
+        } else {
+            ""
+        };
+        let size = entry.size.map(|size| size.to_string()).unwrap_or_default();
+        let modified = entry
+            .modified
+            .map(|modified| HttpDate::from(modified).to_string())
+            .unwrap_or_default();
+
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            utf8_percent_encode(&entry.name, LINK),
+            escape(&entry.name),
+        );
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    html
+}
+
+/// Escapes text for HTML element content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+/// Serializes modification times as HTTP dates, like `Last-Modified`.
+fn http_date<S: serde::Serializer>(
+    modified: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match modified {
+        Some(modified) => serializer.serialize_str(&HttpDate::from(*modified).to_string()),
+        None => serializer.serialize_none(),
+    }
+}
@@ -0,0 +1,197 @@
+//! Content codings for static files, see
+//! [RFC 9110 section 8.4](https://www.rfc-editor.org/rfc/rfc9110#section-8.4).
+//! Clients list the codings they accept in the `Accept-Encoding` header. Files
+//! that have a precompressed variant next to them, like `app.js.br` for
+//! `app.js`, are sent as they are, and other files can be compressed on the
+//! fly. See [`crate::config::Compression`].
+
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    Level,
+};
+use bytes::Bytes;
+use http::HeaderMap;
+use http_body_util::combinators::BoxBody;
+use hyper::header;
+use tokio::{fs::File, io::BufReader};
+
+use crate::http::body::{self, BodyError};
+
+/// Content codings supported by the server, in order of preference when the
+/// client accepts more than one with the same weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// All the supported codings, in order of preference.
+    const ALL: [Self; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    /// Name used in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of precompressed variants.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+
+    /// Streams `file` compressed with this coding.
+    pub fn encode(self, file: File) -> BoxBody<Bytes, BodyError> {
+        let file = BufReader::new(file);
+
+        match self {
+            // The default quality of Brotli is the best one, which is too slow
+            // for compressing on every request.
+            Self::Brotli => body::reader(BrotliEncoder::with_quality(file, Level::Precise(4))),
+            Self::Zstd => body::reader(ZstdEncoder::new(file)),
+            Self::Gzip => body::reader(GzipEncoder::new(file)),
+        }
+    }
+}
+
+/// Returns the codings accepted by the client, best first. Codings are
+/// sorted by the weight given by the client and then by our own preference,
+/// see [`Encoding::ALL`]. Codings with weight 0 are not acceptable.
+pub(super) fn accepted(headers: &HeaderMap) -> Vec<Encoding> {
+    let mut weights: [Option<u16>; Encoding::ALL.len()] = [None; Encoding::ALL.len()];
+    let mut any = None;
+
+    let values = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    for value in values {
+        let mut params = value.split(';').map(str::trim);
+
+        let coding = match params.next().unwrap_or_default().to_ascii_lowercase() {
+            coding if coding == "x-gzip" => String::from("gzip"),
+            coding => coding,
+        };
+
+        let Some(weight) = params
+            .find_map(|param| param.strip_prefix("q=").or(param.strip_prefix("Q=")))
+            .map_or(Some(1000), super::qvalue)
+        else {
+            continue;
+        };
+
+        match coding.as_str() {
+            "*" => any = Some(weight),
+            coding => {
+                if let Some(i) = Encoding::ALL.iter().position(|e| e.name() == coding) {
+                    weights[i] = Some(weight);
+                }
+            }
+        }
+    }
+
+    let mut accepted: Vec<(u16, Encoding)> = Encoding::ALL
+        .into_iter()
+        .zip(weights)
+        .filter_map(|(encoding, weight)| Some((weight.or(any)?, encoding)))
+        .filter(|(weight, _)| *weight > 0)
+        .collect();
+
+    // Stable sort, so equal weights keep the order of preference.
+    accepted.sort_by_key(|(weight, _)| u16::MAX - weight);
+
+    accepted.into_iter().map(|(_, encoding)| encoding).collect()
+}
+
+/// Whether files of the given media type are worth compressing. Most binary
+/// formats like images, videos or archives are already compressed.
+pub(super) fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn accept_encoding(value: &'static str) -> Vec<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        accepted(&headers)
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        use Encoding::*;
+
+        let cases = [
+            ("", vec![]),
+            ("identity", vec![]),
+            ("gzip", vec![Gzip]),
+            ("gzip, deflate, br, zstd", vec![Brotli, Zstd, Gzip]),
+            ("gzip;q=1.0, br;q=0.5", vec![Gzip, Brotli]),
+            ("br;q=0.8, zstd;q=0.9, GZIP;Q=0.1", vec![Zstd, Brotli, Gzip]),
+            ("*", vec![Brotli, Zstd, Gzip]),
+            ("*;q=0.5, gzip", vec![Gzip, Brotli, Zstd]),
+            ("*, br;q=0", vec![Zstd, Gzip]),
+            ("x-gzip", vec![Gzip]),
+            ("gzip;q=2, br;q=0.1234, zstd", vec![Zstd]),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(accept_encoding(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn compressible_types() {
+        for content_type in [
+            "text/html",
+            "text/css; charset=utf-8",
+            "application/json",
+            "image/svg+xml",
+        ] {
+            assert!(is_compressible(content_type), "{content_type}");
+        }
+
+        for content_type in [
+            "image/png",
+            "video/mp4",
+            "application/zip",
+            "application/octet-stream",
+        ] {
+            assert!(!is_compressible(content_type), "{content_type}");
+        }
+    }
+}
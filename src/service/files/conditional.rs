@@ -0,0 +1,495 @@
+//! Validators and conditional requests, see
+//! [RFC 9110 section 13](https://www.rfc-editor.org/rfc/rfc9110#section-13).
+//! Files are sent with `Last-Modified` and `ETag` headers, and clients can use
+//! them to make the request conditional: caches revalidate their copies with
+//! `If-None-Match` or `If-Modified-Since` and get `304 Not Modified` if they
+//! are still fresh, while `If-Match` and `If-Unmodified-Since` prevent
+//! lost updates with `412 Precondition Failed`.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{header, request, HeaderName, Method, StatusCode};
+use httpdate::HttpDate;
+use ring::digest;
+use tokio::{fs::File, io::AsyncReadExt};
+
+use super::compression::Encoding;
+use crate::config::ETag;
+
+/// Entity tags computed from the content of files, by path. Each one is kept
+/// with the size and modification time of the file when it was computed.
+type Hashes = HashMap<PathBuf, (u64, SystemTime, EntityTag)>;
+
+/// Hashes of files that have been computed already, so they are only computed
+/// again when the size or modification time of the file changes.
+static HASHES: LazyLock<Mutex<Hashes>> = LazyLock::new(Mutex::default);
+
+/// The hash cache is cleared when it reaches this many files. That's simpler
+/// than evicting entries one by one and good enough for sites that don't have
+/// that many files.
+const MAX_CACHED_HASHES: usize = 4096;
+
+/// Opaque validator, see
+/// [RFC 9110 section 8.8.3](https://www.rfc-editor.org/rfc/rfc9110#section-8.8.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct EntityTag {
+    /// Weak tags only mean that the content is semantically equivalent.
+    weak: bool,
+
+    /// Content of the tag without quotes.
+    tag: String,
+}
+
+impl EntityTag {
+    /// Both tags are strong and have the same content.
+    fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Both tags have the same content, strong or not.
+    fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Validators of the file that would be sent in the response.
+pub(super) struct Validators {
+    /// Entity tag, if it could be computed.
+    pub etag: Option<EntityTag>,
+
+    /// Modification time of the file, if the platform provides it.
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Computes the validators of the file at `path`.
+    pub async fn new(kind: ETag, path: &Path, metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+
+        let etag = match kind {
+            ETag::Metadata => last_modified.map(|modified| {
+                let nanos = modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+
+                EntityTag {
+                    weak: true,
+                    tag: format!("{:x}-{nanos:x}", metadata.len()),
+                }
+            }),
+
+            ETag::Hash => match last_modified {
+                Some(modified) => hash(path, metadata.len(), modified).await,
+                None => None,
+            },
+        };
+
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    /// Validators of the file compressed on the fly with `encoding`. Entity
+    /// tags must be different for each coding, and they are weak because
+    /// encoders don't guarantee the same output for the same input.
+    pub fn encoded(mut self, encoding: Encoding) -> Self {
+        if let Some(etag) = &mut self.etag {
+            etag.weak = true;
+            etag.tag = format!("{}-{}", etag.tag, encoding.name());
+        }
+
+        self
+    }
+
+    /// `Last-Modified` header value.
+    pub fn last_modified_header(&self) -> Option<String> {
+        self.last_modified
+            .map(|last_modified| HttpDate::from(last_modified).to_string())
+    }
+
+    /// Dates have a resolution of one second, so they are only strong
+    /// validators if the file was not modified again during the same second.
+    /// See [RFC 9110 section 8.8.2.2](https://www.rfc-editor.org/rfc/rfc9110#section-8.8.2.2).
+    fn has_strong_date(&self) -> bool {
+        self.last_modified.is_some_and(|last_modified| {
+            SystemTime::now()
+                .duration_since(last_modified)
+                .is_ok_and(|elapsed| elapsed >= Duration::from_secs(1))
+        })
+    }
+
+    /// Whether the file has been modified after `date`.
+    fn modified_since(&self, date: HttpDate) -> bool {
+        self.last_modified
+            .is_none_or(|last_modified| HttpDate::from(last_modified) > date)
+    }
+}
+
+/// Computes a strong entity tag from the SHA-256 hash of the file content,
+/// or returns the cached one if the file has not changed.
+async fn hash(path: &Path, len: u64, modified: SystemTime) -> Option<EntityTag> {
+    if let Some((cached_len, cached_modified, etag)) = HASHES.lock().unwrap().get(path) {
+        if (*cached_len, *cached_modified) == (len, modified) {
+            return Some(etag.clone());
+        }
+    }
+
+    let mut file = File::open(path).await.ok()?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        match file.read(&mut buf).await.ok()? {
+            0 => break,
+            read => context.update(&buf[..read]),
+        }
+    }
+
+    let etag = EntityTag {
+        weak: false,
+        tag: URL_SAFE_NO_PAD.encode(context.finish()),
+    };
+
+    let mut hashes = HASHES.lock().unwrap();
+
+    if hashes.len() >= MAX_CACHED_HASHES {
+        hashes.clear();
+    }
+
+    hashes.insert(path.to_path_buf(), (len, modified, etag.clone()));
+
+    Some(etag)
+}
+
+/// Condition of `If-Match` and `If-None-Match` headers.
+#[derive(Debug, PartialEq)]
+enum Condition {
+    /// `*`, matches any current representation.
+    Any,
+
+    /// List of entity tags.
+    Tags(Vec<EntityTag>),
+}
+
+impl Condition {
+    /// Parses all the values of the header `name`. Returns [`None`] if the
+    /// header is not present or is invalid, in which case it's ignored.
+    fn parse(request: &request::Parts, name: HeaderName) -> Option<Self> {
+        let mut tags = Vec::new();
+        let mut present = false;
+
+        for value in request.headers.get_all(name) {
+            present = true;
+
+            let value = value.to_str().ok()?.trim();
+
+            if value == "*" {
+                return Some(Self::Any);
+            }
+
+            tags.extend(parse_tags(value)?);
+        }
+
+        present.then_some(Self::Tags(tags))
+    }
+
+    /// Evaluates the condition against the current entity tag using the
+    /// given comparison function.
+    fn matches(&self, etag: Option<&EntityTag>, eq: fn(&EntityTag, &EntityTag) -> bool) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Tags(tags) => etag.is_some_and(|etag| tags.iter().any(|tag| eq(tag, etag))),
+        }
+    }
+}
+
+/// Parses a comma separated list of entity tags.
+fn parse_tags(mut value: &str) -> Option<Vec<EntityTag>> {
+    let mut tags = Vec::new();
+
+    loop {
+        value = value.trim_start_matches([' ', '\t', ',']);
+
+        if value.is_empty() {
+            return Some(tags);
+        }
+
+        let weak = value.starts_with("W/");
+
+        if weak {
+            value = &value[2..];
+        }
+
+        let (tag, rest) = value.strip_prefix('"')?.split_once('"')?;
+
+        tags.push(EntityTag {
+            weak,
+            tag: String::from(tag),
+        });
+
+        value = rest.trim_start_matches([' ', '\t']);
+
+        if !value.is_empty() && !value.starts_with(',') {
+            return None;
+        }
+    }
+}
+
+/// Parses the date of the header `name`.
+fn date(request: &request::Parts, name: HeaderName) -> Option<HttpDate> {
+    request.headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Evaluates the preconditions of the request in the order defined by
+/// [RFC 9110 section 13.2.2](https://www.rfc-editor.org/rfc/rfc9110#section-13.2.2).
+/// Returns the status code of the response if the request should not be
+/// served normally, which is either `304 Not Modified` or
+/// `412 Precondition Failed`. `If-Range` is evaluated separately, see
+/// [`if_range`].
+pub(super) fn evaluate(request: &request::Parts, validators: &Validators) -> Option<StatusCode> {
+    let etag = validators.etag.as_ref();
+    let is_get_or_head = request.method == Method::GET || request.method == Method::HEAD;
+
+    if let Some(if_match) = Condition::parse(request, header::IF_MATCH) {
+        if !if_match.matches(etag, EntityTag::strong_eq) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(date) = date(request, header::IF_UNMODIFIED_SINCE) {
+        if validators.modified_since(date) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = Condition::parse(request, header::IF_NONE_MATCH) {
+        if if_none_match.matches(etag, EntityTag::weak_eq) {
+            return Some(match is_get_or_head {
+                true => StatusCode::NOT_MODIFIED,
+                false => StatusCode::PRECONDITION_FAILED,
+            });
+        }
+    } else if let Some(date) = date(request, header::IF_MODIFIED_SINCE) {
+        if is_get_or_head && !validators.modified_since(date) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+
+    None
+}
+
+/// Evaluates the `If-Range` precondition, see
+/// [RFC 9110 section 13.1.5](https://www.rfc-editor.org/rfc/rfc9110#section-13.1.5).
+/// The range request is only honored if the file has not changed since the
+/// client obtained the validator, otherwise the full file is sent. Only strong
+/// validators can be used.
+pub(super) fn if_range(if_range: &str, validators: &Validators) -> bool {
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match (parse_tags(if_range).as_deref(), &validators.etag) {
+            (Some([tag]), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        };
+    }
+
+    let (Ok(date), Some(last_modified)) = (if_range.parse::<HttpDate>(), validators.last_modified)
+    else {
+        return false;
+    };
+
+    validators.has_strong_date() && date == HttpDate::from(last_modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+
+    use super::*;
+
+    fn strong(tag: &str) -> EntityTag {
+        EntityTag {
+            weak: false,
+            tag: String::from(tag),
+        }
+    }
+
+    fn weak(tag: &str) -> EntityTag {
+        EntityTag {
+            weak: true,
+            tag: String::from(tag),
+        }
+    }
+
+    fn request(method: Method, headers: &[(HeaderName, &str)]) -> request::Parts {
+        let mut request = Request::builder().method(method);
+
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+
+        request.body(()).unwrap().into_parts().0
+    }
+
+    /// Validators of a file modified on 2024-01-01 00:00:00 UTC.
+    fn validators(etag: EntityTag) -> Validators {
+        Validators {
+            etag: Some(etag),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(1704067200)),
+        }
+    }
+
+    #[test]
+    fn entity_tag_lists() {
+        assert_eq!(
+            parse_tags(r#""abc", W/"d,e" ,"""#),
+            Some(vec![strong("abc"), weak("d,e"), strong("")])
+        );
+
+        for invalid in [r#"abc"#, r#""abc"#, r#""a" "b""#, r#"w/"a""#] {
+            assert_eq!(parse_tags(invalid), None, "{invalid}");
+        }
+
+        assert_eq!(weak("1-2").to_string(), r#"W/"1-2""#);
+        assert_eq!(strong("xyz").to_string(), r#""xyz""#);
+    }
+
+    #[test]
+    fn if_none_match() {
+        let validators = validators(weak("abc"));
+
+        let cases = [
+            (Method::GET, r#""abc""#, Some(StatusCode::NOT_MODIFIED)),
+            (Method::HEAD, r#"W/"abc""#, Some(StatusCode::NOT_MODIFIED)),
+            (
+                Method::GET,
+                r#""xyz", W/"abc""#,
+                Some(StatusCode::NOT_MODIFIED),
+            ),
+            (Method::GET, "*", Some(StatusCode::NOT_MODIFIED)),
+            (Method::PUT, "*", Some(StatusCode::PRECONDITION_FAILED)),
+            (Method::GET, r#""xyz""#, None),
+        ];
+
+        for (method, value, expected) in cases {
+            let request = request(method, &[(header::IF_NONE_MATCH, value)]);
+            assert_eq!(evaluate(&request, &validators), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn if_match() {
+        let cases = [
+            (strong("abc"), r#""abc""#, None),
+            (strong("abc"), "*", None),
+            (
+                strong("abc"),
+                r#""xyz""#,
+                Some(StatusCode::PRECONDITION_FAILED),
+            ),
+            (
+                weak("abc"),
+                r#""abc""#,
+                Some(StatusCode::PRECONDITION_FAILED),
+            ),
+            (
+                weak("abc"),
+                r#"W/"abc""#,
+                Some(StatusCode::PRECONDITION_FAILED),
+            ),
+        ];
+
+        for (etag, value, expected) in cases {
+            let request = request(Method::PUT, &[(header::IF_MATCH, value)]);
+            assert_eq!(evaluate(&request, &validators(etag)), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn dates() {
+        let validators = validators(weak("abc"));
+
+        let cases = [
+            (
+                header::IF_MODIFIED_SINCE,
+                "Mon, 01 Jan 2024 00:00:00 GMT",
+                Some(StatusCode::NOT_MODIFIED),
+            ),
+            (
+                header::IF_MODIFIED_SINCE,
+                "Tue, 02 Jan 2024 00:00:00 GMT",
+                Some(StatusCode::NOT_MODIFIED),
+            ),
+            (
+                header::IF_MODIFIED_SINCE,
+                "Sun, 31 Dec 2023 00:00:00 GMT",
+                None,
+            ),
+            (header::IF_MODIFIED_SINCE, "yesterday", None),
+            (
+                header::IF_UNMODIFIED_SINCE,
+                "Mon, 01 Jan 2024 00:00:00 GMT",
+                None,
+            ),
+            (
+                header::IF_UNMODIFIED_SINCE,
+                "Sun, 31 Dec 2023 00:00:00 GMT",
+                Some(StatusCode::PRECONDITION_FAILED),
+            ),
+        ];
+
+        for (name, value, expected) in cases {
+            let request = request(Method::GET, &[(name, value)]);
+            assert_eq!(evaluate(&request, &validators), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn entity_tags_take_precedence_over_dates() {
+        let validators = validators(weak("abc"));
+
+        // The date alone would produce 304.
+        let request = request(Method::GET, &[
+            (header::IF_NONE_MATCH, r#""xyz""#),
+            (header::IF_MODIFIED_SINCE, "Tue, 02 Jan 2024 00:00:00 GMT"),
+        ]);
+        assert_eq!(evaluate(&request, &validators), None);
+
+        // The date alone would produce 412.
+        let request = self::request(Method::GET, &[
+            (header::IF_MATCH, "*"),
+            (header::IF_UNMODIFIED_SINCE, "Sun, 31 Dec 2023 00:00:00 GMT"),
+        ]);
+        assert_eq!(evaluate(&request, &validators), None);
+    }
+
+    #[test]
+    fn if_range_validators() {
+        let strong_validators = validators(strong("abc"));
+        let weak_validators = validators(weak("abc"));
+
+        assert!(if_range(r#""abc""#, &strong_validators));
+        assert!(!if_range(r#""xyz""#, &strong_validators));
+        assert!(!if_range(r#"W/"abc""#, &weak_validators));
+        assert!(!if_range(r#""abc""#, &weak_validators));
+
+        assert!(if_range("Mon, 01 Jan 2024 00:00:00 GMT", &weak_validators));
+        assert!(!if_range("Tue, 02 Jan 2024 00:00:00 GMT", &weak_validators));
+    }
+}
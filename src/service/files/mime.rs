@@ -0,0 +1,210 @@
+//! Media types of static files, see [`content_type`].
+
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
+/// Media type of files whose extension is unknown. Clients won't try to
+/// render them, they're downloaded instead.
+const DEFAULT: &str = "application/octet-stream";
+
+/// Known extensions and their media types, sorted by extension so that they
+/// can be binary searched. Based on the [IANA registry](https://www.iana.org/assignments/media-types/media-types.xhtml)
+/// and the types that browsers expect for the web platform.
+const TYPES: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("aac", "audio/aac"),
+    ("apng", "image/apng"),
+    ("atom", "application/atom+xml"),
+    ("avi", "video/x-msvideo"),
+    ("avif", "image/avif"),
+    ("bin", "application/octet-stream"),
+    ("bmp", "image/bmp"),
+    ("bz2", "application/x-bzip2"),
+    ("cjs", "text/javascript"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("epub", "application/epub+zip"),
+    ("flac", "audio/flac"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/x-icon"),
+    ("ics", "text/calendar"),
+    ("jar", "application/java-archive"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("m4a", "audio/mp4"),
+    ("m4v", "video/mp4"),
+    ("map", "application/json"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("opus", "audio/opus"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("rar", "application/vnd.rar"),
+    ("rss", "application/rss+xml"),
+    ("rtf", "application/rtf"),
+    ("sh", "application/x-sh"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+/// Returns the value of the `Content-Type` header for the file at `path`
+/// based on its extension. Extensions are case insensitive and `overrides`
+/// take precedence over the builtin table, see
+/// [`crate::config::Server::mime_types`]. Text types get the UTF-8 charset
+/// parameter unless it's already there.
+pub(super) fn content_type<'a>(
+    path: &Path,
+    overrides: &'a HashMap<String, String>,
+) -> Cow<'a, str> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    let media_type = overrides
+        .get(&extension)
+        .map(String::as_str)
+        .or_else(|| lookup(&extension))
+        .unwrap_or(DEFAULT);
+
+    if media_type.contains(';') || !is_text(media_type) {
+        return Cow::Borrowed(media_type);
+    }
+
+    Cow::Owned(format!("{media_type}; charset=utf-8"))
+}
+
+/// Finds the media type of an extension in the builtin table.
+fn lookup(extension: &str) -> Option<&'static str> {
+    TYPES
+        .binary_search_by_key(&extension, |(extension, _)| extension)
+        .ok()
+        .map(|index| TYPES[index].1)
+}
+
+/// Whether the media type describes text that clients decode with a charset.
+fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type,
+            "application/json" | "application/xml" | "application/toml" | "application/yaml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_is_sorted() {
+        for window in TYPES.windows(2) {
+            assert!(window[0].0 < window[1].0, "{} {}", window[0].0, window[1].0);
+        }
+    }
+
+    #[test]
+    fn builtin_types() {
+        let overrides = HashMap::new();
+
+        for (file, expected) in [
+            ("index.html", "text/html; charset=utf-8"),
+            ("app.js", "text/javascript; charset=utf-8"),
+            ("data.json", "application/json; charset=utf-8"),
+            ("logo.svg", "image/svg+xml; charset=utf-8"),
+            ("module.wasm", "application/wasm"),
+            ("font.woff2", "font/woff2"),
+            ("photo.jpg", "image/jpeg"),
+            ("PHOTO.JPG", "image/jpeg"),
+            ("archive.tar.gz", "application/gzip"),
+            ("README", "application/octet-stream"),
+            ("file.unknown", "application/octet-stream"),
+        ] {
+            assert_eq!(
+                content_type(Path::new(file), &overrides),
+                expected,
+                "{file}"
+            );
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let overrides = HashMap::from([
+            (String::from("js"), String::from("application/javascript")),
+            (
+                String::from("log"),
+                String::from("text/plain; charset=latin1"),
+            ),
+            (String::from("dat"), String::from("application/x-custom")),
+        ]);
+
+        for (file, expected) in [
+            ("app.js", "application/javascript"),
+            ("server.log", "text/plain; charset=latin1"),
+            ("save.dat", "application/x-custom"),
+            ("style.css", "text/css; charset=utf-8"),
+        ] {
+            assert_eq!(
+                content_type(Path::new(file), &overrides),
+                expected,
+                "{file}"
+            );
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Static files server sub-service.
+
+mod autoindex;
+mod compression;
+mod conditional;
+mod mime;
+mod range;
+
+use std::{
+    ffi::OsString,
+    fs::Metadata,
+    path::{Path, PathBuf},
+};
+
+use http::{request, Method, StatusCode};
+use hyper::header;
+use tokio::fs::File;
+
+use self::{compression::Encoding, conditional::Validators, range::Ranges};
+use crate::{
+    config::{Fallback, Files, Forward, Server},
+    http::{
+        body,
+        response::{BoxBodyResponse, LocalResponse},
+        uri,
+    },
+};
+
+/// Result of [`transfer`].
+pub(super) enum Transfer<'a> {
+    /// Response generated locally.
+    Response(BoxBodyResponse),
+
+    /// None of [`Files::try_files`] exists and the request must be forwarded
+    /// to the [`Fallback::Forward`] backends.
+    Forward(&'a Forward),
+}
+
+/// Returns an HTTP response whose body is the content of a file. The file
+/// must be located inside the root directory specified by the configuration
+/// and must be readable, otherwise a 404 response is returned. The path of the
+/// file is `uri_path` relative to `root`, unless [`Files::try_files`] is
+/// configured, see [`try_files`]. `uri_path` is the path of the request URI
+/// already decoded and normalized with [`uri::normalize_path`], so it can't
+/// contain dot segments. Symlinks are still followed, and files that end up
+/// outside of the root are not served, see [`resolve`].
+///
+/// The media type of files is guessed from their extension, see [`mime`],
+/// using the overrides of the `server` configuration.
+pub(super) async fn transfer<'a>(
+    request: &request::Parts,
+    uri_path: &str,
+    files: &'a Files,
+    server: &Server,
+) -> Result<Transfer<'a>, hyper::Error> {
+    let Ok(directory) = Path::new(&files.root).canonicalize() else {
+        return Ok(Transfer::Response(LocalResponse::not_found()));
+    };
+
+    if !files.try_files.is_empty() {
+        return try_files(request, uri_path, files, server, &directory).await;
+    }
+
+    serve(request, uri_path, files, server, &directory)
+        .await
+        .map(Transfer::Response)
+}
+
+/// Serves the file at `uri_path`.
+///
+/// Directories are served through their index files, see [`Files::index`].
+/// Directory URIs must end with a slash so that relative links in the index
+/// work, clients are redirected otherwise. If there's no index file the
+/// directory is listed when [`Files::autoindex`] is enabled, see
+/// [`autoindex`].
+async fn serve(
+    request: &request::Parts,
+    uri_path: &str,
+    files: &Files,
+    server: &Server,
+    directory: &Path,
+) -> Result<BoxBodyResponse, hyper::Error> {
+    let Some(path) = resolve(directory, Path::new(uri_path.trim_start_matches('/'))) else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    if !metadata.is_dir() {
+        return send(request, files, server, directory, path).await;
+    }
+
+    if !uri_path.ends_with('/') {
+        let mut location = format!("{}/", uri::encode_path(uri_path));
+
+        if let Some(query) = request.uri.query() {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        return Ok(LocalResponse::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, location)
+            .body(body::empty())
+            .unwrap());
+    }
+
+    let response = index(request, uri_path, files, server, directory, &path).await?;
+
+    Ok(response.unwrap_or_else(LocalResponse::not_found))
+}
+
+/// Tries each candidate of [`Files::try_files`] in order, with `$uri`
+/// replaced by `uri_path`. Candidates that end with a slash only match
+/// directories that have an index file or can be listed, the rest only match
+/// files. If nothing matches the [`Files::fallback`] takes over.
+async fn try_files<'a>(
+    request: &request::Parts,
+    uri_path: &str,
+    files: &'a Files,
+    server: &Server,
+    directory: &Path,
+) -> Result<Transfer<'a>, hyper::Error> {
+    for candidate in &files.try_files {
+        let candidate = candidate.replace("$uri", uri_path);
+
+        let Some(path) = resolve(directory, Path::new(candidate.trim_start_matches('/'))) else {
+            continue;
+        };
+
+        let Ok(metadata) = tokio::fs::metadata(&path).await else {
+            continue;
+        };
+
+        let response = match (candidate.ends_with('/'), metadata.is_dir()) {
+            (false, false) => Some(send(request, files, server, directory, path).await?),
+            (true, true) => index(request, &candidate, files, server, directory, &path).await?,
+            _ => None,
+        };
+
+        if let Some(response) = response {
+            return Ok(Transfer::Response(response));
+        }
+    }
+
+    Ok(match &files.fallback {
+        Fallback::Status(status) => Transfer::Response(LocalResponse::status(*status)),
+        Fallback::Forward(forward) => Transfer::Forward(forward),
+    })
+}
+
+/// Serves the first index file of the directory at `path` that exists, or
+/// lists the directory if [`Files::autoindex`] is enabled. Returns [`None`]
+/// otherwise.
+async fn index(
+    request: &request::Parts,
+    uri_path: &str,
+    files: &Files,
+    server: &Server,
+    directory: &Path,
+    path: &Path,
+) -> Result<Option<BoxBodyResponse>, hyper::Error> {
+    for index in &files.index {
+        let Some(index) = resolve(directory, &path.join(index)) else {
+            continue;
+        };
+
+        if tokio::fs::metadata(&index)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            return send(request, files, server, directory, index)
+                .await
+                .map(Some);
+        }
+    }
+
+    if files.autoindex {
+        return Ok(Some(
+            autoindex::list(request, uri_path, directory, path).await,
+        ));
+    }
+
+    Ok(None)
+}
+
+/// Resolves `path` relative to the root `directory`, following symlinks.
+/// Returns [`None`] if the file doesn't exist or is outside the root.
+fn resolve(directory: &Path, path: &Path) -> Option<PathBuf> {
+    let path = directory.join(path).canonicalize().ok()?;
+
+    path.starts_with(directory).then_some(path)
+}
+
+/// Sends the file at `path`, which must be already resolved inside the root
+/// `directory`. The content is streamed with [`body::file`], so large files
+/// are never loaded into memory, or compressed if the client accepts it, see
+/// [`compression`].
+///
+/// Responses include validators that clients can use in conditional requests,
+/// see [`conditional`], and `GET` requests can ask for parts of the file with
+/// the `Range` header, see [`range`].
+async fn send(
+    request: &request::Parts,
+    files: &Files,
+    server: &Server,
+    directory: &Path,
+    path: PathBuf,
+) -> Result<BoxBodyResponse, hyper::Error> {
+    let content_type = mime::content_type(&path, &server.mime_types);
+    let content_type = content_type.as_ref();
+
+    let Some((file, metadata)) = open(&path).await else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    let compression = &files.compression;
+    let accepted = compression::accepted(&request.headers);
+
+    let mut precompressed = None;
+
+    if compression.precompressed {
+        for encoding in &accepted {
+            precompressed = open_precompressed(&path, directory, *encoding).await;
+            if precompressed.is_some() {
+                break;
+            }
+        }
+    }
+
+    let compressible = compression.on_the_fly
+        && compression::is_compressible(content_type)
+        && metadata.len() >= compression.min_size;
+
+    // Compressing on the fly makes range requests impossible, so clients
+    // asking for ranges get the original file.
+    let on_the_fly = accepted
+        .first()
+        .filter(|_| compressible && precompressed.is_none())
+        .filter(|_| !request.headers.contains_key(header::RANGE));
+
+    let (path, file, metadata, encoding) = match precompressed {
+        Some((path, file, metadata, encoding)) => (path, file, metadata, Some(encoding)),
+        None => (path, file, metadata, on_the_fly.copied()),
+    };
+
+    let len = metadata.len();
+    let mut validators = Validators::new(files.etag, &path, &metadata).await;
+
+    if let Some(encoding) = on_the_fly {
+        validators = validators.encoded(*encoding);
+    }
+
+    let mut response = LocalResponse::builder();
+
+    if server.nosniff {
+        response = response.header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    }
+
+    if compression.precompressed || compressible {
+        response = response.header(header::VARY, "Accept-Encoding");
+    }
+
+    if let Some(etag) = &validators.etag {
+        response = response.header(header::ETAG, etag.to_string());
+    }
+
+    if let Some(last_modified) = validators.last_modified_header() {
+        response = response.header(header::LAST_MODIFIED, last_modified);
+    }
+
+    if let Some(status) = conditional::evaluate(request, &validators) {
+        return Ok(response.status(status).body(body::empty()).unwrap());
+    }
+
+    response = response.header(header::ACCEPT_RANGES, "bytes");
+
+    if let Some(encoding) = encoding {
+        response = response.header(header::CONTENT_ENCODING, encoding.name());
+    }
+
+    if let Some(encoding) = on_the_fly {
+        let response = response
+            .header(header::CONTENT_TYPE, content_type)
+            .body(encoding.encode(file));
+
+        return Ok(response.unwrap());
+    }
+
+    let ranges = request
+        .headers
+        .get(header::RANGE)
+        .filter(|_| request.method == Method::GET)
+        .filter(|_| {
+            request
+                .headers
+                .get(header::IF_RANGE)
+                .is_none_or(|if_range| {
+                    if_range
+                        .to_str()
+                        .is_ok_and(|if_range| conditional::if_range(if_range, &validators))
+                })
+        })
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| range::parse(value, len));
+
+    let response = match ranges {
+        None => response
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, len)
+            .body(body::file(file, 0..len)),
+
+        Some(Ranges::Unsatisfiable) => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, range::unsatisfied_range(len))
+            .header(header::CONTENT_LENGTH, 0)
+            .body(body::empty()),
+
+        Some(Ranges::Satisfiable(ranges)) if ranges.len() == 1 => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_RANGE, range::content_range(&ranges[0], len))
+            .header(header::CONTENT_LENGTH, ranges[0].end - ranges[0].start)
+            .body(body::file(file, ranges[0].clone())),
+
+        Some(Ranges::Satisfiable(ranges)) => {
+            let (boundary, segments) = range::multipart(&ranges, len, content_type);
+            let content_length: u64 = segments.iter().map(body::FileSegment::len).sum();
+
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={boundary}"),
+                )
+                .header(header::CONTENT_LENGTH, content_length)
+                .body(body::file_segments(file, segments))
+        }
+    };
+
+    Ok(response.unwrap())
+}
+
+/// Parses a weight (`qvalue`) of `Accept` or `Accept-Encoding` headers in
+/// thousandths, so "0.5" is 500.
+fn qvalue(value: &str) -> Option<u16> {
+    let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = format!("{fraction:0<3}").parse::<u16>().ok()?;
+
+    match integer {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Opens the file at `path`, which must be a regular file.
+async fn open(path: &Path) -> Option<(File, Metadata)> {
+    let file = File::open(path).await.ok()?;
+    let metadata = file.metadata().await.ok()?;
+
+    metadata.is_file().then_some((file, metadata))
+}
+
+/// Opens the variant of the file at `path` precompressed with `encoding`, if
+/// it exists. Variants can be symlinks, but they must point inside the root
+/// `directory` like any other file.
+async fn open_precompressed(
+    path: &Path,
+    directory: &Path,
+    encoding: Encoding,
+) -> Option<(PathBuf, File, Metadata, Encoding)> {
+    let mut variant = OsString::from(path);
+    variant.push(".");
+    variant.push(encoding.extension());
+
+    let variant = resolve(directory, Path::new(&variant))?;
+    let (file, metadata) = open(&variant).await?;
+
+    Some((variant, file, metadata, encoding))
+}
@@ -0,0 +1,258 @@
+//! Range requests, see [RFC 9110 section 14](https://www.rfc-editor.org/rfc/rfc9110#section-14).
+//! Only the `bytes` unit is supported. A single range is sent as it is in a
+//! `206 Partial Content` response, multiple ranges are sent as
+//! `multipart/byteranges`.
+
+use std::ops::Range;
+
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::http::body::FileSegment;
+
+/// Range headers with more ranges than this are ignored and the full file is
+/// sent instead, since they are probably not sent by legit clients.
+const MAX_RANGES: usize = 32;
+
+/// Ranges requested by the client, see [`parse`].
+#[derive(Debug, PartialEq)]
+pub(super) enum Ranges {
+    /// At least one of the ranges overlaps the file. Only those are included,
+    /// limited to the size of the file.
+    Satisfiable(Vec<Range<u64>>),
+
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Parses the value of a `Range` header for a file of `len` bytes. Returns
+/// [`None`] if the header should be ignored because it has invalid syntax,
+/// an unknown unit or too many ranges. Overlapping ranges are coalesced.
+pub(super) fn parse(value: &str, len: u64) -> Option<Ranges> {
+    let (unit, specs) = value.split_once('=')?;
+
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut ranges = Vec::with_capacity(specs.len());
+
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+
+        let range = match (first, last) {
+            ("", suffix) => {
+                let suffix = number(suffix)?;
+                (suffix > 0 && len > 0).then(|| len.saturating_sub(suffix)..len)
+            }
+            (first, "") => {
+                let first = number(first)?;
+                (first < len).then_some(first..len)
+            }
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return None;
+                }
+                (first < len).then(|| first..len.min(last.saturating_add(1)))
+            }
+        };
+
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return Some(Ranges::Unsatisfiable);
+    }
+
+    Some(Ranges::Satisfiable(coalesce(ranges)))
+}
+
+/// Parses a sequence of digits, unlike [`str::parse`] signs are not allowed.
+fn number(digits: &str) -> Option<u64> {
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    digits.parse().ok()
+}
+
+/// Merges overlapping ranges, so clients can't make the server send the same
+/// bytes many times. Ranges are kept in the order requested by the client if
+/// none of them overlap.
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let overlap = ranges.iter().enumerate().any(|(i, range)| {
+        ranges[i + 1..]
+            .iter()
+            .any(|other| range.start < other.end && other.start < range.end)
+    });
+
+    if !overlap {
+        return ranges;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+
+    let mut coalesced: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+
+    coalesced
+}
+
+/// Value of the `Content-Range` header for `range` of a file of `len` bytes.
+pub(super) fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{len}", range.start, range.end - 1)
+}
+
+/// Value of the `Content-Range` header of `416 Range Not Satisfiable`
+/// responses.
+pub(super) fn unsatisfied_range(len: u64) -> String {
+    format!("bytes */{len}")
+}
+
+/// Body of a `multipart/byteranges` response. Each range of the file is
+/// preceded by its own headers and separated by the boundary. Returns the
+/// boundary and the body segments.
+pub(super) fn multipart(
+    ranges: &[Range<u64>],
+    len: u64,
+    content_type: &str,
+) -> (String, Vec<FileSegment>) {
+    let boundary = boundary();
+    let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
+
+    for range in ranges {
+        let headers = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range, len)
+        );
+
+        segments.push(FileSegment::Bytes(Bytes::from(headers)));
+        segments.push(FileSegment::File(range.clone()));
+    }
+
+    segments.push(FileSegment::Bytes(Bytes::from(format!(
+        "\r\n--{boundary}--\r\n"
+    ))));
+
+    (boundary, segments)
+}
+
+/// Random boundary that can't be found in the file by chance.
+fn boundary() -> String {
+    let mut bytes = [0; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random generator failed");
+
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        let cases = [
+            ("bytes=0-499", 0..500),
+            ("bytes=500-999", 500..1000),
+            ("bytes=500-", 500..1000),
+            ("bytes=-200", 800..1000),
+            ("bytes=-5000", 0..1000),
+            ("bytes=900-5000", 900..1000),
+            ("Bytes = 0-0", 0..1),
+        ];
+
+        for (value, range) in cases {
+            assert_eq!(
+                parse(value, 1000),
+                Some(Ranges::Satisfiable(vec![range])),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            parse("bytes=500-599, 0-99,,-100", 1000),
+            Some(Ranges::Satisfiable(vec![500..600, 0..100, 900..1000]))
+        );
+
+        // Overlapping and unsatisfiable ranges.
+        assert_eq!(
+            parse("bytes=0-199,100-299,400-,2000-3000", 1000),
+            Some(Ranges::Satisfiable(vec![0..300, 400..1000]))
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        for value in [
+            "bytes=1000-",
+            "bytes=1000-2000",
+            "bytes=-0",
+            "bytes=2000-,3000-",
+        ] {
+            assert_eq!(parse(value, 1000), Some(Ranges::Unsatisfiable), "{value}");
+        }
+
+        assert_eq!(parse("bytes=-100", 0), Some(Ranges::Unsatisfiable));
+    }
+
+    #[test]
+    fn ignored_ranges() {
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+
+        for value in [
+            "items=0-10",
+            "bytes",
+            "bytes=",
+            "bytes=10",
+            "bytes=10-5",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=0-10,x",
+            too_many.as_str(),
+        ] {
+            assert_eq!(parse(value, 1000), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn multipart_body() {
+        let (boundary, segments) = multipart(&[0..10, 20..30], 100, "text/plain");
+
+        assert_eq!(boundary.len(), 32);
+        assert_eq!(segments.len(), 5);
+
+        let FileSegment::Bytes(headers) = &segments[2] else {
+            panic!("expected part headers");
+        };
+
+        assert_eq!(
+            headers,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 20-29/100\r\n\r\n"
+            )
+            .as_bytes()
+        );
+    }
+}
@@ -9,92 +9,155 @@
 
 mod files;
 mod proxy;
+pub(crate) mod tcp;
 
-use std::{future::Future, net::SocketAddr, pin::Pin};
+use std::{future::Future, pin::Pin, sync::Arc};
 
-use hyper::{body::Incoming, service::Service, Request};
-use tokio::time::Instant;
+use hyper::{header, service::Service, Request, StatusCode};
+use tokio::{sync::watch, time::Instant};
 
+use self::files::Transfer;
 use crate::{
     config::{self, Action, Forward},
     http::{
+        body::RequestBody,
         request::ProxyRequest,
         response::{BoxBodyResponse, LocalResponse},
+        uri,
     },
+    net::Address,
+    tls::{acme, server::ClientIdentity},
 };
 
 /// Implements [`Service`] and handles incoming requests.
 pub(crate) struct Rxh {
-    /// Reference to the configuration stored by [`crate::task::server::Server`]
-    /// instance running this services.
-    config: &'static config::Server,
+    /// Configuration of the [`crate::task::server::Server`] instance running
+    /// this service. Each request uses the latest one, so routing changes
+    /// when the configuration is reloaded.
+    config: watch::Receiver<Arc<config::Server>>,
 
     // Socket address of the connected client.
-    client_addr: SocketAddr,
+    client_addr: Address,
 
     // Listening socket address.
-    server_addr: SocketAddr,
+    server_addr: Address,
+
+    /// Verified client certificate, only available on TLS connections where
+    /// the client sent one.
+    identity: Option<Arc<ClientIdentity>>,
 }
 
 impl Rxh {
     /// Creates a new [`Rxh`] service.
     pub fn new(
-        config: &'static config::Server,
-        client_addr: SocketAddr,
-        server_addr: SocketAddr,
+        config: watch::Receiver<Arc<config::Server>>,
+        client_addr: Address,
+        server_addr: Address,
+        identity: Option<Arc<ClientIdentity>>,
     ) -> Self {
         Self {
             config,
             client_addr,
             server_addr,
+            identity,
         }
     }
 }
 
-impl Service<Request<Incoming>> for Rxh {
+impl<B: RequestBody> Service<Request<B>> for Rxh {
     type Response = BoxBodyResponse;
 
     type Error = hyper::Error;
 
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&mut self, request: Request<Incoming>) -> Self::Future {
+    fn call(&self, request: Request<B>) -> Self::Future {
         let Rxh {
-            client_addr,
-            server_addr,
-            config,
+            ref client_addr,
+            ref server_addr,
+            ref config,
+            ref identity,
         } = *self;
 
+        let config = config.borrow().clone();
+        let identity = identity.clone();
+        let client_addr = client_addr.clone();
+        let server_addr = server_addr.clone();
+
         let instant = Instant::now();
 
         Box::pin(async move {
             let uri = request.uri().to_string();
             let method = request.method().to_string();
 
+            // ACME servers validate http-01 challenges on any server listening
+            // on port 80, so these requests don't go through the patterns.
+            if let Some(key_authorization) = acme::challenge::http_01(request.uri().path()) {
+                println!(
+                    "{client_addr} -> {} ACME http-01 validation",
+                    config.log_name
+                );
+                return Ok(LocalResponse::builder()
+                    .header(header::CONTENT_TYPE, "application/octet-stream")
+                    .body(crate::http::body::full(key_authorization))
+                    .unwrap());
+            }
+
+            // HTTP/2 requests carry the scheme and authority in the URI, so
+            // patterns are matched against the path only. Normalized paths
+            // make sure that "/public/../private" doesn't match "/public".
+            let route = uri::route_path(request.uri().path());
+
             let maybe_pattern = config
                 .patterns
                 .iter()
-                .find(|pattern| uri.starts_with(pattern.uri.as_str()));
+                .find(|pattern| route.starts_with(pattern.uri.as_str()));
 
             let Some(pattern) = maybe_pattern else {
                 return Ok(LocalResponse::not_found());
             };
 
-            let response = match &pattern.action {
-                Action::Forward(Forward { scheduler, .. }) => {
-                    let by = config.name.as_ref().map(|name| name.clone());
-                    let request = ProxyRequest::new(request, client_addr, server_addr, by);
-                    proxy::forward(request, scheduler.next_server()).await
-                }
+            let client_auth = config.tls.as_ref().and_then(|tls| tls.client_auth.as_ref());
+
+            if pattern.client_auth && client_auth.is_some() && identity.is_none() {
+                return Ok(LocalResponse::forbidden());
+            }
 
-                Action::Serve(directory) => {
-                    let path = if request.uri().path().starts_with("/") {
-                        &request.uri().path()[1..]
-                    } else {
-                        request.uri().path()
-                    };
-                    files::transfer(path, directory).await
+            let response = 'response: {
+                let (request, Forward { scheduler, .. }) = match &pattern.action {
+                    Action::Forward(forward) => (request, forward),
+
+                    Action::Serve(files) => {
+                        let Some(path) = uri::normalize_path(request.uri().path()) else {
+                            break 'response Ok(LocalResponse::status(StatusCode::BAD_REQUEST));
+                        };
+
+                        // Decoded slashes could take the path somewhere else,
+                        // like "/public/..%2Fprivate".
+                        if !path.starts_with(pattern.uri.as_str()) {
+                            break 'response Ok(LocalResponse::not_found());
+                        }
+
+                        let (parts, body) = request.into_parts();
+
+                        // Missing files can fall back to a backend.
+                        match files::transfer(&parts, &path, files, &config).await {
+                            Ok(Transfer::Forward(forward)) => {
+                                (Request::from_parts(parts, body), forward)
+                            }
+                            Ok(Transfer::Response(response)) => break 'response Ok(response),
+                            Err(err) => break 'response Err(err),
+                        }
+                    }
+                };
+
+                let by = config.name.clone();
+                let mut request = ProxyRequest::new(request, client_addr.clone(), server_addr, by);
+                request.downgrade_to_http1();
+                if let Some(client_auth) = client_auth {
+                    request.set_client_identity(&client_auth.headers, identity.as_deref());
                 }
+                proxy::forward(request, scheduler.next_server(), &config.socket.upstream).await
             };
 
             if let Ok(response) = &response {
@@ -1,49 +1,127 @@
 //! Proxy specific sub-service. See also [`crate::http`] module.
 
-use std::net::SocketAddr;
-
 use http_body_util::BodyExt;
-use hyper::{body::Incoming, header, upgrade::OnUpgrade};
-use tokio::net::TcpStream;
+use hyper::{header, upgrade::OnUpgrade};
+use hyper_util::rt::TokioIo;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::http::{
-    request::ProxyRequest,
-    response::{BoxBodyResponse, LocalResponse, ProxyResponse},
+use crate::{
+    config::{Backend, TcpOptions},
+    http::{
+        body::RequestBody,
+        request::ProxyRequest,
+        response::{BoxBodyResponse, LocalResponse, ProxyResponse},
+    },
+    net::{self, proxy_protocol, Address},
 };
 
 /// Forwards the request to the target server and returns the response sent
 /// by the target server. See [`ProxyRequest`] and [`ProxyResponse`]. If the
 /// client wants to upgrade the connection and the server agrees by sending
 /// a `101` status code, then a TCP tunnel that forwards traffic bidirectionally
-/// is spawned in a new Tokio task. See [`tunnel`].
-pub(super) async fn forward(
-    mut request: ProxyRequest<Incoming>,
-    to: SocketAddr,
+/// is spawned in a new Tokio task. See [`tunnel`]. Backends configured with
+/// TLS get the request through an encrypted stream, see
+/// [`crate::tls::client`]. The connection to the backend is configured with
+/// the given socket `options`, see [`connect`].
+pub(super) async fn forward<B: RequestBody>(
+    request: ProxyRequest<B>,
+    to: &Backend,
+    options: &TcpOptions,
 ) -> Result<BoxBodyResponse, hyper::Error> {
-    let Ok(stream) = TcpStream::connect(to).await else {
+    let connection = connect(to, request.client_addr(), request.server_addr(), options);
+
+    let Ok(stream) = connection.await else {
         return Ok(LocalResponse::bad_gateway());
     };
 
+    let Some(tls) = &to.tls else {
+        return send(request, stream).await;
+    };
+
+    match tls.connector.connect(stream, &to.address).await {
+        Ok(stream) => send(request, stream).await,
+        Err(err) => {
+            println!("TLS handshake with {} failed: {err}", to.address);
+            Ok(LocalResponse::bad_gateway())
+        }
+    }
+}
+
+/// Connects to the backend `to` with the given socket `options`. If the
+/// backend wants to know the address of the client with the PROXY protocol,
+/// the header is sent right away, see [`proxy_protocol::encode`]. TLS is not
+/// negotiated here, the caller does that on top of the returned stream.
+pub(super) async fn connect(
+    to: &Backend,
+    client_addr: &Address,
+    server_addr: &Address,
+    options: &TcpOptions,
+) -> Result<net::Stream, io::Error> {
+    let mut stream = net::connect(&to.address, options).await?;
+
+    if let Some(version) = to.send_proxy_protocol {
+        let header = client_addr
+            .as_tcp()
+            .zip(server_addr.as_tcp())
+            .map(|(source, destination)| proxy_protocol::Header {
+                source,
+                destination,
+            });
+
+        let header = proxy_protocol::encode(version, header);
+
+        if let Err(err) = stream.write_all(&header).await {
+            println!(
+                "Failed to send PROXY protocol header to {}: {err}",
+                to.address
+            );
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Sends the request through an already connected `stream`, which might be a
+/// plain TCP or Unix stream or a TLS stream.
+async fn send<B, S>(
+    mut request: ProxyRequest<B>,
+    stream: S,
+) -> Result<BoxBodyResponse, hyper::Error>
+where
+    B: RequestBody,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let (mut sender, conn) = hyper::client::conn::http1::Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
-        .handshake(stream)
+        .handshake(TokioIo::new(stream))
         .await?;
 
     tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
+        if let Err(err) = conn.with_upgrades().await {
             println!("Connection failed: {:?}", err);
         }
     });
 
     let mut maybe_client_upgrade = None;
 
+    // HTTP/3 requests can't be upgraded, so there's nothing to remove.
     if request.headers().contains_key(header::UPGRADE) {
-        let upgrade = request.extensions_mut().remove::<OnUpgrade>().unwrap();
-        maybe_client_upgrade = Some(upgrade);
+        maybe_client_upgrade = request.extensions_mut().remove::<OnUpgrade>();
     }
 
-    let mut response = sender.send_request(request.into_forwarded()).await?;
+    // The backend might close the connection without sending a response, for
+    // example when it rejects our TLS client certificate. That's a problem
+    // with the upstream server, so the client gets a 502 instead of a closed
+    // connection.
+    let mut response = match sender.send_request(request.into_forwarded()).await {
+        Ok(response) => response,
+        Err(err) => {
+            println!("Failed to receive response from backend: {err}");
+            return Ok(LocalResponse::bad_gateway());
+        }
+    };
 
     if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
         if let Some(client_upgrade) = maybe_client_upgrade {
@@ -56,7 +134,7 @@ pub(super) async fn forward(
         }
     }
 
-    Ok(ProxyResponse::new(response.map(|body| body.boxed())).into_forwarded())
+    Ok(ProxyResponse::new(response.map(|body| body.map_err(Into::into).boxed())).into_forwarded())
 }
 
 /// TCP tunnel for upgraded connections such as Websockets or any other custom
@@ -64,7 +142,9 @@ pub(super) async fn forward(
 /// [`hyper::upgrade::Upgraded`] connection won't resolve until we send an
 /// `HTTP 101` response back to the client.
 async fn tunnel(client: OnUpgrade, server: OnUpgrade) {
-    let (mut upgraded_client, mut upgraded_server) = tokio::try_join!(client, server).unwrap();
+    let (upgraded_client, upgraded_server) = tokio::try_join!(client, server).unwrap();
+    let (mut upgraded_client, mut upgraded_server) =
+        (TokioIo::new(upgraded_client), TokioIo::new(upgraded_server));
 
     match tokio::io::copy_bidirectional(&mut upgraded_client, &mut upgraded_server).await {
         Ok((client_bytes, server_bytes)) => {
@@ -0,0 +1,208 @@
+//! Layer 4 proxy. Servers configured with `protocol = "tcp"` don't parse
+//! HTTP at all, every accepted connection is bridged to a backend chosen by
+//! the scheduler of the server and bytes are copied in both directions until
+//! one of the peers closes the connection. Servers configured with
+//! `protocol = "tls_passthrough"` work the same way, but they read the TLS
+//! ClientHello first to choose the backend. See [`config::Protocol`].
+
+use std::time::Duration;
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+use super::proxy;
+use crate::{
+    config::{self, Action, Backend},
+    net::{client_hello, Address},
+    sync::notify::Subscription,
+};
+
+/// Maximum time that clients have to send the TLS ClientHello after
+/// connecting to a TLS passthrough server.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Forwards the `client` stream, which can be a plain TCP or Unix stream or a
+/// TLS stream, to the next backend of the server. The connection to the
+/// backend is made like in [`proxy::forward`], so PROXY protocol headers and
+/// backend TLS work the same way as for HTTP.
+///
+/// Unlike HTTP connections, raw streams have no way of telling whether they
+/// are idle, so when the server shuts down the bridge keeps copying data until
+/// [`config::Server::shutdown_timeout`] and is closed after that.
+pub(crate) async fn forward<S>(
+    client: S,
+    client_addr: &Address,
+    server_addr: &Address,
+    config: &config::Server,
+    subscription: &mut Subscription,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Validated when the config is deserialized.
+    let Some(Action::Forward(forward)) = config.patterns.first().map(|pattern| &pattern.action)
+    else {
+        return;
+    };
+
+    let backend = forward.scheduler.next_server();
+    let addresses = (client_addr, server_addr);
+
+    tunnel(client, &[], backend, addresses, config, subscription).await;
+}
+
+/// Reads the TLS ClientHello sent by the `client` and forwards the connection
+/// to a backend of the first pattern that matches the server name, see
+/// [`config::Pattern::sni`]. The ClientHello is sent to the backend as it
+/// was received, the TLS handshake is completed by the backend. Clients that
+/// don't send a valid ClientHello within [`CLIENT_HELLO_TIMEOUT`] are
+/// disconnected, and so are clients that are still sending it when the server
+/// shuts down. Otherwise this works like [`forward`].
+pub(crate) async fn passthrough<S>(
+    mut client: S,
+    client_addr: &Address,
+    server_addr: &Address,
+    config: &config::Server,
+    subscription: &mut Subscription,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let log_name = &config.log_name;
+
+    let client_hello = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, client_hello::read(&mut client));
+
+    let result = tokio::select! {
+        result = client_hello => result,
+        _ = subscription.wait_for_notification() => return,
+    };
+
+    let (bytes, server_name) = match result {
+        Ok(Ok(client_hello)) => client_hello,
+        Ok(Err(err)) => {
+            println!("{client_addr} -> {log_name} TLS passthrough failed: {err}");
+            return;
+        }
+        Err(_) => {
+            println!("{client_addr} -> {log_name} Timed out waiting for TLS ClientHello");
+            return;
+        }
+    };
+
+    let server_name = server_name.as_deref();
+
+    let forward = config
+        .patterns
+        .iter()
+        .find(|pattern| pattern.matches_sni(server_name))
+        .and_then(|pattern| match &pattern.action {
+            Action::Forward(forward) => Some(forward),
+            Action::Serve(_) => None,
+        });
+
+    let Some(forward) = forward else {
+        println!(
+            "{client_addr} -> {log_name} No backend for server name '{}'",
+            server_name.unwrap_or_default()
+        );
+        return;
+    };
+
+    let backend = forward.scheduler.next_server();
+    let addresses = (client_addr, server_addr);
+
+    tunnel(client, &bytes, backend, addresses, config, subscription).await;
+}
+
+/// Connects to `backend`, sends the bytes that were already read from the
+/// `client` (the `prefix`) and bridges both streams. The result is logged.
+async fn tunnel<S>(
+    client: S,
+    prefix: &[u8],
+    backend: &Backend,
+    (client_addr, server_addr): (&Address, &Address),
+    config: &config::Server,
+    subscription: &mut Subscription,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let log_name = &config.log_name;
+    let instant = Instant::now();
+
+    let connection = proxy::connect(backend, client_addr, server_addr, &config.socket.upstream);
+
+    let mut stream = match connection.await {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!(
+                "{client_addr} -> {log_name} Failed to connect to {}: {err}",
+                backend.address
+            );
+            return;
+        }
+    };
+
+    // Backends of TLS passthrough servers can't use TLS, so the prefix always
+    // goes to a plain stream.
+    if let Err(err) = stream.write_all(prefix).await {
+        println!(
+            "{client_addr} -> {log_name} Failed to send data to {}: {err}",
+            backend.address
+        );
+        return;
+    }
+
+    let result = match &backend.tls {
+        None => bridge(client, stream, config, subscription).await,
+
+        Some(tls) => match tls.connector.connect(stream, &backend.address).await {
+            Ok(stream) => bridge(client, stream, config, subscription).await,
+            Err(err) => {
+                println!(
+                    "{client_addr} -> {log_name} TLS handshake with {} failed: {err}",
+                    backend.address
+                );
+                return;
+            }
+        },
+    };
+
+    let elapsed = instant.elapsed();
+
+    match result {
+        Some(Ok((sent, received))) => println!(
+            "{client_addr} -> {log_name} TCP {} {} bytes sent, {received} bytes received {elapsed:?}",
+            backend.address,
+            prefix.len() as u64 + sent,
+        ),
+        Some(Err(err)) => println!(
+            "{client_addr} -> {log_name} TCP {} failed: {err} {elapsed:?}",
+            backend.address
+        ),
+        None => println!("{log_name} => Closing connection after shutdown timeout"),
+    }
+}
+
+/// Copies data between `client` and `server` until both directions are done.
+/// Returns [`None`] if the connection had to be closed because of the
+/// shutdown timeout.
+async fn bridge<C, S>(
+    mut client: C,
+    mut server: S,
+    config: &config::Server,
+    subscription: &mut Subscription,
+) -> Option<Result<(u64, u64), io::Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let copy = io::copy_bidirectional(&mut client, &mut server);
+    tokio::pin!(copy);
+
+    tokio::select! {
+        result = copy.as_mut() => Some(result),
+        _ = subscription.wait_for_notification() => {
+            tokio::time::timeout(config.shutdown_timeout, copy).await.ok()
+        }
+    }
+}
@@ -43,6 +43,10 @@ pub(crate) struct Subscription {
 
     /// Sender half of the acknowledgements channel.
     acknowledge_sender: mpsc::Sender<()>,
+
+    /// Notification obtained by [`Subscription::wait_for_notification`] that
+    /// was not read yet by [`Subscription::receive_notification`].
+    received: Option<Notification>,
 }
 
 impl Notifier {
@@ -76,8 +80,10 @@ impl Notifier {
     }
 
     /// Waits for all the subscribers to acknowledge the last sent
-    /// [`Notification`].
-    pub async fn collect_acknowledgements(self) {
+    /// [`Notification`]. The `on_acknowledgement` callback runs every time
+    /// a subscriber acknowledges, which allows the caller to keep track of
+    /// how many subscribers are left.
+    pub async fn collect_acknowledgements(self, mut on_acknowledgement: impl FnMut()) {
         let Self {
             notification_sender,
             mut acknowledge_receiver,
@@ -93,7 +99,7 @@ impl Notifier {
         // Wait for all acks one by one. As stated above, the channel is closed
         // when all senders are dropped, which causes `recv` to return None.
         while let Some(_ack) = acknowledge_receiver.recv().await {
-            // Wait for all acks
+            on_acknowledgement();
         }
 
         // This one is dropped at the end, otherwise receivers will get an
@@ -111,6 +117,7 @@ impl Subscription {
         Self {
             notification_receiver,
             acknowledge_sender,
+            received: None,
         }
     }
 
@@ -119,18 +126,39 @@ impl Subscription {
     /// misuse the [`Notifier`] struct:
     ///
     /// - [`broadcast::error::TryRecvError::Closed`]: can't happen because the
-    /// [`Notifier`] won't close it's sender half until acks are collected, see
-    /// [`Notifier::collect_acknowledgements`]. If that function is not called,
-    /// this error is possible and would be discarded.
+    ///   [`Notifier`] won't close it's sender half until acks are collected,
+    ///   see [`Notifier::collect_acknowledgements`]. If that function is not
+    ///   called, this error is possible and would be discarded.
     ///
     /// - [`broadcast::error::TryRecvError::Lagged`]: can't happen because the
-    /// sender will wait for acks before sending new values to the channel. If
-    /// the sender doesn't wait, then again, this error is possible.
+    ///   sender will wait for acks before sending new values to the channel. If
+    ///   the sender doesn't wait, then again, this error is possible.
     ///
     /// - [`broadcast::error::TryRecvError::Empty`]: we don't even care about
-    /// empty buffers, if no notification was sent then return `None`.
+    ///   empty buffers, if no notification was sent then return `None`.
+    ///
+    /// Notifications obtained with [`Subscription::wait_for_notification`]
+    /// are returned here as well, so tasks can always check at the end whether
+    /// they have to acknowledge something.
     pub fn receive_notification(&mut self) -> Option<Notification> {
-        self.notification_receiver.try_recv().ok()
+        self.received
+            .take()
+            .or_else(|| self.notification_receiver.try_recv().ok())
+    }
+
+    /// Same as [`Subscription::receive_notification`] but waits until a
+    /// notification is sent. Tasks that have to react to notifications while
+    /// they are still working can select on this future. If the [`Notifier`]
+    /// is dropped without sending anything this never completes.
+    pub async fn wait_for_notification(&mut self) -> Notification {
+        if let Some(notification) = self.received {
+            return notification;
+        }
+
+        match self.notification_receiver.recv().await {
+            Ok(notification) => *self.received.insert(notification),
+            Err(_) => std::future::pending().await,
+        }
     }
 
     /// Sends an ACK on the acknowledgements channel. For now, acks are not
@@ -138,7 +166,7 @@ impl Subscription {
     /// read notification". Errors as discarded here as well:
     ///
     /// - [`mpsc::error::SendError<T>`]: can't happen unless all receivers
-    /// closed their channel, which they don't until sending the ack.
+    ///   closed their channel, which they don't until sending the ack.
     pub async fn acknowledge_notification(&self) {
         self.acknowledge_sender.send(()).await.unwrap();
     }
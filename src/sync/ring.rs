@@ -20,14 +20,14 @@ pub(crate) struct Ring<T> {
 }
 
 impl<T> Ring<T> {
-    /// Creates a new [`Ring`]. The first value returned when calling one of
-    /// the getter functions is going to be located at index 0 in `values` vec.
-    /// Subsequent calls to any getter will return the value at the next index
+    /// Creates a new [`Ring`]. The first value returned by
+    /// [`Ring::next_as_ref`] is going to be located at index 0 in `values` vec.
+    /// Subsequent calls will return the value at the next index
     /// until the last one is reached, after that it starts again from the
     /// beginning. Note that `values` must have a length greater than 0, in
     /// other words it cannot be an empty [`Vec`].
     pub fn new(values: Vec<T>) -> Self {
-        assert!(values.len() > 0, "Ring<T> doesn't work with empty Vec<T>");
+        assert!(!values.is_empty(), "Ring<T> doesn't work with empty Vec<T>");
         Self {
             values,
             next: AtomicUsize::new(0),
@@ -52,20 +52,3 @@ impl<T> Ring<T> {
         &self.values[self.next_index()]
     }
 }
-
-impl<T: Copy> Ring<T> {
-    /// Returns the next value in the ring by making a copy.
-    #[inline]
-    pub fn next_as_owned(&self) -> T {
-        *self.next_as_ref()
-    }
-}
-
-impl<T: Clone> Ring<T> {
-    /// Returns the next value in the ring by cloning it.
-    #[allow(dead_code)]
-    #[inline]
-    pub fn next_as_cloned(&self) -> T {
-        self.next_as_ref().clone()
-    }
-}
@@ -1,12 +1,27 @@
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
 use std::{
+    collections::HashSet,
     future::{self, Future},
-    net::SocketAddr,
+    io,
+    path::{Path, PathBuf},
     pin::Pin,
 };
 
-use tokio::sync::{broadcast, watch};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+};
 
-use crate::{config::Config, Server, State};
+use super::server::Reloader;
+#[cfg(unix)]
+use super::{systemd, upgrade};
+use crate::{
+    config::{self, Address, Config},
+    net,
+    Server,
+    State,
+};
 
 /// The master task is responsible for creating, spawning and shutting down all
 /// the [`Server`] instances described in the configuration file. Both spawning
@@ -98,21 +113,271 @@ use crate::{config::Config, Server, State};
 /// encounter a server in the config that listens on multiple addresses we
 /// simply clone that server multiple times giving each clone a different
 /// listening address. See [`Server`] for more implementation details.
+///
+/// # Accept Loops
+///
+/// With `reuse_port` (see [`config::Socket`]), each TCP address is bound
+/// multiple times using `SO_REUSEPORT` and every socket gets its own replica:
+///
+/// ```toml
+/// [[server]]
+///
+/// listen = "127.0.0.1:8080"
+/// forward = "127.0.0.1:9000"
+/// socket = { reuse_port = 4 }
+/// ```
+///
+/// The kernel distributes incoming connections between the sockets, and since
+/// each replica runs on its own task, the accept loops are spread over the
+/// worker threads of the Tokio runtime instead of a single task accepting
+/// everything. Unix sockets always have a single accept loop. With `http3`,
+/// only the first accept loop of each address binds the UDP socket and
+/// accepts QUIC connections, the others just tell their clients about it.
+///
+/// # Reloading
+///
+/// The configuration can be replaced while the master is running, either by
+/// sending `SIGHUP` to the process (see [`Master::reload_on_sighup`]) or by
+/// using a [`ReloadHandle`]. Replicas are matched by their listening address
+/// and by whether they use a listener or a UDP socket, since a TCP server and
+/// a UDP server can share the same address (see [`config::Protocol`]):
+///
+/// - Replicas whose address is still present keep their socket, they only swap
+///   the configuration, so routing, backends, TLS certificates and so on change
+///   without dropping connections.
+///
+/// - Replicas whose address is gone are shut down gracefully.
+///
+/// - New addresses get new replicas, which start listening right away.
+///
+/// Enabling or disabling HTTP/3 on an address replaces its replicas, since the
+/// UDP socket can't be added or removed from a running [`Server`]. The new
+/// replicas take over the listening sockets of the old ones, so clients are
+/// not refused while the replicas are replaced. Other changes to the options
+/// of the listening socket are applied to the running sockets, and changes to
+/// the number of accept loops only start or stop replicas. The exception is
+/// `ipv6_only`, which can't change once the socket is bound, so the replicas
+/// are replaced and new sockets are bound next to the old ones, which only
+/// works if both of them use `reuse_port`. If any new socket can't be bound,
+/// the whole configuration is rejected and the previous one keeps running.
+///
+/// # Binary Upgrades
+///
+/// New versions can be deployed without closing listening sockets. When the
+/// process receives `SIGUSR2` (see [`Master::upgrade_on_sigusr2`]) it executes
+/// the binary again and hands off all its listeners to the new process, which
+/// adopts them in [`Master::init`]. Once the new process is ready, the old one
+/// shuts down gracefully like it does on CTRL-C. See [`super::upgrade`] for
+/// the details.
 pub struct Master {
-    /// All the servers that the master has spawned.
+    /// Servers that have been initialized but are not running yet. They are
+    /// spawned when [`Master::run`] is called.
     servers: Vec<Server>,
 
-    /// Subscriptions to state updates from each server.
-    states: Vec<(SocketAddr, watch::Receiver<State>)>,
+    /// Handles to all the servers that the master has initialized.
+    replicas: Vec<Replica>,
 
     /// Shutdown future. The master polls this future and when it's ready it
     /// sends the shutdown signal to all the servers, then waits for them to
     /// finish their pending tasks.
     shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
 
-    /// Shutdown notifications channel. Spawned servers are subscribed to this
-    /// channel and can receive the shutdown signal.
-    shutdown_notify: broadcast::Sender<()>,
+    /// Configuration file that is read again when the process receives
+    /// `SIGHUP`.
+    config_file: Option<PathBuf>,
+
+    /// Whether `SIGUSR2` starts a binary upgrade.
+    upgrade: bool,
+
+    /// Sender half of the reload requests channel. Only used to give away
+    /// [`ReloadHandle`] instances.
+    reload_sender: mpsc::Sender<ReloadRequest>,
+
+    /// Reload requests sent through [`ReloadHandle`] instances.
+    reload_receiver: mpsc::Receiver<ReloadRequest>,
+}
+
+/// Everything that the master needs to control a single [`Server`] after it
+/// has been spawned.
+struct Replica {
+    /// Address in the config file, which is used to match replicas when the
+    /// configuration is reloaded.
+    listen: Address,
+
+    /// Actual address of the listening socket.
+    address: Address,
+
+    /// Index of the accept loop of this replica among all the replicas that
+    /// share the same address. See the "Accept Loops" section of [`Master`].
+    accept_loop: usize,
+
+    /// State updates of the server.
+    state: watch::Receiver<State>,
+
+    /// Used to swap the configuration of the server.
+    reloader: Reloader,
+
+    /// Sends the shutdown signal to this particular server.
+    shutdown: oneshot::Sender<()>,
+
+    /// Duplicated descriptor of the listening socket, which is sent to the
+    /// new process on binary upgrades. It's closed when the replica is shut
+    /// down.
+    #[cfg(unix)]
+    listener: OwnedFd,
+}
+
+/// Listening socket opened by another process, either a previous RXH process
+/// or systemd.
+struct Inherited {
+    /// Local address of the socket.
+    address: Address,
+
+    /// Name given by systemd, if any.
+    name: Option<String>,
+
+    /// The socket itself.
+    listener: net::Listener,
+}
+
+/// Something that the master has to react to while the servers are running.
+enum Event {
+    /// Configuration received from a [`ReloadHandle`].
+    Reload(ReloadRequest),
+
+    /// `SIGHUP` received.
+    Hangup,
+
+    /// `SIGUSR2` received.
+    Upgrade,
+}
+
+/// New configuration sent through a [`ReloadHandle`] and the channel where
+/// the result is sent back.
+type ReloadRequest = (Config, oneshot::Sender<Result<Vec<Address>, crate::Error>>);
+
+/// Allows other tasks to reload the configuration of a running [`Master`]. See
+/// [`Master::reload_handle`].
+#[derive(Clone)]
+pub struct ReloadHandle {
+    sender: mpsc::Sender<ReloadRequest>,
+}
+
+impl ReloadHandle {
+    /// Sends `config` to the master and waits until it's applied. On success,
+    /// all the listening sockets are returned, including the new ones. If the
+    /// configuration is rejected, the master keeps running with the previous
+    /// one and the error is returned instead.
+    pub async fn reload(&self, config: Config) -> Result<Vec<Address>, crate::Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        let not_running = || io::Error::new(io::ErrorKind::NotConnected, "master is not running");
+
+        self.sender
+            .send((config, sender))
+            .await
+            .map_err(|_| not_running())?;
+
+        receiver.await.map_err(|_| not_running())?
+    }
+}
+
+impl Replica {
+    /// Initializes all the accept loops of the given replica of `config`. The
+    /// `inherited` function is called for each one of them and can return a
+    /// listener opened by another process, otherwise a new one is bound.
+    fn init_all(
+        config: &config::Server,
+        replica: usize,
+        mut inherited: impl FnMut() -> Option<net::Listener>,
+    ) -> Result<Vec<(Self, Server)>, io::Error> {
+        let mut replicas: Vec<(Self, Server)> = Vec::new();
+
+        for accept_loop in 0..accept_loops(config, &config.listen[replica]) {
+            let listener = match (inherited(), replicas.first()) {
+                (Some(listener), _) => Some(listener),
+                // Port 0 would give us a different port for each loop, they
+                // have to use the port assigned to the first one.
+                (None, Some((first, _))) => Some(Server::bind(config, &first.address)?),
+                (None, None) => None,
+            };
+
+            replicas.push(Self::init(config.clone(), replica, accept_loop, listener)?);
+        }
+
+        Ok(replicas)
+    }
+
+    /// Initializes a [`Server`] for the given replica of `config` and returns
+    /// the handle that the master keeps. If a `listener` is given, the server
+    /// adopts it instead of binding.
+    fn init(
+        config: config::Server,
+        replica: usize,
+        accept_loop: usize,
+        listener: Option<net::Listener>,
+    ) -> Result<(Self, Server), io::Error> {
+        let listen = config.listen[replica].clone();
+        let (shutdown, shutdown_notification) = oneshot::channel();
+
+        let server = match listener {
+            Some(listener) if accept_loop > 0 => Server::adopt_without_quic(config, listener)?,
+            Some(listener) => Server::adopt(config, listener)?,
+            None => Server::init(config, replica)?,
+        };
+
+        let server = server.shutdown_on(shutdown_notification);
+
+        let replica = Self {
+            listen,
+            address: server.socket_address(),
+            accept_loop,
+            state: server.subscribe(),
+            reloader: server.reloader(),
+            shutdown,
+            #[cfg(unix)]
+            listener: server.duplicate_listener()?,
+        };
+
+        Ok((replica, server))
+    }
+
+    /// Initializes the replicas of `config` that replace the `old` ones, which
+    /// keep running until they are shut down. See the "Reloading" section of
+    /// [`Master`]. If this fails, the sockets of the old replicas might have
+    /// been reconfigured already.
+    fn replace(
+        old: &[&Self],
+        config: &config::Server,
+        replica: usize,
+    ) -> Result<Vec<(Self, Server)>, io::Error> {
+        #[cfg(unix)]
+        let mut listeners = {
+            let mut listeners = Vec::new();
+
+            if !rebinds(&old[0].reloader.current(), config, &old[0].address) {
+                for old in old.iter().take(accept_loops(config, &old[0].listen)) {
+                    net::reconfigure(&old.listener, &config.socket)?;
+                    let fd = old.listener.try_clone()?;
+                    listeners.push(net::Listener::from_fd(&old.address, fd)?);
+                }
+            }
+
+            listeners.into_iter()
+        };
+
+        #[cfg(not(unix))]
+        let mut listeners = std::iter::empty();
+
+        Self::init_all(config, replica, || listeners.next())
+    }
+
+    /// Starts the graceful shutdown process of the server.
+    fn shutdown(self) -> watch::Receiver<State> {
+        // The server might have stopped already.
+        let _ = self.shutdown.send(());
+        self.state
+    }
 }
 
 impl Master {
@@ -120,40 +385,86 @@ impl Master {
     /// file or in the received `config`. The initialization only acquires and
     /// configures the TCP sockets, but does not listen or accept connections.
     /// See [`Server::init`] for more details.
+    ///
+    /// If this process was started by a binary upgrade or by systemd socket
+    /// activation, the listeners that are already open are adopted by the
+    /// servers that listen on the same addresses. Sockets passed by systemd
+    /// can also be matched by name, using `FileDescriptorName=` in the socket
+    /// unit and `name` in the config file. Listeners that don't match any
+    /// server are closed. See [`super::upgrade`] and [`super::systemd`].
     pub fn init(config: Config) -> Result<Self, crate::Error> {
         let mut servers = Vec::new();
-        let mut states = Vec::new();
+        let mut replicas = Vec::new();
         let shutdown = Box::pin(future::pending());
-        let (shutdown_notify, _) = broadcast::channel(1);
+        let (reload_sender, reload_receiver) = mpsc::channel(1);
+
+        let mut inherited = Vec::new();
+
+        #[cfg(unix)]
+        let upgrade = match upgrade::inherit()? {
+            Some((stream, listeners)) => {
+                for (address, fd) in listeners {
+                    inherited.push(Inherited {
+                        listener: net::Listener::from_fd(&address, fd)?,
+                        address,
+                        name: None,
+                    });
+                }
+                Some(stream)
+            }
+            None => None,
+        };
+
+        #[cfg(unix)]
+        for (name, address, fd) in systemd::listeners()? {
+            inherited.push(Inherited {
+                listener: net::Listener::from_fd(&address, fd)?,
+                address,
+                name,
+            });
+        }
 
         for server_config in config.servers {
             for replica in 0..server_config.listen.len() {
-                let server = Server::init(server_config.clone(), replica)?;
-                states.push((server.socket_address(), server.subscribe()));
-                servers.push(server);
+                let initialized = Replica::init_all(&server_config, replica, || {
+                    take_inherited(
+                        &mut inherited,
+                        &server_config.listen[replica],
+                        &server_config,
+                    )
+                })?;
+
+                for (replica, server) in initialized {
+                    replicas.push(replica);
+                    servers.push(server);
+                }
             }
         }
 
+        for unused in inherited {
+            println!("Master => Closing unused socket {}", unused.address);
+        }
+
+        #[cfg(unix)]
+        if let Some(stream) = upgrade {
+            println!("Master => Adopted listeners from previous process");
+            upgrade::ready(stream)?;
+        }
+
         Ok(Self {
             servers,
-            states,
+            replicas,
             shutdown,
-            shutdown_notify,
+            config_file: None,
+            upgrade: false,
+            reload_sender,
+            reload_receiver,
         })
     }
 
     /// When `future` is ready, the graceful shutdown process begins. See
     /// [`Master`] definition, [`Server`] and [`crate::sync::notify`].
     pub fn shutdown_on(mut self, future: impl Future + Send + 'static) -> Self {
-        self.servers = self
-            .servers
-            .into_iter()
-            .map(|server| {
-                let mut shutdown_notification = self.shutdown_notify.subscribe();
-                server.shutdown_on(async move { shutdown_notification.recv().await })
-            })
-            .collect();
-
         self.shutdown = Box::pin(async move {
             future.await;
         });
@@ -161,31 +472,103 @@ impl Master {
         self
     }
 
+    /// Reads `path` again and applies the new configuration every time the
+    /// process receives `SIGHUP`. Invalid files are logged and ignored. This
+    /// does nothing on platforms without signals.
+    pub fn reload_on_sighup(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Starts a binary upgrade every time the process receives `SIGUSR2`. See
+    /// the "Binary Upgrades" section of [`Master`]. This does nothing on
+    /// platforms without signals.
+    pub fn upgrade_on_sigusr2(mut self) -> Self {
+        self.upgrade = true;
+        self
+    }
+
+    /// Returns a [`ReloadHandle`] that can be used to replace the configuration
+    /// once the master is running.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            sender: self.reload_sender.clone(),
+        }
+    }
+
     /// All the servers are put into `listen` mode and they start accepting
     /// connections.
-    pub async fn run(self) -> Result<(), crate::Error> {
-        let mut set = tokio::task::JoinSet::new();
+    pub async fn run(mut self) -> Result<(), crate::Error> {
+        let mut set = JoinSet::new();
 
-        for server in self.servers {
+        for server in self.servers.drain(..) {
             set.spawn(server.run());
         }
 
+        let mut hangup = hangup_signal(self.config_file.is_some())?;
+        let mut upgrade = upgrade_signal(self.upgrade)?;
+
         let mut first_error = None;
 
-        tokio::select! {
-            Some(Ok(Err(err))) = set.join_next() => {
-                first_error = Some(err);
-                println!("Master => Received error while waiting for shutdown");
-            }
+        loop {
+            let event = tokio::select! {
+                Some(Ok(Err(err))) = set.join_next() => {
+                    first_error = Some(err);
+                    println!("Master => Received error while waiting for shutdown");
+                    break;
+                }
+
+                // TODO: Check for first join error. That means a server has panicked.
+
+                _ = &mut self.shutdown => {
+                    println!("Master => Sending shutdown signal to all servers");
+                    break;
+                }
+
+                Some(request) = self.reload_receiver.recv() => Event::Reload(request),
+
+                Some(()) = recv_signal(&mut hangup) => Event::Hangup,
+
+                Some(()) = recv_signal(&mut upgrade) => Event::Upgrade,
+            };
 
-            // TODO: Check for first join error. That means a server has panicked.
+            match event {
+                Event::Reload((config, result)) => {
+                    let _ = result.send(self.reload(config, &mut set).await);
+                }
 
-            _ = self.shutdown => {
-                println!("Master => Sending shutdown signal to all servers");
+                Event::Hangup => {
+                    // Only set when config_file is set.
+                    let path = self.config_file.clone().unwrap();
+                    println!("Master => Received SIGHUP, reloading {}", path.display());
+
+                    let reloaded = match read_config(&path).await {
+                        Ok(config) => self.reload(config, &mut set).await,
+                        Err(err) => Err(err),
+                    };
+
+                    if let Err(err) = reloaded {
+                        println!("Master => Configuration rejected: {err}");
+                    }
+                }
+
+                Event::Upgrade => {
+                    println!("Master => Received SIGUSR2, upgrading binary");
+
+                    match self.upgrade().await {
+                        Ok(pid) => {
+                            println!("Master => New process {pid} is ready, shutting down");
+                            break;
+                        }
+                        Err(err) => println!("Master => Upgrade failed: {err}"),
+                    }
+                }
             }
         }
 
-        self.shutdown_notify.send(()).unwrap();
+        for replica in self.replicas.drain(..) {
+            replica.shutdown();
+        }
 
         while let Some(result) = set.join_next().await {
             if let Err(err) = result.unwrap() {
@@ -195,12 +578,305 @@ impl Master {
 
         match first_error {
             None => Ok(()),
-            Some(err) => Err(crate::Error::from(err)),
+            Some(err) => Err(err),
+        }
+    }
+
+    /// Applies a new configuration. See the "Reloading" section of [`Master`].
+    /// New sockets are bound before any server is shut down or reloaded, so
+    /// if that fails the running servers are not affected.
+    async fn reload(
+        &mut self,
+        config: Config,
+        set: &mut JoinSet<Result<(), crate::Error>>,
+    ) -> Result<Vec<Address>, crate::Error> {
+        let mut kept = Vec::new();
+        let mut reconfigured = Vec::new();
+        let mut replaced = Vec::new();
+        let mut added = Vec::new();
+        let mut addresses = HashSet::new();
+
+        for server_config in &config.servers {
+            for (replica, listen) in server_config.listen.iter().enumerate() {
+                let datagram = server_config.protocol.is_datagram();
+
+                if !listen.is_ephemeral() && !addresses.insert((listen, datagram)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("address {listen} is used by more than one server"),
+                    )
+                    .into());
+                }
+
+                // Indexes of the accept loops of this address, in order.
+                let mut running: Vec<usize> = (0..self.replicas.len())
+                    .filter(|index| {
+                        let running = &self.replicas[*index];
+                        !running.listen.is_ephemeral()
+                            && running.listen == *listen
+                            && running.reloader.current().protocol.is_datagram() == datagram
+                    })
+                    .collect();
+
+                running.sort_by_key(|index| self.replicas[*index].accept_loop);
+
+                let Some(first) = running.first() else {
+                    added.extend(Replica::init_all(server_config, replica, || None)?);
+                    continue;
+                };
+
+                let current = self.replicas[*first].reloader.current();
+
+                if current.http3.is_some() != server_config.http3.is_some()
+                    || rebinds(&current, server_config, &self.replicas[*first].address)
+                {
+                    replaced.push((running, server_config.clone(), replica));
+                    continue;
+                }
+
+                let mut server_config = server_config.clone();
+
+                if let (Some(tls), Some(current)) = (&mut server_config.tls, &current.tls) {
+                    tls.keep_acme(current);
+                }
+
+                // Accept loops that are no longer needed are not kept, so
+                // they are shut down below.
+                running.truncate(accept_loops(&server_config, listen));
+
+                for index in &running {
+                    kept.push((*index, server_config.clone()));
+                }
+
+                reconfigured.push((running, server_config, replica));
+            }
+        }
+
+        // Sockets that are still in use are reconfigured last, because that
+        // has to be undone if anything fails. New accept loops of running
+        // addresses are bound afterwards, they might need `SO_REUSEPORT` to
+        // be set on the running sockets first.
+        let mut replacements = Vec::new();
+
+        let mut prepare = || -> Result<(), io::Error> {
+            for (indexes, config, replica) in &reconfigured {
+                let first = &self.replicas[indexes[0]];
+
+                #[cfg(unix)]
+                for index in indexes {
+                    net::reconfigure(&self.replicas[*index].listener, &config.socket)?;
+                }
+
+                for accept_loop in indexes.len()..accept_loops(config, &first.listen) {
+                    let listener = Server::bind(config, &first.listen)?;
+                    let initialized =
+                        Replica::init(config.clone(), *replica, accept_loop, Some(listener))?;
+                    added.push(initialized);
+                }
+            }
+
+            for (indexes, config, replica) in &replaced {
+                let old: Vec<_> = indexes.iter().map(|index| &self.replicas[*index]).collect();
+                replacements.push(Replica::replace(&old, config, *replica)?);
+            }
+
+            Ok(())
+        };
+
+        if let Err(err) = prepare() {
+            #[cfg(unix)]
+            for index in reconfigured
+                .iter()
+                .chain(&replaced)
+                .flat_map(|(indexes, ..)| indexes)
+            {
+                let old = &self.replicas[*index];
+                let _ = net::reconfigure(&old.listener, &old.reloader.current().socket);
+            }
+
+            return Err(err.into());
+        }
+
+        // Nothing can fail from here on. Each index appears only once because
+        // duplicated addresses are rejected above.
+        let mut running: Vec<_> = self.replicas.drain(..).map(Some).collect();
+
+        for (index, config) in kept {
+            let replica = running[index].take().unwrap();
+            replica.reloader.reload(config);
+            self.replicas.push(replica);
+        }
+
+        for ((indexes, ..), new) in replaced.into_iter().zip(replacements) {
+            println!("Master => Replacing server at {}", new[0].0.address);
+
+            for index in indexes {
+                running[index].take().unwrap().shutdown();
+            }
+
+            added.extend(new);
+        }
+
+        for removed in running.into_iter().flatten() {
+            println!("Master => Shutting down server at {}", removed.address);
+            removed.shutdown();
+        }
+
+        for (replica, server) in added {
+            set.spawn(server.run());
+            self.replicas.push(replica);
         }
+
+        println!("Master => Configuration reloaded");
+
+        Ok(self.sockets())
+    }
+
+    /// Hands off all the listening sockets to a new process and returns its
+    /// PID once it's ready. HTTP/3 endpoints can't be shared with the new
+    /// process, so servers that use them can't be upgraded this way. The
+    /// returned future doesn't borrow the master, which can't be shared
+    /// between threads.
+    #[cfg(unix)]
+    fn upgrade(&self) -> impl Future<Output = Result<u32, io::Error>> + Send + 'static {
+        let listeners = if self
+            .replicas
+            .iter()
+            .any(|replica| replica.reloader.current().http3.is_some())
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HTTP/3 listeners can't be handed off",
+            ))
+        } else {
+            self.replicas
+                .iter()
+                .map(|replica| Ok((replica.address.clone(), replica.listener.try_clone()?)))
+                .collect::<Result<Vec<_>, io::Error>>()
+        };
+
+        async move { upgrade::hand_off(listeners?).await }
+    }
+
+    #[cfg(not(unix))]
+    fn upgrade(&self) -> impl Future<Output = Result<u32, io::Error>> + Send + 'static {
+        future::ready(Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binary upgrades are only supported on Unix",
+        )))
     }
 
     /// Returns all the listening sockets.
-    pub fn sockets(&self) -> Vec<SocketAddr> {
-        self.states.iter().map(|(addr, _)| *addr).collect()
+    pub fn sockets(&self) -> Vec<Address> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.address.clone())
+            .collect()
+    }
+}
+
+/// Number of accept loops of the given `listen` address of a server. Unix
+/// sockets can't be bound more than once.
+fn accept_loops(config: &config::Server, listen: &Address) -> usize {
+    match listen {
+        Address::Tcp(_) => config.socket.accept_loops(),
+        Address::Unix(_) => 1,
+    }
+}
+
+/// Whether the sockets of a server running with `current` at `address` have
+/// to be bound again for `new`. Only `ipv6_only` can't change once they are
+/// bound, see [`net::reconfigure`].
+fn rebinds(current: &config::Server, new: &config::Server, address: &Address) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(address, Address::Tcp(address) if address.is_ipv6())
+            && current.socket.ipv6_only != new.socket.ipv6_only
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = address;
+        !current.socket.binds_like(&new.socket)
+    }
+}
+
+/// Removes and returns the inherited listener bound to `listen` that suits the
+/// protocol of the server. If there's no such listener, the first one named
+/// after the server is used instead.
+fn take_inherited(
+    inherited: &mut Vec<Inherited>,
+    listen: &Address,
+    config: &config::Server,
+) -> Option<net::Listener> {
+    let name = config.name.as_deref();
+    let datagram = config.protocol.is_datagram();
+
+    let index = inherited
+        .iter()
+        .position(|candidate| {
+            candidate.address == *listen && candidate.listener.is_datagram() == datagram
+        })
+        .or_else(|| {
+            inherited.iter().position(|candidate| {
+                name.is_some()
+                    && candidate.name.as_deref() == name
+                    && candidate.listener.is_datagram() == datagram
+            })
+        })?;
+
+    Some(inherited.remove(index).listener)
+}
+
+/// Reads and parses the configuration file at `path`.
+async fn read_config(path: &Path) -> Result<Config, crate::Error> {
+    Ok(toml::from_str(&tokio::fs::read_to_string(path).await?)?)
+}
+
+#[cfg(unix)]
+type Signal = Option<tokio::signal::unix::Signal>;
+
+#[cfg(not(unix))]
+type Signal = ();
+
+/// Listens for `SIGHUP` if `enabled` is true.
+#[cfg(unix)]
+fn hangup_signal(enabled: bool) -> Result<Signal, io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    enabled.then(|| signal(SignalKind::hangup())).transpose()
+}
+
+/// Listens for `SIGUSR2` if `enabled` is true.
+#[cfg(unix)]
+fn upgrade_signal(enabled: bool) -> Result<Signal, io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    enabled
+        .then(|| signal(SignalKind::user_defined2()))
+        .transpose()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal(_enabled: bool) -> Result<Signal, io::Error> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn upgrade_signal(_enabled: bool) -> Result<Signal, io::Error> {
+    Ok(())
+}
+
+/// Completes when the next signal arrives, never if it's not enabled.
+#[cfg(unix)]
+async fn recv_signal(signal: &mut Signal) -> Option<()> {
+    match signal {
+        Some(signal) => signal.recv().await,
+        None => future::pending().await,
     }
 }
+
+#[cfg(not(unix))]
+async fn recv_signal(_signal: &mut Signal) -> Option<()> {
+    future::pending().await
+}
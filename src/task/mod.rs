@@ -8,4 +8,10 @@
 //! through message passing. See [`master`] and [`server`] for more details.
 
 pub(crate) mod master;
+pub(crate) mod quic;
 pub(crate) mod server;
+#[cfg(unix)]
+pub(crate) mod systemd;
+pub(crate) mod udp;
+#[cfg(unix)]
+pub(crate) mod upgrade;
@@ -0,0 +1,321 @@
+//! HTTP/3 listener. Each [`super::server::Server`] with `http3` enabled also
+//! accepts QUIC connections on the same port using UDP. Requests go through
+//! the same [`Rxh`] service as HTTP/1.1 and HTTP/2 requests, only the
+//! transport is different. See [`crate::config::Http3`].
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use h3::{error::StreamError, server::RequestStream};
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Frame},
+    header,
+    service::Service,
+    Response,
+};
+use quinn::{Endpoint, Incoming, TransportConfig, VarInt};
+use tokio::{
+    sync::{watch, Semaphore},
+    task::JoinSet,
+    time::Instant,
+};
+
+use crate::{
+    config,
+    service::Rxh,
+    sync::notify::{Notification, Notifier, Subscription},
+    tls::server::ClientIdentity,
+};
+
+/// Application error code sent when closing connections that are done, as
+/// defined in RFC 9114 section 8.1.
+const H3_NO_ERROR: VarInt = VarInt::from_u32(0x100);
+
+/// Creates a QUIC endpoint bound to `address` that uses the TLS settings of
+/// the server.
+pub(super) fn endpoint(
+    address: SocketAddr,
+    http3: &config::Http3,
+    tls: &config::ServerTls,
+) -> Result<Endpoint, io::Error> {
+    Endpoint::server(server_config(http3, tls)?, address)
+}
+
+/// Builds the QUIC settings of an endpoint. Running endpoints are updated
+/// with a new one when the configuration is reloaded.
+pub(super) fn server_config(
+    http3: &config::Http3,
+    tls: &config::ServerTls,
+) -> Result<quinn::ServerConfig, io::Error> {
+    let mut transport = TransportConfig::default();
+
+    transport.max_concurrent_bidi_streams(http3.max_concurrent_streams.into());
+    transport.max_idle_timeout(Some(http3.idle_timeout.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "HTTP/3 idle_timeout is too large",
+        )
+    })?));
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls.acceptor.quic_config()?));
+    server_config.transport_config(Arc::new(transport));
+
+    Ok(server_config)
+}
+
+/// Accepts QUIC connections and spawns tasks to handle them, same as
+/// [`super::server::Server`] does for TCP connections.
+pub(super) struct QuicListener<'a> {
+    /// QUIC endpoint. New connections are refused once the server starts
+    /// shutting down, see [`QuicListener::close`].
+    endpoint: &'a Endpoint,
+
+    /// Configuration of this server, which might change while listening.
+    config: watch::Receiver<Arc<config::Server>>,
+
+    /// Connection tasks are notified when the server shuts down.
+    notifier: &'a Notifier,
+
+    /// Connection permits, shared with the TCP listener.
+    connections: Arc<Semaphore>,
+}
+
+impl<'a> QuicListener<'a> {
+    /// Creates a new [`QuicListener`].
+    pub fn new(
+        endpoint: &'a Endpoint,
+        config: watch::Receiver<Arc<config::Server>>,
+        notifier: &'a Notifier,
+        connections: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            endpoint,
+            config,
+            notifier,
+            connections,
+        }
+    }
+
+    /// Accepts connections until the endpoint is closed.
+    pub async fn listen(&self) -> Result<(), crate::Error> {
+        let server_addr = self.endpoint.local_addr()?;
+
+        loop {
+            // We don't close the semaphore so unwrapping is OK.
+            let permit = self.connections.clone().acquire_owned().await.unwrap();
+
+            let Some(incoming) = self.endpoint.accept().await else {
+                return Ok(());
+            };
+
+            let config = self.config.clone();
+            let subscription = self.notifier.subscribe();
+
+            tokio::task::spawn(async move {
+                serve(incoming, config, server_addr, subscription).await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// Stops accepting connections. Established connections are not affected.
+    pub fn close(&self) {
+        self.endpoint.set_server_config(None);
+    }
+}
+
+/// Handles all the requests sent on a single QUIC connection. When the server
+/// shuts down, the client receives a `GOAWAY` frame and the connection is
+/// closed as soon as the requests that were already accepted are done, or
+/// when [`config::Server::shutdown_timeout`] expires.
+async fn serve(
+    incoming: Incoming,
+    updates: watch::Receiver<Arc<config::Server>>,
+    server_addr: SocketAddr,
+    mut subscription: Subscription,
+) {
+    let client_addr = incoming.remote_address();
+    let config = updates.borrow().clone();
+    let log_name = &config.log_name;
+
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            println!("{client_addr} -> {log_name} QUIC handshake failed: {err}");
+            return;
+        }
+    };
+
+    let identity = ClientIdentity::from_quic(&connection).map(Arc::new);
+
+    let mut h3 =
+        match h3::server::Connection::new(h3_quinn::Connection::new(connection.clone())).await {
+            Ok(h3) => h3,
+            Err(err) => {
+                println!("{client_addr} -> {log_name} HTTP/3 connection failed: {err}");
+                return;
+            }
+        };
+
+    let mut requests = JoinSet::new();
+    let mut shutting_down = false;
+    let mut deadline = Instant::now();
+
+    loop {
+        if shutting_down && requests.is_empty() {
+            break;
+        }
+
+        tokio::select! {
+            accepted = h3.accept() => match accepted {
+                Ok(Some(resolver)) => {
+                    let service = Rxh::new(updates.clone(), client_addr.into(), server_addr.into(), identity.clone());
+                    let log_name = log_name.clone();
+
+                    requests.spawn(async move {
+                        if let Err(err) = respond(resolver, service).await {
+                            println!("{client_addr} -> {log_name} HTTP/3 request failed: {err}");
+                        }
+                    });
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    if !err.is_h3_no_error() {
+                        println!("{client_addr} -> {log_name} HTTP/3 connection failed: {err}");
+                    }
+                    break;
+                }
+            },
+
+            Some(_) = requests.join_next(), if !requests.is_empty() => {}
+
+            Notification::Shutdown = subscription.wait_for_notification(), if !shutting_down => {
+                shutting_down = true;
+                deadline = Instant::now() + config.shutdown_timeout;
+                if h3.shutdown(0).await.is_err() {
+                    break;
+                }
+            }
+
+            _ = tokio::time::sleep_until(deadline), if shutting_down => {
+                println!("{client_addr} -> {log_name} Closing QUIC connection after shutdown timeout");
+                requests.abort_all();
+                break;
+            }
+        }
+    }
+
+    while requests.join_next().await.is_some() {}
+
+    connection.close(H3_NO_ERROR, b"");
+
+    if let Some(Notification::Shutdown) = subscription.receive_notification() {
+        subscription.acknowledge_notification().await;
+    }
+}
+
+/// Reads the request from the stream, calls the [`Rxh`] service and sends the
+/// response back.
+async fn respond(
+    resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>,
+    service: Rxh,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut sender, receiver) = stream.split();
+
+    let request = request.map(|()| RequestBody::new(receiver));
+
+    let (mut parts, mut body) = service.call(request).await?.into_parts();
+
+    // Connection specific headers are not allowed in HTTP/3, but backends
+    // send them in HTTP/1.1 responses.
+    for name in [
+        header::CONNECTION,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+        header::HeaderName::from_static("keep-alive"),
+        header::HeaderName::from_static("proxy-connection"),
+    ] {
+        parts.headers.remove(name);
+    }
+
+    sender
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => sender.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    sender.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+
+    Ok(sender.finish().await?)
+}
+
+/// Body of a request received on an HTTP/3 stream.
+struct RequestBody {
+    /// Receiving half of the request stream.
+    stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+
+    /// All the data frames have been received.
+    data_done: bool,
+
+    /// Trailers have been received or there are none.
+    finished: bool,
+}
+
+impl RequestBody {
+    fn new(stream: RequestStream<h3_quinn::RecvStream, Bytes>) -> Self {
+        Self {
+            stream,
+            data_done: false,
+            finished: false,
+        }
+    }
+}
+
+impl Body for RequestBody {
+    type Data = Bytes;
+
+    type Error = StreamError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        if !self.data_done {
+            match ready!(self.stream.poll_recv_data(cx))? {
+                Some(mut data) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                None => self.data_done = true,
+            }
+        }
+
+        let trailers = ready!(self.stream.poll_recv_trailers(cx))?;
+        self.finished = true;
+
+        Poll::Ready(trailers.map(|trailers| Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.finished
+    }
+}
@@ -1,16 +1,39 @@
-use std::{future::Future, io, net::SocketAddr, pin::Pin, ptr, sync::Arc};
-
+#[cfg(unix)]
+use std::os::fd::{AsFd, OwnedFd};
+use std::{cmp::Ordering, future::Future, io, pin::Pin, sync::Arc, time::Duration};
+
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderValue},
+    service::{service_fn, Service},
+    Request,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
 use tokio::{
-    net::{TcpListener, TcpSocket},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     sync::{watch, Semaphore},
 };
 
+use super::{
+    quic::{self, QuicListener},
+    udp::UdpListener,
+};
 use crate::{
     config,
-    service::Rxh,
-    sync::notify::{Notification, Notifier},
+    net::{self, proxy_protocol, Address},
+    service::{tcp, Rxh},
+    sync::notify::{Notification, Notifier, Subscription},
+    tls::{self, server::ClientIdentity},
 };
 
+/// Maximum time that load balancers have to send the PROXY protocol header
+/// after connecting.
+const PROXY_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// The [`Server`] struct represents a particular `[[server]]` instance from the
 /// config file. It is responsible for accepting new connections and spawning
 /// Tokio tasks to handle them properly, as well as gracefully stopping the
@@ -48,14 +71,20 @@ pub struct Server {
     /// [`State`] of this server.
     state: watch::Sender<State>,
 
-    /// TCP listener used to accept connections.
-    listener: TcpListener,
+    /// TCP or Unix listener used to accept connections, or UDP socket used
+    /// to receive datagrams.
+    listener: net::Listener,
 
-    /// Configuration for this server.
-    config: config::Server,
+    /// Configuration for this server. It can be swapped while the server is
+    /// running, see [`Reloader`].
+    config: watch::Sender<Arc<config::Server>>,
 
     /// Socket address used by this server to listen for incoming connections.
-    address: SocketAddr,
+    address: Address,
+
+    /// QUIC endpoint bound to the same address using UDP if HTTP/3 is
+    /// enabled.
+    quic: Option<quinn::Endpoint>,
 
     /// [`Notifier`] object used to send notifications to tasks spawned by
     /// this server.
@@ -112,22 +141,81 @@ impl Server {
     /// it's a number that indicates which address should this server choose for
     /// listening, since the config file allows multiple addresses.
     pub fn init(config: config::Server, replica: usize) -> Result<Self, io::Error> {
-        let (state, _) = watch::channel(State::Starting);
+        let listener = Self::bind(&config, &config.listen[replica])?;
 
-        let socket = if config.listen[replica].is_ipv4() {
-            TcpSocket::new_v4()?
+        Self::adopt(config, listener)
+    }
+
+    /// Binds a socket to `address` that suits the protocol of `config`, a
+    /// UDP socket for [`config::Protocol::Udp`] and a listener otherwise.
+    pub(crate) fn bind(
+        config: &config::Server,
+        address: &Address,
+    ) -> Result<net::Listener, io::Error> {
+        if config.protocol.is_datagram() {
+            net::Listener::bind_udp(address, &config.socket)
         } else {
-            TcpSocket::new_v6()?
-        };
+            net::Listener::bind(address, &config.socket)
+        }
+    }
+
+    /// Same as [`Server::init`] but uses a `listener` that is already bound
+    /// instead of creating a new socket. This is how listening sockets
+    /// inherited from another process are put to use, see
+    /// [`super::master::Master`]. The address of the `listener` takes
+    /// precedence over the addresses in the `config`.
+    pub fn adopt(
+        config: config::Server,
+        listener: impl Into<net::Listener>,
+    ) -> Result<Self, io::Error> {
+        Self::adopt_listener(config, listener.into(), true)
+    }
+
+    /// Same as [`Server::adopt`] but HTTP/3 connections are not accepted, for
+    /// accept loops other than the first one. See the "Accept Loops" section
+    /// of [`super::master::Master`].
+    pub(crate) fn adopt_without_quic(
+        config: config::Server,
+        listener: net::Listener,
+    ) -> Result<Self, io::Error> {
+        Self::adopt_listener(config, listener, false)
+    }
+
+    /// Implementation of [`Server::adopt`]. The QUIC endpoint is only created
+    /// if `quic` is `true`.
+    fn adopt_listener(
+        mut config: config::Server,
+        listener: net::Listener,
+        quic: bool,
+    ) -> Result<Self, io::Error> {
+        let (state, _) = watch::channel(State::Starting);
 
-        #[cfg(not(windows))]
-        socket.set_reuseaddr(true)?;
+        let address = listener.local_address()?;
 
-        socket.bind(config.listen[replica])?;
+        if listener.is_datagram() != config.protocol.is_datagram() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "socket {address} can't be used with protocol '{}'",
+                    config.protocol
+                ),
+            ));
+        }
 
-        // TODO: Hardcoded backlog, maybe this should be configurable.
-        let listener = socket.listen(1024)?;
-        let address = listener.local_addr().unwrap();
+        // The UDP socket uses the actual port of the TCP listener, since
+        // clients find HTTP/3 through the Alt-Svc header sent on TCP.
+        let quic = match (&config.http3, &config.tls, &address) {
+            (Some(http3), Some(tls), Address::Tcp(address)) if quic => {
+                Some(quic::endpoint(*address, http3, tls)?)
+            }
+            (Some(_), _, Address::Unix(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'http3' can't be used on {address}"),
+                ))
+            }
+            _ => None,
+        };
 
         let notifier = Notifier::new();
 
@@ -137,17 +225,33 @@ impl Server {
 
         let connections = Arc::new(Semaphore::new(config.max_connections));
 
+        config.log_name = log_name(&address, &config);
+        let (config, _) = watch::channel(Arc::new(config));
+
         Ok(Self {
             state,
             listener,
             config,
             address,
+            quic,
             notifier,
             shutdown,
             connections,
         })
     }
 
+    /// Same as [`Server::adopt`] but takes a [`std::net::TcpListener`], which
+    /// is useful for embedding RXH in applications that create their own
+    /// sockets. The listener is switched to non-blocking mode. This must be
+    /// called from within a Tokio runtime.
+    pub fn from_std_listener(
+        config: config::Server,
+        listener: std::net::TcpListener,
+    ) -> Result<Self, io::Error> {
+        listener.set_nonblocking(true)?;
+        Self::adopt(config, TcpListener::from_std(listener)?)
+    }
+
     /// The [`Server`] will poll the given `future` and whenever it completes,
     /// the graceful shutdown process starts. If only one server is
     /// instantiated, this could be called with [`tokio::signal::ctrl_c`], but
@@ -163,8 +267,8 @@ impl Server {
 
     /// Address of the listening socket. This is necessary for obtaining the
     /// actual address in cases port 0 was used.
-    pub fn socket_address(&self) -> SocketAddr {
-        self.address
+    pub fn socket_address(&self) -> Address {
+        self.address.clone()
     }
 
     /// By subscribing to this server the caller obtains a channel where the
@@ -175,75 +279,173 @@ impl Server {
         self.state.subscribe()
     }
 
+    /// Returns a [`Reloader`] that can swap the configuration of this server
+    /// once it's running.
+    pub(crate) fn reloader(&self) -> Reloader {
+        Reloader {
+            address: self.address.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    /// Duplicates the file descriptor of the listening socket so that it can
+    /// be sent to another process.
+    #[cfg(unix)]
+    pub(crate) fn duplicate_listener(&self) -> Result<OwnedFd, io::Error> {
+        self.listener.as_fd().try_clone_to_owned()
+    }
+
     /// This is the entry point, by calling and `await`ing this function the
     /// server starts to process connections.
     pub async fn run(self) -> Result<(), crate::Error> {
         let Self {
-            mut config,
+            config,
             state,
             listener,
             notifier,
-            shutdown,
+            mut shutdown,
             address,
+            quic,
             connections,
         } = self;
 
-        let log_name = if let Some(ref id) = config.name {
-            format!("{address} ({id})")
-        } else {
-            address.to_string()
-        };
-
-        config.log_name = log_name.clone();
+        let mut updates = config.subscribe();
+        let mut log_name = updates.borrow().log_name.clone();
 
         state.send_replace(State::Listening);
-        println!("{log_name} => Listening for requests");
 
-        // Leak the configuration to get a 'static lifetime, which we need to
-        // spawn tokio tasks. Later when all tasks have finished, we'll drop this
-        // value to avoid actual memory leaks.
-        let config = Box::leak(Box::new(config));
+        match &listener {
+            net::Listener::Udp(_) => println!("{log_name} => Listening for datagrams"),
+            _ => println!("{log_name} => Listening for requests"),
+        }
 
-        let listener = Listener {
-            config,
-            connections,
-            listener,
-            notifier: &notifier,
-            state: &state,
+        if quic.is_some() {
+            println!("{log_name} => Listening for HTTP/3 requests");
+        }
+
+        let quic_listener = quic.as_ref().map(|endpoint| {
+            QuicListener::new(endpoint, config.subscribe(), &notifier, connections.clone())
+        });
+
+        // UDP sockets don't accept connections, they receive datagrams.
+        let (listener, udp_listener) = match listener {
+            net::Listener::Udp(socket) => {
+                let udp_listener =
+                    UdpListener::new(socket, config.subscribe(), &notifier, connections.clone());
+                (None, Some(udp_listener))
+            }
+            listener => {
+                let listener = Listener {
+                    config: config.subscribe(),
+                    connections: connections.clone(),
+                    listener,
+                    notifier: &notifier,
+                    state: &state,
+                    address,
+                };
+                (Some(listener), None)
+            }
         };
 
-        tokio::select! {
-            result = listener.listen() => {
-                if let Err(err) = result {
-                    println!("{log_name} => Error while accepting connections: {err}");
+        // Rotated certificates are picked up and ACME certificates are renewed
+        // while we accept connections. These tasks never complete and they
+        // survive reloads unless the certificate changes, otherwise every
+        // reload would cancel ACME orders in progress.
+        let certificate_watcher = watch_certificate(updates.borrow().clone());
+        let certificate_provisioning = provision_certificate(updates.borrow().clone());
+        tokio::pin!(certificate_watcher, certificate_provisioning);
+
+        loop {
+            let current = updates.borrow_and_update().clone();
+
+            let accept = async {
+                match &listener {
+                    Some(listener) => listener.listen().await,
+                    None => std::future::pending().await,
                 }
+            };
+
+            let udp_receive = async {
+                match &udp_listener {
+                    Some(udp_listener) => udp_listener.listen().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let quic_accept = async {
+                match &quic_listener {
+                    Some(quic_listener) => quic_listener.listen().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                result = accept => {
+                    if let Err(err) = result {
+                        println!("{log_name} => Error while accepting connections: {err}");
+                    }
+                }
+                result = udp_receive => {
+                    if let Err(err) = result {
+                        println!("{log_name} => Error while receiving datagrams: {err}");
+                    }
+                }
+                result = quic_accept => {
+                    if let Err(err) = result {
+                        println!("{log_name} => Error while accepting QUIC connections: {err}");
+                    }
+                }
+                _ = &mut shutdown => {
+                    println!("{log_name} => Received shutdown signal");
+                }
+                Ok(()) = updates.changed() => {
+                    let new = updates.borrow().clone();
+                    reconfigure(&current, &new, &connections, quic.as_ref());
+
+                    if !same_certificate(&current, &new) {
+                        certificate_watcher.set(watch_certificate(new.clone()));
+                        certificate_provisioning.set(provision_certificate(new.clone()));
+                    }
+
+                    log_name = new.log_name.clone();
+                    println!("{log_name} => Configuration reloaded");
+                    continue;
+                }
+                _ = &mut certificate_watcher => {}
+                _ = &mut certificate_provisioning => {}
             }
-            _ = shutdown => {
-                println!("{log_name} => Received shutdown signal");
-            }
+
+            break;
         }
 
         // Drop the listener to stop accepting new connections. This will cause
         // a "Connection Refused" error on any new client socket that attempts
         // to connect. Already connected sockets will still be able to send and
-        // receive data.
+        // receive data. UDP sessions keep their own reference to the socket,
+        // so they can still send replies to their clients.
         drop(listener);
+        drop(udp_listener);
+
+        if let Some(quic_listener) = &quic_listener {
+            quic_listener.close();
+        }
 
         if let Ok(num_tasks) = notifier.send(Notification::Shutdown) {
             println!("{log_name} => Can't shutdown yet, {num_tasks} pending connections");
             state.send_replace(State::ShuttingDown(ShutdownState::PendingConnections(
                 num_tasks,
             )));
-            notifier.collect_acknowledgements().await;
-        }
 
-        // SAFETY: Nobody is reading this configuration anymore because all
-        // tasks have ended at this point, so there are no more references to
-        // this address. It's an ugly hack, but we don't have to use Arc if we
-        // do this, we can simply skip the reference counting and avoid atomic
-        // operations.
-        unsafe {
-            drop(Box::from_raw(ptr::from_ref(config).cast_mut()));
+            // Keep the state updated while connections are closing.
+            let mut pending = num_tasks;
+            notifier
+                .collect_acknowledgements(|| {
+                    pending = pending.saturating_sub(1);
+                    state.send_replace(State::ShuttingDown(ShutdownState::PendingConnections(
+                        pending,
+                    )));
+                })
+                .await;
         }
 
         state.send_replace(State::ShuttingDown(ShutdownState::Done));
@@ -253,16 +455,120 @@ impl Server {
     }
 }
 
+/// Swaps the configuration of a running [`Server`] without closing its
+/// listening socket. Connections that are already established keep their
+/// protocol settings, but their next requests are routed using the new
+/// configuration.
+#[derive(Clone)]
+pub(crate) struct Reloader {
+    /// Address of the listening socket, needed to build the log name.
+    address: Address,
+
+    /// Sender half of the configuration channel owned by the [`Server`].
+    config: watch::Sender<Arc<config::Server>>,
+}
+
+impl Reloader {
+    /// Configuration that the server is using right now.
+    pub fn current(&self) -> Arc<config::Server> {
+        self.config.borrow().clone()
+    }
+
+    /// Replaces the configuration of the server with `config`.
+    pub fn reload(&self, mut config: config::Server) {
+        config.log_name = log_name(&self.address, &config);
+        self.config.send_replace(Arc::new(config));
+    }
+}
+
+/// Picks up rotated certificates of the `config` while we accept connections.
+/// Never completes, see [`tls::server::Acceptor::watch_certificate`].
+async fn watch_certificate(config: Arc<config::Server>) {
+    match &config.tls {
+        Some(tls) if !tls.reload_interval.is_zero() => {
+            tls.acceptor
+                .watch_certificate(tls.reload_interval, &config.log_name)
+                .await
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+/// Obtains certificates with ACME if the `config` says so. Never completes,
+/// see [`tls::server::Acceptor::provision_certificate`].
+async fn provision_certificate(config: Arc<config::Server>) {
+    match &config.tls {
+        Some(tls) => tls.acceptor.provision_certificate(&config.log_name).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Returns `true` if the certificate tasks of the `old` configuration also
+/// work for the `new` one, so they don't have to start over. See
+/// [`config::ServerTls::keep_acme`].
+fn same_certificate(old: &config::Server, new: &config::Server) -> bool {
+    match (&old.tls, &new.tls) {
+        (Some(old), Some(new)) => {
+            old.reload_interval == new.reload_interval
+                && old.acceptor.shares_certificate(&new.acceptor)
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Name used to identify the server in the logs.
+fn log_name(address: &Address, config: &config::Server) -> String {
+    match &config.name {
+        Some(id) => format!("{address} ({id})"),
+        None => address.to_string(),
+    }
+}
+
+/// Applies the settings that are not read on every connection when the
+/// configuration of a running server changes from `old` to `new`. The QUIC
+/// endpoint is only updated here, enabling or disabling HTTP/3 requires a new
+/// [`Server`], see [`super::master::Master`].
+fn reconfigure(
+    old: &config::Server,
+    new: &config::Server,
+    connections: &Arc<Semaphore>,
+    quic: Option<&quinn::Endpoint>,
+) {
+    match new.max_connections.cmp(&old.max_connections) {
+        Ordering::Greater => connections.add_permits(new.max_connections - old.max_connections),
+        Ordering::Less => {
+            // Permits that are in use can't be taken away, so we wait until
+            // connections release them.
+            let excess = (old.max_connections - new.max_connections) as u32;
+            let connections = connections.clone();
+            tokio::task::spawn(async move {
+                if let Ok(permits) = connections.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+        Ordering::Equal => {}
+    }
+
+    if let (Some(endpoint), Some(http3), Some(tls)) = (quic, &new.http3, &new.tls) {
+        match quic::server_config(http3, tls) {
+            Ok(server_config) => endpoint.set_server_config(Some(server_config)),
+            Err(err) => println!("{} => Failed to update HTTP/3: {err}", new.log_name),
+        }
+    }
+}
+
 /// Listens for incoming connections and spawns tasks to handle them if permits
 /// are available.
 struct Listener<'a> {
-    /// Underlying TCP listener. We take ownership of this so that when this
-    /// struct is dropped the socket is also dropped and we stop accepting
-    /// connections.
-    listener: TcpListener,
+    /// Underlying TCP or Unix listener. We take ownership of this so that when
+    /// this struct is dropped the socket is also dropped and we stop
+    /// accepting connections.
+    listener: net::Listener,
 
-    /// Reference to the configuration of this server.
-    config: &'static config::Server,
+    /// Configuration of this server, which might change while listening.
+    config: watch::Receiver<Arc<config::Server>>,
 
     /// Needed to obtain subscriptions and pass them down to request handler
     /// tasks.
@@ -273,14 +579,17 @@ struct Listener<'a> {
 
     /// Connections permits.
     connections: Arc<Semaphore>,
+
+    /// Address of the listening socket, used for the `Alt-Svc` header.
+    address: Address,
 }
 
 impl<'a> Listener<'a> {
     pub async fn listen(&self) -> Result<(), crate::Error> {
         loop {
-            // Move out of config to get a static lifetime that we can pass down
-            // to the new Tokio task.
-            let config = self.config;
+            // Each connection gets the configuration that is current when it's
+            // accepted, but requests always use the latest one.
+            let config = self.config.borrow().clone();
 
             let mut notify_listening_again = false;
 
@@ -305,18 +614,124 @@ impl<'a> Listener<'a> {
             }
 
             let (stream, client_addr) = self.listener.accept().await?;
+
+            if let Err(err) = stream.set_options(&config.socket.accepted) {
+                println!(
+                    "{client_addr} -> {} Failed to set socket options: {err}",
+                    config.log_name
+                );
+            }
+
             let mut subscription = self.notifier.subscribe();
-            let server_addr = stream.local_addr()?;
+            let server_addr = stream.local_address()?;
+            let updates = self.config.clone();
+
+            // Clients that connected through TCP are told that they can switch
+            // to HTTP/3 on the same port.
+            let alt_svc =
+                config
+                    .http3
+                    .as_ref()
+                    .zip(self.address.as_tcp())
+                    .map(|(http3, address)| {
+                        let value = format!("h3=\":{}\"; ma={}", address.port(), http3.max_age);
+                        HeaderValue::from_str(&value).unwrap()
+                    });
 
             tokio::task::spawn(async move {
-                if let Err(err) = hyper::server::conn::http1::Builder::new()
-                    .preserve_header_case(true)
-                    .title_case_headers(true)
-                    .serve_connection(stream, Rxh::new(config, client_addr, server_addr))
-                    .with_upgrades()
-                    .await
-                {
-                    println!("Failed to serve connection: {:?}", err);
+                let mut stream = stream;
+
+                // Behind a load balancer the peer is the balancer, the real
+                // client comes in the PROXY protocol header. Like TLS
+                // handshakes, this doesn't wait for shutdowns.
+                let client_addr = match &config.proxy_protocol {
+                    None => Some(client_addr),
+                    Some(proxy_protocol) => {
+                        let header =
+                            read_proxy_protocol(&mut stream, client_addr, proxy_protocol, &config);
+
+                        tokio::select! {
+                            address = header => address,
+                            _ = subscription.wait_for_notification() => None,
+                        }
+                    }
+                };
+
+                match (client_addr, &config.tls) {
+                    (None, _) => {}
+
+                    (Some(client_addr), None) => match config.protocol {
+                        config::Protocol::Http => {
+                            let service = Rxh::new(updates, client_addr, server_addr, None);
+                            serve(stream, service, &config, alt_svc, &mut subscription).await;
+                        }
+                        config::Protocol::Tcp => {
+                            let (client, server) = (&client_addr, &server_addr);
+                            tcp::forward(stream, client, server, &config, &mut subscription).await;
+                        }
+                        config::Protocol::TlsPassthrough => {
+                            let (client, server) = (&client_addr, &server_addr);
+                            tcp::passthrough(stream, client, server, &config, &mut subscription)
+                                .await;
+                        }
+                        // Listeners are replaced by UDP sockets when the
+                        // protocol changes, see super::master::Master.
+                        config::Protocol::Udp => {}
+                    },
+
+                    (Some(client_addr), Some(tls)) => {
+                        // Clients that are still in the middle of the handshake
+                        // don't have requests in flight, so they are dropped
+                        // as soon as the server shuts down.
+                        let handshake = tokio::select! {
+                            result = tls.acceptor.accept(stream) => Some(result),
+                            _ = subscription.wait_for_notification() => None,
+                        };
+
+                        match handshake {
+                            None => {}
+
+                            Some(Ok(stream)) if tls::server::is_acme_validation(&stream) => {
+                                println!(
+                                    "{client_addr} -> {} ACME tls-alpn-01 validation",
+                                    config.log_name
+                                )
+                            }
+
+                            // There's no way of sending 403 responses on raw
+                            // streams, clients without the required certificate
+                            // are disconnected instead.
+                            Some(Ok(stream))
+                                if tls.client_auth.is_some()
+                                    && config.patterns[0].client_auth
+                                    && ClientIdentity::from_stream(&stream).is_none()
+                                    && config.protocol == config::Protocol::Tcp =>
+                            {
+                                println!(
+                                    "{client_addr} -> {} Client certificate required",
+                                    config.log_name
+                                )
+                            }
+
+                            Some(Ok(stream)) if config.protocol == config::Protocol::Tcp => {
+                                let (client, server) = (&client_addr, &server_addr);
+                                tcp::forward(stream, client, server, &config, &mut subscription)
+                                    .await;
+                            }
+
+                            Some(Ok(stream)) => {
+                                let identity = ClientIdentity::from_stream(&stream).map(Arc::new);
+                                let service =
+                                    Rxh::new(updates, client_addr.clone(), server_addr, identity);
+                                serve(stream, service, &config, alt_svc, &mut subscription).await;
+                            }
+
+                            Some(Err(err)) => println!(
+                                "{client_addr} -> {} TLS handshake failed: {err}",
+                                config.log_name
+                            ),
+                        }
+                    }
                 }
 
                 if let Some(Notification::Shutdown) = subscription.receive_notification() {
@@ -330,3 +745,121 @@ impl<'a> Listener<'a> {
         }
     }
 }
+
+/// Reads the PROXY protocol header sent by the load balancer in front of this
+/// server and returns the address of the original client. Connections from
+/// untrusted peers, without a valid header or that take longer than
+/// [`PROXY_PROTOCOL_TIMEOUT`] to send it are rejected by returning [`None`].
+/// If the header doesn't carry the client address, such as in health checks,
+/// the address of the `peer` is used.
+async fn read_proxy_protocol(
+    stream: &mut net::Stream,
+    peer: Address,
+    proxy_protocol: &config::ProxyProtocol,
+    config: &config::Server,
+) -> Option<Address> {
+    let log_name = &config.log_name;
+
+    if !proxy_protocol.trusts(&peer) {
+        println!("{peer} -> {log_name} Not a trusted PROXY protocol source");
+        return None;
+    }
+
+    match tokio::time::timeout(PROXY_PROTOCOL_TIMEOUT, proxy_protocol::read(stream)).await {
+        Ok(Ok(Some(header))) => Some(header.source.into()),
+        Ok(Ok(None)) => Some(peer),
+        Ok(Err(err)) => {
+            println!("{peer} -> {log_name} Invalid PROXY protocol header: {err}");
+            None
+        }
+        Err(_) => {
+            println!("{peer} -> {log_name} Timed out waiting for PROXY protocol header");
+            None
+        }
+    }
+}
+
+/// Serves HTTP requests on an accepted connection using the [`Rxh`] service.
+/// The stream can be a plain TCP stream or a TLS stream. HTTP/1.1 and HTTP/2
+/// are both supported, the protocol is detected by reading the connection
+/// preface (which also works after ALPN negotiated `h2`). If HTTP/3 is
+/// enabled, responses include the `alt_svc` header.
+///
+/// When the server shuts down the connection is closed gracefully: idle
+/// connections are closed immediately, HTTP/2 clients get a `GOAWAY` frame
+/// and requests in flight can still complete. Connections that are not done
+/// after [`config::Server::shutdown_timeout`] are closed anyway.
+async fn serve<I>(
+    stream: I,
+    service: Rxh,
+    config: &config::Server,
+    alt_svc: Option<HeaderValue>,
+    subscription: &mut Subscription,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let http2 = &config.http2;
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+
+    builder
+        .http1()
+        .preserve_header_case(true)
+        .title_case_headers(true);
+
+    let mut h2 = builder.http2();
+
+    h2.timer(TokioTimer::new())
+        .max_concurrent_streams(http2.max_concurrent_streams)
+        .initial_stream_window_size(http2.initial_stream_window_size)
+        .initial_connection_window_size(http2.initial_connection_window_size)
+        .adaptive_window(http2.adaptive_window)
+        .keep_alive_timeout(http2.keepalive_timeout);
+
+    if !http2.keepalive_interval.is_zero() {
+        h2.keep_alive_interval(http2.keepalive_interval);
+    }
+
+    let service = service_fn(move |request: Request<Incoming>| {
+        let response = service.call(request);
+        let alt_svc = alt_svc.clone();
+
+        async move {
+            let mut response = response.await?;
+            if let Some(alt_svc) = alt_svc {
+                response.headers_mut().insert(header::ALT_SVC, alt_svc);
+            }
+            Ok::<_, hyper::Error>(response)
+        }
+    });
+
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+
+    let finished = tokio::select! {
+        result = connection.as_mut() => Some(result),
+        _ = subscription.wait_for_notification() => None,
+    };
+
+    let result = match finished {
+        Some(result) => result,
+        None => {
+            connection.as_mut().graceful_shutdown();
+
+            match tokio::time::timeout(config.shutdown_timeout, connection).await {
+                Ok(result) => result,
+                Err(_) => {
+                    println!(
+                        "{} => Closing connection after shutdown timeout",
+                        config.log_name
+                    );
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Err(err) = result {
+        println!("Failed to serve connection: {:?}", err);
+    }
+}
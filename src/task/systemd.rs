@@ -0,0 +1,142 @@
+//! Systemd socket activation. When RXH is started by a `.socket` unit, the
+//! listening sockets are already open and they are passed to the process
+//! starting at file descriptor 3. This allows binding privileged ports
+//! without running as root and starting RXH only when the first connection
+//! arrives. The protocol is described in `sd_listen_fds(3)`:
+//!
+//! - `LISTEN_PID`: PID of the process that should take the sockets. Child
+//!   processes inherit the environment, so other PIDs must ignore it.
+//!
+//! - `LISTEN_FDS`: number of sockets passed.
+//!
+//! - `LISTEN_FDNAMES`: colon separated names of the sockets, as configured with
+//!   `FileDescriptorName=` in the socket unit.
+
+use std::{
+    env,
+    io,
+    os::fd::{AsFd, FromRawFd, OwnedFd, RawFd},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{getsockopt, sockopt, SockType},
+};
+
+use crate::net::{self, Address};
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors can only be owned once, so they are only taken by the
+/// first call to [`listeners`].
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the listening sockets passed by systemd along with their names and
+/// addresses, or nothing if the process was not socket activated.
+pub(crate) fn listeners() -> Result<Vec<(Option<String>, Address, OwnedFd)>, io::Error> {
+    let fds = parse(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        process::id(),
+    )?;
+
+    if fds.is_empty() || CLAIMED.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    let mut listeners = Vec::new();
+
+    for (fd, name) in fds {
+        // SAFETY: Systemd passes these descriptors to this process and
+        // nothing else in the process uses them, see CLAIMED above.
+        let listener = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Don't leak the sockets to processes that we execute, binary
+        // upgrades pass them explicitly.
+        fcntl(&listener, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+        // FIFOs, Unix datagram sockets and others can be passed as well, but
+        // only TCP and Unix stream listeners and UDP sockets can be adopted
+        // by servers.
+        let address = match getsockopt(&listener, sockopt::SockType) {
+            Ok(SockType::Stream) => net::local_address(listener.as_fd()).ok(),
+            Ok(SockType::Datagram) => net::local_address(listener.as_fd())
+                .ok()
+                .filter(|address| matches!(address, Address::Tcp(_))),
+            _ => None,
+        };
+
+        let Some(address) = address else {
+            println!("Master => Ignoring socket {fd} passed by systemd, it's not a TCP, UDP or Unix stream socket");
+            continue;
+        };
+
+        listeners.push((name, address, listener));
+    }
+
+    Ok(listeners)
+}
+
+/// Parses the environment variables and returns the file descriptors with
+/// their names.
+fn parse(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, Option<String>)>, io::Error> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(Vec::new());
+    };
+
+    if listen_pid.parse() != Ok(pid) {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = listen_fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid LISTEN_FDS value '{listen_fds}'"),
+        )
+    })?;
+
+    let names: Vec<&str> = listen_fdnames.map_or(Vec::new(), |names| names.split(':').collect());
+
+    Ok((0..count)
+        .map(|index| {
+            let name = names
+                .get(index as usize)
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string());
+
+            (LISTEN_FDS_START + index, name)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_fds() {
+        let fds = parse(Some("42"), Some("3"), Some("http:https"), 42).unwrap();
+
+        assert_eq!(fds, vec![
+            (3, Some(String::from("http"))),
+            (4, Some(String::from("https"))),
+            (5, None),
+        ]);
+    }
+
+    #[test]
+    fn ignore_listen_fds_for_other_processes() {
+        assert!(parse(Some("42"), Some("2"), None, 43).unwrap().is_empty());
+        assert!(parse(None, Some("2"), None, 43).unwrap().is_empty());
+        assert!(parse(Some("42"), Some("many"), None, 42).is_err());
+    }
+}
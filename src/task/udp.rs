@@ -0,0 +1,287 @@
+//! UDP relay. Servers with `protocol = "udp"` don't accept connections, they
+//! receive datagrams from any client and relay them to a backend chosen by the
+//! scheduler of the server. Each client address gets a session with its own
+//! socket connected to the backend, so replies find their way back to the
+//! client that sent the request. Sessions expire after a period without
+//! traffic, see [`config::Udp`].
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch,
+        Semaphore,
+    },
+    time::Instant,
+};
+
+use crate::{
+    config::{self, Action},
+    sync::notify::{Notification, Notifier, Subscription},
+};
+
+/// Largest datagram that can be received.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Datagrams that a session can have queued before new ones are dropped, as
+/// it would happen with a full socket buffer.
+const SESSION_QUEUE_SIZE: usize = 64;
+
+/// Sessions by client address. Each session is a task that receives the
+/// datagrams of its client through the channel.
+type Sessions = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
+
+/// Receives datagrams and spawns a session task for each new client, same as
+/// [`super::server::Server`] does for TCP connections.
+pub(super) struct UdpListener<'a> {
+    /// Socket bound to the listening address. Sessions send replies through
+    /// it, so it's only closed when all of them are done.
+    socket: Arc<UdpSocket>,
+
+    /// Configuration of this server, which might change while listening.
+    config: watch::Receiver<Arc<config::Server>>,
+
+    /// Session tasks are notified when the server shuts down.
+    notifier: &'a Notifier,
+
+    /// Each session holds a permit while it's alive.
+    connections: Arc<Semaphore>,
+
+    /// Sessions that are still alive.
+    sessions: Sessions,
+}
+
+impl<'a> UdpListener<'a> {
+    /// Creates a new [`UdpListener`].
+    pub fn new(
+        socket: UdpSocket,
+        config: watch::Receiver<Arc<config::Server>>,
+        notifier: &'a Notifier,
+        connections: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            config,
+            notifier,
+            connections,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// Receives datagrams and passes them to the session of their client,
+    /// starting a new session if there isn't one. Unlike TCP listeners, this
+    /// can't wait for permits, since that would block the datagrams of the
+    /// sessions that are already running, so datagrams of new clients are
+    /// dropped when the limit is reached.
+    pub async fn listen(&self) -> Result<(), crate::Error> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, client_addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Some platforms report ICMP errors of previous datagrams here.
+                Err(err) if is_icmp_error(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            let mut datagram = Bytes::copy_from_slice(&buf[..len]);
+
+            let mut sessions = self.sessions.lock().unwrap();
+
+            if let Some(session) = sessions.get(&client_addr) {
+                match session.try_send(datagram) {
+                    Ok(()) | Err(TrySendError::Full(_)) => continue,
+                    // The session has just expired, a new one takes its place.
+                    Err(TrySendError::Closed(closed)) => datagram = closed,
+                }
+            }
+
+            let config = self.config.borrow().clone();
+
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                println!(
+                    "{client_addr} -> {} Reached max connections: {}, datagram dropped",
+                    config.log_name, config.max_connections
+                );
+                continue;
+            };
+
+            let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+            sessions.insert(client_addr, sender);
+            drop(sessions);
+
+            let session = Session {
+                client_addr,
+                socket: self.socket.clone(),
+                config,
+            };
+
+            let sessions = self.sessions.clone();
+            let mut subscription = self.notifier.subscribe();
+
+            tokio::task::spawn(async move {
+                session.relay(datagram, receiver, &mut subscription).await;
+                remove_session(&sessions, client_addr);
+
+                if let Some(Notification::Shutdown) = subscription.receive_notification() {
+                    subscription.acknowledge_notification().await;
+                }
+
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Datagrams exchanged between a client and the backend that was chosen for
+/// it.
+struct Session {
+    /// Address of the client.
+    client_addr: SocketAddr,
+
+    /// Listening socket, used to send replies to the client.
+    socket: Arc<UdpSocket>,
+
+    /// Configuration of the server when the session started.
+    config: Arc<config::Server>,
+}
+
+impl Session {
+    /// Sends `first` and everything received through `datagrams` to the
+    /// backend, and the replies of the backend to the client, until the
+    /// session has been idle for [`config::Udp::session_timeout`]. When the
+    /// server shuts down, no more datagrams are received from the client but
+    /// replies are still relayed until the session expires or
+    /// [`config::Server::shutdown_timeout`] elapses.
+    async fn relay(
+        self,
+        first: Bytes,
+        mut datagrams: mpsc::Receiver<Bytes>,
+        subscription: &mut Subscription,
+    ) {
+        let Self {
+            client_addr,
+            ref config,
+            ..
+        } = self;
+
+        let log_name = &config.log_name;
+
+        // Validated when the config is deserialized.
+        let Some(Action::Forward(forward)) = config.patterns.first().map(|pattern| &pattern.action)
+        else {
+            return;
+        };
+
+        let backend = forward.scheduler.next_server();
+
+        let Some(backend_addr) = backend.address.as_tcp() else {
+            return;
+        };
+
+        let instant = Instant::now();
+
+        let upstream = match connect(backend_addr).await {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                println!("{client_addr} -> {log_name} Failed to connect to {backend_addr}: {err}");
+                return;
+            }
+        };
+
+        let timeout = config.udp.session_timeout;
+        let mut deadline = Instant::now() + timeout;
+        let mut shutdown_deadline = None;
+
+        let (mut sent, mut received) = (0, 0);
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        let mut result = upstream.send(&first).await.map(|_| sent += 1);
+
+        while result.is_ok() {
+            tokio::select! {
+                Some(datagram) = datagrams.recv(), if shutdown_deadline.is_none() => {
+                    result = upstream.send(&datagram).await.map(|_| sent += 1);
+                }
+
+                reply = upstream.recv(&mut buf) => {
+                    result = match reply {
+                        Ok(len) => self.socket.send_to(&buf[..len], client_addr).await.map(|_| received += 1),
+                        Err(err) => Err(err),
+                    };
+                }
+
+                _ = tokio::time::sleep_until(deadline) => break,
+
+                _ = subscription.wait_for_notification(), if shutdown_deadline.is_none() => {
+                    shutdown_deadline = Some(Instant::now() + config.shutdown_timeout);
+                }
+            }
+
+            deadline = Instant::now() + timeout;
+
+            if let Some(shutdown_deadline) = shutdown_deadline {
+                deadline = deadline.min(shutdown_deadline);
+            }
+        }
+
+        drop(datagrams);
+
+        let elapsed = instant.elapsed();
+
+        match result {
+            Ok(()) => println!(
+                "{client_addr} -> {log_name} UDP {backend_addr} {sent} datagrams sent, {received} datagrams received {elapsed:?}"
+            ),
+            Err(err) => println!(
+                "{client_addr} -> {log_name} UDP {backend_addr} failed: {err} {elapsed:?}"
+            ),
+        }
+    }
+}
+
+/// Removes the session of `client_addr` once it's done, whether it expired or
+/// couldn't even start. The channel is closed by then, since the session owns
+/// the receiver.
+fn remove_session(sessions: &Sessions, client_addr: SocketAddr) {
+    let mut sessions = sessions.lock().unwrap();
+
+    // A new session might have replaced this one already.
+    if sessions
+        .get(&client_addr)
+        .is_some_and(mpsc::Sender::is_closed)
+    {
+        sessions.remove(&client_addr);
+    }
+}
+
+/// Binds a UDP socket on an ephemeral port and connects it to `backend`, so
+/// that only datagrams sent by the backend are received.
+async fn connect(backend: SocketAddr) -> Result<UdpSocket, io::Error> {
+    let local: SocketAddr = match backend {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(backend).await?;
+
+    Ok(socket)
+}
+
+/// Errors caused by ICMP messages received for datagrams that were sent
+/// earlier, which don't affect the socket itself.
+fn is_icmp_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused
+    )
+}
@@ -0,0 +1,396 @@
+//! Zero-downtime binary upgrades. The running process executes a new binary
+//! and passes its listening sockets to it through a Unix socket pair using
+//! `SCM_RIGHTS` ancillary messages. The new process inherits its end of the
+//! pair, so no other process can get hold of the listeners. Once the new
+//! process has adopted the sockets and is ready to accept connections, the
+//! old one shuts down gracefully. Connections that arrive in the meantime
+//! wait in the accept queue of the shared socket, so clients never get
+//! "Connection Refused".
+//!
+//! ```text
+//!   Old process                                  New process
+//!   -----------                                  -----------
+//!   SIGUSR2
+//!   Create socket pair
+//!   Spawn new binary  ------- RXH_UPGRADE_FD ------> Inherit socket
+//!   Send listeners    ----- addresses + FDs -------> Adopt listeners
+//!   Shutdown          <---------- ready ------------ Start accepting
+//! ```
+
+use std::{
+    env,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{
+        self,
+        getsockopt,
+        sockopt,
+        ControlMessage,
+        ControlMessageOwned,
+        MsgFlags,
+        SockType,
+    },
+};
+use tokio::process::Command;
+
+use crate::net::{self, Address};
+
+/// Environment variable that tells the new process which of its file
+/// descriptors is the socket it receives the listeners from.
+const SOCKET_ENV: &str = "RXH_UPGRADE_FD";
+
+/// Socket connected to the previous process along with the listeners that it
+/// handed off, see [`inherit`].
+pub(crate) type Handoff = (UnixStream, Vec<(Address, OwnedFd)>);
+
+/// The inherited socket can only be owned once, so it's only taken by the
+/// first call to [`inherit`].
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Maximum number of listeners that can be passed in a single handoff.
+const MAX_LISTENERS: usize = 64;
+
+/// Maximum size of the addresses sent along with the listeners. Unix socket
+/// paths can't be longer than `PATH_MAX`.
+const MAX_PAYLOAD: usize = MAX_LISTENERS * 4096;
+
+/// Maximum time to wait for the new process to become ready.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Byte sent by the new process once it's accepting connections.
+const READY: u8 = b'1';
+
+/// Executes the current binary again with the same arguments and sends it the
+/// given `listeners`. Returns the PID of the new process once it's ready, at
+/// which point the caller should start shutting down. If anything fails the
+/// new process is killed and the caller keeps running.
+pub(crate) async fn hand_off(listeners: Vec<(Address, OwnedFd)>) -> Result<u32, io::Error> {
+    // Both ends are created with CLOEXEC, the one that the new process
+    // inherits only drops the flag in the child right before exec().
+    let (stream, inherited) = UnixStream::pair()?;
+    let fd = inherited.as_raw_fd();
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(SOCKET_ENV, fd.to_string());
+
+    // SAFETY: fcntl() is async-signal-safe and the descriptor stays open
+    // until spawn() returns.
+    unsafe {
+        command.pre_exec(move || {
+            fcntl(
+                BorrowedFd::borrow_raw(fd),
+                FcntlArg::F_SETFD(FdFlag::empty()),
+            )?;
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    // Otherwise we would never see EOF if the new process exits early.
+    drop(inherited);
+
+    let result = tokio::task::spawn_blocking(move || {
+        send(&stream, &listeners)?;
+        wait_until_ready(&stream)
+    })
+    .await?;
+
+    match result {
+        Ok(()) => Ok(child.id().unwrap_or_default()),
+        Err(err) => {
+            let _ = child.kill().await;
+            Err(err)
+        }
+    }
+}
+
+/// Takes the socket inherited from the process that is handing off its
+/// listeners and receives them. Returns nothing if this process was not
+/// started by a binary upgrade. The returned stream must be passed to
+/// [`ready`] once the listeners are accepting connections.
+pub(crate) fn inherit() -> Result<Option<Handoff>, io::Error> {
+    let Some(value) = env::var_os(SOCKET_ENV) else {
+        return Ok(None);
+    };
+
+    if CLAIMED.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+
+    let fd: RawFd = value
+        .to_str()
+        .and_then(|value| value.parse().ok())
+        .filter(|fd| *fd >= 0)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {SOCKET_ENV} value {value:?}"),
+            )
+        })?;
+
+    // SAFETY: Only checks that the descriptor is open, it's not used if
+    // fcntl() fails.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+
+    // Don't leak the socket to processes that we execute, including the
+    // next upgrade.
+    fcntl(borrowed, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+    // SAFETY: The previous process passes this descriptor to us and nothing
+    // else in the process uses it, see CLAIMED above.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let is_unix_stream = getsockopt(&fd, sockopt::SockType) == Ok(SockType::Stream)
+        && matches!(net::local_address(fd.as_fd()), Ok(Address::Unix(_)));
+
+    if !is_unix_stream {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{SOCKET_ENV} descriptor {} is not a Unix stream socket",
+                fd.as_raw_fd()
+            ),
+        ));
+    }
+
+    let stream = UnixStream::from(fd);
+    let listeners = receive(&stream)?;
+
+    Ok(Some((stream, listeners)))
+}
+
+/// Tells the old process that it can start shutting down.
+pub(crate) fn ready(mut stream: UnixStream) -> Result<(), io::Error> {
+    stream.write_all(&[READY])
+}
+
+/// Sends the file descriptors of the `listeners` in a single message. The
+/// payload contains the address of each listener, one per line, in the same
+/// order as the descriptors, preceded by its length as a big endian `u32`
+/// because stream sockets don't preserve message boundaries.
+fn send(mut stream: &UnixStream, listeners: &[(Address, OwnedFd)]) -> Result<(), io::Error> {
+    if listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't hand off more than {MAX_LISTENERS} listeners"),
+        ));
+    }
+
+    let payload = listeners
+        .iter()
+        .map(|(address, _)| address.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("listener addresses can't take more than {MAX_PAYLOAD} bytes"),
+        ));
+    }
+
+    let mut message = (payload.len() as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(payload.as_bytes());
+
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+
+    // The descriptors go with the first bytes, the rest of the payload might
+    // need more than one write.
+    let sent = socket::sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&message)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    stream.write_all(&message[sent..])
+}
+
+/// Receives the message sent by [`send`].
+fn receive(mut stream: &UnixStream) -> Result<Vec<(Address, OwnedFd)>, io::Error> {
+    let mut length = [0; 4];
+    let mut control = nix::cmsg_space!([RawFd; MAX_LISTENERS]);
+
+    let mut iov = [IoSliceMut::new(&mut length)];
+
+    let message = socket::recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut control),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    let mut fds = Vec::new();
+
+    for cmsg in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // SAFETY: The kernel has just installed these descriptors in our
+            // process and nobody else owns them.
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+
+    // Descriptors that don't fit are closed by the kernel, so the listeners
+    // we got would not match the addresses.
+    if message.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("received more than {MAX_LISTENERS} file descriptors"),
+        ));
+    }
+
+    let received = message.bytes;
+
+    if received == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "previous process closed the socket without sending listeners",
+        ));
+    }
+
+    stream.read_exact(&mut length[received..])?;
+
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("listener addresses take {length} bytes, more than {MAX_PAYLOAD}"),
+        ));
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+
+    let payload = std::str::from_utf8(&payload)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let addresses = payload
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse::<Address>()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if addresses.len() != fds.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "received {} addresses but {} file descriptors",
+                addresses.len(),
+                fds.len()
+            ),
+        ));
+    }
+
+    Ok(addresses.into_iter().zip(fds).collect())
+}
+
+/// Blocks until the new process sends [`READY`] or the connection is closed.
+fn wait_until_ready(mut stream: &UnixStream) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+
+    let mut byte = [0];
+
+    match stream.read(&mut byte)? {
+        1 if byte[0] == READY => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "new process exited before accepting connections",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, os::fd::OwnedFd};
+
+    use super::*;
+
+    #[test]
+    fn listeners_are_sent_with_their_addresses() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let addresses = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+        let listeners = vec![
+            (Address::from(addresses[0]), OwnedFd::from(first)),
+            (Address::from(addresses[1]), OwnedFd::from(second)),
+        ];
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        send(&sender, &listeners).unwrap();
+        drop(listeners);
+
+        let received: Vec<_> = receive(&receiver)
+            .unwrap()
+            .into_iter()
+            .map(|(address, fd)| (address, TcpListener::from(fd)))
+            .collect();
+
+        assert_eq!(received.len(), 2);
+
+        for ((address, listener), expected) in received.iter().zip(addresses) {
+            assert_eq!(*address, Address::from(expected));
+            assert_eq!(listener.local_addr().unwrap(), expected);
+        }
+
+        // The original descriptors are closed, the received ones still work.
+        let mut client = std::net::TcpStream::connect(addresses[1]).unwrap();
+        let (mut accepted, _) = received[1].1.accept().unwrap();
+        accepted.write_all(b"Hello").unwrap();
+
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hello");
+    }
+
+    #[test]
+    fn long_unix_addresses_are_not_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Close to the limit of Unix socket paths.
+        let name = "a".repeat(100 - dir.path().as_os_str().len());
+
+        let listeners: Vec<_> = (0..MAX_LISTENERS)
+            .map(|i| {
+                let path = dir.path().join(format!("{name}{i:02}"));
+                let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+                (Address::Unix(path), OwnedFd::from(listener))
+            })
+            .collect();
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        send(&sender, &listeners).unwrap();
+
+        let received = receive(&receiver).unwrap();
+
+        assert_eq!(received.len(), MAX_LISTENERS);
+
+        for ((address, _), (expected, _)) in received.iter().zip(&listeners) {
+            assert_eq!(address, expected);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! ACME account key and request signing. Every request sent to the ACME
+//! server is a JSON Web Signature (RFC 7515) signed with the account key,
+//! which is an ECDSA P-256 key stored in the state directory.
+
+use std::{io, path::Path};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::{json, Value};
+
+use super::challenge::rcgen_error;
+
+/// Key pair that identifies our ACME account.
+pub(super) struct Account {
+    /// Signing key.
+    key: EcdsaKeyPair,
+
+    /// Public key in JWK format.
+    jwk: Value,
+
+    /// Base64 encoded SHA-256 digest of the JWK (RFC 7638), used for
+    /// building key authorizations.
+    thumbprint: String,
+
+    /// Randomness source for ECDSA signatures.
+    rng: SystemRandom,
+}
+
+impl Account {
+    /// Loads the account key stored at `path` or generates a new one if the
+    /// file doesn't exist yet.
+    pub async fn load_or_create(path: &Path) -> Result<Self, io::Error> {
+        let pem = match tokio::fs::read_to_string(path).await {
+            Ok(pem) => pem,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let pem = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+                    .map_err(rcgen_error)?
+                    .serialize_pem();
+                super::write_private(path, pem.as_bytes()).await?;
+                pem
+            }
+            Err(err) => return Err(err),
+        };
+
+        let pkcs8 = KeyPair::from_pem(&pem)
+            .map_err(rcgen_error)?
+            .serialize_der();
+        let rng = SystemRandom::new();
+
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|err| super::super::invalid_data(format!("{}: {err}", path.display())))?;
+
+        // Uncompressed point: 0x04 followed by the X and Y coordinates.
+        let point = key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        let (x, y) = (URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y));
+
+        // The thumbprint is computed over the required members only, ordered
+        // lexicographically and without whitespace.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint = base64(ring::digest::digest(
+            &ring::digest::SHA256,
+            canonical.as_bytes(),
+        ));
+
+        Ok(Self {
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            rng,
+        })
+    }
+
+    /// Key authorization for a challenge token, see RFC 8555 section 8.1.
+    pub fn key_authorization(&self, token: &str) -> String {
+        format!("{token}.{}", self.thumbprint)
+    }
+
+    /// Builds the JWS body of a request to `url`. Before the account is
+    /// created the public key is sent in the `jwk` header, afterwards the
+    /// account URL (`kid`) is used instead. A [`None`] payload produces a
+    /// "POST-as-GET" request.
+    pub fn sign(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&Value>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+
+        match kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+
+        let protected = base64(protected.to_string());
+        let payload = match payload {
+            Some(payload) => base64(payload.to_string()),
+            None => String::new(),
+        };
+
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| io::Error::other("failed to sign ACME request"))?;
+
+        let body = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64(signature),
+        });
+
+        Ok(body.to_string().into_bytes())
+    }
+}
+
+/// URL safe Base64 without padding, as required by JWS.
+pub(super) fn base64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
@@ -0,0 +1,122 @@
+//! Responses to ACME challenges. Challenges are published while an order is
+//! being authorized and removed once the ACME server has validated them.
+//!
+//! The stores are global because the `http-01` challenge is answered on port
+//! 80, which is usually a different [`crate::config::Server`] than the one
+//! that needs the certificate.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+
+/// Path prefix of `http-01` challenge requests.
+const HTTP_01_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Protocol negotiated with ALPN by the ACME server when it validates
+/// `tls-alpn-01` challenges.
+pub(crate) const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+/// Key authorizations of `http-01` challenges indexed by token.
+static HTTP_01: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Validation certificates of `tls-alpn-01` challenges indexed by domain.
+static TLS_ALPN_01: Mutex<BTreeMap<String, Arc<CertifiedKey>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the key authorization that must be sent back to the ACME server
+/// if `path` is the path of a published `http-01` challenge.
+pub(crate) fn http_01(path: &str) -> Option<String> {
+    let token = path.strip_prefix(HTTP_01_PATH)?;
+
+    HTTP_01.lock().unwrap().get(token).cloned()
+}
+
+/// Returns the validation certificate of a published `tls-alpn-01` challenge
+/// for the given domain.
+pub(crate) fn tls_alpn_01(domain: &str) -> Option<Arc<CertifiedKey>> {
+    let domain = domain.to_ascii_lowercase();
+
+    TLS_ALPN_01.lock().unwrap().get(&domain).cloned()
+}
+
+/// A challenge response that can be found by the ACME server. The response
+/// is removed when this value is dropped.
+pub(super) enum Published {
+    Http01(String),
+    TlsAlpn01(String),
+}
+
+impl Published {
+    /// Publishes the `key_authorization` of an `http-01` challenge so that
+    /// it can be served at `/.well-known/acme-challenge/{token}`.
+    pub fn http_01(token: &str, key_authorization: String) -> Self {
+        HTTP_01
+            .lock()
+            .unwrap()
+            .insert(token.to_owned(), key_authorization);
+
+        Self::Http01(token.to_owned())
+    }
+
+    /// Publishes a self-signed certificate for `domain` that contains the
+    /// SHA-256 digest of the `key_authorization` in the `acmeIdentifier`
+    /// extension, as described in RFC 8737.
+    pub fn tls_alpn_01(
+        domain: &str,
+        key_authorization: &str,
+        provider: &CryptoProvider,
+    ) -> Result<Self, io::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+
+        let mut params = CertificateParams::new(vec![domain.to_owned()]).map_err(rcgen_error)?;
+        params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+
+        let key = KeyPair::generate().map_err(rcgen_error)?;
+        let cert = params.self_signed(&key).map_err(rcgen_error)?;
+
+        // CertifiedKey::from_der can't be used here because it parses the
+        // certificate with webpki, which rejects the critical extension.
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signing_key = provider
+            .key_provider
+            .load_private_key(key)
+            .map_err(super::super::rustls_error)?;
+
+        let certified_key = CertifiedKey::new(vec![cert.der().clone()], signing_key);
+
+        let domain = domain.to_ascii_lowercase();
+
+        TLS_ALPN_01
+            .lock()
+            .unwrap()
+            .insert(domain.clone(), Arc::new(certified_key));
+
+        Ok(Self::TlsAlpn01(domain))
+    }
+}
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        match self {
+            Self::Http01(token) => {
+                HTTP_01.lock().unwrap().remove(token);
+            }
+            Self::TlsAlpn01(domain) => {
+                TLS_ALPN_01.lock().unwrap().remove(domain);
+            }
+        }
+    }
+}
+
+/// Maps [`rcgen::Error`] to [`io::Error`].
+pub(super) fn rcgen_error(err: rcgen::Error) -> io::Error {
+    io::Error::other(format!("certificate generation: {err}"))
+}
@@ -0,0 +1,157 @@
+//! Minimal HTTP client for talking to ACME servers. ACME only needs a few
+//! requests per certificate, so every request uses a new connection.
+
+use std::io;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{header, HeaderMap, Method, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::tls::client::Connector;
+
+/// Sends requests to the ACME server, see [`Client::send`].
+pub(super) struct Client {
+    /// Used for `https` URLs. Plain `http` is only useful for local testing.
+    connector: Connector,
+}
+
+/// Response received from the ACME server with the body already collected.
+pub(super) struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Response {
+    /// Value of the given header if present and valid UTF-8.
+    pub fn header(&self, name: header::HeaderName) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+
+    /// Deserializes the JSON body.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, io::Error> {
+        serde_json::from_slice(&self.body)
+            .map_err(|err| super::super::invalid_data(format!("ACME response: {err}")))
+    }
+}
+
+impl Client {
+    /// Creates a new client. If `ca` is [`None`] the ACME server certificate
+    /// is verified using the Mozilla root certificates.
+    pub fn new(ca: Option<&std::path::Path>) -> Result<Self, io::Error> {
+        Ok(Self {
+            connector: Connector::new(ca, None, None, None, true)?,
+        })
+    }
+
+    /// `GET` request.
+    pub async fn get(&self, url: &str) -> Result<Response, io::Error> {
+        self.send(Method::GET, url, None).await
+    }
+
+    /// `HEAD` request.
+    pub async fn head(&self, url: &str) -> Result<Response, io::Error> {
+        self.send(Method::HEAD, url, None).await
+    }
+
+    /// `POST` request with a JWS body, see [`super::account::Account::sign`].
+    pub async fn post(&self, url: &str, jws: Vec<u8>) -> Result<Response, io::Error> {
+        self.send(Method::POST, url, Some(jws)).await
+    }
+
+    /// Connects to the host of `url` and sends the request.
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response, io::Error> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|_| super::super::invalid_data(format!("invalid ACME URL '{url}'")))?;
+
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => {
+                return Err(super::super::invalid_data(format!(
+                    "invalid ACME URL '{url}'"
+                )))
+            }
+        };
+
+        let Some(authority) = uri.authority().cloned() else {
+            return Err(super::super::invalid_data(format!(
+                "invalid ACME URL '{url}'"
+            )));
+        };
+
+        // IPv6 hosts are written between brackets in URLs.
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(header::HOST, authority.as_str())
+            .header(
+                header::USER_AGENT,
+                concat!("rxh/", env!("CARGO_PKG_VERSION")),
+            );
+
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/jose+json");
+        }
+
+        let request = request
+            .body(Full::new(Bytes::from(body.unwrap_or_default())))
+            .unwrap();
+
+        let stream = TcpStream::connect((host, port)).await?;
+
+        if https {
+            let stream = self.connector.connect_to_host(stream, host).await?;
+            send(request, stream).await
+        } else {
+            send(request, stream).await
+        }
+    }
+}
+
+/// Sends the request through an already connected stream and collects the
+/// response.
+async fn send<S>(request: Request<Full<Bytes>>, stream: S) -> Result<Response, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+
+    tokio::task::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let (parts, body) = sender
+        .send_request(request)
+        .await
+        .map_err(io::Error::other)?
+        .into_parts();
+
+    let body = body.collect().await.map_err(io::Error::other)?.to_bytes();
+
+    Ok(Response {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    })
+}
@@ -0,0 +1,568 @@
+//! Automatic certificate provisioning with the ACME protocol
+//! ([RFC 8555](https://www.rfc-editor.org/rfc/rfc8555)). See
+//! [`crate::config::Acme`] for the available options. The process for
+//! obtaining a certificate looks like this:
+//!
+//! ```text
+//! +---------+   +-------+   +-----------+   +----------+   +----------+
+//! | Account | → | Order | → | Challenge | → | Finalize | → | Download |
+//! +---------+   +-------+   +-----------+   +----------+   +----------+
+//! ```
+//!
+//! Certificates and keys are written to the state directory and loaded by
+//! [`crate::tls::server::Acceptor`], which also decides when to renew them.
+
+mod account;
+pub(crate) mod challenge;
+mod client;
+
+use std::{
+    fmt,
+    io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::header::{self, HeaderName};
+use rcgen::{CertificateParams, KeyPair};
+use rustls::{crypto::CryptoProvider, pki_types::CertificateDer};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{Mutex, MutexGuard},
+};
+use x509_parser::{
+    extensions::GeneralName,
+    prelude::{FromDer, X509Certificate},
+};
+
+use self::{
+    account::Account,
+    challenge::{rcgen_error, Published},
+    client::{Client, Response},
+};
+use crate::config::{Acme, Challenge};
+
+/// Header that carries the anti-replay nonce of the next request.
+const REPLAY_NONCE: HeaderName = HeaderName::from_static("replay-nonce");
+
+/// Error type returned by the server when a nonce has already been used or
+/// has expired. Requests that fail with this error can be retried.
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// How many times we check the status of an order or authorization before
+/// giving up.
+const MAX_POLL_ATTEMPTS: usize = 30;
+
+/// Minimum time between two certificates obtained successfully, see
+/// [`Manager::min_renewal_delay`].
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Obtains and renews the certificate of a single server.
+pub(crate) struct Manager {
+    /// ACME options.
+    config: Acme,
+
+    /// Needed for building `tls-alpn-01` validation certificates.
+    provider: Arc<CryptoProvider>,
+
+    /// HTTP client for the ACME server.
+    client: Client,
+
+    /// Replicas of the same server share the manager, only the one holding
+    /// this lock talks to the ACME server.
+    lock: Mutex<()>,
+}
+
+impl Manager {
+    /// Creates a new [`Manager`]. Nothing is requested until
+    /// [`Manager::issue`] is called.
+    pub fn new(config: &Acme, provider: Arc<CryptoProvider>) -> Result<Self, io::Error> {
+        Ok(Self {
+            config: config.clone(),
+            provider,
+            client: Client::new(config.ca.as_deref())?,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Acquires the right to manage the certificate, waiting while some other
+    /// replica is doing it. Replicas that stop release it, so one of the
+    /// remaining replicas takes over.
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+
+    /// Domains included in the certificate.
+    pub fn domains(&self) -> &[String] {
+        &self.config.domains
+    }
+
+    /// Returns how long we can wait before renewing the current certificate.
+    /// If there's no certificate, it can't be parsed or it doesn't include
+    /// all the configured domains, it has to be issued right now.
+    pub fn renewal_delay(&self) -> Duration {
+        let Some(current) = self.current_certificate() else {
+            return Duration::ZERO;
+        };
+
+        if !current.covers_all_domains {
+            return Duration::ZERO;
+        }
+
+        let renew_before = self.config.renew_before.saturating_mul(24 * 60 * 60) as i64;
+        let renew_at = current.not_after - renew_before;
+
+        Duration::from_secs(renew_at.saturating_sub(unix_now()).max(0) as u64)
+    }
+
+    /// Returns how long we have to wait after obtaining a certificate before
+    /// asking for another one, no matter what [`Manager::renewal_delay`] says.
+    /// If the CA issues certificates that live less than `renew_before` days
+    /// or that don't match the configured domains, they are due for renewal
+    /// as soon as they are issued, and renewing them in a loop would only
+    /// hit the rate limits of the CA. So we wait for a third of the lifetime
+    /// of the certificate, and never less than [`MIN_RENEWAL_INTERVAL`].
+    pub fn min_renewal_delay(&self) -> Duration {
+        let lifetime = self.current_certificate().map_or(0, |current| {
+            current.not_after.saturating_sub(current.not_before).max(0) as u64
+        });
+
+        Duration::from_secs(lifetime / 3).max(MIN_RENEWAL_INTERVAL)
+    }
+
+    /// Parses the certificate stored in the state directory, if any.
+    fn current_certificate(&self) -> Option<CurrentCertificate> {
+        let certs = super::load_certs(&self.config.cert_path()).ok()?;
+        let (_, cert) = X509Certificate::from_der(certs.first()?).ok()?;
+
+        let names: Vec<String> = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(dns.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let covers_all_domains = self
+            .config
+            .domains
+            .iter()
+            .all(|domain| names.contains(&domain.to_ascii_lowercase()));
+
+        Some(CurrentCertificate {
+            not_before: cert.validity().not_before.timestamp(),
+            not_after: cert.validity().not_after.timestamp(),
+            covers_all_domains,
+        })
+    }
+
+    /// Runs the whole ACME process and stores the new certificate and key in
+    /// the state directory. Returns the end-entity certificate of the chain.
+    pub async fn issue(&self) -> Result<CertificateDer<'static>, io::Error> {
+        fs::create_dir_all(&self.config.state).await?;
+
+        let account = Account::load_or_create(&self.config.account_key_path()).await?;
+        let mut session = Session::start(&self.client, &account, &self.config.directory).await?;
+
+        session.register(&self.config.contact).await?;
+
+        let (order_url, order) = session.new_order(&self.config.domains).await?;
+
+        for url in &order.authorizations {
+            session
+                .authorize(url, self.config.challenge, &self.provider)
+                .await?;
+        }
+
+        let order: Order = session
+            .poll(&order_url, |order: &Order| order.status != Status::Pending)
+            .await?;
+
+        if order.status != Status::Ready {
+            return Err(order.failure("order not ready"));
+        }
+
+        let key = KeyPair::generate().map_err(rcgen_error)?;
+        let csr = CertificateParams::new(self.config.domains.clone())
+            .and_then(|params| params.serialize_request(&key))
+            .map_err(rcgen_error)?;
+
+        let payload = json!({ "csr": account::base64(csr.der()) });
+        session.post(&order.finalize, Some(&payload)).await?;
+
+        let order: Order = session
+            .poll(&order_url, |order: &Order| {
+                !matches!(order.status, Status::Ready | Status::Processing)
+            })
+            .await?;
+
+        let (Status::Valid, Some(certificate)) = (order.status, &order.certificate) else {
+            return Err(order.failure("certificate not issued"));
+        };
+
+        let chain = session.post(certificate, None).await?.body;
+
+        let Some(certificate) = rustls_pemfile::certs(&mut chain.as_ref())
+            .next()
+            .transpose()?
+        else {
+            return Err(super::invalid_data(String::from(
+                "ACME server sent an empty certificate chain",
+            )));
+        };
+
+        fs::create_dir_all(self.config.cert_path().parent().unwrap()).await?;
+        write_private(&self.config.key_path(), key.serialize_pem().as_bytes()).await?;
+        write_private(&self.config.cert_path(), &chain).await?;
+
+        Ok(certificate)
+    }
+}
+
+/// What we need to know about the certificate stored in the state directory
+/// to decide when to renew it.
+struct CurrentCertificate {
+    /// Start of the validity period, seconds since the Unix epoch.
+    not_before: i64,
+
+    /// End of the validity period, seconds since the Unix epoch.
+    not_after: i64,
+
+    /// Whether the certificate includes all the configured domains.
+    covers_all_domains: bool,
+}
+
+/// State of the conversation with the ACME server while processing an order.
+struct Session<'a> {
+    client: &'a Client,
+
+    account: &'a Account,
+
+    /// URLs of the ACME resources.
+    directory: Directory,
+
+    /// Nonce for the next request. Each response contains a new one.
+    nonce: Option<String>,
+
+    /// Account URL, available once we've registered.
+    kid: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    /// Fetches the directory resource.
+    async fn start(
+        client: &'a Client,
+        account: &'a Account,
+        directory: &str,
+    ) -> Result<Session<'a>, io::Error> {
+        let response = client.get(directory).await?;
+
+        if !response.status.is_success() {
+            return Err(io::Error::other(format!(
+                "ACME directory {directory} returned HTTP {}",
+                response.status
+            )));
+        }
+
+        Ok(Self {
+            client,
+            account,
+            directory: response.json()?,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    /// Creates the account or finds the existing one for our key.
+    async fn register(&mut self, contact: &[String]) -> Result<(), io::Error> {
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+
+        let Some(kid) = response.header(header::LOCATION) else {
+            return Err(super::invalid_data(String::from(
+                "ACME account response without Location header",
+            )));
+        };
+
+        self.kid = Some(kid.to_owned());
+
+        Ok(())
+    }
+
+    /// Creates a new order for `domains` and returns its URL.
+    async fn new_order(&mut self, domains: &[String]) -> Result<(String, Order), io::Error> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+
+        let Some(order_url) = response.header(header::LOCATION) else {
+            return Err(super::invalid_data(String::from(
+                "ACME order response without Location header",
+            )));
+        };
+
+        Ok((order_url.to_owned(), response.json()?))
+    }
+
+    /// Proves that we control the domain of the authorization at `url`. The
+    /// challenge response is published until the server is done validating.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        challenge: Challenge,
+        provider: &CryptoProvider,
+    ) -> Result<(), io::Error> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+
+        if authorization.status == Status::Valid {
+            return Ok(());
+        }
+
+        let domain = &authorization.identifier.value;
+
+        let kind = match challenge {
+            Challenge::Http01 => "http-01",
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+        };
+
+        let Some(offered) = authorization.challenges.iter().find(|c| c.kind == kind) else {
+            return Err(io::Error::other(format!(
+                "ACME server doesn't offer {kind} challenges for {domain}"
+            )));
+        };
+
+        let Some(token) = &offered.token else {
+            return Err(super::invalid_data(format!(
+                "ACME {kind} challenge without token"
+            )));
+        };
+
+        let key_authorization = self.account.key_authorization(token);
+
+        let _published = match challenge {
+            Challenge::Http01 => Published::http_01(token, key_authorization),
+            Challenge::TlsAlpn01 => Published::tls_alpn_01(domain, &key_authorization, provider)?,
+        };
+
+        // Empty object tells the server that we're ready for validation.
+        self.post(&offered.url, Some(&json!({}))).await?;
+
+        let authorization: Authorization = self
+            .poll(url, |authorization: &Authorization| {
+                authorization.status != Status::Pending
+            })
+            .await?;
+
+        if authorization.status != Status::Valid {
+            let problem = authorization
+                .challenges
+                .iter()
+                .find_map(|challenge| challenge.error.as_ref())
+                .map_or(String::from("unknown error"), Problem::to_string);
+
+            return Err(io::Error::other(format!(
+                "ACME {kind} validation of {domain} failed: {problem}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the resource at `url` until `done` returns `true`. The server
+    /// can tell us how long to wait between requests with `Retry-After`.
+    async fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> bool,
+    ) -> Result<T, io::Error> {
+        for _ in 0..MAX_POLL_ATTEMPTS {
+            let response = self.post(url, None).await?;
+            let resource = response.json()?;
+
+            if done(&resource) {
+                return Ok(resource);
+            }
+
+            let delay = response
+                .header(header::RETRY_AFTER)
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(1)
+                .clamp(1, 10);
+
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("ACME resource {url} is still not ready"),
+        ))
+    }
+
+    /// Sends a signed `POST` request. A [`None`] payload is a "POST-as-GET"
+    /// request, which is how resources are fetched in ACME.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, io::Error> {
+        let mut retried = false;
+
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let jws = self
+                .account
+                .sign(url, &nonce, self.kid.as_deref(), payload)?;
+
+            let response = self.client.post(url, jws).await?;
+
+            self.nonce = response.header(REPLAY_NONCE).map(String::from);
+
+            if response.status.is_success() {
+                return Ok(response);
+            }
+
+            let problem: Problem = response.json().unwrap_or_default();
+
+            if problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+
+            return Err(io::Error::other(format!(
+                "ACME request to {url} failed with HTTP {}: {problem}",
+                response.status
+            )));
+        }
+    }
+
+    /// Requests a fresh nonce.
+    async fn new_nonce(&self) -> Result<String, io::Error> {
+        let response = self.client.head(&self.directory.new_nonce).await?;
+
+        response
+            .header(REPLAY_NONCE)
+            .map(String::from)
+            .ok_or_else(|| super::invalid_data(String::from("ACME server didn't send a nonce")))
+    }
+}
+
+/// URLs of the ACME resources we need.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// Status of orders, authorizations and challenges.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Expired,
+    Deactivated,
+    Revoked,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: Status,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+impl Order {
+    /// Error describing why the order failed.
+    fn failure(&self, message: &str) -> io::Error {
+        match &self.error {
+            Some(problem) => io::Error::other(format!("ACME {message}: {problem}")),
+            None => io::Error::other(format!("ACME {message}, status {:?}", self.status)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: Status,
+    challenges: Vec<ChallengeObject>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct ChallengeObject {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+    error: Option<Problem>,
+}
+
+/// Error document (RFC 7807) sent by the ACME server.
+#[derive(Deserialize, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    detail: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{detail} ({})", self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Current time in seconds since the Unix epoch.
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64)
+}
+
+/// Writes `contents` to `path` so that only the owner can read it. Readers
+/// never see partially written files because the data is written to a
+/// temporary file first and then renamed.
+async fn write_private(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+
+    fs::rename(tmp, path).await
+}
@@ -0,0 +1,180 @@
+//! TLS connections to upstream servers. See [`crate::config::BackendTls`] for
+//! the available options.
+
+use std::{io, path::Path, sync::Arc};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig,
+    DigitallySignedStruct,
+    RootCertStore,
+    SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::net::Address;
+
+/// Wraps a [`TlsConnector`] with everything we need to know in order to start
+/// a TLS handshake with a backend server.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    /// Connector configured with the certificate authorities and optional
+    /// client certificate.
+    connector: TlsConnector,
+
+    /// Name sent in the SNI extension and used for verifying the backend
+    /// certificate. When [`None`] we use the IP address of the backend.
+    server_name: Option<ServerName<'static>>,
+}
+
+impl Connector {
+    /// Builds a new [`Connector`] reading all the necessary files from disk.
+    /// If `ca` is [`None`] the Mozilla root certificates are used instead.
+    /// Client certificate and key are only used for mutual TLS, so they're
+    /// optional as well but one can't be provided without the other.
+    pub fn new(
+        ca: Option<&Path>,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        sni: Option<&str>,
+        verify: bool,
+    ) -> Result<Self, io::Error> {
+        let provider = super::provider();
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(super::rustls_error)?;
+
+        let builder = if verify {
+            let mut roots = RootCertStore::empty();
+
+            if let Some(ca) = ca {
+                for cert in super::load_certs(ca)? {
+                    roots.add(cert).map_err(super::rustls_error)?;
+                }
+            } else {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+
+            builder.with_root_certificates(roots)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        };
+
+        let config = match (cert, key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(super::load_certs(cert)?, super::load_key(key)?)
+                .map_err(super::rustls_error)?,
+
+            (None, None) => builder.with_no_client_auth(),
+
+            _ => {
+                return Err(super::invalid_data(String::from(
+                    "client certificate and key must be provided together",
+                )))
+            }
+        };
+
+        let server_name = match sni {
+            Some(name) => Some(ServerName::try_from(name.to_owned()).map_err(|_| {
+                super::invalid_data(format!("'{name}' is not a valid server name"))
+            })?),
+            None => None,
+        };
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Performs the TLS handshake on an already connected `stream`. The
+    /// `address` of the backend is used as the server name when no SNI
+    /// override was configured, which is required for Unix sockets.
+    pub async fn connect<S>(&self, stream: S, address: &Address) -> Result<TlsStream<S>, io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = match (&self.server_name, address) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Tcp(address)) => ServerName::IpAddress(address.ip().into()),
+            (None, Address::Unix(_)) => {
+                return Err(super::invalid_data(format!(
+                    "backend {address} requires 'sni' to verify its certificate"
+                )))
+            }
+        };
+
+        self.connector.connect(server_name, stream).await
+    }
+
+    /// Same as [`Connector::connect`] but the certificate is verified against
+    /// `host`, which can be a DNS name or an IP address. The SNI override is
+    /// ignored.
+    pub async fn connect_to_host<S>(&self, stream: S, host: &str) -> Result<TlsStream<S>, io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|_| super::invalid_data(format!("'{host}' is not a valid server name")))?;
+
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+/// Certificate verifier that accepts any certificate the backend sends. Only
+/// signatures are checked, so this is still TLS, but anybody in the middle
+/// could impersonate the backend. Useful for labs and self-signed backends,
+/// never for production.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,68 @@
+//! TLS support built on top of [`rustls`]. This module only knows how to load
+//! certificates and keys from disk and how to turn the TLS options of the
+//! config file into [`rustls`] configurations, the actual encryption is done
+//! by [`tokio_rustls`] streams.
+
+pub(crate) mod acme;
+pub(crate) mod client;
+pub(crate) mod server;
+
+use std::{fs::File, io, io::BufReader, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+};
+
+/// Cryptography provider used for all TLS configurations. We don't rely on
+/// the process wide default provider because embedders could install a
+/// different one.
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Reads all the PEM encoded certificates stored in `path`. Fails if the file
+/// can't be read or doesn't contain any certificate at all.
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, io::Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| with_path(err, path))?);
+
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| with_path(err, path))?;
+
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+/// Reads the first PEM encoded private key (PKCS#1, PKCS#8 or SEC1) found in
+/// `path`.
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, io::Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| with_path(err, path))?);
+
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|err| with_path(err, path))?
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", path.display())))
+}
+
+/// Maps [`rustls::Error`] to [`io::Error`], which is what we use for
+/// reporting configuration problems.
+pub(crate) fn rustls_error(err: rustls::Error) -> io::Error {
+    invalid_data(format!("TLS error: {err}"))
+}
+
+/// Builds an [`io::ErrorKind::InvalidData`] error with the given message.
+pub(crate) fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Adds the file path to IO errors, otherwise "No such file or directory" is
+/// not very helpful when the config references multiple files.
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
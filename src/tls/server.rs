@@ -0,0 +1,485 @@
+//! TLS termination for listeners, including client certificate verification,
+//! certificate hot-reloading and ACME. See [`crate::config::ServerTls`] for
+//! the available options.
+
+use std::{
+    fmt::Debug,
+    fs,
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::CertificateDer,
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore,
+    ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::MissedTickBehavior,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use x509_parser::{
+    extensions::GeneralName,
+    prelude::{FromDer, X509Certificate},
+};
+
+use super::acme::{self, challenge::ACME_TLS_ALPN_PROTOCOL};
+use crate::config::{Acme, ClientAuth, Reject};
+
+/// Wraps a [`TlsAcceptor`] configured with the certificate of the server and
+/// the client verifier if client authentication is enabled.
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    acceptor: TlsAcceptor,
+
+    /// Certificate used for new handshakes, it can be replaced at any time.
+    /// See [`Acceptor::watch_certificate`].
+    resolver: Arc<Resolver>,
+
+    /// Obtains certificates automatically if ACME is enabled.
+    acme: Option<Arc<acme::Manager>>,
+}
+
+impl Acceptor {
+    /// Builds a new [`Acceptor`] reading the certificate chain and private key
+    /// of the server from disk. When `client_auth` is given, clients are asked
+    /// for a certificate signed by the configured CA. Depending on
+    /// [`ClientAuth::reject`] clients without a certificate either get a TLS
+    /// alert or are allowed to complete the handshake.
+    pub fn new(
+        cert: &Path,
+        key: &Path,
+        client_auth: Option<&ClientAuth>,
+    ) -> Result<Self, io::Error> {
+        let provider = super::provider();
+        let resolver = Resolver::new(cert, key, provider.clone())?;
+
+        Self::build(provider, resolver, client_auth, None)
+    }
+
+    /// Builds an [`Acceptor`] whose certificate is managed by ACME. If there's
+    /// no valid certificate in the state directory yet, handshakes fail until
+    /// [`Acceptor::provision_certificate`] obtains one, except for ACME
+    /// `tls-alpn-01` validation handshakes.
+    pub fn with_acme(acme: &Acme, client_auth: Option<&ClientAuth>) -> Result<Self, io::Error> {
+        let provider = super::provider();
+        let resolver = Resolver::pending(&acme.cert_path(), &acme.key_path(), provider.clone());
+        let manager = acme::Manager::new(acme, provider.clone())?;
+
+        Self::build(provider, resolver, client_auth, Some(manager))
+    }
+
+    /// Common configuration for all acceptors.
+    fn build(
+        provider: Arc<CryptoProvider>,
+        resolver: Resolver,
+        client_auth: Option<&ClientAuth>,
+        acme: Option<acme::Manager>,
+    ) -> Result<Self, io::Error> {
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(super::rustls_error)?;
+
+        let builder = match client_auth {
+            Some(client_auth) => {
+                let mut roots = RootCertStore::empty();
+
+                for cert in super::load_certs(&client_auth.ca)? {
+                    roots.add(cert).map_err(super::rustls_error)?;
+                }
+
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(roots.into(), provider.clone());
+
+                let verifier = match client_auth.reject {
+                    Reject::Alert => verifier,
+                    Reject::Forbidden => verifier.allow_unauthenticated(),
+                };
+
+                let verifier = verifier
+                    .build()
+                    .map_err(|err| super::invalid_data(format!("client verifier: {err}")))?;
+
+                builder.with_client_cert_verifier(verifier)
+            }
+
+            None => builder.with_no_client_auth(),
+        };
+
+        let resolver = Arc::new(resolver);
+
+        let mut config = builder.with_cert_resolver(resolver.clone());
+
+        // HTTP/2 is preferred when the client supports it.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        if acme.is_some() {
+            config.alpn_protocols.push(ACME_TLS_ALPN_PROTOCOL.to_vec());
+        }
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            resolver,
+            acme: acme.map(Arc::new),
+        })
+    }
+
+    /// Checks the certificate and key files every `interval` and loads them
+    /// again when they change on disk, so that certificates renewed by tools
+    /// like certbot or cert-manager are used without restarting the server.
+    /// We poll file metadata instead of relying on filesystem events because
+    /// files are commonly replaced by swapping symlinks (Kubernetes secrets)
+    /// or live on network filesystems, where events are not reliable.
+    ///
+    /// The new certificate is only used for new handshakes, established
+    /// connections keep their session. If the files can't be loaded, for
+    /// example because they were only partially written, the old certificate
+    /// is kept and we try again on the next tick. This future never
+    /// completes.
+    pub async fn watch_certificate(&self, interval: Duration, log_name: &str) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            match self.resolver.reload_if_modified() {
+                Ok(true) => println!("{log_name} => Reloaded TLS certificate"),
+                Ok(false) => {}
+                Err(err) => println!("{log_name} => Failed to reload TLS certificate: {err}"),
+            }
+        }
+    }
+
+    /// Obtains a certificate with ACME if the current one is missing or about
+    /// to expire, and keeps renewing it for as long as the server runs. Failed
+    /// attempts are retried with exponential backoff, and successful ones are
+    /// never repeated before [`acme::Manager::min_renewal_delay`]. This future
+    /// never completes, and it does nothing if ACME is not enabled.
+    pub async fn provision_certificate(&self, log_name: &str) {
+        let Some(acme) = &self.acme else {
+            return std::future::pending().await;
+        };
+
+        // Replicas of the same server share the manager, so only one of them
+        // has to do the job.
+        let _guard = acme.lock().await;
+        let domains = acme.domains().join(", ");
+        let mut backoff = MIN_ACME_BACKOFF;
+        let mut min_delay = Duration::ZERO;
+
+        loop {
+            tokio::time::sleep(acme.renewal_delay().max(min_delay)).await;
+
+            println!("{log_name} => Requesting certificate for {domains}");
+
+            // The certificate watcher might load the new files before we do,
+            // so what matters is whether the new certificate is in use, not
+            // who loaded it.
+            let result = acme.issue().await.and_then(|certificate| {
+                match self.resolver.reload_if_modified() {
+                    Ok(_) if self.resolver.serves(&certificate) => Ok(()),
+                    Ok(_) => Err(io::Error::other("the new certificate was not loaded")),
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("can't load the new certificate: {err}"),
+                    )),
+                }
+            });
+
+            match result {
+                Ok(()) => {
+                    println!("{log_name} => Obtained certificate for {domains}");
+                    backoff = MIN_ACME_BACKOFF;
+                    min_delay = acme.min_renewal_delay();
+
+                    if acme.renewal_delay() < min_delay {
+                        println!(
+                            "{log_name} => The new certificate is already due for renewal, \
+                            it expires too soon or doesn't include all the domains. \
+                            Next attempt in {min_delay:?}"
+                        );
+                    }
+                }
+
+                Err(err) => {
+                    println!("{log_name} => ACME failed, retrying in {backoff:?}: {err}");
+                    min_delay = Duration::ZERO;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACME_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Makes this acceptor use the certificate and the ACME manager of the
+    /// `previous` one, which must have the same ACME settings. This way the
+    /// configuration can be reloaded without starting a new ACME order, see
+    /// [`crate::config::ServerTls::keep_acme`].
+    pub fn keep_acme(&mut self, previous: &Self) {
+        let mut config = ServerConfig::clone(self.acceptor.config());
+        config.cert_resolver = previous.resolver.clone();
+
+        self.acceptor = TlsAcceptor::from(Arc::new(config));
+        self.resolver = previous.resolver.clone();
+        self.acme = previous.acme.clone();
+    }
+
+    /// Returns `true` if both acceptors use the same certificate, which means
+    /// the background tasks of one of them also work for the other. See
+    /// [`Acceptor::watch_certificate`] and [`Acceptor::provision_certificate`].
+    pub fn shares_certificate(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.resolver, &other.resolver)
+    }
+
+    /// Performs the server side of the TLS handshake on `stream`.
+    pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.acceptor.accept(stream).await
+    }
+
+    /// TLS configuration for QUIC endpoints. It shares the certificate
+    /// resolver and the client verifier with this acceptor, so reloaded
+    /// certificates are also used for HTTP/3, but it only negotiates `h3`.
+    pub fn quic_config(&self) -> Result<QuicServerConfig, io::Error> {
+        let mut config = ServerConfig::clone(self.acceptor.config());
+        config.alpn_protocols = vec![b"h3".to_vec()];
+
+        QuicServerConfig::try_from(config)
+            .map_err(|err| super::invalid_data(format!("QUIC TLS config: {err}")))
+    }
+}
+
+/// Minimum time to wait before retrying a failed ACME order.
+const MIN_ACME_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum time to wait before retrying a failed ACME order.
+const MAX_ACME_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Returns `true` if the handshake was performed by an ACME server validating
+/// a `tls-alpn-01` challenge. These connections must be closed right after the
+/// handshake.
+pub(crate) fn is_acme_validation<S>(stream: &TlsStream<S>) -> bool {
+    stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN_PROTOCOL)
+}
+
+/// Certificate resolver that always returns the same certificate, like
+/// [`ServerConfig::with_single_cert`] does, except that the certificate can be
+/// swapped atomically while the server is running. It also answers ACME
+/// `tls-alpn-01` challenges, see [`acme::challenge`].
+struct Resolver {
+    /// PEM file containing the certificate chain.
+    cert: PathBuf,
+
+    /// PEM file containing the private key.
+    key: PathBuf,
+
+    /// Needed to turn the private key into a signing key.
+    provider: Arc<CryptoProvider>,
+
+    /// Certificate and key currently in use. Only [`None`] while waiting for
+    /// ACME to issue the first certificate.
+    certified_key: RwLock<Option<Arc<CertifiedKey>>>,
+
+    /// Metadata of the files the last time we loaded them. Replicas of the
+    /// same server share the resolver, so only the first one that notices a
+    /// change actually reloads the files.
+    loaded: Mutex<[Option<FileVersion>; 2]>,
+}
+
+/// Modification time and size of a file, used for detecting changes.
+type FileVersion = (SystemTime, u64);
+
+impl Resolver {
+    fn new(cert: &Path, key: &Path, provider: Arc<CryptoProvider>) -> Result<Self, io::Error> {
+        let loaded = Mutex::new([file_version(cert), file_version(key)]);
+        let certified_key = certified_key(cert, key, &provider)?;
+
+        Ok(Self {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            provider,
+            certified_key: RwLock::new(Some(Arc::new(certified_key))),
+            loaded,
+        })
+    }
+
+    /// Same as [`Resolver::new`] but the files don't have to exist yet.
+    fn pending(cert: &Path, key: &Path, provider: Arc<CryptoProvider>) -> Self {
+        let loaded = Mutex::new([file_version(cert), file_version(key)]);
+        let certified_key = certified_key(cert, key, &provider).ok().map(Arc::new);
+
+        Self {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            provider,
+            certified_key: RwLock::new(certified_key),
+            loaded,
+        }
+    }
+
+    /// Loads the certificate and key again if any of the files changed since
+    /// the last successful load. Returns `true` if the certificate was
+    /// replaced. Failed loads don't count, so files that can't be loaded are
+    /// tried again on every call even if their metadata doesn't change.
+    fn reload_if_modified(&self) -> Result<bool, io::Error> {
+        let current = [file_version(&self.cert), file_version(&self.key)];
+
+        let mut loaded = self.loaded.lock().unwrap();
+
+        if *loaded == current {
+            return Ok(false);
+        }
+
+        let certified_key = certified_key(&self.cert, &self.key, &self.provider)?;
+        *self.certified_key.write().unwrap() = Some(Arc::new(certified_key));
+        *loaded = current;
+
+        Ok(true)
+    }
+
+    /// Whether `certificate` is the end-entity certificate currently in use.
+    fn serves(&self, certificate: &CertificateDer<'_>) -> bool {
+        self.certified_key
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|certified_key| certified_key.end_entity_cert().ok())
+            .is_some_and(|current| current == certificate)
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let is_acme_validation = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN_PROTOCOL));
+
+        if is_acme_validation {
+            return acme::challenge::tls_alpn_01(client_hello.server_name()?);
+        }
+
+        self.certified_key.read().unwrap().clone()
+    }
+}
+
+impl Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+/// Loads a certificate chain and its private key. [`CertifiedKey::from_der`]
+/// makes sure that they actually belong together, which matters because files
+/// are usually not replaced at the same time and we might read them in
+/// between.
+fn certified_key(
+    cert: &Path,
+    key: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, io::Error> {
+    CertifiedKey::from_der(super::load_certs(cert)?, super::load_key(key)?, provider)
+        .map_err(super::rustls_error)
+}
+
+/// Returns [`None`] if the metadata of the file can't be read. Symlinks are
+/// followed, since that's what changes when certificates are rotated by
+/// swapping links.
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Information about a client certificate that has already been verified
+/// during the TLS handshake. It's forwarded to upstream servers in headers,
+/// see [`crate::config::IdentityHeaders`].
+#[derive(Debug)]
+pub(crate) struct ClientIdentity {
+    /// Distinguished name of the certificate subject, for example
+    /// `CN=client.example.com, O=Example`.
+    pub subject: String,
+
+    /// Comma separated list of subject alternative names using the OpenSSL
+    /// notation: `DNS:client.example.com, IP:10.0.0.2`.
+    pub san: String,
+
+    /// Hex encoded SHA-256 hash of the DER certificate.
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    /// Extracts the identity of the client from a completed handshake. Returns
+    /// [`None`] if the client didn't send a certificate, which is only possible
+    /// when unauthenticated clients are allowed.
+    pub fn from_stream<S>(stream: &TlsStream<S>) -> Option<Self> {
+        let (_, connection) = stream.get_ref();
+
+        Self::from_certificates(connection.peer_certificates()?)
+    }
+
+    /// Same as [`ClientIdentity::from_stream`] for QUIC connections.
+    pub fn from_quic(connection: &quinn::Connection) -> Option<Self> {
+        let identity = connection.peer_identity()?;
+        let certs = identity.downcast_ref::<Vec<CertificateDer<'static>>>()?;
+
+        Self::from_certificates(certs)
+    }
+
+    /// Builds the identity from the certificate chain sent by the client. The
+    /// first certificate is the client certificate.
+    fn from_certificates(certs: &[CertificateDer<'_>]) -> Option<Self> {
+        let der = certs.first()?;
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let san = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(general_name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => String::new(),
+        };
+
+        let fingerprint = ring::digest::digest(&ring::digest::SHA256, der)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            san,
+            fingerprint,
+        })
+    }
+}
+
+/// Formats a subject alternative name the same way OpenSSL does. Names that
+/// can't be represented as text are skipped.
+fn general_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+        GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+        GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+        GeneralName::IPAddress(&[a, b, c, d]) => Some(format!("IP:{}", Ipv4Addr::new(a, b, c, d))),
+        GeneralName::IPAddress(bytes) => <[u8; 16]>::try_from(*bytes)
+            .ok()
+            .map(|octets| format!("IP:{}", Ipv6Addr::from(octets))),
+        _ => None,
+    }
+}
@@ -1,37 +1,79 @@
 //! RXH proxy integration tests.
 
-#![feature(associated_type_bounds)]
 #![feature(trait_alias)]
 
 mod util;
 
-use std::{io, sync::atomic::Ordering};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
 
 use bytes::Bytes;
 use http::HeaderValue;
-use http_body_util::{Empty, Full};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::{header, service::service_fn, Request, Response};
-use rxh::{ShutdownState, State};
+use hyper_util::rt::TokioIo;
+use rxh::{
+    config::{
+        Acme,
+        Address,
+        Backend,
+        BackendTls,
+        Challenge,
+        ClientAuth,
+        ETag,
+        Fallback,
+        Http3,
+        IdentityHeaders,
+        Keepalive,
+        ProxyProtocol,
+        ProxyProtocolVersion,
+        Reject,
+        ServerTls,
+    },
+    ShutdownState,
+    State,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UnixListener, UnixStream},
     sync::mpsc,
 };
 
 use crate::util::{
+    acme::spawn_acme_server,
     config,
+    files::{create_old_file, create_sparse_file, decode, resident_memory},
     http::{
+        http2_client,
         http_client,
         request,
         send_http_request,
+        send_raw_http_request,
         spawn_backend_server,
         spawn_backends_with_request_counters,
         spawn_client,
         spawn_master,
+        spawn_master_with_reload_handle,
         spawn_reverse_proxy,
         spawn_reverse_proxy_with_controllers,
+        tcp_sockets,
     },
+    quic::send_http3_request,
     service::{serve_connection, RequestInterceptor},
-    tcp::{ping_all, ping_tcp_server, usable_socket, usable_tcp_listener},
+    tcp::{ping_all, ping_tcp_server, spawn_echo_server, usable_socket, usable_tcp_listener},
+    tls::{
+        self,
+        send_https_request,
+        spawn_tls_backend_server,
+        tls_connect,
+        wait_for_trusted_certificate,
+        TestCa,
+    },
+    udp::{exchange, spawn_udp_echo_server, udp_client},
 };
 
 #[tokio::test]
@@ -106,17 +148,53 @@ async fn reverse_proxy_backend() {
     );
 }
 
+#[tokio::test]
+async fn http2_prior_knowledge() {
+    let (listener, server_addr) = usable_tcp_listener();
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config::proxy::single_backend(server_addr));
+
+    ping_tcp_server(proxy_addr).await;
+
+    let client = tokio::task::spawn(async move {
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut sender = http2_client(stream).await;
+
+        let request = Request::builder()
+            .uri(format!("http://{proxy_addr}/api?page=1"))
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        sender.send_request(request).await.unwrap()
+    });
+
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let (stream, _) = listener.accept().await.unwrap();
+    serve_connection(stream, RequestInterceptor::new(tx)).await;
+
+    // Backends get a regular HTTP/1.1 request.
+    let (parts, _) = rx.recv().await.unwrap();
+
+    assert_eq!(parts.version, http::Version::HTTP_11);
+    assert_eq!(parts.uri, "/api?page=1");
+    assert_eq!(parts.headers[header::HOST], proxy_addr.to_string());
+
+    let response = client.await.unwrap();
+
+    assert_eq!(response.version(), http::Version::HTTP_2);
+    assert_eq!(response.status(), http::StatusCode::OK);
+}
+
 #[tokio::test]
 async fn graceful_shutdown() {
-    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
-        Ok(Response::new(Full::<Bytes>::from("Hello world")))
-    }));
+    let (listener, server_addr) = usable_tcp_listener();
 
     let (proxy_addr, _, shutdown, mut state) = spawn_reverse_proxy_with_controllers(
         config::proxy::single_backend_with_uri(server_addr, "/hello"),
     );
 
-    ping_all(&[server_addr, proxy_addr]).await;
+    ping_tcp_server(proxy_addr).await;
 
     // Make sure server is listening.
     state.changed().await.unwrap();
@@ -126,25 +204,28 @@ async fn graceful_shutdown() {
     let (sock2, _) = usable_socket();
     let (sock3, _) = usable_socket();
 
-    // Open a couple sockets but don't send anything yet.
-    let stream1 = sock1.connect(proxy_addr).await.unwrap();
-    let stream2 = sock2.connect(proxy_addr).await.unwrap();
+    // The first connection is idle, the second one has a request in flight.
+    let mut idle = sock1.connect(proxy_addr).await.unwrap();
+    let mut busy = sock2.connect(proxy_addr).await.unwrap();
+
+    busy.write_all(b"GET /hello HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
 
-    // Yield execution back to Tokio so that the server task can run and accept
-    // the previous connections.
-    tokio::task::yield_now().await;
+    // Once the backend gets the request we know it's in flight.
+    let (backend, _) = listener.accept().await.unwrap();
 
     // Shutdown the server.
     shutdown();
 
-    // Wait for the state change.
-    state.changed().await.unwrap();
+    // The idle connection is closed right away, so only the busy one is left.
+    state
+        .wait_for(|state| *state == State::ShuttingDown(ShutdownState::PendingConnections(1)))
+        .await
+        .unwrap();
 
-    // Now the server should know that there are still 2 pending connections.
-    assert_eq!(
-        *state.borrow(),
-        State::ShuttingDown(ShutdownState::PendingConnections(2))
-    );
+    let mut buff = [0; 1024];
+    assert_eq!(idle.read(&mut buff).await.unwrap(), 0);
 
     // If we try to connect using another socket it should not allow us.
     assert_eq!(
@@ -152,25 +233,53 @@ async fn graceful_shutdown() {
         io::ErrorKind::ConnectionRefused
     );
 
-    for stream in [stream1, stream2] {
-        // Send a simple HTTP request with the connected sockets.
-        stream.writable().await.unwrap();
-        assert!(stream.try_write(b"GET /hello HTTP/1.1\r\n\r\n").is_ok());
+    tokio::task::spawn(serve_connection(
+        backend,
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello world"))) }),
+    ));
 
-        // Read the response.
-        stream.readable().await.unwrap();
-        let mut buff = [0; 1024];
-        let bytes = stream.try_read(buff.as_mut_slice()).unwrap();
+    // The request in flight still gets its response.
+    let mut response = Vec::new();
+    busy.read_to_end(&mut response).await.unwrap();
 
-        // Check that we've received an OK response with the body that we used
-        // when spawning the backend server.
-        assert!(buff.starts_with(b"HTTP/1.1 200 OK"));
-        assert!(buff[..bytes].ends_with(b"Hello world"));
-    }
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(response.ends_with(b"Hello world"));
 
-    // Finally, after the streams above are dropped, server should be down.
-    state.changed().await.unwrap();
-    assert_eq!(*state.borrow(), State::ShuttingDown(ShutdownState::Done));
+    state
+        .wait_for(|state| *state == State::ShuttingDown(ShutdownState::Done))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn graceful_shutdown_timeout() {
+    let (listener, server_addr) = usable_tcp_listener();
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.shutdown_timeout = Duration::from_millis(100);
+
+    let (proxy_addr, _, shutdown, mut state) = spawn_reverse_proxy_with_controllers(config);
+
+    ping_tcp_server(proxy_addr).await;
+
+    let (socket, _) = usable_socket();
+    let mut stream = socket.connect(proxy_addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+    // The backend accepts the request but never responds.
+    let (_backend, _) = listener.accept().await.unwrap();
+
+    shutdown();
+
+    let done = state.wait_for(|state| *state == State::ShuttingDown(ShutdownState::Done));
+    tokio::time::timeout(Duration::from_secs(5), done)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The connection was closed without a response.
+    let mut buff = [0; 1024];
+    assert_eq!(stream.read(&mut buff).await.unwrap(), 0);
 }
 
 #[tokio::test]
@@ -214,7 +323,7 @@ async fn limited_connections() {
 async fn upgraded_connection() {
     let (server_addr, _) = spawn_backend_server(service_fn(|req| async {
         tokio::task::spawn(async move {
-            let mut upgraded = hyper::upgrade::on(req).await.unwrap();
+            let mut upgraded = TokioIo::new(hyper::upgrade::on(req).await.unwrap());
             let mut buff = [0; 1024];
             let bytes = upgraded.read(&mut buff).await.unwrap();
             upgraded.write_all(&buff[0..bytes]).await.unwrap();
@@ -244,8 +353,8 @@ async fn upgraded_connection() {
 
     let res = sender.send_request(req).await.unwrap();
 
-    let mut upgraded = hyper::upgrade::on(res).await.unwrap();
-    upgraded.write(b"Test String").await.unwrap();
+    let mut upgraded = TokioIo::new(hyper::upgrade::on(res).await.unwrap());
+    upgraded.write_all(b"Test String").await.unwrap();
 
     let mut buff = [0; 1024];
     let bytes = upgraded.read(&mut buff).await.unwrap();
@@ -259,7 +368,10 @@ async fn load_balancing() {
     let weights = vec![1, 3, 2];
     let (backends, request_counters) = spawn_backends_with_request_counters(&weights);
 
-    let servers: Vec<_> = backends.iter().map(|backend| backend.address).collect();
+    let servers: Vec<_> = backends
+        .iter()
+        .map(|backend| backend.address.as_tcp().unwrap())
+        .collect();
     let (proxy, _) = spawn_reverse_proxy(config::proxy::multiple_weighted_backends(backends));
 
     ping_all(&servers).await;
@@ -282,82 +394,2631 @@ async fn load_balancing() {
 }
 
 #[tokio::test]
-async fn static_files() {
-    let html = r#"
-        <!DOCTYPE html>
-        <html lang="en">
-        <head>
-            <meta charset="UTF-8">
-            <meta http-equiv="X-UA-Compatible" content="IE=edge">
-            <meta name="viewport" content="width=device-width, initial-scale=1.0">
-            <title>Test</title>
-        </head>
-        <body>
-            <p>Hello World</p>
-        </body>
-        </html>
-    "#;
+async fn tls_backend() {
+    let ca = TestCa::new();
+    let backend_cert = ca.issue("localhost");
 
-    let dir = tempfile::tempdir().unwrap();
-    let mut file = tokio::fs::File::create(dir.path().join("index.html"))
+    let server_addr = spawn_tls_backend_server(
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello TLS"))) }),
+        tls::server_config(&backend_cert, None),
+    );
+
+    let tls = BackendTls::new(
+        Some(ca.cert_path()),
+        None,
+        None,
+        Some(String::from("localhost")),
+        true,
+    )
+    .unwrap();
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config::proxy::single_tls_backend(server_addr, tls));
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let (parts, body) = send_http_request(proxy_addr, request::empty()).await;
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello TLS"));
+}
+
+#[tokio::test]
+async fn tls_backend_with_untrusted_certificate() {
+    let backend_ca = TestCa::new();
+    let proxy_ca = TestCa::new();
+    let backend_cert = backend_ca.issue("localhost");
+
+    let server_addr = spawn_tls_backend_server(
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello TLS"))) }),
+        tls::server_config(&backend_cert, None),
+    );
+
+    let tls = BackendTls::new(
+        Some(proxy_ca.cert_path()),
+        None,
+        None,
+        Some(String::from("localhost")),
+        true,
+    )
+    .unwrap();
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config::proxy::single_tls_backend(server_addr, tls));
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let (parts, _) = send_http_request(proxy_addr, request::empty()).await;
+
+    assert_eq!(parts.status, http::StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn tls_backend_without_verification() {
+    let ca = TestCa::new();
+    let backend_cert = ca.issue("backend.internal");
+
+    let server_addr = spawn_tls_backend_server(
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello TLS"))) }),
+        tls::server_config(&backend_cert, None),
+    );
+
+    let tls = BackendTls::new(None, None, None, None, false).unwrap();
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config::proxy::single_tls_backend(server_addr, tls));
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let (parts, body) = send_http_request(proxy_addr, request::empty()).await;
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello TLS"));
+}
+
+#[tokio::test]
+async fn tls_backend_with_client_certificate() {
+    let ca = TestCa::new();
+    let backend_cert = ca.issue("localhost");
+    let proxy_cert = ca.issue("rxh.localhost");
+
+    let server_addr = spawn_tls_backend_server(
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello mTLS"))) }),
+        tls::server_config(&backend_cert, Some(&ca.cert_path())),
+    );
+
+    let with_client_cert = BackendTls::new(
+        Some(ca.cert_path()),
+        Some(proxy_cert.cert),
+        Some(proxy_cert.key),
+        Some(String::from("localhost")),
+        true,
+    )
+    .unwrap();
+
+    let without_client_cert = BackendTls::new(
+        Some(ca.cert_path()),
+        None,
+        None,
+        Some(String::from("localhost")),
+        true,
+    )
+    .unwrap();
+
+    let (authenticated_proxy, _) = spawn_reverse_proxy(config::proxy::single_tls_backend(
+        server_addr,
+        with_client_cert,
+    ));
+
+    let (anonymous_proxy, _) = spawn_reverse_proxy(config::proxy::single_tls_backend(
+        server_addr,
+        without_client_cert,
+    ));
+
+    ping_all(&[server_addr, authenticated_proxy, anonymous_proxy]).await;
+
+    let (parts, body) = send_http_request(authenticated_proxy, request::empty()).await;
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello mTLS"));
+
+    let (parts, _) = send_http_request(anonymous_proxy, request::empty()).await;
+    assert_eq!(parts.status, http::StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn tls_listener() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (parts, body) =
+        send_https_request(proxy_addr, "localhost", client_config, request::empty())
+            .await
+            .unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello world"));
+}
+
+#[tokio::test]
+async fn tls_listener_negotiates_http2() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let mut client_config = tls::client_config(&ca.cert_path(), None);
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let stream = tls_connect(proxy_addr, "localhost", client_config)
         .await
         .unwrap();
-    file.write(html.as_bytes()).await.unwrap();
 
-    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
 
-    ping_tcp_server(addr).await;
+    let mut sender = http2_client(stream).await;
 
-    let (parts, body) = send_http_request(addr, request::empty_with_uri("/index.html")).await;
+    let request = Request::builder()
+        .uri(format!("https://localhost:{}/", proxy_addr.port()))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = sender.send_request(request).await.unwrap().into_parts();
+    let body = body.collect().await.unwrap().to_bytes();
 
+    assert_eq!(parts.version, http::Version::HTTP_2);
     assert_eq!(parts.status, http::StatusCode::OK);
-    assert_eq!(body, html.as_bytes());
+    assert_eq!(body, String::from("Hello world"));
 }
 
 #[tokio::test]
-async fn client_receives_404_if_file_not_found() {
-    let dir = tempfile::tempdir().unwrap();
+async fn http3_listener() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
 
-    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
 
-    ping_tcp_server(addr).await;
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+    config.http3 = Some(Http3::default());
 
-    let doesnt_exist = ["/test.html", "/styles.css", "/app.js"];
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
 
-    for file in doesnt_exist {
-        let (parts, body) = send_http_request(addr, request::empty_with_uri(file)).await;
-        assert_eq!(parts.status, http::StatusCode::NOT_FOUND);
-        assert!(body.starts_with(b"HTTP 404 NOT FOUND"));
-    }
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    // TCP clients are told where to find HTTP/3.
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (parts, _) = send_https_request(proxy_addr, "localhost", client_config, request::empty())
+        .await
+        .unwrap();
+
+    let alt_svc = format!("h3=\":{}\"; ma=86400", proxy_addr.port());
+    assert_eq!(parts.headers[header::ALT_SVC], alt_svc.as_str());
+
+    let request = Request::builder()
+        .uri(format!("https://localhost:{}/", proxy_addr.port()))
+        .body(())
+        .unwrap();
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (parts, body) = send_http3_request(proxy_addr, "localhost", client_config, request)
+        .await
+        .unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello world"));
 }
 
 #[tokio::test]
-async fn multi_server() {
-    let dir = tempfile::tempdir().unwrap();
-    let mut file = tokio::fs::File::create(dir.path().join("test.txt"))
-        .await
+async fn http3_graceful_shutdown() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (listener, server_addr) = usable_tcp_listener();
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+    config.http3 = Some(Http3::default());
+
+    let (proxy_addr, _, shutdown, mut state) = spawn_reverse_proxy_with_controllers(config);
+
+    state.changed().await.unwrap();
+    assert_eq!(*state.borrow(), State::Listening);
+
+    let request = Request::builder()
+        .uri(format!("https://localhost:{}/", proxy_addr.port()))
+        .body(())
         .unwrap();
-    file.write(b"Hello World File").await.unwrap();
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let client = tokio::task::spawn(send_http3_request(
+        proxy_addr,
+        "localhost",
+        client_config,
+        request,
+    ));
+
+    // Once the backend gets the request we know it's in flight.
+    let (stream, _) = listener.accept().await.unwrap();
+
+    shutdown();
+
+    state.changed().await.unwrap();
+    assert_eq!(
+        *state.borrow(),
+        State::ShuttingDown(ShutdownState::PendingConnections(1))
+    );
+
+    tokio::task::spawn(serve_connection(
+        stream,
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello world"))) }),
+    ));
+
+    // The request accepted before shutting down still gets a response.
+    let (parts, body) = client.await.unwrap().unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello world"));
+
+    state.changed().await.unwrap();
+    assert_eq!(*state.borrow(), State::ShuttingDown(ShutdownState::Done));
+}
+
+#[tokio::test]
+async fn tls_listener_reloads_rotated_certificate() {
+    let old_ca = TestCa::new();
+    let new_ca = TestCa::new();
+    let proxy_cert = old_ca.issue("localhost");
+    let rotated_cert = new_ca.issue("localhost");
 
     let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
-        Ok(Response::new(Full::<Bytes>::from("Hello World Response")))
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
     }));
 
-    let config = rxh::config::Config {
-        servers: vec![
-            config::proxy::single_backend_with_uri(server_addr, "/api"),
-            config::files::serve(dir.path().to_str().unwrap()),
-        ],
-    };
+    let mut server_tls =
+        ServerTls::new(proxy_cert.cert.clone(), proxy_cert.key.clone(), None).unwrap();
+    server_tls.reload_interval = Duration::from_millis(10);
 
-    let (sockets, _) = spawn_master(config);
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(server_tls);
 
-    ping_all(&sockets).await;
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
 
-    let (_, api_req_body) = send_http_request(sockets[0], request::empty_with_uri("/api")).await;
-    let (_, file_req_body) =
-        send_http_request(sockets[1], request::empty_with_uri("/test.txt")).await;
+    ping_all(&[server_addr, proxy_addr]).await;
 
-    assert_eq!(api_req_body, &"Hello World Response");
-    assert_eq!(file_req_body, &"Hello World File");
+    // This connection is established before the certificate is rotated.
+    let mut old_client = tls::https_client(
+        proxy_addr,
+        "localhost",
+        tls::client_config(&old_ca.cert_path(), None),
+    )
+    .await
+    .unwrap();
+
+    std::fs::copy(&rotated_cert.key, &proxy_cert.key).unwrap();
+    std::fs::copy(&rotated_cert.cert, &proxy_cert.cert).unwrap();
+
+    let new_client_config = tls::client_config(&new_ca.cert_path(), None);
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while send_https_request(
+            proxy_addr,
+            "localhost",
+            new_client_config.clone(),
+            request::empty(),
+        )
+        .await
+        .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("rotated certificate was never loaded");
+
+    let old_client_config = tls::client_config(&old_ca.cert_path(), None);
+    let result =
+        send_https_request(proxy_addr, "localhost", old_client_config, request::empty()).await;
+
+    assert!(result.is_err());
+
+    let response = old_client.send_request(request::empty()).await.unwrap();
+
+    assert_eq!(response.status(), http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn tls_listener_retries_certificate_that_failed_to_load() {
+    let old_ca = TestCa::new();
+    let new_ca = TestCa::new();
+    let proxy_cert = old_ca.issue("localhost");
+    let rotated_cert = new_ca.issue("localhost");
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let mut server_tls =
+        ServerTls::new(proxy_cert.cert.clone(), proxy_cert.key.clone(), None).unwrap();
+    server_tls.reload_interval = Duration::from_millis(10);
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(server_tls);
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    // The certificate is half written when the server polls, then fixed
+    // without changing its modification time or length.
+    let rotated = std::fs::read(&rotated_cert.cert).unwrap();
+    let modified = SystemTime::now() - Duration::from_secs(60);
+
+    let set_modified = |path: &std::path::Path| {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    };
+
+    std::fs::copy(&rotated_cert.key, &proxy_cert.key).unwrap();
+    std::fs::write(&proxy_cert.cert, vec![b'-'; rotated.len()]).unwrap();
+    set_modified(&proxy_cert.cert);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    std::fs::write(&proxy_cert.cert, &rotated).unwrap();
+    set_modified(&proxy_cert.cert);
+
+    let new_client_config = tls::client_config(&new_ca.cert_path(), None);
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while send_https_request(
+            proxy_addr,
+            "localhost",
+            new_client_config.clone(),
+            request::empty(),
+        )
+        .await
+        .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("certificate was not loaded again after a failed attempt");
+}
+
+#[tokio::test]
+async fn acme_does_not_renew_certificates_in_a_loop() {
+    let ca = Arc::new(TestCa::new());
+    let acme_server = spawn_acme_server(ca.clone());
+    let state = tempfile::tempdir().unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (http_addr, _) = spawn_reverse_proxy(config::proxy::single_backend(server_addr));
+    acme_server.validate_at(http_addr);
+
+    // Every certificate the CA issues is due for renewal right away.
+    let acme = Acme {
+        domains: vec![String::from("acme.localhost")],
+        contact: vec![],
+        directory: acme_server.directory_url(),
+        ca: None,
+        challenge: Challenge::Http01,
+        state: state.path().to_path_buf(),
+        renew_before: 10_000_000,
+    };
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::with_acme(acme, None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, http_addr, proxy_addr]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    wait_for_trusted_certificate(proxy_addr, "acme.localhost", client_config).await;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(acme_server.issued(), 1);
+}
+
+#[tokio::test]
+async fn acme_survives_configuration_reloads() {
+    let ca = Arc::new(TestCa::new());
+    let acme_server = spawn_acme_server(ca.clone());
+    let state = tempfile::tempdir().unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (http_addr, _) = spawn_reverse_proxy(config::proxy::single_backend(server_addr));
+    acme_server.validate_at(http_addr);
+
+    // Starting over would issue a new certificate right away.
+    let acme = Acme {
+        domains: vec![String::from("acme.localhost")],
+        contact: vec![],
+        directory: acme_server.directory_url(),
+        ca: None,
+        challenge: Challenge::Http01,
+        state: state.path().to_path_buf(),
+        renew_before: 10_000_000,
+    };
+
+    let (_, listen) = usable_socket();
+
+    let mut server = config::proxy::single_backend(server_addr);
+    server.listen = vec![listen.into()];
+    server.socket.reuse_port = Some(2);
+    server.tls = Some(ServerTls::with_acme(acme.clone(), None).unwrap());
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![server.clone()],
+    });
+
+    ping_all(&[server_addr, http_addr]).await;
+    ping_all(&sockets).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    wait_for_trusted_certificate(listen, "acme.localhost", client_config).await;
+
+    // Same as SIGHUP, the config file is parsed again, and one more accept
+    // loop joins the existing ones.
+    server.socket.reuse_port = Some(3);
+
+    for _ in 0..3 {
+        server.tls = Some(ServerTls::with_acme(acme.clone(), None).unwrap());
+        reload
+            .reload(rxh::config::Config {
+                servers: vec![server.clone()],
+            })
+            .await
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(acme_server.issued(), 1);
+
+    for _ in 0..3 {
+        let client_config = tls::client_config(&ca.cert_path(), None);
+        let (_, body) =
+            send_https_request(listen, "acme.localhost", client_config, request::empty())
+                .await
+                .unwrap();
+        assert_eq!(body, "Hello world");
+    }
+}
+
+#[tokio::test]
+async fn acme_http_01_certificate() {
+    let ca = Arc::new(TestCa::new());
+    let acme_server = spawn_acme_server(ca.clone());
+    let state = tempfile::tempdir().unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    // Plain HTTP server that answers the challenge, port 80 in production.
+    let (http_addr, _) = spawn_reverse_proxy(config::proxy::single_backend(server_addr));
+    acme_server.validate_at(http_addr);
+
+    let acme = Acme {
+        domains: vec![String::from("acme.localhost")],
+        contact: vec![String::from("mailto:admin@acme.localhost")],
+        directory: acme_server.directory_url(),
+        ca: None,
+        challenge: Challenge::Http01,
+        state: state.path().to_path_buf(),
+        renew_before: 30,
+    };
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::with_acme(acme.clone(), None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, http_addr, proxy_addr]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    wait_for_trusted_certificate(proxy_addr, "acme.localhost", client_config.clone()).await;
+
+    let (parts, body) = send_https_request(
+        proxy_addr,
+        "acme.localhost",
+        client_config.clone(),
+        request::empty(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, String::from("Hello world"));
+    assert_eq!(acme_server.issued(), 1);
+    assert!(state.path().join("account.key").exists());
+
+    // Certificates are stored in the state directory, so a new server with the
+    // same configuration doesn't need to request them again.
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::with_acme(acme, None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_tcp_server(proxy_addr).await;
+
+    let (parts, _) = send_https_request(
+        proxy_addr,
+        "acme.localhost",
+        client_config,
+        request::empty(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(acme_server.issued(), 1);
+}
+
+#[tokio::test]
+async fn acme_tls_alpn_01_certificate() {
+    let ca = Arc::new(TestCa::new());
+    let acme_server = spawn_acme_server(ca.clone());
+    let state = tempfile::tempdir().unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let acme = Acme {
+        domains: vec![
+            String::from("alpn.localhost"),
+            String::from("www.alpn.localhost"),
+        ],
+        contact: Vec::new(),
+        directory: acme_server.directory_url(),
+        ca: None,
+        challenge: Challenge::TlsAlpn01,
+        state: state.path().to_path_buf(),
+        renew_before: 30,
+    };
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::with_acme(acme, None).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    // The challenge is answered by the same server, port 443 in production.
+    acme_server.validate_at(proxy_addr);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    wait_for_trusted_certificate(proxy_addr, "www.alpn.localhost", client_config).await;
+
+    assert_eq!(acme_server.issued(), 1);
+}
+
+#[tokio::test]
+async fn mutual_tls_listener_forwards_client_identity() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+    let client_cert = ca.issue("client.localhost");
+
+    let (listener, server_addr) = usable_tcp_listener();
+
+    let client_auth = ClientAuth {
+        ca: ca.cert_path(),
+        reject: Reject::Alert,
+        headers: IdentityHeaders::default(),
+    };
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, Some(client_auth)).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_tcp_server(proxy_addr).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), Some(&client_cert));
+
+    // The client attempts to impersonate someone else, which should not work.
+    let request = Request::builder()
+        .header("X-Client-Cert-Subject", "CN=admin")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    tokio::task::spawn(send_https_request(
+        proxy_addr,
+        "localhost",
+        client_config,
+        request,
+    ));
+
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let (stream, _) = listener.accept().await.unwrap();
+    serve_connection(stream, RequestInterceptor::new(tx)).await;
+
+    let (parts, _) = rx.recv().await.unwrap();
+
+    let subjects: Vec<_> = parts
+        .headers
+        .get_all("X-Client-Cert-Subject")
+        .iter()
+        .collect();
+
+    assert_eq!(subjects, ["CN=client.localhost"]);
+    assert_eq!(parts.headers["X-Client-Cert-San"], "DNS:client.localhost");
+    assert_eq!(parts.headers["X-Client-Cert-Fingerprint"].len(), 64);
+}
+
+#[tokio::test]
+async fn mutual_tls_listener_rejects_anonymous_clients_with_alert() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let client_auth = ClientAuth {
+        ca: ca.cert_path(),
+        reject: Reject::Alert,
+        headers: IdentityHeaders::default(),
+    };
+
+    let mut config = config::proxy::single_backend(server_addr);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, Some(client_auth)).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let result = send_https_request(proxy_addr, "localhost", client_config, request::empty()).await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn mutual_tls_listener_rejects_anonymous_clients_with_403() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+    let client_cert = ca.issue("client.localhost");
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let client_auth = ClientAuth {
+        ca: ca.cert_path(),
+        reject: Reject::Forbidden,
+        headers: IdentityHeaders::default(),
+    };
+
+    // Only "/private" requires a client certificate.
+    let mut config = config::proxy::single_backend_with_uri(server_addr, "/private");
+    let mut public = config::proxy::single_backend(server_addr).patterns;
+    public[0].client_auth = false;
+    config.patterns.extend(public);
+    config.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, Some(client_auth)).unwrap());
+
+    let (proxy_addr, _) = spawn_reverse_proxy(config);
+
+    ping_all(&[server_addr, proxy_addr]).await;
+
+    let anonymous = tls::client_config(&ca.cert_path(), None);
+    let authenticated = tls::client_config(&ca.cert_path(), Some(&client_cert));
+
+    let cases = [
+        (anonymous.clone(), "/private", http::StatusCode::FORBIDDEN),
+        (anonymous.clone(), "/public", http::StatusCode::OK),
+        (anonymous.clone(), "/public/%ff%00%5c", http::StatusCode::OK),
+        (authenticated, "/private", http::StatusCode::OK),
+        // Encoded forms of "/private" still need a certificate.
+        (
+            anonymous.clone(),
+            "/%70rivate%00",
+            http::StatusCode::FORBIDDEN,
+        ),
+        (
+            anonymous.clone(),
+            "/%70rivate%5c",
+            http::StatusCode::FORBIDDEN,
+        ),
+        // The backend might not decode the slash, so this is under "/private".
+        (
+            anonymous,
+            "/private/..%2fpublic",
+            http::StatusCode::FORBIDDEN,
+        ),
+    ];
+
+    for (client_config, uri, status) in cases {
+        let (parts, _) = send_https_request(
+            proxy_addr,
+            "localhost",
+            client_config,
+            request::empty_with_uri(uri),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(parts.status, status);
+    }
+}
+
+#[tokio::test]
+async fn static_files() {
+    let html = r#"
+        <!DOCTYPE html>
+        <html lang="en">
+        <head>
+            <meta charset="UTF-8">
+            <meta http-equiv="X-UA-Compatible" content="IE=edge">
+            <meta name="viewport" content="width=device-width, initial-scale=1.0">
+            <title>Test</title>
+        </head>
+        <body>
+            <p>Hello World</p>
+        </body>
+        </html>
+    "#;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut file = tokio::fs::File::create(dir.path().join("index.html"))
+        .await
+        .unwrap();
+    file.write_all(html.as_bytes()).await.unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, body) = send_http_request(addr, request::empty_with_uri("/index.html")).await;
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, html.as_bytes());
+}
+
+#[tokio::test]
+async fn client_receives_404_if_file_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let doesnt_exist = ["/test.html", "/styles.css", "/app.js"];
+
+    for file in doesnt_exist {
+        let (parts, body) = send_http_request(addr, request::empty_with_uri(file)).await;
+        assert_eq!(parts.status, http::StatusCode::NOT_FOUND);
+        assert!(body.starts_with(b"HTTP 404 NOT FOUND"));
+    }
+}
+
+#[tokio::test]
+async fn large_files_are_streamed() {
+    const FILE_SIZE: u64 = 256 * 1024 * 1024;
+    const MAX_MEMORY_GROWTH: usize = 32 * 1024 * 1024;
+
+    let dir = tempfile::tempdir().unwrap();
+    create_sparse_file(&dir.path().join("large.iso"), FILE_SIZE, b"end").await;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let baseline = resident_memory();
+
+    let mut sender = http_client(TcpStream::connect(addr).await.unwrap()).await;
+    let response = sender
+        .send_request(request::empty_with_uri("/large.iso"))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_LENGTH],
+        FILE_SIZE.to_string()
+    );
+
+    // Don't read the body for a while. The server should stop reading the
+    // file once the socket buffers are full.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut peak = resident_memory();
+    let mut body = response.into_body();
+    let mut received = 0;
+    let mut tail = Vec::new();
+
+    while let Some(frame) = body.frame().await {
+        let data = frame.unwrap().into_data().unwrap();
+        received += data.len() as u64;
+
+        tail.extend_from_slice(&data);
+        tail.drain(..tail.len().saturating_sub(3));
+
+        peak = peak.max(resident_memory());
+    }
+
+    assert_eq!(received, FILE_SIZE);
+    assert_eq!(tail, b"end");
+
+    if let (Some(baseline), Some(peak)) = (baseline, peak) {
+        assert!(
+            peak.saturating_sub(baseline) < MAX_MEMORY_GROWTH,
+            "memory grew by {} bytes while streaming a file of {FILE_SIZE} bytes",
+            peak - baseline
+        );
+    }
+}
+
+#[tokio::test]
+async fn range_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    create_old_file(
+        &dir.path().join("alphabet.txt"),
+        b"abcdefghijklmnopqrstuvwxyz",
+    )
+    .await;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, body) = send_http_request(addr, request::empty_with_uri("/alphabet.txt")).await;
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(parts.headers[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(body, "abcdefghijklmnopqrstuvwxyz");
+
+    for (range, content_range, expected) in [
+        ("bytes=0-4", "bytes 0-4/26", "abcde"),
+        ("bytes=20-", "bytes 20-25/26", "uvwxyz"),
+        ("bytes=-3", "bytes 23-25/26", "xyz"),
+        ("bytes=10-1000", "bytes 10-25/26", "klmnopqrstuvwxyz"),
+    ] {
+        let request = Request::builder()
+            .uri("/alphabet.txt")
+            .header(header::RANGE, range)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, body) = send_http_request(addr, request).await;
+
+        assert_eq!(parts.status, http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(parts.headers[header::CONTENT_RANGE], content_range);
+        assert_eq!(
+            parts.headers[header::CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+        assert_eq!(body, expected);
+    }
+
+    let request = Request::builder()
+        .uri("/alphabet.txt")
+        .header(header::RANGE, "bytes=26-")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    assert_eq!(parts.status, http::StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(parts.headers[header::CONTENT_RANGE], "bytes */26");
+    assert!(body.is_empty());
+
+    // Invalid ranges are ignored.
+    let request = Request::builder()
+        .uri("/alphabet.txt")
+        .header(header::RANGE, "bytes=5-2")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(body, "abcdefghijklmnopqrstuvwxyz");
+}
+
+#[tokio::test]
+async fn multipart_range_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    create_old_file(
+        &dir.path().join("alphabet.txt"),
+        b"abcdefghijklmnopqrstuvwxyz",
+    )
+    .await;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let request = Request::builder()
+        .uri("/alphabet.txt")
+        .header(header::RANGE, "bytes=0-2, -2")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    assert_eq!(parts.status, http::StatusCode::PARTIAL_CONTENT);
+
+    let content_type = parts.headers[header::CONTENT_TYPE].to_str().unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+
+    let expected = format!(
+        "\r\n--{boundary}\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Range: bytes 0-2/26\r\n\r\n\
+        abc\r\n--{boundary}\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Range: bytes 24-25/26\r\n\r\n\
+        yz\r\n--{boundary}--\r\n"
+    );
+
+    assert_eq!(
+        parts.headers[header::CONTENT_LENGTH],
+        expected.len().to_string()
+    );
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn if_range_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    create_old_file(
+        &dir.path().join("alphabet.txt"),
+        b"abcdefghijklmnopqrstuvwxyz",
+    )
+    .await;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/alphabet.txt")).await;
+    let last_modified = parts.headers[header::LAST_MODIFIED].clone();
+
+    for (if_range, status) in [
+        (last_modified, http::StatusCode::PARTIAL_CONTENT),
+        (
+            HeaderValue::from_static("Sat, 01 Jan 2000 00:00:00 GMT"),
+            http::StatusCode::OK,
+        ),
+        (HeaderValue::from_static("\"abc\""), http::StatusCode::OK),
+    ] {
+        let request = Request::builder()
+            .uri("/alphabet.txt")
+            .header(header::RANGE, "bytes=0-2")
+            .header(header::IF_RANGE, if_range)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, _) = send_http_request(addr, request).await;
+        assert_eq!(parts.status, status);
+    }
+}
+
+#[tokio::test]
+async fn conditional_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    create_old_file(&dir.path().join("index.html"), b"<p>Hello</p>").await;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/index.html")).await;
+    let etag = parts.headers[header::ETAG].to_str().unwrap().to_owned();
+    let last_modified = parts.headers[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // Metadata tags are weak.
+    assert!(etag.starts_with("W/\""));
+
+    for (name, value, status) in [
+        (
+            header::IF_NONE_MATCH,
+            etag.as_str(),
+            http::StatusCode::NOT_MODIFIED,
+        ),
+        (header::IF_NONE_MATCH, "\"other\"", http::StatusCode::OK),
+        (
+            header::IF_MODIFIED_SINCE,
+            last_modified.as_str(),
+            http::StatusCode::NOT_MODIFIED,
+        ),
+        (
+            header::IF_MODIFIED_SINCE,
+            "Sat, 01 Jan 2000 00:00:00 GMT",
+            http::StatusCode::OK,
+        ),
+        (
+            header::IF_MATCH,
+            etag.as_str(),
+            http::StatusCode::PRECONDITION_FAILED,
+        ),
+        (header::IF_MATCH, "*", http::StatusCode::OK),
+        (
+            header::IF_UNMODIFIED_SINCE,
+            "Sat, 01 Jan 2000 00:00:00 GMT",
+            http::StatusCode::PRECONDITION_FAILED,
+        ),
+        (
+            header::IF_UNMODIFIED_SINCE,
+            last_modified.as_str(),
+            http::StatusCode::OK,
+        ),
+    ] {
+        let request = Request::builder()
+            .uri("/index.html")
+            .header(&name, value)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, body) = send_http_request(addr, request).await;
+
+        assert_eq!(parts.status, status, "{name}: {value}");
+        assert_eq!(parts.headers[header::ETAG], etag.as_str());
+
+        if status != http::StatusCode::OK {
+            assert!(body.is_empty());
+        }
+    }
+}
+
+#[tokio::test]
+async fn content_hash_entity_tags() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.txt");
+    create_old_file(&path, b"abcdefghijklmnopqrstuvwxyz").await;
+
+    let mut files = config::files::root(dir.path().to_str().unwrap());
+    files.etag = ETag::Hash;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_files(files));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/data.txt")).await;
+    let etag = parts.headers[header::ETAG].clone();
+
+    assert!(etag.to_str().unwrap().starts_with('"'));
+
+    // Strong tags work with If-Match and If-Range.
+    let request = Request::builder()
+        .uri("/data.txt")
+        .header(header::IF_MATCH, &etag)
+        .header(header::RANGE, "bytes=0-2")
+        .header(header::IF_RANGE, &etag)
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    assert_eq!(parts.status, http::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, "abc");
+
+    // New content, new tag.
+    create_old_file(&path, b"ABCDEFGHIJKLMNOPQRSTUVWXYZ!").await;
+
+    let request = Request::builder()
+        .uri("/data.txt")
+        .header(header::IF_NONE_MATCH, &etag)
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_ne!(parts.headers[header::ETAG], etag);
+    assert_eq!(body, "ABCDEFGHIJKLMNOPQRSTUVWXYZ!");
+}
+
+#[tokio::test]
+async fn precompressed_files() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::write(dir.path().join("app.js"), "console.log('Hello')")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("app.js.br"), "brotli")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("app.js.gz"), "gzip")
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    for (accept_encoding, content_encoding, expected) in [
+        ("gzip, br", Some("br"), "brotli"),
+        ("gzip, br;q=0.5", Some("gzip"), "gzip"),
+        ("zstd, gzip", Some("gzip"), "gzip"),
+        ("zstd", None, "console.log('Hello')"),
+        ("identity", None, "console.log('Hello')"),
+    ] {
+        let request = Request::builder()
+            .uri("/app.js")
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, body) = send_http_request(addr, request).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK);
+        assert_eq!(parts.headers[header::VARY], "Accept-Encoding");
+        assert_eq!(
+            parts.headers[header::CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            parts
+                .headers
+                .get(header::CONTENT_ENCODING)
+                .map(|v| v.to_str().unwrap()),
+            content_encoding
+        );
+        assert_eq!(
+            parts.headers[header::CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+        assert_eq!(body, expected);
+    }
+}
+
+#[tokio::test]
+async fn on_the_fly_compression() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = "All work and no play makes Jack a dull boy.\n".repeat(100);
+    tokio::fs::write(dir.path().join("large.txt"), &text)
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("small.txt"), "Hello")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("image.png"), &text)
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/large.txt")).await;
+    let identity_etag = parts.headers[header::ETAG].to_str().unwrap().to_owned();
+
+    for encoding in ["br", "zstd", "gzip"] {
+        let request = Request::builder()
+            .uri("/large.txt")
+            .header(header::ACCEPT_ENCODING, encoding)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, body) = send_http_request(addr, request).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK);
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], encoding);
+        assert_eq!(parts.headers[header::VARY], "Accept-Encoding");
+        assert!(!parts.headers.contains_key(header::CONTENT_LENGTH));
+        assert!(body.len() < text.len());
+        assert_eq!(decode(encoding, &body).await, text.as_bytes());
+
+        // Each coding is a different representation.
+        let etag = parts.headers[header::ETAG].to_str().unwrap();
+        assert_ne!(etag, identity_etag);
+        assert!(etag.ends_with(&format!("-{encoding}\"")));
+
+        let request = Request::builder()
+            .uri("/large.txt")
+            .header(header::ACCEPT_ENCODING, encoding)
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let (parts, _) = send_http_request(addr, request).await;
+        assert_eq!(parts.status, http::StatusCode::NOT_MODIFIED);
+    }
+
+    // Small files, binary files and range requests are not compressed.
+    for (uri, range) in [
+        ("/small.txt", None),
+        ("/image.png", None),
+        ("/large.txt", Some("bytes=0-9")),
+    ] {
+        let mut request = Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, "gzip");
+
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+
+        let (parts, _) =
+            send_http_request(addr, request.body(Empty::<Bytes>::new()).unwrap()).await;
+
+        assert!(parts.status.is_success());
+        assert!(
+            !parts.headers.contains_key(header::CONTENT_ENCODING),
+            "{uri}"
+        );
+        assert!(parts.headers.contains_key(header::CONTENT_LENGTH), "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn directory_index_files() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::create_dir(dir.path().join("sub")).await.unwrap();
+    tokio::fs::create_dir(dir.path().join("empty"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("index.html"), "Root index")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("sub/index.html"), "Sub index")
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    for (uri, expected) in [("/", "Root index"), ("/sub/", "Sub index")] {
+        let (parts, body) = send_http_request(addr, request::empty_with_uri(uri)).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK);
+        assert_eq!(body, expected);
+    }
+
+    // Directories are redirected to their canonical URI so that relative
+    // links in the index file work.
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/sub?page=2")).await;
+    assert_eq!(parts.status, http::StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(parts.headers[header::LOCATION], "/sub/?page=2");
+
+    // No index file and no autoindex.
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/empty/")).await;
+    assert_eq!(parts.status, http::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn autoindex_listings() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::create_dir(dir.path().join("docs"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("notes <1>.txt"), "Hello")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join(".secret"), "Hidden")
+        .await
+        .unwrap();
+
+    let mut files = config::files::root(dir.path().to_str().unwrap());
+    files.autoindex = true;
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_files(files));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, body) = send_http_request(addr, request::empty_with_uri("/")).await;
+    let html = String::from_utf8(body.to_vec()).unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(
+        parts.headers[header::CONTENT_TYPE],
+        "text/html; charset=utf-8"
+    );
+    assert!(html.contains("<a href=\"docs/\">docs/</a>"));
+    assert!(html.contains("<a href=\"notes%20%3C1%3E.txt\">notes &lt;1&gt;.txt</a>"));
+    assert!(!html.contains(".secret"));
+    assert!(html.find("docs/").unwrap() < html.find("notes").unwrap());
+
+    let request = Request::builder()
+        .uri("/")
+        .header(header::ACCEPT, "text/html;q=0.5, application/json")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(parts.status, http::StatusCode::OK);
+    assert_eq!(parts.headers[header::CONTENT_TYPE], "application/json");
+    assert_eq!(parts.headers[header::VARY], "Accept");
+    assert_eq!(listing.as_array().unwrap().len(), 2);
+    assert_eq!(listing[0]["name"], "docs");
+    assert_eq!(listing[0]["type"], "directory");
+    assert_eq!(listing[0]["size"], serde_json::Value::Null);
+    assert_eq!(listing[1]["name"], "notes <1>.txt");
+    assert_eq!(listing[1]["type"], "file");
+    assert_eq!(listing[1]["size"], 5);
+    assert!(listing[1]["modified"].as_str().unwrap().ends_with("GMT"));
+
+    // Hidden files are not listed but can still be requested directly.
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/.secret")).await;
+    assert_eq!(parts.status, http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn media_types() {
+    let dir = tempfile::tempdir().unwrap();
+
+    for file in [
+        "logo.svg",
+        "app.wasm",
+        "data.json",
+        "photo.JPG",
+        "notes.md",
+        "blob",
+    ] {
+        tokio::fs::write(dir.path().join(file), "content")
+            .await
+            .unwrap();
+    }
+
+    let mut config = config::files::serve(dir.path().to_str().unwrap());
+    config.nosniff = true;
+    config.mime_types = HashMap::from([(
+        String::from("md"),
+        String::from("text/plain; charset=iso-8859-1"),
+    )]);
+
+    let (addr, _) = spawn_reverse_proxy(config);
+
+    ping_tcp_server(addr).await;
+
+    for (uri, expected) in [
+        ("/logo.svg", "image/svg+xml; charset=utf-8"),
+        ("/app.wasm", "application/wasm"),
+        ("/data.json", "application/json; charset=utf-8"),
+        ("/photo.JPG", "image/jpeg"),
+        ("/notes.md", "text/plain; charset=iso-8859-1"),
+        ("/blob", "application/octet-stream"),
+    ] {
+        let (parts, _) = send_http_request(addr, request::empty_with_uri(uri)).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK);
+        assert_eq!(parts.headers[header::CONTENT_TYPE], expected, "{uri}");
+        assert_eq!(parts.headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    }
+}
+
+#[tokio::test]
+async fn try_files_single_page_app() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::create_dir(dir.path().join("docs"))
+        .await
+        .unwrap();
+    tokio::fs::create_dir(dir.path().join("empty"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("index.html"), "App")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("app.js"), "console.log('App')")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("docs/index.html"), "Docs")
+        .await
+        .unwrap();
+
+    let mut files = config::files::root(dir.path().to_str().unwrap());
+    files.try_files = ["$uri", "$uri/", "/index.html"].map(String::from).to_vec();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_files(files));
+
+    ping_tcp_server(addr).await;
+
+    for (uri, expected) in [
+        ("/app.js", "console.log('App')"),
+        ("/docs", "Docs"),
+        ("/docs/", "Docs"),
+        ("/", "App"),
+        ("/users/1/settings", "App"),
+        ("/app.js/", "App"),
+        ("/empty/", "App"),
+    ] {
+        let (parts, body) = send_http_request(addr, request::empty_with_uri(uri)).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK, "{uri}");
+        assert_eq!(body, expected, "{uri}");
+    }
+
+    // Fallback status code.
+    let mut files = config::files::root(dir.path().to_str().unwrap());
+    files.try_files = vec![String::from("$uri")];
+    files.fallback = Fallback::Status(http::StatusCode::GONE);
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_files(files));
+
+    ping_tcp_server(addr).await;
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/missing")).await;
+    assert_eq!(parts.status, http::StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn try_files_fallback_forward() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::write(dir.path().join("style.css"), "body {}")
+        .await
+        .unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|request: Request<_>| async move {
+        let body = format!("Backend {}", request.uri());
+        Ok(Response::new(Full::<Bytes>::from(body)))
+    }));
+
+    let mut files = config::files::root(dir.path().to_str().unwrap());
+    files.try_files = vec![String::from("$uri")];
+    files.fallback = config::files::fallback_to(server_addr);
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_files(files));
+
+    ping_tcp_server(addr).await;
+
+    for (uri, expected) in [
+        ("/style.css", "body {}"),
+        ("/api/users?page=2", "Backend /api/users?page=2"),
+    ] {
+        let (parts, body) = send_http_request(addr, request::empty_with_uri(uri)).await;
+
+        assert_eq!(parts.status, http::StatusCode::OK, "{uri}");
+        assert_eq!(body, expected, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn percent_encoded_file_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::create_dir(dir.path().join("my dir"))
+        .await
+        .unwrap();
+    tokio::fs::create_dir(dir.path().join("sub")).await.unwrap();
+    tokio::fs::write(dir.path().join("my file.txt"), "Spaces")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("café.txt"), "Unicode")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("sub/file.txt"), "Sub")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("my dir/index.html"), "Index")
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(dir.path().to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    for (target, expected) in [
+        ("/my%20file.txt", "Spaces"),
+        ("/caf%C3%A9.txt", "Unicode"),
+        ("/my%20dir/", "Index"),
+        ("//sub///file.txt", "Sub"),
+        ("/sub/./file.txt", "Sub"),
+        ("/sub%2Ffile.txt", "Sub"),
+        ("/sub/../my%20file.txt", "Spaces"),
+    ] {
+        let (status, body) = send_raw_http_request(addr, target).await;
+
+        assert_eq!(status, 200, "{target}");
+        assert_eq!(body, expected.as_bytes(), "{target}");
+    }
+
+    let (parts, _) = send_http_request(addr, request::empty_with_uri("/my%20dir")).await;
+    assert_eq!(parts.status, http::StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(parts.headers[header::LOCATION], "/my%20dir/");
+}
+
+#[tokio::test]
+async fn static_files_path_traversal() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("public");
+    tokio::fs::create_dir_all(root.join("sub")).await.unwrap();
+    tokio::fs::write(root.join("index.html"), "Public")
+        .await
+        .unwrap();
+    tokio::fs::write(root.join("sub/file.txt"), "Sub")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("secret.txt"), "Secret")
+        .await
+        .unwrap();
+    tokio::fs::symlink(dir.path().join("secret.txt"), root.join("link.txt"))
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve(root.to_str().unwrap()));
+
+    ping_tcp_server(addr).await;
+
+    // Dot segments, encoded or not, never go above the root.
+    for target in [
+        "/../secret.txt",
+        "/sub/../../secret.txt",
+        "/%2e%2e/secret.txt",
+        "/%2E%2E%2Fsecret.txt",
+        "/sub/..%2f..%2fsecret.txt",
+        "/sub/%2e%2e/%2e%2e/%2e%2e/secret.txt",
+        "/.%2e/.%2e/.%2e/etc/passwd",
+        "/link.txt",
+    ] {
+        let (status, body) = send_raw_http_request(addr, target).await;
+
+        assert_eq!(status, 404, "{target}");
+        assert!(!body.windows(6).any(|w| w == b"Secret"), "{target}");
+    }
+
+    // Encoded NUL bytes and backslashes are rejected.
+    for target in [
+        "/index.html%00.txt",
+        "/%00",
+        "/..%5csecret.txt",
+        "/sub%5C..%5C..%5Csecret.txt",
+        "/..\\secret.txt",
+    ] {
+        let (status, _) = send_raw_http_request(addr, target).await;
+
+        assert_eq!(status, 400, "{target}");
+    }
+}
+
+#[tokio::test]
+async fn dot_segments_do_not_escape_patterns() {
+    let dir = tempfile::tempdir().unwrap();
+    tokio::fs::create_dir(dir.path().join("public"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("public/index.html"), "Public")
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("private.txt"), "Private")
+        .await
+        .unwrap();
+
+    let (addr, _) = spawn_reverse_proxy(config::files::serve_at_uri(
+        dir.path().to_str().unwrap(),
+        "/public",
+    ));
+
+    ping_tcp_server(addr).await;
+
+    let (status, body) = send_raw_http_request(addr, "/public/index.html").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"Public");
+
+    for target in [
+        "/public/../private.txt",
+        "/public/%2e%2e/private.txt",
+        "/public/..%2Fprivate.txt",
+    ] {
+        let (status, _) = send_raw_http_request(addr, target).await;
+
+        assert_eq!(status, 404, "{target}");
+    }
+}
+
+#[tokio::test]
+async fn multi_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut file = tokio::fs::File::create(dir.path().join("test.txt"))
+        .await
+        .unwrap();
+    file.write_all(b"Hello World File").await.unwrap();
+
+    let (server_addr, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello World Response")))
+    }));
+
+    let config = rxh::config::Config {
+        servers: vec![
+            config::proxy::single_backend_with_uri(server_addr, "/api"),
+            config::files::serve(dir.path().to_str().unwrap()),
+        ],
+    };
+
+    let (sockets, _) = spawn_master(config);
+
+    ping_all(&sockets).await;
+
+    let (_, api_req_body) = send_http_request(sockets[0], request::empty_with_uri("/api")).await;
+    let (_, file_req_body) =
+        send_http_request(sockets[1], request::empty_with_uri("/test.txt")).await;
+
+    assert_eq!(api_req_body, &"Hello World Response");
+    assert_eq!(file_req_body, &"Hello World File");
+}
+
+#[tokio::test]
+async fn hot_reload() {
+    let (first_backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("First")))
+    }));
+
+    let (second_backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Second")))
+    }));
+
+    // Replicas are matched by address, so we need fixed ports.
+    let (_, kept_addr) = usable_socket();
+    let (_, removed_addr) = usable_socket();
+
+    let mut kept = config::proxy::single_backend(first_backend);
+    kept.listen = vec![kept_addr.into()];
+
+    let mut removed = config::proxy::single_backend(first_backend);
+    removed.listen = vec![removed_addr.into()];
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![kept.clone(), removed],
+    });
+
+    ping_all(&sockets).await;
+
+    // This connection should stay open after reloading.
+    let mut sender = http_client(TcpStream::connect(kept_addr).await.unwrap()).await;
+    let response = sender.send_request(request::empty()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "First");
+
+    let mut kept = config::proxy::single_backend(second_backend);
+    kept.listen = vec![kept_addr.into()];
+
+    let sockets = reload
+        .reload(rxh::config::Config {
+            servers: vec![kept, config::proxy::single_backend(second_backend)],
+        })
+        .await
+        .unwrap();
+    let sockets = tcp_sockets(sockets);
+
+    assert_eq!(sockets.len(), 2);
+    assert_eq!(sockets[0], kept_addr);
+
+    let response = sender.send_request(request::empty()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "Second");
+
+    ping_tcp_server(sockets[1]).await;
+    let (_, body) = send_http_request(sockets[1], request::empty()).await;
+    assert_eq!(body, "Second");
+
+    // The removed server stops listening as soon as it gets the signal.
+    for _ in 0..10 {
+        if TcpStream::connect(removed_addr).await.is_err() {
+            return;
+        }
+        tokio::task::yield_now().await;
+    }
+
+    panic!("Removed server is still listening on {removed_addr}");
+}
+
+#[tokio::test]
+async fn hot_reload_rejects_invalid_config() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (_, listen) = usable_socket();
+
+    let mut server = config::proxy::single_backend(backend);
+    server.listen = vec![listen.into()];
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![server.clone()],
+    });
+
+    ping_all(&sockets).await;
+
+    // Address already in use by another process.
+    let (_busy_listener, busy_addr) = usable_tcp_listener();
+    let mut conflict = config::proxy::single_backend(backend);
+    conflict.listen = vec![busy_addr.into()];
+
+    let result = reload
+        .reload(rxh::config::Config {
+            servers: vec![server.clone(), conflict],
+        })
+        .await;
+    assert!(result.is_err());
+
+    // Same address used twice.
+    let result = reload
+        .reload(rxh::config::Config {
+            servers: vec![server.clone(), server],
+        })
+        .await;
+    assert!(result.is_err());
+
+    // The previous configuration is still running.
+    let (_, body) = send_http_request(listen, request::empty()).await;
+    assert_eq!(body, "Hello world");
+}
+
+#[tokio::test]
+async fn server_adopts_existing_listener() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (listener, addr) = usable_tcp_listener();
+
+    let server = rxh::Server::adopt(config::proxy::single_backend(backend), listener).unwrap();
+    assert_eq!(server.socket_address(), addr.into());
+
+    tokio::task::spawn(server.run());
+
+    let (_, body) = send_http_request(addr, request::empty()).await;
+    assert_eq!(body, "Hello world");
+}
+
+#[tokio::test]
+async fn server_from_std_listener() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server =
+        rxh::Server::from_std_listener(config::proxy::single_backend(backend), listener).unwrap();
+
+    tokio::task::spawn(server.run());
+
+    let (_, body) = send_http_request(addr, request::empty()).await;
+    assert_eq!(body, "Hello world");
+}
+
+#[tokio::test]
+async fn unix_socket_listener() {
+    let (backend, _) = spawn_backend_server(service_fn(|request: Request<_>| async move {
+        let forwarded = request.headers()[header::FORWARDED].clone();
+        Ok(Response::new(Full::<Bytes>::from(
+            forwarded.as_bytes().to_vec(),
+        )))
+    }));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("rxh.sock");
+
+    // Leftover socket file of a process that didn't clean up.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let mut config = config::proxy::single_backend(backend);
+    config.listen = vec![Address::Unix(path.clone())];
+
+    let server = rxh::Server::init(config, 0).unwrap();
+    assert_eq!(server.socket_address(), Address::Unix(path.clone()));
+    tokio::task::spawn(server.run());
+
+    let mut sender = http_client(UnixStream::connect(&path).await.unwrap()).await;
+    let response = sender.send_request(request::empty()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+
+    assert_eq!(body, "for=unknown;by=unknown;host=localhost");
+}
+
+#[tokio::test]
+async fn unix_socket_listener_does_not_remove_other_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("data.txt");
+    let link = dir.path().join("link.sock");
+
+    std::fs::write(&file, "Important").unwrap();
+    std::os::unix::fs::symlink(&file, &link).unwrap();
+
+    for path in [&file, &link] {
+        let mut config = config::proxy::single_backend(usable_socket().1);
+        config.listen = vec![Address::Unix(path.clone())];
+
+        let err = rxh::Server::init(config, 0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse, "{}", path.display());
+    }
+
+    assert!(std::fs::symlink_metadata(&link).unwrap().is_symlink());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "Important");
+}
+
+#[tokio::test]
+async fn unix_socket_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("backend.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::task::spawn(serve_connection(
+                stream,
+                service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello world"))) }),
+            ));
+        }
+    });
+
+    let (proxy, _) =
+        spawn_reverse_proxy(config::proxy::multiple_weighted_backends(vec![Backend {
+            address: Address::Unix(path),
+            weight: 1,
+            tls: None,
+            send_proxy_protocol: None,
+        }]));
+
+    let (_, body) = send_http_request(proxy, request::empty()).await;
+    assert_eq!(body, "Hello world");
+}
+
+#[tokio::test]
+async fn reuse_port_accept_loops() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let mut server = config::proxy::single_backend(backend);
+    server.socket.reuse_port = Some(4);
+    server.socket.backlog = 128;
+    server.socket.accepted.tcp_nodelay = true;
+    server.socket.upstream.tcp_nodelay = true;
+    server.socket.upstream.keepalive = Some(Keepalive {
+        time: Some(Duration::from_secs(30)),
+        interval: None,
+        retries: None,
+    });
+
+    let (sockets, _) = spawn_master(rxh::config::Config {
+        servers: vec![server],
+    });
+
+    // All the accept loops share the port assigned to the first one.
+    assert_eq!(sockets.len(), 4);
+    assert!(sockets.iter().all(|socket| *socket == sockets[0]));
+
+    for _ in 0..10 {
+        let (_, body) = send_http_request(sockets[0], request::empty()).await;
+        assert_eq!(body, "Hello world");
+    }
+}
+
+#[tokio::test]
+async fn hot_reload_keeps_servers_that_cant_be_replaced() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (_, listen) = usable_socket();
+
+    let mut server = config::proxy::single_backend(backend);
+    server.listen = vec![listen.into()];
+    server.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![server.clone()],
+    });
+
+    ping_all(&sockets).await;
+
+    // Enabling HTTP/3 replaces the server, but the UDP port is taken.
+    let busy = std::net::UdpSocket::bind(listen).unwrap();
+    server.http3 = Some(Http3::default());
+
+    let result = reload
+        .reload(rxh::config::Config {
+            servers: vec![server.clone()],
+        })
+        .await;
+    assert!(result.is_err());
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (parts, body) = send_https_request(listen, "localhost", client_config, request::empty())
+        .await
+        .unwrap();
+    assert_eq!(body, "Hello world");
+    assert!(!parts.headers.contains_key(header::ALT_SVC));
+
+    // Same thing once the port is free.
+    drop(busy);
+
+    let sockets = reload
+        .reload(rxh::config::Config {
+            servers: vec![server],
+        })
+        .await
+        .unwrap();
+    assert_eq!(tcp_sockets(sockets), vec![listen]);
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (parts, body) = send_https_request(listen, "localhost", client_config, request::empty())
+        .await
+        .unwrap();
+    assert_eq!(body, "Hello world");
+    assert!(parts.headers.contains_key(header::ALT_SVC));
+}
+
+#[tokio::test]
+async fn hot_reload_changes_accept_loops() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (_, listen) = usable_socket();
+
+    let mut server = config::proxy::single_backend(backend);
+    server.listen = vec![listen.into()];
+    server.socket.reuse_port = Some(2);
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![server.clone()],
+    });
+
+    assert_eq!(sockets.len(), 2);
+    ping_all(&sockets).await;
+
+    // Existing sockets have SO_REUSEPORT, so more can be bound right away.
+    server.socket.reuse_port = Some(3);
+    let sockets = reload
+        .reload(rxh::config::Config {
+            servers: vec![server.clone()],
+        })
+        .await
+        .unwrap();
+    assert_eq!(tcp_sockets(sockets), vec![listen; 3]);
+
+    // SO_REUSEPORT is disabled on the socket that keeps running.
+    server.socket.reuse_port = None;
+    let sockets = reload
+        .reload(rxh::config::Config {
+            servers: vec![server],
+        })
+        .await
+        .unwrap();
+    assert_eq!(tcp_sockets(sockets), vec![listen]);
+
+    let (_, body) = send_http_request(listen, request::empty()).await;
+    assert_eq!(body, "Hello world");
+}
+
+#[tokio::test]
+async fn http3_with_reuse_port_accept_loops() {
+    let ca = TestCa::new();
+    let proxy_cert = ca.issue("localhost");
+
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (_, listen) = usable_socket();
+
+    let mut server = config::proxy::single_backend(backend);
+    server.listen = vec![listen.into()];
+    server.socket.reuse_port = Some(2);
+    server.tls = Some(ServerTls::new(proxy_cert.cert, proxy_cert.key, None).unwrap());
+    server.http3 = Some(Http3::default());
+
+    // Only the first accept loop binds the UDP socket.
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![server.clone()],
+    });
+
+    assert_eq!(sockets, vec![listen; 2]);
+    ping_all(&sockets).await;
+
+    let request = || {
+        Request::builder()
+            .uri(format!("https://localhost:{}/", listen.port()))
+            .body(())
+            .unwrap()
+    };
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    let (_, body) = send_http3_request(listen, "localhost", client_config, request())
+        .await
+        .unwrap();
+    assert_eq!(body, "Hello world");
+
+    // The running sockets are reconfigured, so the UDP socket stays where it
+    // is, whether accept loops are added or removed.
+    for reuse_port in [Some(3), None, Some(2)] {
+        server.socket.reuse_port = reuse_port;
+        let sockets = reload
+            .reload(rxh::config::Config {
+                servers: vec![server.clone()],
+            })
+            .await
+            .unwrap();
+        assert_eq!(tcp_sockets(sockets), vec![listen; reuse_port.unwrap_or(1)]);
+
+        let client_config = tls::client_config(&ca.cert_path(), None);
+        let (parts, _) = send_https_request(listen, "localhost", client_config, request::empty())
+            .await
+            .unwrap();
+        assert!(parts.headers.contains_key(header::ALT_SVC));
+
+        let client_config = tls::client_config(&ca.cert_path(), None);
+        let (_, body) = send_http3_request(listen, "localhost", client_config, request())
+            .await
+            .unwrap();
+        assert_eq!(body, "Hello world");
+    }
+}
+
+#[tokio::test]
+async fn binary_upgrade_hands_off_listeners() {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Command, Stdio},
+    };
+
+    use nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    };
+
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let (_, listen) = usable_socket();
+    let dir = tempfile::tempdir().unwrap();
+
+    std::fs::write(
+        dir.path().join("rxh.toml"),
+        format!("[[server]]\nlisten = \"{listen}\"\nforward = \"{backend}\"\n"),
+    )
+    .unwrap();
+
+    let mut old = Command::new(env!("CARGO_BIN_EXE_rxh"))
+        .current_dir(dir.path())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The new process writes to the same pipe.
+    let (sender, mut logs) = mpsc::unbounded_channel();
+    let stdout = BufReader::new(old.stdout.take().unwrap());
+    std::thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            let _ = sender.send(line);
+        }
+    });
+
+    let mut wait_for_log = async |pattern: &str| loop {
+        let line = tokio::time::timeout(Duration::from_secs(10), logs.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        if line.contains(pattern) {
+            break line;
+        }
+    };
+
+    wait_for_log("Listening for requests").await;
+    let (_, body) = send_http_request(listen, request::empty()).await;
+    assert_eq!(body, "Hello world");
+
+    kill(Pid::from_raw(old.id() as i32), Signal::SIGUSR2).unwrap();
+
+    let ready = wait_for_log("is ready, shutting down").await;
+    let new: i32 = ready
+        .split_whitespace()
+        .find_map(|word| word.parse().ok())
+        .unwrap();
+
+    let status = tokio::task::spawn_blocking(move || old.wait().unwrap())
+        .await
+        .unwrap();
+    assert!(status.success());
+
+    // Only the new process is left.
+    let (_, body) = send_http_request(listen, request::empty()).await;
+    assert_eq!(body, "Hello world");
+
+    // The pipe is closed once the new process exits as well.
+    kill(Pid::from_raw(new), Signal::SIGINT).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while logs.recv().await.is_some() {}
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn proxy_protocol_listener() {
+    let (backend, _) = spawn_backend_server(service_fn(|request: Request<_>| async move {
+        let forwarded = request.headers()[header::FORWARDED].clone();
+        Ok(Response::new(Full::<Bytes>::from(
+            forwarded.as_bytes().to_vec(),
+        )))
+    }));
+
+    let mut config = config::proxy::single_backend(backend);
+    config.proxy_protocol = Some(ProxyProtocol {
+        trusted: vec!["127.0.0.0/8".parse().unwrap()],
+    });
+
+    let (proxy, _) = spawn_reverse_proxy(config);
+
+    let mut v2 = Vec::from(*b"\r\n\r\n\0\r\nQUIT\n");
+    v2.extend_from_slice(&[0x21, 0x21, 0, 36]);
+    v2.extend_from_slice(
+        &"2001:db8::7"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    v2.extend_from_slice(
+        &"2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    v2.extend_from_slice(&[0x0F, 0xA0, 0x01, 0xBB]);
+
+    let headers = [
+        (
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 4000 443\r\n".to_vec(),
+            "for=203.0.113.7:4000",
+        ),
+        (v2, "for=[2001:db8::7]:4000"),
+        (b"PROXY UNKNOWN\r\n".to_vec(), "for=127.0.0.1:"),
+    ];
+
+    for (header, expected) in headers {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&header).await.unwrap();
+
+        let mut sender = http_client(stream).await;
+        let response = sender.send_request(request::empty()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert!(
+            body.starts_with(expected.as_bytes()),
+            "{body:?} doesn't start with {expected}"
+        );
+    }
+
+    // Clients that don't send the header can't get in.
+    let mut sender = http_client(TcpStream::connect(proxy).await.unwrap()).await;
+    assert!(sender.send_request(request::empty()).await.is_err());
+}
+
+#[tokio::test]
+async fn proxy_protocol_rejects_untrusted_sources() {
+    let (backend, _) = spawn_backend_server(service_fn(|_| async {
+        Ok(Response::new(Full::<Bytes>::from("Hello world")))
+    }));
+
+    let mut config = config::proxy::single_backend(backend);
+    config.proxy_protocol = Some(ProxyProtocol {
+        trusted: vec!["10.0.0.0/8".parse().unwrap()],
+    });
+
+    let (proxy, _) = spawn_reverse_proxy(config);
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 4000 443\r\n")
+        .await
+        .unwrap();
+
+    let mut sender = http_client(stream).await;
+    assert!(sender.send_request(request::empty()).await.is_err());
+}
+
+#[tokio::test]
+async fn proxy_protocol_trusts_loopback_by_default() {
+    let (backend, _) = spawn_backend_server(service_fn(|request: Request<_>| async move {
+        let forwarded = request.headers()[header::FORWARDED].clone();
+        Ok(Response::new(Full::<Bytes>::from(
+            forwarded.as_bytes().to_vec(),
+        )))
+    }));
+
+    let parse = |proxy_protocol: &str| {
+        toml::from_str::<rxh::config::Config>(&format!(
+            "[[server]]\nlisten = \"127.0.0.1:0\"\nforward = \"{backend}\"\nproxy_protocol = {proxy_protocol}"
+        ))
+    };
+
+    // Trusting nobody would reject every connection.
+    assert!(parse("{ trusted = [] }").is_err());
+
+    let config = parse("true").unwrap();
+    let (proxy, _) = spawn_reverse_proxy(config.servers.into_iter().next().unwrap());
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 4000 443\r\n")
+        .await
+        .unwrap();
+
+    let mut sender = http_client(stream).await;
+    let response = sender.send_request(request::empty()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.starts_with(b"for=203.0.113.7:4000"), "{body:?}");
+}
+
+#[tokio::test]
+async fn send_proxy_protocol_to_backend() {
+    let (listener, backend) = usable_tcp_listener();
+    let (headers_sender, mut headers) = mpsc::unbounded_channel();
+
+    tokio::task::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut header = Vec::new();
+            while !header.ends_with(b"\r\n") {
+                header.push(stream.read_u8().await.unwrap());
+            }
+
+            headers_sender
+                .send(String::from_utf8(header).unwrap())
+                .unwrap();
+
+            tokio::task::spawn(serve_connection(
+                stream,
+                service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello world"))) }),
+            ));
+        }
+    });
+
+    let (proxy, _) =
+        spawn_reverse_proxy(config::proxy::multiple_weighted_backends(vec![Backend {
+            address: backend.into(),
+            weight: 1,
+            tls: None,
+            send_proxy_protocol: Some(ProxyProtocolVersion::V1),
+        }]));
+
+    ping_tcp_server(proxy).await;
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let client = stream.local_addr().unwrap();
+
+    let mut sender = http_client(stream).await;
+    let response = sender.send_request(request::empty()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "Hello world");
+
+    assert_eq!(
+        headers.recv().await.unwrap(),
+        format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            client.ip(),
+            proxy.ip(),
+            client.port(),
+            proxy.port()
+        )
+    );
+}
+
+#[tokio::test]
+async fn tcp_proxy_load_balancing() {
+    let backends = [spawn_echo_server(b"one"), spawn_echo_server(b"two")];
+
+    let (proxy, _) = spawn_reverse_proxy(config::tcp::forward(&backends));
+
+    ping_all(&[backends[0], backends[1], proxy]).await;
+
+    let mut greetings = Vec::new();
+
+    for _ in 0..4 {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        greetings.push(greeting);
+
+        // Bytes are not parsed as HTTP, they go straight to the backend.
+        stream.write_all(b"PING\r\n").await.unwrap();
+        let mut echo = [0; 6];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"PING\r\n");
+    }
+
+    // Pinging the proxy took one turn already, but both backends still get
+    // the same number of connections.
+    assert_eq!(
+        greetings
+            .iter()
+            .filter(|greeting| greeting == &b"one")
+            .count(),
+        2
+    );
+    assert_eq!(
+        greetings
+            .iter()
+            .filter(|greeting| greeting == &b"two")
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn tcp_proxy_graceful_shutdown() {
+    let backend = spawn_echo_server(b"hello");
+
+    let (proxy, _, shutdown, mut state) =
+        spawn_reverse_proxy_with_controllers(config::tcp::forward(&[backend]));
+
+    ping_all(&[backend, proxy]).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut greeting = [0; 5];
+    stream.read_exact(&mut greeting).await.unwrap();
+
+    shutdown();
+
+    // Raw streams can't be told apart from idle ones, so the connection is
+    // kept open until the client is done.
+    state
+        .wait_for(|state| *state == State::ShuttingDown(ShutdownState::PendingConnections(1)))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        TcpStream::connect(proxy).await.err().unwrap().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+
+    stream.write_all(b"still here").await.unwrap();
+    let mut echo = [0; 10];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"still here");
+
+    drop(stream);
+
+    state
+        .wait_for(|state| *state == State::ShuttingDown(ShutdownState::Done))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn tcp_proxy_shutdown_timeout() {
+    let backend = spawn_echo_server(b"hello");
+
+    let mut config = config::tcp::forward(&[backend]);
+    config.shutdown_timeout = Duration::from_millis(100);
+
+    let (proxy, _, shutdown, mut state) = spawn_reverse_proxy_with_controllers(config);
+
+    ping_all(&[backend, proxy]).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut greeting = [0; 5];
+    stream.read_exact(&mut greeting).await.unwrap();
+
+    shutdown();
+
+    let done = state.wait_for(|state| *state == State::ShuttingDown(ShutdownState::Done));
+    tokio::time::timeout(Duration::from_secs(5), done)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut buff = [0; 1024];
+    assert_eq!(stream.read(&mut buff).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn udp_proxy_load_balancing() {
+    let backends = [
+        spawn_udp_echo_server(b"one:").await,
+        spawn_udp_echo_server(b"two:").await,
+    ];
+
+    let (proxy, _) = spawn_reverse_proxy(config::udp::forward(&backends));
+
+    let mut replies = Vec::new();
+
+    for _ in 0..4 {
+        let client = udp_client().await;
+        let reply = exchange(&client, proxy, b"ping").await;
+
+        // The session sticks to the same backend.
+        assert_eq!(exchange(&client, proxy, b"again").await[..4], reply[..4]);
+
+        replies.push(reply);
+    }
+
+    assert_eq!(replies, [
+        b"one:ping",
+        b"two:ping",
+        b"one:ping",
+        b"two:ping"
+    ]);
+}
+
+#[tokio::test]
+async fn udp_session_expires_when_idle() {
+    let backends = [
+        spawn_udp_echo_server(b"one:").await,
+        spawn_udp_echo_server(b"two:").await,
+    ];
+
+    let mut config = config::udp::forward(&backends);
+    config.udp.session_timeout = Duration::from_millis(100);
+
+    let (proxy, _) = spawn_reverse_proxy(config);
+
+    let client = udp_client().await;
+    assert_eq!(exchange(&client, proxy, b"ping").await, b"one:ping");
+    assert_eq!(exchange(&client, proxy, b"ping").await, b"one:ping");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // The expired session is replaced by a new one that picks the next backend.
+    assert_eq!(exchange(&client, proxy, b"ping").await, b"two:ping");
+}
+
+#[tokio::test]
+async fn udp_proxy_graceful_shutdown() {
+    let backend = spawn_udp_echo_server(b"one:").await;
+
+    let mut config = config::udp::forward(&[backend]);
+    config.shutdown_timeout = Duration::from_millis(100);
+
+    let (proxy, _, shutdown, mut state) = spawn_reverse_proxy_with_controllers(config);
+
+    let client = udp_client().await;
+    assert_eq!(exchange(&client, proxy, b"ping").await, b"one:ping");
+
+    shutdown();
+
+    // Sessions can't tell when clients are done, they are closed after the
+    // shutdown timeout.
+    state
+        .wait_for(|state| *state == State::ShuttingDown(ShutdownState::PendingConnections(1)))
+        .await
+        .unwrap();
+
+    let done = state.wait_for(|state| *state == State::ShuttingDown(ShutdownState::Done));
+    tokio::time::timeout(Duration::from_secs(5), done)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn tcp_and_udp_servers_share_address() {
+    let tcp_backend = spawn_echo_server(b"tcp");
+    let udp_backend = spawn_udp_echo_server(b"udp:").await;
+
+    let (_, listen) = usable_socket();
+
+    let mut tcp = config::tcp::forward(&[tcp_backend]);
+    tcp.listen = vec![listen.into()];
+
+    let mut udp = config::udp::forward(&[udp_backend]);
+    udp.listen = vec![listen.into()];
+
+    let (sockets, _, reload) = spawn_master_with_reload_handle(rxh::config::Config {
+        servers: vec![tcp.clone(), udp.clone()],
+    });
+
+    assert_eq!(sockets, [listen, listen]);
+
+    let mut stream = TcpStream::connect(listen).await.unwrap();
+    let mut greeting = [0; 3];
+    stream.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"tcp");
+
+    let client = udp_client().await;
+    assert_eq!(exchange(&client, listen, b"ping").await, b"udp:ping");
+
+    // Both servers keep their sockets when reloading.
+    let sockets = reload
+        .reload(rxh::config::Config {
+            servers: vec![tcp, udp],
+        })
+        .await
+        .unwrap();
+    assert_eq!(sockets.len(), 2);
+
+    assert_eq!(exchange(&client, listen, b"pong").await, b"udp:pong");
+}
+
+#[tokio::test]
+async fn tls_passthrough_routes_by_sni() {
+    let ca = TestCa::new();
+
+    let mut backends = Vec::new();
+
+    for name in ["api.example.com", "www.example.com", "other.test"] {
+        let service =
+            service_fn(move |_| async move { Ok(Response::new(Full::<Bytes>::from(name))) });
+        let config = tls::server_config(&ca.issue(name), None);
+        backends.push(spawn_tls_backend_server(service, config));
+    }
+
+    let (proxy, _) = spawn_reverse_proxy(config::tls_passthrough::routes(&[
+        (Some("api.example.com"), backends[0]),
+        (Some("*.example.com"), backends[1]),
+        (None, backends[2]),
+    ]));
+
+    ping_all(&[backends[0], backends[1], backends[2], proxy]).await;
+
+    // Certificates are presented by the backends, the proxy only routes.
+    for (server_name, expected) in [
+        ("api.example.com", "api.example.com"),
+        ("API.Example.Com", "api.example.com"),
+        ("www.example.com", "www.example.com"),
+        ("other.test", "other.test"),
+    ] {
+        let client_config = tls::client_config(&ca.cert_path(), None);
+        let (parts, body) = send_https_request(proxy, server_name, client_config, request::empty())
+            .await
+            .unwrap();
+
+        assert_eq!(parts.status, http::StatusCode::OK);
+        assert_eq!(body, expected);
+    }
+}
+
+#[tokio::test]
+async fn tls_passthrough_closes_unmatched_connections() {
+    let ca = TestCa::new();
+
+    let backend = spawn_tls_backend_server(
+        service_fn(|_| async { Ok(Response::new(Full::<Bytes>::from("Hello world"))) }),
+        tls::server_config(&ca.issue("api.example.com"), None),
+    );
+
+    let (proxy, _) = spawn_reverse_proxy(config::tls_passthrough::routes(&[(
+        Some("api.example.com"),
+        backend,
+    )]));
+
+    ping_all(&[backend, proxy]).await;
+
+    let client_config = tls::client_config(&ca.cert_path(), None);
+    assert!(tls_connect(proxy, "www.example.com", client_config)
+        .await
+        .is_err());
+
+    // Plain text is not a ClientHello.
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut buff = [0; 1024];
+    assert_eq!(stream.read(&mut buff).await.unwrap(), 0);
 }
@@ -0,0 +1,594 @@
+//! Stand-in ACME server for integration tests. It works like a tiny version of
+//! [Pebble](https://github.com/letsencrypt/pebble): signatures and nonces are
+//! verified, challenges are validated by connecting to the proxy and
+//! certificates are signed by a [`TestCa`]. Everything is served over plain
+//! HTTP to keep things simple.
+
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, header, service::service_fn, Method, Request, Response, StatusCode};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig,
+    DigitallySignedStruct,
+    SignatureScheme,
+};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{
+    http::send_http_request,
+    service::serve_connection,
+    tcp::usable_tcp_listener,
+    tls::TestCa,
+};
+
+/// OID of the `acmeIdentifier` extension used by `tls-alpn-01`.
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// Handle to a running stand-in ACME server.
+pub struct AcmeServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl AcmeServer {
+    /// URL of the directory resource.
+    pub fn directory_url(&self) -> String {
+        format!("http://{}/dir", self.addr)
+    }
+
+    /// Challenges are validated by connecting to `addr` instead of resolving
+    /// the domain, since all the domains are fake.
+    pub fn validate_at(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().validation_addr = Some(addr);
+    }
+
+    /// Number of certificates issued so far.
+    pub fn issued(&self) -> usize {
+        self.state.lock().unwrap().issued
+    }
+}
+
+/// Everything the server knows about.
+struct State {
+    ca: Arc<TestCa>,
+    base: String,
+    validation_addr: Option<SocketAddr>,
+    nonces: HashSet<String>,
+    next_nonce: usize,
+    rejected_first_nonce: bool,
+    accounts: Vec<Value>,
+    orders: Vec<Order>,
+    authorizations: Vec<Authorization>,
+    issued: usize,
+}
+
+/// Payload, key and account of a request with a valid signature.
+type Verified = (Option<Value>, Value, Option<usize>);
+
+struct Order {
+    authorizations: Vec<usize>,
+    certificate: Option<String>,
+}
+
+struct Authorization {
+    domain: String,
+    token: String,
+    status: &'static str,
+    error: Option<String>,
+}
+
+/// Starts a stand-in ACME server that issues certificates signed by `ca`.
+pub fn spawn_acme_server(ca: Arc<TestCa>) -> AcmeServer {
+    let (listener, addr) = usable_tcp_listener();
+
+    let state = Arc::new(Mutex::new(State {
+        ca,
+        base: format!("http://{addr}"),
+        validation_addr: None,
+        nonces: HashSet::new(),
+        next_nonce: 0,
+        rejected_first_nonce: false,
+        accounts: Vec::new(),
+        orders: Vec::new(),
+        authorizations: Vec::new(),
+        issued: 0,
+    }));
+
+    let server_state = state.clone();
+
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let state = server_state.clone();
+            tokio::task::spawn(async move {
+                let service = service_fn(move |request| handle(state.clone(), request));
+                serve_connection(stream, service).await;
+            });
+        }
+    });
+
+    AcmeServer { addr, state }
+}
+
+/// Routes requests to the different ACME resources.
+async fn handle(
+    state: Arc<Mutex<State>>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    if method != Method::POST {
+        let mut state = state.lock().unwrap();
+        let base = state.base.clone();
+        return Ok(match path.as_str() {
+            "/dir" => state.json(
+                StatusCode::OK,
+                json!({
+                    "newNonce": format!("{base}/nonce"),
+                    "newAccount": format!("{base}/account"),
+                    "newOrder": format!("{base}/order"),
+                }),
+            ),
+            "/nonce" => state.json(StatusCode::OK, Value::Null),
+            _ => state.problem(StatusCode::NOT_FOUND, "malformed", "not found"),
+        });
+    }
+
+    let body = request.into_body().collect().await.unwrap().to_bytes();
+
+    let (payload, jwk, account) = {
+        let mut state = state.lock().unwrap();
+        let url = format!("{}{path}", state.base);
+        match state.verify(&url, &body) {
+            Ok(verified) => verified,
+            Err(response) => return Ok(*response),
+        }
+    };
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    let response = match (segments.as_slice(), account) {
+        (["account"], _) => state.lock().unwrap().new_account(payload.unwrap(), jwk),
+        (["order"], Some(_)) => state.lock().unwrap().new_order(payload.unwrap()),
+        (["order", id], Some(_)) => state.lock().unwrap().order(id.parse().unwrap()),
+        (["authz", id], Some(_)) => state.lock().unwrap().authorization(id.parse().unwrap()),
+        (["chall", id, kind], Some(account)) => {
+            let id = id.parse().unwrap();
+            validate(&state, id, kind, account).await;
+            state.lock().unwrap().authorization(id)
+        }
+        (["finalize", id], Some(_)) => state
+            .lock()
+            .unwrap()
+            .finalize(id.parse().unwrap(), payload.unwrap()),
+        (["cert", id], Some(_)) => state.lock().unwrap().certificate(id.parse().unwrap()),
+        _ => state
+            .lock()
+            .unwrap()
+            .problem(StatusCode::NOT_FOUND, "malformed", "not found"),
+    };
+
+    Ok(response)
+}
+
+impl State {
+    /// Checks the JWS of a `POST` request. Returns the decoded payload, the
+    /// key that signed the request and its account if already registered.
+    fn verify(&mut self, url: &str, body: &[u8]) -> Result<Verified, Box<Response<Full<Bytes>>>> {
+        let jws: Value = serde_json::from_slice(body).unwrap();
+        let protected = jws["protected"].as_str().unwrap();
+        let payload = jws["payload"].as_str().unwrap();
+        let signature = decode(jws["signature"].as_str().unwrap());
+
+        let header: Value = serde_json::from_slice(&decode(protected)).unwrap();
+
+        if header["alg"] != "ES256" || header["url"] != url {
+            return Err(Box::new(self.problem(
+                StatusCode::BAD_REQUEST,
+                "malformed",
+                "bad header",
+            )));
+        }
+
+        let nonce = header["nonce"].as_str().unwrap_or_default();
+
+        // The first nonce is always rejected to make sure that clients retry.
+        if !self.nonces.remove(nonce) || !self.rejected_first_nonce {
+            self.rejected_first_nonce = true;
+            return Err(Box::new(self.problem(
+                StatusCode::BAD_REQUEST,
+                "badNonce",
+                "bad nonce",
+            )));
+        }
+
+        let (jwk, account) = match header["kid"].as_str() {
+            Some(kid) => {
+                let id: usize = kid.rsplit('/').next().unwrap().parse().unwrap();
+                (self.accounts[id].clone(), Some(id))
+            }
+            None => {
+                let jwk = header["jwk"].clone();
+                let id = self.accounts.iter().position(|account| *account == jwk);
+                (jwk, id)
+            }
+        };
+
+        let mut point = vec![0x04];
+        point.extend(decode(jwk["x"].as_str().unwrap()));
+        point.extend(decode(jwk["y"].as_str().unwrap()));
+
+        let signed = format!("{protected}.{payload}");
+
+        if UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(signed.as_bytes(), &signature)
+            .is_err()
+        {
+            return Err(Box::new(self.problem(
+                StatusCode::BAD_REQUEST,
+                "malformed",
+                "bad signature",
+            )));
+        }
+
+        let payload = match payload {
+            "" => None,
+            payload => Some(serde_json::from_slice(&decode(payload)).unwrap()),
+        };
+
+        Ok((payload, jwk, account))
+    }
+
+    /// Registers the key or finds the existing account, as RFC 8555 requires.
+    fn new_account(&mut self, payload: Value, jwk: Value) -> Response<Full<Bytes>> {
+        assert_eq!(payload["termsOfServiceAgreed"], true);
+
+        let (status, id) = match self.accounts.iter().position(|account| *account == jwk) {
+            Some(id) => (StatusCode::OK, id),
+            None => {
+                self.accounts.push(jwk);
+                (StatusCode::CREATED, self.accounts.len() - 1)
+            }
+        };
+
+        let location = format!("{}/account/{id}", self.base);
+        let mut response = self.json(status, json!({ "status": "valid" }));
+        response
+            .headers_mut()
+            .insert(header::LOCATION, location.parse().unwrap());
+
+        response
+    }
+
+    fn new_order(&mut self, payload: Value) -> Response<Full<Bytes>> {
+        let mut authorizations = Vec::new();
+
+        for identifier in payload["identifiers"].as_array().unwrap() {
+            authorizations.push(self.authorizations.len());
+            self.authorizations.push(Authorization {
+                domain: identifier["value"].as_str().unwrap().to_owned(),
+                token: format!("token-{}", self.authorizations.len()),
+                status: "pending",
+                error: None,
+            });
+        }
+
+        self.orders.push(Order {
+            authorizations,
+            certificate: None,
+        });
+
+        let id = self.orders.len() - 1;
+        let mut response = self.order(id);
+        *response.status_mut() = StatusCode::CREATED;
+
+        response
+    }
+
+    fn order(&mut self, id: usize) -> Response<Full<Bytes>> {
+        let order = &self.orders[id];
+
+        let statuses: Vec<_> = order
+            .authorizations
+            .iter()
+            .map(|authz| self.authorizations[*authz].status)
+            .collect();
+
+        let status = if order.certificate.is_some() {
+            "valid"
+        } else if statuses.iter().all(|status| *status == "valid") {
+            "ready"
+        } else if statuses.contains(&"invalid") {
+            "invalid"
+        } else {
+            "pending"
+        };
+
+        let mut order_json = json!({
+            "status": status,
+            "identifiers": [],
+            "authorizations": order
+                .authorizations
+                .iter()
+                .map(|authz| format!("{}/authz/{authz}", self.base))
+                .collect::<Vec<_>>(),
+            "finalize": format!("{}/finalize/{id}", self.base),
+        });
+
+        if order.certificate.is_some() {
+            order_json["certificate"] = json!(format!("{}/cert/{id}", self.base));
+        }
+
+        let location = format!("{}/order/{id}", self.base);
+        let mut response = self.json(StatusCode::OK, order_json);
+        response
+            .headers_mut()
+            .insert(header::LOCATION, location.parse().unwrap());
+
+        response
+    }
+
+    fn authorization(&mut self, id: usize) -> Response<Full<Bytes>> {
+        let authz = &self.authorizations[id];
+
+        let challenges: Vec<Value> = ["http-01", "tls-alpn-01"]
+            .iter()
+            .map(|kind| {
+                let mut challenge = json!({
+                    "type": kind,
+                    "url": format!("{}/chall/{id}/{kind}", self.base),
+                    "token": authz.token,
+                    "status": authz.status,
+                });
+                if let Some(error) = &authz.error {
+                    challenge["error"] = json!({
+                        "type": "urn:ietf:params:acme:error:unauthorized",
+                        "detail": error,
+                    });
+                }
+                challenge
+            })
+            .collect();
+
+        let body = json!({
+            "identifier": { "type": "dns", "value": authz.domain },
+            "status": authz.status,
+            "challenges": challenges,
+        });
+
+        self.json(StatusCode::OK, body)
+    }
+
+    fn finalize(&mut self, id: usize, payload: Value) -> Response<Full<Bytes>> {
+        let csr = decode(payload["csr"].as_str().unwrap());
+        self.orders[id].certificate = Some(self.ca.sign_request(&csr));
+        self.issued += 1;
+
+        self.order(id)
+    }
+
+    fn certificate(&mut self, id: usize) -> Response<Full<Bytes>> {
+        let chain = self.orders[id].certificate.clone().unwrap();
+        let mut response = self.json(StatusCode::OK, Value::Null);
+        *response.body_mut() = Full::from(chain);
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            "application/pem-certificate-chain".parse().unwrap(),
+        );
+
+        response
+    }
+
+    /// JSON response with a fresh nonce.
+    fn json(&mut self, status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+        self.next_nonce += 1;
+        let nonce = format!("nonce-{}", self.next_nonce);
+        self.nonces.insert(nonce.clone());
+
+        let body = match body {
+            Value::Null => Bytes::new(),
+            body => Bytes::from(body.to_string()),
+        };
+
+        Response::builder()
+            .status(status)
+            .header("Replay-Nonce", nonce)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(body))
+            .unwrap()
+    }
+
+    /// Error document as described in RFC 8555 section 6.7.
+    fn problem(&mut self, status: StatusCode, kind: &str, detail: &str) -> Response<Full<Bytes>> {
+        let body = json!({
+            "type": format!("urn:ietf:params:acme:error:{kind}"),
+            "detail": detail,
+        });
+
+        let mut response = self.json(status, body);
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            "application/problem+json".parse().unwrap(),
+        );
+
+        response
+    }
+}
+
+/// Validates a challenge and updates the authorization status.
+async fn validate(state: &Mutex<State>, id: usize, kind: &str, account: usize) {
+    let (addr, domain, key_authorization) = {
+        let state = state.lock().unwrap();
+        let authz = &state.authorizations[id];
+        let key_authorization = format!("{}.{}", authz.token, thumbprint(&state.accounts[account]));
+        (
+            state.validation_addr.unwrap(),
+            authz.domain.clone(),
+            key_authorization,
+        )
+    };
+
+    let result = match kind {
+        "http-01" => validate_http_01(addr, &domain, &key_authorization).await,
+        "tls-alpn-01" => validate_tls_alpn_01(addr, &domain, &key_authorization).await,
+        _ => Err(format!("unknown challenge {kind}")),
+    };
+
+    let mut state = state.lock().unwrap();
+    let authz = &mut state.authorizations[id];
+
+    match result {
+        Ok(()) => authz.status = "valid",
+        Err(error) => {
+            authz.status = "invalid";
+            authz.error = Some(error);
+        }
+    }
+}
+
+/// Fetches the key authorization from the well known path.
+async fn validate_http_01(
+    addr: SocketAddr,
+    domain: &str,
+    key_authorization: &str,
+) -> Result<(), String> {
+    let token = key_authorization.split('.').next().unwrap();
+
+    let request = Request::builder()
+        .uri(format!("/.well-known/acme-challenge/{token}"))
+        .header(header::HOST, domain)
+        .body(Full::<Bytes>::default())
+        .unwrap();
+
+    let (parts, body) = send_http_request(addr, request).await;
+
+    if parts.status != StatusCode::OK || body != key_authorization {
+        return Err(format!("unexpected response {}: {body:?}", parts.status));
+    }
+
+    Ok(())
+}
+
+/// Performs a TLS handshake negotiating `acme-tls/1` and checks the digest in
+/// the `acmeIdentifier` extension of the certificate.
+async fn validate_tls_alpn_01(
+    addr: SocketAddr,
+    domain: &str,
+    key_authorization: &str,
+) -> Result<(), String> {
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+            .with_no_client_auth();
+
+    config.alpn_protocols = vec![b"acme-tls/1".to_vec()];
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(domain.to_owned()).unwrap(), stream)
+        .await
+        .map_err(|err| format!("handshake failed: {err}"))?;
+
+    let (_, connection) = stream.get_ref();
+
+    if connection.alpn_protocol() != Some(b"acme-tls/1") {
+        return Err(String::from("acme-tls/1 not negotiated"));
+    }
+
+    let der = &connection.peer_certificates().unwrap()[0];
+    let (_, cert) = X509Certificate::from_der(der).unwrap();
+
+    let extension = cert
+        .extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == ACME_IDENTIFIER_OID)
+        .ok_or_else(|| String::from("missing acmeIdentifier extension"))?;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+
+    // DER encoded OCTET STRING containing the digest.
+    let mut expected = vec![0x04, 0x20];
+    expected.extend_from_slice(digest.as_ref());
+
+    if !extension.critical || extension.value != expected {
+        return Err(String::from("wrong acmeIdentifier extension"));
+    }
+
+    Ok(())
+}
+
+/// JWK thumbprint as described in RFC 7638.
+fn thumbprint(jwk: &Value) -> String {
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+        jwk["x"], jwk["y"]
+    );
+
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        canonical.as_bytes(),
+    ))
+}
+
+fn decode(data: &str) -> Vec<u8> {
+    URL_SAFE_NO_PAD.decode(data).unwrap()
+}
+
+/// The validation certificate is self-signed, only the extension matters.
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -3,10 +3,22 @@
 pub mod proxy {
     //! Proxy specific configurations.
 
-    use std::net::SocketAddr;
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
     use rxh::{
-        config::{Action, Algorithm, Backend, Forward, Pattern, Server},
+        config::{
+            Action,
+            Algorithm,
+            Backend,
+            BackendTls,
+            Forward,
+            Http2,
+            Pattern,
+            Protocol,
+            Server,
+            Socket,
+            Udp,
+        },
         sched,
     };
 
@@ -18,11 +30,28 @@ pub mod proxy {
     /// Forwards all requests to a single backend server when the request URI
     /// matches the given URI.
     pub fn single_backend_with_uri(address: SocketAddr, uri: &str) -> Server {
-        let backends = vec![Backend { address, weight: 1 }];
+        let backends = vec![Backend {
+            address: address.into(),
+            weight: 1,
+            tls: None,
+            send_proxy_protocol: None,
+        }];
 
         multiple_weighted_backends_with_uri(backends, uri)
     }
 
+    /// Forwards all requests to a single backend server using TLS.
+    pub fn single_tls_backend(address: SocketAddr, tls: BackendTls) -> Server {
+        let backends = vec![Backend {
+            address: address.into(),
+            weight: 1,
+            tls: Some(tls),
+            send_proxy_protocol: None,
+        }];
+
+        multiple_weighted_backends(backends)
+    }
+
     /// Forwards requests to multiple backend servers using the default
     /// algorithm (WRR).
     pub fn multiple_weighted_backends(backends: Vec<Backend>) -> Server {
@@ -44,9 +73,21 @@ pub mod proxy {
             name: None,
             log_name: String::from("unnamed"),
             listen: vec!["127.0.0.1:0".parse().unwrap()],
+            protocol: Protocol::Http,
             max_connections: 1024,
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+            http2: Http2::default(),
+            http3: None,
+            udp: Udp::default(),
+            proxy_protocol: None,
+            socket: Socket::default(),
+            mime_types: HashMap::new(),
+            nosniff: false,
             patterns: vec![Pattern {
                 uri: String::from(uri),
+                sni: None,
+                client_auth: true,
                 action: Action::Forward(forward),
             }],
         }
@@ -56,7 +97,34 @@ pub mod proxy {
 pub mod files {
     //! Static files server configurations.
 
-    use rxh::config::{Action, Pattern, Server};
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+    use rxh::config::{
+        Action,
+        Compression,
+        ETag,
+        Fallback,
+        Files,
+        Http2,
+        Pattern,
+        Protocol,
+        Server,
+        Socket,
+        Udp,
+    };
+
+    /// Default options for serving files from `root`.
+    pub fn root(root: &str) -> Files {
+        Files {
+            root: String::from(root),
+            etag: ETag::default(),
+            compression: Compression::default(),
+            index: vec![String::from("index.html")],
+            autoindex: false,
+            try_files: Vec::new(),
+            fallback: Fallback::default(),
+        }
+    }
 
     /// Serves files from `root` for all requests.
     pub fn serve(root: &str) -> Server {
@@ -65,15 +133,137 @@ pub mod files {
 
     /// Serves files from `root` if the request URI matchees `uri`.
     pub fn serve_at_uri(root: &str, uri: &str) -> Server {
+        serve_files_at_uri(self::root(root), uri)
+    }
+
+    /// Serves files with custom options for all requests.
+    pub fn serve_files(files: Files) -> Server {
+        serve_files_at_uri(files, "/")
+    }
+
+    /// Serves files with custom options if the request URI matches `uri`.
+    pub fn serve_files_at_uri(files: Files, uri: &str) -> Server {
         Server {
             name: None,
             log_name: String::from("unnamed"),
             listen: vec!["127.0.0.1:0".parse().unwrap()],
+            protocol: Protocol::Http,
             max_connections: 1024,
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+            http2: Http2::default(),
+            http3: None,
+            udp: Udp::default(),
+            proxy_protocol: None,
+            socket: Socket::default(),
+            mime_types: HashMap::new(),
+            nosniff: false,
             patterns: vec![Pattern {
                 uri: String::from(uri),
-                action: Action::Serve(String::from(root)),
+                sni: None,
+                client_auth: true,
+                action: Action::Serve(files),
             }],
         }
     }
+
+    /// Fallback that forwards requests to a single backend server.
+    pub fn fallback_to(address: SocketAddr) -> Fallback {
+        let mut config = super::proxy::single_backend(address);
+
+        let Action::Forward(forward) = config.patterns.remove(0).action else {
+            unreachable!("proxy configs always forward");
+        };
+
+        Fallback::Forward(forward)
+    }
+}
+
+pub mod tcp {
+    //! Layer 4 proxy configurations.
+
+    use std::net::SocketAddr;
+
+    use rxh::config::{Backend, Protocol, Server};
+
+    /// Load balances TCP connections between `backends` using WRR with equal
+    /// weights.
+    pub fn forward(backends: &[SocketAddr]) -> Server {
+        let backends = backends
+            .iter()
+            .map(|address| Backend {
+                address: (*address).into(),
+                weight: 1,
+                tls: None,
+                send_proxy_protocol: None,
+            })
+            .collect();
+
+        let mut server = super::proxy::multiple_weighted_backends(backends);
+        server.protocol = Protocol::Tcp;
+
+        server
+    }
+}
+
+pub mod udp {
+    //! UDP relay configurations.
+
+    use std::net::SocketAddr;
+
+    use rxh::config::{Protocol, Server};
+
+    /// Load balances UDP sessions between `backends` using WRR with equal
+    /// weights.
+    pub fn forward(backends: &[SocketAddr]) -> Server {
+        let mut server = super::tcp::forward(backends);
+        server.protocol = Protocol::Udp;
+
+        server
+    }
+}
+
+pub mod tls_passthrough {
+    //! TLS passthrough configurations.
+
+    use std::net::SocketAddr;
+
+    use rxh::{
+        config::{Action, Algorithm, Backend, Forward, Pattern, Protocol, Server},
+        sched,
+    };
+
+    /// Forwards TLS connections to the backend of the first route whose server
+    /// name matches the SNI sent by the client. Routes without name match all
+    /// connections.
+    pub fn routes(routes: &[(Option<&str>, SocketAddr)]) -> Server {
+        let patterns = routes
+            .iter()
+            .map(|(sni, address)| {
+                let backends = vec![Backend {
+                    address: (*address).into(),
+                    weight: 1,
+                    tls: None,
+                    send_proxy_protocol: None,
+                }];
+
+                Pattern {
+                    uri: String::from("/"),
+                    sni: sni.map(String::from),
+                    client_auth: true,
+                    action: Action::Forward(Forward {
+                        scheduler: sched::make(Algorithm::Wrr, &backends),
+                        algorithm: Algorithm::Wrr,
+                        backends,
+                    }),
+                }
+            })
+            .collect();
+
+        let mut server = super::tcp::forward(&[routes[0].1]);
+        server.protocol = Protocol::TlsPassthrough;
+        server.patterns = patterns;
+
+        server
+    }
 }
@@ -0,0 +1,62 @@
+//! Static files utilities for integration tests.
+
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// Creates a file of `len` bytes at `path` without writing all of them, so
+/// that large files don't take disk space or time. All bytes are zero except
+/// the last ones, which are `tail`.
+pub async fn create_sparse_file(path: &Path, len: u64, tail: &[u8]) {
+    let mut file = tokio::fs::File::create(path).await.unwrap();
+    file.set_len(len).await.unwrap();
+    file.seek(std::io::SeekFrom::End(-(tail.len() as i64)))
+        .await
+        .unwrap();
+    file.write_all(tail).await.unwrap();
+}
+
+/// Memory used by this process in bytes, as reported by the kernel. Only
+/// available on Linux.
+pub fn resident_memory() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+    // Page size is 4 KiB on all the platforms we run tests on.
+    Some(pages * 4096)
+}
+
+/// Creates a file at `path` with the given `content` that was last modified
+/// an hour ago, so that its modification date is a strong validator.
+pub async fn create_old_file(path: &Path, content: &[u8]) {
+    tokio::fs::write(path, content).await.unwrap();
+
+    let modified = SystemTime::now() - Duration::from_secs(3600);
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+/// Decompresses `body` according to the `Content-Encoding` of the response.
+pub async fn decode(content_encoding: &str, body: &[u8]) -> Vec<u8> {
+    use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder};
+    use tokio::io::AsyncReadExt;
+
+    let mut decoded = Vec::new();
+
+    match content_encoding {
+        "br" => BrotliDecoder::new(body).read_to_end(&mut decoded).await,
+        "zstd" => ZstdDecoder::new(body).read_to_end(&mut decoded).await,
+        "gzip" => GzipDecoder::new(body).read_to_end(&mut decoded).await,
+        other => panic!("unexpected content encoding {other}"),
+    }
+    .unwrap();
+
+    decoded
+}
@@ -13,15 +13,17 @@ use bytes::Bytes;
 use http_body_util::{BodyExt, Empty};
 use hyper::{
     body::Incoming,
-    client::conn::http1::SendRequest,
+    client::conn::{http1::SendRequest, http2},
     service::{service_fn, Service},
     Request,
     Response,
 };
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use rxh::config::Backend;
 use tokio::{
     self,
-    net::{TcpSocket, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpSocket,
     sync::{oneshot, watch},
     task::JoinHandle,
 };
@@ -60,7 +62,12 @@ pub fn spawn_backend_server_with_request_counter(weight: usize) -> (Backend, Arc
     let owned_request_counter = request_counter.clone();
 
     let (listener, address) = usable_tcp_listener();
-    let backend = Backend { address, weight };
+    let backend = Backend {
+        address: address.into(),
+        weight,
+        tls: None,
+        send_proxy_protocol: None,
+    };
 
     tokio::task::spawn(async move {
         loop {
@@ -98,7 +105,7 @@ pub fn spawn_backends_with_request_counters(
 pub fn spawn_reverse_proxy(config: rxh::config::Server) -> (SocketAddr, JoinHandle<()>) {
     let server = rxh::Server::init(config, 0).unwrap();
 
-    let addr = server.socket_address();
+    let addr = server.socket_address().as_tcp().unwrap();
 
     let handle = tokio::task::spawn(async {
         server.run().await.unwrap();
@@ -121,7 +128,7 @@ pub fn spawn_reverse_proxy_with_controllers(
 
     let server = rxh::Server::init(config, 0).unwrap().shutdown_on(rx);
 
-    let addr = server.socket_address();
+    let addr = server.socket_address().as_tcp().unwrap();
     let state = server.subscribe();
 
     let handle = tokio::task::spawn(async {
@@ -135,7 +142,7 @@ pub fn spawn_reverse_proxy_with_controllers(
 /// like [`spawn_reverse_proxy_with_controllers`].
 pub fn spawn_master(config: rxh::config::Config) -> (Vec<SocketAddr>, JoinHandle<()>) {
     let master = rxh::Master::init(config).unwrap();
-    let sockets = master.sockets();
+    let sockets = tcp_sockets(master.sockets());
     let handle = tokio::task::spawn(async move {
         master.run().await.unwrap();
     });
@@ -143,10 +150,54 @@ pub fn spawn_master(config: rxh::config::Config) -> (Vec<SocketAddr>, JoinHandle
     (sockets, handle)
 }
 
+/// Same as [`spawn_master`] but also returns a handle that can be used to
+/// reload the configuration.
+pub fn spawn_master_with_reload_handle(
+    config: rxh::config::Config,
+) -> (Vec<SocketAddr>, JoinHandle<()>, rxh::ReloadHandle) {
+    let master = rxh::Master::init(config).unwrap();
+    let sockets = tcp_sockets(master.sockets());
+    let reload = master.reload_handle();
+    let handle = tokio::task::spawn(async move {
+        master.run().await.unwrap();
+    });
+
+    (sockets, handle, reload)
+}
+
+/// Maps the sockets returned by [`rxh::Master`] to their TCP addresses.
+pub fn tcp_sockets(sockets: Vec<rxh::config::Address>) -> Vec<SocketAddr> {
+    sockets
+        .iter()
+        .map(|socket| socket.as_tcp().unwrap())
+        .collect()
+}
+
 /// Provides an HTTP client that spawns a connection object in the background
 /// to manage request transmissions.
-pub async fn http_client<B: AsyncBody>(stream: TcpStream) -> SendRequest<B> {
-    let (sender, conn) = hyper::client::conn::http1::handshake(stream).await.unwrap();
+pub async fn http_client<B, S>(stream: S) -> SendRequest<B>
+where
+    B: AsyncBody,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::task::spawn(async move { conn.with_upgrades().await.unwrap() });
+
+    sender
+}
+
+/// Same as [`http_client`] but speaks HTTP/2. Works with prior knowledge on
+/// plain TCP streams or on TLS streams that negotiated `h2`.
+pub async fn http2_client<B, S>(stream: S) -> http2::SendRequest<B>
+where
+    B: AsyncBody + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+        .await
+        .unwrap();
     tokio::task::spawn(async move { conn.await.unwrap() });
 
     sender
@@ -177,6 +228,27 @@ where
     send_http_request_from(usable_socket().0, to, req).await
 }
 
+/// Sends a GET request for `target` written by hand, so that clients can't
+/// normalize or reject the request target before it reaches the server.
+/// Returns the status code and the body, which must not be chunked.
+pub async fn send_raw_http_request(to: SocketAddr, target: &str) -> (u16, Vec<u8>) {
+    let mut stream = usable_socket().0.connect(to).await.unwrap();
+
+    let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    let status = std::str::from_utf8(&response[9..12])
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body_start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+
+    (status, response.split_off(body_start))
+}
+
 /// Same as [`send_http_request_from`] but runs as a different task. This allows
 /// the current task to continue execution.
 pub fn spawn_client<B>(target: SocketAddr, req: Request<B>) -> (SocketAddr, JoinHandle<()>)
@@ -1,7 +1,12 @@
 //! Some nice utilities for writing automated tests for servers and reverse
 //! proxies running on the same tokio runtime.
 
+pub mod acme;
 pub mod config;
+pub mod files;
 pub mod http;
+pub mod quic;
 pub mod service;
 pub mod tcp;
+pub mod tls;
+pub mod udp;
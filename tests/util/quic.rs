@@ -0,0 +1,58 @@
+//! HTTP/3 client for integration tests.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::Request;
+use quinn::{crypto::rustls::QuicClientConfig, Endpoint};
+use rustls::ClientConfig;
+
+/// Sends an HTTP/3 request to the QUIC endpoint at `to` verifying that its
+/// certificate is valid for `server_name`, and collects the response. Errors
+/// are returned as strings because they come from different layers (QUIC,
+/// TLS and HTTP/3) and tests only need to know that something failed.
+pub async fn send_http3_request(
+    to: SocketAddr,
+    server_name: &str,
+    mut config: ClientConfig,
+    req: Request<()>,
+) -> Result<(http::response::Parts, Bytes), String> {
+    config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = QuicClientConfig::try_from(config).map_err(|err| err.to_string())?;
+
+    let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+
+    let connection = endpoint
+        .connect(to, server_name)
+        .map_err(|err| err.to_string())?
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(connection))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    tokio::task::spawn(async move { driver.wait_idle().await });
+
+    let mut stream = sender
+        .send_request(req)
+        .await
+        .map_err(|err| err.to_string())?;
+    stream.finish().await.map_err(|err| err.to_string())?;
+
+    let (parts, ()) = stream
+        .recv_response()
+        .await
+        .map_err(|err| err.to_string())?
+        .into_parts();
+
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = stream.recv_data().await.map_err(|err| err.to_string())? {
+        body.put(chunk);
+    }
+
+    Ok((parts, body.freeze()))
+}
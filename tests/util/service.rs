@@ -10,7 +10,12 @@ use hyper::{
     Request,
     Response,
 };
-use tokio::{self, net::TcpStream, sync::mpsc};
+use hyper_util::rt::TokioIo;
+use tokio::{
+    self,
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
 
 /// Backend server that can run on different tasks and shares every request that
 /// it receives on a channel. This allows us to write cleaner tests where all
@@ -32,7 +37,7 @@ impl Service<Request<Incoming>> for RequestInterceptor {
 
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
         let tx = self.tx.clone();
         Box::pin(async move {
             let (parts, body) = req.into_parts();
@@ -50,16 +55,18 @@ impl Service<Request<Incoming>> for RequestInterceptor {
 /// Trait alias for request and response generic body bounds.
 pub trait AsyncBody = Body<Data: Send, Error: Sync + Send + std::error::Error> + Send + 'static;
 
-/// Serves HTTP connection using [`service_fn`].
-pub async fn serve_connection<S, B>(stream: TcpStream, service: S)
+/// Serves HTTP connection using [`service_fn`]. The stream can be a plain TCP
+/// stream or a TLS stream.
+pub async fn serve_connection<I, S, B>(stream: I, service: S)
 where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible>,
     B: AsyncBody,
 {
     hyper::server::conn::http1::Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
-        .serve_connection(stream, service)
+        .serve_connection(TokioIo::new(stream), service)
         .with_upgrades()
         .await
         .unwrap();
@@ -4,7 +4,7 @@ use std::net::SocketAddr;
 
 use tokio::{
     self,
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpSocket, TcpStream},
 };
 
@@ -63,3 +63,29 @@ pub async fn ping_all(addrs: &[SocketAddr]) {
         ping_tcp_server(*addr).await;
     }
 }
+
+/// Spawns a TCP server that sends `greeting` to every client and then echoes
+/// back whatever it receives.
+pub fn spawn_echo_server(greeting: &'static [u8]) -> SocketAddr {
+    let (listener, addr) = usable_tcp_listener();
+
+    tokio::task::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            tokio::task::spawn(async move {
+                stream.write_all(greeting).await.unwrap();
+
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
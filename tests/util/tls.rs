@@ -0,0 +1,296 @@
+//! TLS utilities for integration tests. Certificates are generated on the fly
+//! with [`rcgen`] and written to a temporary directory because RXH reads them
+//! from files, just like it would in production.
+
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    body::Incoming,
+    client::conn::http1::SendRequest,
+    service::Service,
+    Request,
+    Response,
+};
+use hyper_util::rt::TokioIo;
+use rcgen::{
+    BasicConstraints,
+    Certificate,
+    CertificateParams,
+    CertificateSigningRequestParams,
+    DnType,
+    ExtendedKeyUsagePurpose,
+    IsCa,
+    KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::{
+    pki_types::ServerName,
+    server::WebPkiClientVerifier,
+    ClientConfig,
+    RootCertStore,
+    ServerConfig,
+};
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+
+use super::{
+    service::{serve_connection, AsyncBody},
+    tcp::usable_tcp_listener,
+};
+
+/// Certificate authority that can issue certificates for servers and clients.
+/// All the files are stored in a temporary directory that is removed when the
+/// CA is dropped.
+pub struct TestCa {
+    dir: TempDir,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+/// Paths of a certificate and its private key.
+pub struct CertFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TestCa {
+    /// Generates a new self-signed CA.
+    pub fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "RXH Test CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ca.pem"), cert.pem()).unwrap();
+
+        Self { dir, cert, key }
+    }
+
+    /// Path of the PEM file that contains the CA certificate.
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    /// Issues a certificate valid for `name`, which can be a DNS name or an IP
+    /// address. The certificate can be used both by servers and clients.
+    pub fn issue(&self, name: &str) -> CertFiles {
+        let mut params = CertificateParams::new(vec![String::from(name)]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+        let files = CertFiles {
+            cert: self.dir.path().join(format!("{name}.pem")),
+            key: self.dir.path().join(format!("{name}.key")),
+        };
+
+        std::fs::write(&files.cert, cert.pem()).unwrap();
+        std::fs::write(&files.key, key.serialize_pem()).unwrap();
+
+        files
+    }
+
+    /// Issues a certificate for a DER encoded certificate signing request and
+    /// returns the PEM chain, including the CA certificate.
+    pub fn sign_request(&self, csr: &[u8]) -> String {
+        let csr = CertificateSigningRequestParams::from_der(&csr.to_vec().into()).unwrap();
+        let cert = csr.signed_by(&self.cert, &self.key).unwrap();
+
+        format!("{}{}", cert.pem(), self.cert.pem())
+    }
+}
+
+/// Reads all the certificates in a PEM file into a [`RootCertStore`].
+fn root_store(ca: &Path) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut std::fs::read(ca).unwrap().as_slice()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+
+    roots
+}
+
+/// Reads a certificate chain and private key from disk.
+fn cert_and_key(
+    files: &CertFiles,
+) -> (
+    Vec<rustls::pki_types::CertificateDer<'static>>,
+    rustls::pki_types::PrivateKeyDer<'static>,
+) {
+    let certs = rustls_pemfile::certs(&mut std::fs::read(&files.cert).unwrap().as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let key = rustls_pemfile::private_key(&mut std::fs::read(&files.key).unwrap().as_slice())
+        .unwrap()
+        .unwrap();
+
+    (certs, key)
+}
+
+/// Builds a [`rustls`] server config using the given certificate. If
+/// `client_ca` is provided, clients must send a certificate signed by it.
+pub fn server_config(files: &CertFiles, client_ca: Option<&Path>) -> ServerConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let (certs, key) = cert_and_key(files);
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap();
+
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(root_store(ca).into(), provider)
+                    .build()
+                    .unwrap();
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder.with_single_cert(certs, key).unwrap()
+}
+
+/// Builds a [`rustls`] client config that trusts the given CA. If `identity`
+/// is provided, the client sends that certificate when the server asks for
+/// one.
+pub fn client_config(ca: &Path, identity: Option<&CertFiles>) -> ClientConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store(ca));
+
+    match identity {
+        Some(files) => {
+            let (certs, key) = cert_and_key(files);
+            builder.with_client_auth_cert(certs, key).unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    }
+}
+
+/// Connects to a TLS server at `to` verifying that its certificate is valid
+/// for `server_name`.
+pub async fn tls_connect(
+    to: SocketAddr,
+    server_name: &str,
+    config: ClientConfig,
+) -> Result<TlsStream<TcpStream>, io::Error> {
+    let stream = TcpStream::connect(to).await?;
+    let server_name = ServerName::try_from(String::from(server_name)).unwrap();
+
+    TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+}
+
+/// Connects to a TLS server with [`tls_connect`] and returns an HTTP client
+/// for that connection. Errors are returned instead of panicking because some
+/// tests expect the server to reject the client.
+pub async fn https_client<B: AsyncBody>(
+    to: SocketAddr,
+    server_name: &str,
+    config: ClientConfig,
+) -> Result<SendRequest<B>, io::Error> {
+    let stream = tls_connect(to, server_name, config).await?;
+
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+
+    tokio::task::spawn(async move {
+        let _ = conn.await;
+    });
+
+    Ok(sender)
+}
+
+/// Sends an HTTPS request and collects the response. See [`https_client`].
+pub async fn send_https_request<B: AsyncBody>(
+    to: SocketAddr,
+    server_name: &str,
+    config: ClientConfig,
+    req: Request<B>,
+) -> Result<(http::response::Parts, Bytes), io::Error> {
+    let mut sender = https_client(to, server_name, config).await?;
+
+    let (parts, body) = sender
+        .send_request(req)
+        .await
+        .map_err(io::Error::other)?
+        .into_parts();
+
+    let body = body.collect().await.map_err(io::Error::other)?.to_bytes();
+
+    Ok((parts, body))
+}
+
+/// Keeps sending requests until the server presents a certificate trusted by
+/// `config`, which is useful when certificates are obtained in the background.
+/// Panics if that doesn't happen within a few seconds.
+pub async fn wait_for_trusted_certificate(to: SocketAddr, server_name: &str, config: ClientConfig) {
+    let request = || Request::builder().body(Empty::<Bytes>::new()).unwrap();
+
+    let wait = async {
+        while send_https_request(to, server_name, config.clone(), request())
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .expect("server never presented a trusted certificate");
+}
+
+/// Same as [`super::http::spawn_backend_server`] but the backend only accepts
+/// TLS connections. Failed handshakes are ignored so that tests can check
+/// what the proxy does when the backend can't be trusted.
+pub fn spawn_tls_backend_server<S, B>(service: S, config: ServerConfig) -> SocketAddr
+where
+    S: Service<Request<Incoming>, Response = Response<B>, Error = Infallible, Future: Send>
+        + Send
+        + Copy
+        + 'static,
+    B: AsyncBody,
+{
+    let (listener, addr) = usable_tcp_listener();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = acceptor.accept(stream).await {
+                serve_connection(stream, service).await;
+            }
+        }
+    });
+
+    addr
+}
@@ -0,0 +1,39 @@
+//! UDP utilities for integration tests.
+
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+/// Spawns a UDP server that replies to every datagram with `name` followed by
+/// the datagram itself, so that tests know which backend replied.
+pub async fn spawn_udp_echo_server(name: &'static [u8]) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    tokio::task::spawn(async move {
+        let mut buf = [0; 1024];
+
+        loop {
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let reply = [name, &buf[..len]].concat();
+            socket.send_to(&reply, client).await.unwrap();
+        }
+    });
+
+    addr
+}
+
+/// Sends `datagram` from `socket` to `addr` and returns the reply.
+pub async fn exchange(socket: &UdpSocket, addr: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    socket.send_to(datagram, addr).await.unwrap();
+
+    let mut buf = [0; 1024];
+    let len = socket.recv(&mut buf).await.unwrap();
+
+    buf[..len].to_vec()
+}
+
+/// Binds a client socket on a random port.
+pub async fn udp_client() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").await.unwrap()
+}